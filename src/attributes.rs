@@ -0,0 +1,382 @@
+use crate::dom::{DomError, Namespace};
+use std::fmt;
+use std::slice;
+
+/// One attribute of an element. `name` is the qualified name, prefix included.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Attribute {
+    pub name: String,
+    pub value: String,
+    pub namespace: Option<Namespace>,
+}
+
+impl Attribute {
+    /// The name without its prefix, `href` for `xlink:href`.
+    pub fn local_name(&self) -> &str {
+        match self.name.split_once(':') {
+            Some((_, local)) if self.namespace.is_some() => local,
+            _ => &self.name,
+        }
+    }
+}
+
+/// The attributes of an element in the order they were written, or set for
+/// the first time.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AttrList {
+    attributes: Vec<Attribute>,
+}
+
+impl AttrList {
+    pub fn new() -> Self {
+        Self {
+            attributes: Vec::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.attributes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.attributes.is_empty()
+    }
+
+    pub fn iter(&self) -> slice::Iter<'_, Attribute> {
+        self.attributes.iter()
+    }
+
+    /// The value of the attribute with this qualified name, compared exactly.
+    pub fn get(&self, name: &str) -> Option<&String> {
+        self.find(name).map(|i| &self.attributes[i].value)
+    }
+
+    // https://dom.spec.whatwg.org/#concept-element-attributes-get-by-namespace
+    pub fn get_ns(&self, namespace: Option<Namespace>, local_name: &str) -> Option<&String> {
+        self.attributes
+            .iter()
+            .find(|a| a.namespace == namespace && a.local_name() == local_name)
+            .map(|a| &a.value)
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.find(name).is_some()
+    }
+
+    /// Changes the value of `name` where it stands, or adds it at the end.
+    pub fn set(&mut self, name: &str, value: &str, namespace: Option<Namespace>) {
+        match self.find(name) {
+            Some(i) => {
+                self.attributes[i].value = value.to_owned();
+                self.attributes[i].namespace = namespace;
+            }
+            None => self.attributes.push(Attribute {
+                name: name.to_owned(),
+                value: value.to_owned(),
+                namespace,
+            }),
+        }
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<Attribute> {
+        self.find(name).map(|i| self.attributes.remove(i))
+    }
+
+    fn find(&self, name: &str) -> Option<usize> {
+        self.attributes.iter().position(|a| a.name == name)
+    }
+}
+
+// the tokenizer has already dropped duplicates, but the first one wins here as well
+impl FromIterator<(String, String)> for AttrList {
+    fn from_iter<I: IntoIterator<Item = (String, String)>>(iter: I) -> Self {
+        let mut list = AttrList::new();
+        for (name, value) in iter {
+            if !list.contains(&name) {
+                list.set(&name, &value, None);
+            }
+        }
+        list
+    }
+}
+
+impl<'a> IntoIterator for &'a AttrList {
+    type Item = &'a Attribute;
+    type IntoIter = slice::Iter<'a, Attribute>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+/// The classes of an element as an ordered set of tokens, like `classList` in
+/// the DOM. It is a copy: changes are written back with
+/// `ElementData::set_class_list` or `Document::set_class_list`.
+// https://dom.spec.whatwg.org/#interface-domtokenlist
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ClassList {
+    tokens: Vec<String>,
+}
+
+impl ClassList {
+    // https://dom.spec.whatwg.org/#concept-ordered-set-parser
+    pub fn parse(value: &str) -> Self {
+        let mut tokens: Vec<String> = Vec::new();
+        for token in value.split_ascii_whitespace() {
+            if !tokens.iter().any(|t| t == token) {
+                tokens.push(token.to_owned());
+            }
+        }
+        Self { tokens }
+    }
+
+    pub fn len(&self) -> usize {
+        self.tokens.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tokens.is_empty()
+    }
+
+    pub fn item(&self, index: usize) -> Option<&str> {
+        self.tokens.get(index).map(String::as_str)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &str> {
+        self.tokens.iter().map(String::as_str)
+    }
+
+    pub fn contains(&self, token: &str) -> bool {
+        self.tokens.iter().any(|t| t == token)
+    }
+
+    /// Adds every token in `tokens` that is not there yet.
+    pub fn add(&mut self, tokens: &[&str]) -> Result<(), DomError> {
+        Self::validate(tokens)?;
+        for &token in tokens {
+            if !self.contains(token) {
+                self.tokens.push(token.to_owned());
+            }
+        }
+        Ok(())
+    }
+
+    pub fn remove(&mut self, tokens: &[&str]) -> Result<(), DomError> {
+        Self::validate(tokens)?;
+        self.tokens.retain(|t| !tokens.contains(&&t[..]));
+        Ok(())
+    }
+
+    /// Removes `token` if it is there and adds it if not, or with `force` only
+    /// ever adds or only ever removes it. Returns whether it is there afterwards.
+    pub fn toggle(&mut self, token: &str, force: Option<bool>) -> Result<bool, DomError> {
+        Self::validate(&[token])?;
+        if self.contains(token) {
+            if force != Some(true) {
+                self.tokens.retain(|t| t != token);
+                return Ok(false);
+            }
+            return Ok(true);
+        }
+        if force != Some(false) {
+            self.tokens.push(token.to_owned());
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    /// Puts `new_token` where `token` was. Returns false if `token` was not there.
+    pub fn replace(&mut self, token: &str, new_token: &str) -> Result<bool, DomError> {
+        Self::validate(&[token, new_token])?;
+        let index = match self.tokens.iter().position(|t| t == token) {
+            Some(i) => i,
+            None => return Ok(false),
+        };
+        if self.contains(new_token) {
+            self.tokens.remove(index);
+            let first = self.tokens.iter().position(|t| t == new_token).unwrap();
+            // the new token keeps whichever of the two places came first
+            if index < first {
+                self.tokens.remove(first);
+                self.tokens.insert(index, new_token.to_owned());
+            }
+        } else {
+            self.tokens[index] = new_token.to_owned();
+        }
+        Ok(true)
+    }
+
+    // https://dom.spec.whatwg.org/#dom-domtokenlist-add
+    // every token is checked before any is used, so a bad one changes nothing
+    fn validate(tokens: &[&str]) -> Result<(), DomError> {
+        for token in tokens {
+            if token.is_empty() {
+                return Err(DomError::EmptyToken);
+            }
+            if token.contains(|c: char| c.is_ascii_whitespace()) {
+                return Err(DomError::InvalidCharacter);
+            }
+        }
+        Ok(())
+    }
+}
+
+// https://dom.spec.whatwg.org/#concept-ordered-set-serializer
+impl fmt::Display for ClassList {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.tokens.join(" "))
+    }
+}
+
+/// The `dir` attribute.
+// https://html.spec.whatwg.org/multipage/dom.html#the-dir-attribute
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Dir {
+    Ltr,
+    Rtl,
+    Auto,
+}
+
+impl Dir {
+    /// The keyword `value` stands for, ignoring case; None for anything else.
+    pub fn parse(value: &str) -> Option<Self> {
+        match &value.to_ascii_lowercase()[..] {
+            "ltr" => Some(Dir::Ltr),
+            "rtl" => Some(Dir::Rtl),
+            "auto" => Some(Dir::Auto),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Dir::Ltr => "ltr",
+            Dir::Rtl => "rtl",
+            Dir::Auto => "auto",
+        }
+    }
+}
+
+// https://html.spec.whatwg.org/multipage/common-microsyntaxes.html#rules-for-parsing-integers
+pub fn parse_integer(value: &str) -> Option<i32> {
+    let value = value.trim_start_matches(|c: char| c.is_ascii_whitespace());
+    let (negative, digits) = match value.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, value.strip_prefix('+').unwrap_or(value)),
+    };
+    let end = digits
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(digits.len());
+    if end == 0 {
+        return None;
+    }
+    let magnitude: i64 = digits[..end].parse().ok()?;
+    let n = if negative { -magnitude } else { magnitude };
+    i32::try_from(n).ok()
+}
+
+// https://html.spec.whatwg.org/multipage/dom.html#dom-dataset
+// `data-foo-bar` is `fooBar` in the dataset
+pub(crate) fn dataset_name(attribute: &str) -> Option<String> {
+    let rest = attribute.strip_prefix("data-")?;
+    let mut name = String::new();
+    let mut chars = rest.chars().peekable();
+    while let Some(c) = chars.next() {
+        match chars.peek() {
+            Some(&next) if c == '-' && next.is_ascii_lowercase() => {
+                name.push(next.to_ascii_uppercase());
+                chars.next();
+            }
+            _ => name.push(c),
+        }
+    }
+    Some(name)
+}
+
+// the other way round; a dash followed by a lowercase letter could never have
+// come from an attribute name
+pub(crate) fn dataset_attribute(name: &str) -> Result<String, DomError> {
+    let mut chars = name.chars().peekable();
+    let mut attribute = String::from("data-");
+    while let Some(c) = chars.next() {
+        if c == '-' && chars.peek().is_some_and(char::is_ascii_lowercase) {
+            return Err(DomError::InvalidCharacter);
+        }
+        if c.is_ascii_uppercase() {
+            attribute.push('-');
+            attribute.push(c.to_ascii_lowercase());
+        } else {
+            attribute.push(c);
+        }
+    }
+    Ok(attribute)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn class_list_is_an_ordered_set() {
+        let mut list = ClassList::parse("  b a\tb  c ");
+        assert_eq!(list.to_string(), "b a c");
+        list.add(&["d", "a", "d"]).unwrap();
+        assert_eq!(list.to_string(), "b a c d");
+        list.remove(&["a", "x", "b"]).unwrap();
+        assert_eq!(list.to_string(), "c d");
+        assert_eq!(list.toggle("c", None), Ok(false));
+        assert_eq!(list.toggle("e", None), Ok(true));
+        assert_eq!(list.toggle("e", Some(true)), Ok(true));
+        assert_eq!(list.toggle("f", Some(false)), Ok(false));
+        assert_eq!(list.to_string(), "d e");
+        assert_eq!(list.replace("x", "y"), Ok(false));
+        assert_eq!(list.replace("d", "f"), Ok(true));
+        assert_eq!(list.to_string(), "f e");
+        // replacing with a token that is already there keeps the earlier place
+        assert_eq!(list.replace("e", "f"), Ok(true));
+        assert_eq!(list.to_string(), "f");
+        list.add(&["g"]).unwrap();
+        assert_eq!(list.replace("f", "g"), Ok(true));
+        assert_eq!(list.to_string(), "g");
+    }
+
+    #[test]
+    fn class_list_rejects_empty_and_spaced_tokens() {
+        let mut list = ClassList::parse("a");
+        assert_eq!(list.add(&["b", ""]), Err(DomError::EmptyToken));
+        assert_eq!(list.add(&["b", "c d"]), Err(DomError::InvalidCharacter));
+        assert_eq!(list.remove(&[""]), Err(DomError::EmptyToken));
+        assert_eq!(list.remove(&["a\n"]), Err(DomError::InvalidCharacter));
+        assert_eq!(list.toggle("", None), Err(DomError::EmptyToken));
+        assert_eq!(
+            list.toggle(" a", Some(true)),
+            Err(DomError::InvalidCharacter)
+        );
+        assert_eq!(list.replace("a", ""), Err(DomError::EmptyToken));
+        assert_eq!(list.replace("a b", "c"), Err(DomError::InvalidCharacter));
+        // nothing was changed by the calls that failed
+        assert_eq!(list.to_string(), "a");
+    }
+
+    #[test]
+    fn dataset_names_are_camel_cased_attribute_names() {
+        assert_eq!(dataset_name("data-foo-bar").as_deref(), Some("fooBar"));
+        assert_eq!(dataset_name("data-foo").as_deref(), Some("foo"));
+        assert_eq!(dataset_name("data-").as_deref(), Some(""));
+        // only a dash before a lowercase letter goes
+        assert_eq!(dataset_name("data-foo-1").as_deref(), Some("foo-1"));
+        assert_eq!(dataset_name("data--foo").as_deref(), Some("Foo"));
+        assert_eq!(dataset_name("datafoo"), None);
+        assert_eq!(dataset_attribute("fooBar").unwrap(), "data-foo-bar");
+        assert_eq!(dataset_attribute("foo-1").unwrap(), "data-foo-1");
+        assert_eq!(dataset_attribute("Foo").unwrap(), "data--foo");
+        assert_eq!(
+            dataset_attribute("foo-bar"),
+            Err(DomError::InvalidCharacter)
+        );
+        for name in ["fooBar", "foo-1", "aBC", ""] {
+            let attribute = dataset_attribute(name).unwrap();
+            assert_eq!(dataset_name(&attribute).as_deref(), Some(name));
+        }
+    }
+}
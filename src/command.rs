@@ -36,7 +36,7 @@ impl Console {
 
     fn get_color(&mut self, layout_box: &LayoutBox, name: &str) -> Option<Color> {
         if let Some(v) = layout_box.styled_node.value(name) {
-            if let Value::Color(ref c) = **v {
+            if let Value::Color(ref c) = *v {
                 return Some(c.clone());
             } else {
                 return None;
@@ -1,17 +1,32 @@
+use crate::source::Span;
 use std::fmt;
 use std::fmt::Formatter;
 
 #[derive(Clone)]
 pub struct StyleSheet {
     pub rules: Vec<Rule>,
+    // the @import rules at the top of the sheet, which the sheets they name go before
+    pub imports: Vec<Import>,
 }
 
 impl StyleSheet {
     pub fn new(rules: Vec<Rule>) -> Self {
-        Self { rules }
+        Self {
+            rules,
+            imports: Vec::new(),
+        }
     }
 }
 
+/// An `@import` rule: the url as written, relative to the importing sheet, and the
+/// media query list that has to match for the imported sheet to apply.
+// https://drafts.csswg.org/css-cascade-4/#at-import
+#[derive(Debug, Clone, PartialEq)]
+pub struct Import {
+    pub url: String,
+    pub media: String,
+}
+
 impl fmt::Debug for StyleSheet {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         let mut rule_result = String::new();
@@ -29,6 +44,10 @@ impl fmt::Debug for StyleSheet {
 pub struct Rule {
     pub selectors: Vec<Selector>,
     pub declarations: Vec<Declaration>,
+    // the media query lists of the @media rules it is inside, outermost first,
+    // every one of which has to match for the rule to apply
+    pub media: Vec<String>,
+    pub span: Span,
 }
 
 impl Rule {
@@ -36,6 +55,8 @@ impl Rule {
         Self {
             selectors,
             declarations,
+            media: Vec::new(),
+            span: Span::default(),
         }
     }
 }
@@ -63,12 +84,21 @@ impl fmt::Debug for Rule {
     }
 }
 
+/// A complex selector such as `nav > ul li`: its compound selectors from left to
+/// right, and the combinator between each two of them, `combinations[i]` coming
+/// after `simple[i]`. A combinator is one of ' ', '>', '+' and '~'.
+// https://drafts.csswg.org/selectors-4/#complex
 #[derive(PartialEq, Default,Clone)]
 pub struct Selector {
     pub simple: Vec<SimpleSelector>,
     pub combinations: Vec<char>,
 }
 
+/// How specific a selector is, compared in order: its ids; its classes, attributes
+/// and pseudo-classes; its type selectors.
+// https://drafts.csswg.org/selectors-4/#specificity-rules
+pub type Specificity = (u32, u32, u32);
+
 impl Selector {
     pub fn new(simple: Vec<SimpleSelector>, combinations: Vec<char>) -> Self {
         Self {
@@ -76,14 +106,41 @@ impl Selector {
             combinations,
         }
     }
+
+    pub fn specificity(&self) -> Specificity {
+        Self::sum(&self.simple)
+    }
+
+    fn sum(simple: &[SimpleSelector]) -> Specificity {
+        simple
+            .iter()
+            .map(SimpleSelector::specificity)
+            .fold((0, 0, 0), add_specificity)
+    }
+
+    // the most specific of a list, as :is(), :not() and :has() count
+    fn most_specific(selectors: &[Selector]) -> Specificity {
+        selectors
+            .iter()
+            .map(Selector::specificity)
+            .max()
+            .unwrap_or_default()
+    }
+}
+
+fn add_specificity(a: Specificity, b: Specificity) -> Specificity {
+    (a.0 + b.0, a.1 + b.1, a.2 + b.2)
 }
 
 impl fmt::Debug for Selector {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         let mut result = String::new();
-        for sel in &self.simple {
-            if result.is_empty() {
-                result.push_str(", ");
+        for (i, sel) in self.simple.iter().enumerate() {
+            if i > 0 {
+                match self.combinations.get(i - 1) {
+                    Some(' ') | None => result.push(' '),
+                    Some(c) => result.push_str(&format!(" {} ", c)),
+                }
             }
             result.push_str(&format!("{:?}", sel));
         }
@@ -96,6 +153,8 @@ pub struct SimpleSelector {
     pub tag_name: Option<String>,
     pub id: Option<String>,
     pub classes: Vec<String>,
+    pub attributes: Vec<AttributeSelector>,
+    pub pseudo_classes: Vec<PseudoClass>,
 }
 
 impl SimpleSelector {
@@ -104,10 +163,187 @@ impl SimpleSelector {
             tag_name,
             id,
             classes,
+            attributes: Vec::new(),
+            pseudo_classes: Vec::new(),
+        }
+    }
+
+    pub fn specificity(&self) -> Specificity {
+        let own = (
+            self.id.is_some() as u32,
+            (self.classes.len() + self.attributes.len()) as u32,
+            self.tag_name.is_some() as u32,
+        );
+        self.pseudo_classes
+            .iter()
+            .map(PseudoClass::specificity)
+            .fold(own, add_specificity)
+    }
+}
+
+/// `[name]`, or `[name op value]` with one of the operators and an optional
+/// `i` or `s` flag.
+// https://drafts.csswg.org/selectors-4/#attribute-selectors
+#[derive(Debug, PartialEq, Clone)]
+pub struct AttributeSelector {
+    // as written; it is lowercased when matched against an html element
+    pub name: String,
+    pub operator: AttributeOperator,
+    // empty for `Exists`
+    pub value: String,
+    pub case: CaseSensitivity,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum AttributeOperator {
+    // [name]
+    Exists,
+    // [name=value]
+    Equals,
+    // [name~=value], one of a whitespace-separated list
+    Includes,
+    // [name|=value], exactly or followed by `-`
+    DashMatch,
+    // [name^=value]
+    Prefix,
+    // [name$=value]
+    Suffix,
+    // [name*=value]
+    Substring,
+}
+
+// https://drafts.csswg.org/selectors-4/#structural-pseudos
+// https://drafts.csswg.org/selectors-4/#logical-combination
+#[derive(PartialEq, Clone)]
+pub enum PseudoClass {
+    Root,
+    // the element a relative selector is matched from, or else the root
+    Scope,
+    Empty,
+    FirstChild,
+    LastChild,
+    OnlyChild,
+    FirstOfType,
+    LastOfType,
+    OnlyOfType,
+    // counting only the siblings that match the `of S` list, when it is not empty
+    NthChild(Nth, Vec<Selector>),
+    NthLastChild(Nth, Vec<Selector>),
+    NthOfType(Nth),
+    NthLastOfType(Nth),
+    Not(Vec<Selector>),
+    Is(Vec<Selector>),
+    // like `Is`, but it adds nothing to specificity
+    Where(Vec<Selector>),
+    // each selector starts with a `Scope` compound standing for the element that
+    // has the pseudo-class, so that `:has(> img)` is `:scope > img`
+    Has(Vec<Selector>),
+    // the dynamic pseudo-classes, which match against `element_state::ElementStates`
+    // https://drafts.csswg.org/selectors-4/#useraction-pseudos
+    Hover,
+    Active,
+    Focus,
+    FocusWithin,
+    Link,
+    Visited,
+    Checked,
+    Disabled,
+}
+
+/// The `an+b` argument of `:nth-child()` and the like, which matches every
+/// 1-based index that is `a * n + b` for some n of 0 or more.
+// https://drafts.csswg.org/css-syntax-3/#anb-microsyntax
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Nth {
+    pub a: i32,
+    pub b: i32,
+}
+
+impl Nth {
+    pub fn new(a: i32, b: i32) -> Self {
+        Self { a, b }
+    }
+
+    pub fn matches(&self, index: i32) -> bool {
+        let offset = index - self.b;
+        if self.a == 0 {
+            return offset == 0;
+        }
+        offset % self.a == 0 && offset / self.a >= 0
+    }
+}
+
+impl PseudoClass {
+    pub fn specificity(&self) -> Specificity {
+        match self {
+            PseudoClass::Where(_) => (0, 0, 0),
+            PseudoClass::Not(s) | PseudoClass::Is(s) => Selector::most_specific(s),
+            // the `:scope` each relative selector starts with is not written
+            PseudoClass::Has(s) => s
+                .iter()
+                .map(|s| Selector::sum(s.simple.get(1..).unwrap_or_default()))
+                .max()
+                .unwrap_or_default(),
+            PseudoClass::NthChild(_, of) | PseudoClass::NthLastChild(_, of) => {
+                add_specificity((0, 1, 0), Selector::most_specific(of))
+            }
+            _ => (0, 1, 0),
+        }
+    }
+}
+
+impl fmt::Debug for PseudoClass {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let list = |selectors: &[Selector]| {
+            let selectors: Vec<_> = selectors.iter().map(|s| format!("{:?}", s)).collect();
+            selectors.join(", ")
+        };
+        let of = |selectors: &[Selector]| match selectors {
+            [] => String::new(),
+            _ => format!(" of {}", list(selectors)),
+        };
+        match self {
+            PseudoClass::Root => write!(f, ":root"),
+            PseudoClass::Scope => write!(f, ":scope"),
+            PseudoClass::Empty => write!(f, ":empty"),
+            PseudoClass::FirstChild => write!(f, ":first-child"),
+            PseudoClass::LastChild => write!(f, ":last-child"),
+            PseudoClass::OnlyChild => write!(f, ":only-child"),
+            PseudoClass::FirstOfType => write!(f, ":first-of-type"),
+            PseudoClass::LastOfType => write!(f, ":last-of-type"),
+            PseudoClass::OnlyOfType => write!(f, ":only-of-type"),
+            PseudoClass::NthChild(n, s) => write!(f, ":nth-child({}n{:+}{})", n.a, n.b, of(s)),
+            PseudoClass::NthLastChild(n, s) => {
+                write!(f, ":nth-last-child({}n{:+}{})", n.a, n.b, of(s))
+            }
+            PseudoClass::NthOfType(n) => write!(f, ":nth-of-type({}n{:+})", n.a, n.b),
+            PseudoClass::NthLastOfType(n) => write!(f, ":nth-last-of-type({}n{:+})", n.a, n.b),
+            PseudoClass::Not(s) => write!(f, ":not({})", list(s)),
+            PseudoClass::Is(s) => write!(f, ":is({})", list(s)),
+            PseudoClass::Where(s) => write!(f, ":where({})", list(s)),
+            PseudoClass::Has(s) => write!(f, ":has({})", list(s)),
+            PseudoClass::Hover => write!(f, ":hover"),
+            PseudoClass::Active => write!(f, ":active"),
+            PseudoClass::Focus => write!(f, ":focus"),
+            PseudoClass::FocusWithin => write!(f, ":focus-within"),
+            PseudoClass::Link => write!(f, ":link"),
+            PseudoClass::Visited => write!(f, ":visited"),
+            PseudoClass::Checked => write!(f, ":checked"),
+            PseudoClass::Disabled => write!(f, ":disabled"),
         }
     }
 }
 
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum CaseSensitivity {
+    // whatever the document language says for the attribute
+    Default,
+    // the `i` flag
+    Insensitive,
+    // the `s` flag
+    Sensitive,
+}
+
 impl fmt::Debug for SimpleSelector {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         let mut result = String::new();
@@ -128,6 +364,31 @@ impl fmt::Debug for SimpleSelector {
             result.push('.');
             result.push_str(class);
         }
+        for attribute in &self.attributes {
+            let operator = match attribute.operator {
+                AttributeOperator::Exists => "",
+                AttributeOperator::Equals => "=",
+                AttributeOperator::Includes => "~=",
+                AttributeOperator::DashMatch => "|=",
+                AttributeOperator::Prefix => "^=",
+                AttributeOperator::Suffix => "$=",
+                AttributeOperator::Substring => "*=",
+            };
+            result.push('[');
+            result.push_str(&attribute.name);
+            if attribute.operator != AttributeOperator::Exists {
+                result.push_str(&format!("{}{:?}", operator, attribute.value));
+            }
+            match attribute.case {
+                CaseSensitivity::Default => {}
+                CaseSensitivity::Insensitive => result.push_str(" i"),
+                CaseSensitivity::Sensitive => result.push_str(" s"),
+            }
+            result.push(']');
+        }
+        for pseudo_class in &self.pseudo_classes {
+            result.push_str(&format!("{:?}", pseudo_class));
+        }
         write!(f, "{}", result)
     }
 }
@@ -135,11 +396,19 @@ impl fmt::Debug for SimpleSelector {
 pub struct Declaration {
     pub property: String,
     pub value: Value,
+    // written with `!important`
+    pub important: bool,
+    pub span: Span,
 }
 
 impl Declaration {
     pub fn new(property: String, value: Value) -> Self {
-        Self { property, value }
+        Self {
+            property,
+            value,
+            important: false,
+            span: Span::default(),
+        }
     }
 }
 
@@ -148,6 +417,8 @@ impl Default for Declaration {
         Declaration {
             property: String::from(""),
             value: Value::Other(String::from("")),
+            important: false,
+            span: Span::default(),
         }
     }
 }
@@ -274,3 +545,29 @@ pub enum Unit {
     Pc,   // pica, 12 points
     Pct,  //petcentage
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::css_parser::CssParser;
+
+    fn specificity(selector: &str) -> (u32, u32, u32) {
+        let selectors = CssParser::new(selector).parse_selector_list().unwrap();
+        selectors[0].specificity()
+    }
+
+    #[test]
+    fn specificity_counts_ids_then_classes_then_types() {
+        assert_eq!(specificity("*"), (0, 0, 0));
+        assert_eq!(specificity("ul li.x[href]:first-child"), (0, 3, 2));
+        assert_eq!(specificity("#a #b > p"), (2, 0, 1));
+    }
+
+    #[test]
+    fn specificity_of_logical_pseudo_classes() {
+        assert_eq!(specificity(":is(p, #a .x)"), (1, 1, 0));
+        assert_eq!(specificity(":not(.x, p)"), (0, 1, 0));
+        assert_eq!(specificity(":where(#a) p"), (0, 0, 1));
+        assert_eq!(specificity("li:has(> img.x)"), (0, 1, 2));
+        assert_eq!(specificity(":nth-child(2n of #a)"), (1, 1, 0));
+    }
+}
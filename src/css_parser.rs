@@ -1,198 +1,510 @@
-use crate::css::{Color, Declaration, Rule, Selector, SimpleSelector, StyleSheet, Unit, Value};
-use std::iter::Peekable;
-use std::str::Chars;
+use crate::css::{
+    AttributeOperator, AttributeSelector, CaseSensitivity, Color, Declaration, Import, Nth,
+    PseudoClass, Rule, Selector, SimpleSelector, StyleSheet, Unit, Value,
+};
+use crate::css_tokenizer::{Numeric, Token, Tokenizer};
+use crate::source::{ParseError, SourcePosition, Span};
+use std::fmt;
+use std::mem;
 
-pub struct CssParser<'a> {
-    chars: Peekable<Chars<'a>>,
+#[derive(Debug, Clone, PartialEq)]
+pub enum ErrorCode {
+    EofInSelector,
+    EofInBlock,
+    EofInComment,
+    EofInString,
+    EofInUrl,
+    NewlineInString,
+    InvalidEscape,
+    BadUrl,
+    InvalidSelector,
+    InvalidAtRule,
+    // a well-formed at-rule that is skipped because nothing here implements it
+    UnsupportedAtRule(String),
+    InvalidDeclaration,
+    MissingColon,
+    MissingSemicolon,
+    EmptyPropertyName,
+    InvalidValue(String),
 }
 
-impl<'a> CssParser<'a> {
-    pub fn new(full_cs: &'a str) -> Self {
+/// A token, or a function or block together with the component values inside it.
+// https://drafts.csswg.org/css-syntax-3/#component-value
+#[derive(Debug, Clone, PartialEq)]
+pub enum ComponentValue {
+    Token(Token),
+    Function(String, Vec<ComponentValue>),
+    // `{`, `[` or `(`, which the block was opened with
+    Block(Token, Vec<ComponentValue>),
+}
+
+impl ComponentValue {
+    fn is_whitespace(&self) -> bool {
+        *self == ComponentValue::Token(Token::Whitespace)
+    }
+}
+
+pub struct CssParser {
+    tokens: Vec<(Token, Span)>,
+    // the next token to consume
+    index: usize,
+    // where the input ends, for errors at the end of it
+    end: SourcePosition,
+    errors: Vec<ParseError<ErrorCode>>,
+}
+
+impl CssParser {
+    pub fn new(full_cs: &str) -> Self {
+        Self::new_at(full_cs, SourcePosition::default())
+    }
+
+    /// For css embedded in another document, such as a `<style>` element, so that
+    /// spans and errors are relative to that document.
+    pub fn new_at(full_cs: &str, start: SourcePosition) -> Self {
+        let mut tokenizer = Tokenizer::new_at(full_cs, start);
+        let tokens = tokenizer.by_ref().collect();
         Self {
-            chars: full_cs.chars().peekable(),
+            tokens,
+            index: 0,
+            end: tokenizer.position(),
+            errors: tokenizer.take_errors(),
         }
     }
 
+    /// Parses like `parse_stylesheet` and also returns every parse error in source order.
+    pub fn parse_with_errors(&mut self) -> (StyleSheet, Vec<ParseError<ErrorCode>>) {
+        let stylesheet = self.parse_stylesheet();
+        (stylesheet, self.take_errors())
+    }
+
+    // https://drafts.csswg.org/css-syntax-3/#parse-stylesheet
     pub fn parse_stylesheet(&mut self) -> StyleSheet {
         let mut stylesheet: StyleSheet = StyleSheet::new(Vec::new());
+        self.parse_rules(&mut stylesheet, &[]);
+        stylesheet
+    }
 
-        while self.chars.peek().is_some() {
-            let selectors = self.parse_selectors();
-            let styles = self.parse_declarations();
-            let rule = Rule::new(selectors, styles);
+    // https://drafts.csswg.org/css-syntax-3/#consume-list-of-rules
+    // the rules of the sheet, or with `media` those of the innermost @media block
+    // the list belongs to, which end at its `}`
+    fn parse_rules(&mut self, stylesheet: &mut StyleSheet, media: &[String]) {
+        while let Some(token) = self.peek() {
+            match token {
+                Token::Whitespace | Token::Cdo | Token::Cdc => {
+                    self.next();
+                }
+                Token::CloseCurly if !media.is_empty() => {
+                    self.next();
+                    return;
+                }
+                Token::AtKeyword(_) => self.parse_at_rule(stylesheet, media),
+                _ => {
+                    if let Some(mut rule) = self.parse_rule() {
+                        rule.media = media.to_vec();
+                        stylesheet.rules.push(rule);
+                    }
+                }
+            }
+        }
+        if !media.is_empty() {
+            self.error(ErrorCode::EofInBlock);
+        }
+    }
 
-            stylesheet.rules.push(rule);
+    /// Parses the whole input as a selector list, the way `querySelector` takes one.
+    pub fn parse_selector_list(&mut self) -> Result<Vec<Selector>, ParseError<ErrorCode>> {
+        let selectors = SelectorParser::new(&self.tokens, self.end).parse_list();
+        let errors = self.take_errors();
+        match (errors.into_iter().next(), selectors) {
+            (Some(error), _) | (None, Err(error)) => Err(error),
+            (None, Ok(selectors)) => Ok(selectors),
         }
-        stylesheet
     }
 
-    fn parse_selectors(&mut self) -> Vec<Selector> {
-        let mut selectors = Vec::new();
+    /// Parses the whole input as a list of component values, such as the value of
+    /// a declaration.
+    // https://drafts.csswg.org/css-syntax-3/#parse-list-of-component-values
+    pub fn parse_component_values(&mut self) -> Vec<ComponentValue> {
+        let mut values = Vec::new();
+        while self.peek().is_some() {
+            values.extend(self.consume_component_value());
+        }
+        values
+    }
 
-        // html {
-        // ____
-        while self.chars.peek().map_or(false, |c| *c != '{') {
-            // get everything before curly brace
-            let selector = self.parse_selector();
+    // https://drafts.csswg.org/css-syntax-3/#consume-at-rule
+    // @import and @media are understood; @charset was dealt with when the bytes
+    // were decoded, and any other at-rule is skipped whole
+    fn parse_at_rule(&mut self, stylesheet: &mut StyleSheet, media: &[String]) {
+        let start = self.position();
+        let name = match self.next() {
+            Some(Token::AtKeyword(name)) => name.to_ascii_lowercase(),
+            _ => return,
+        };
+        let prelude = self.consume_until(|t| matches!(t, Token::Semicolon | Token::OpenCurly));
+        let block = self.next() == Some(Token::OpenCurly);
+        match &name[..] {
+            // https://drafts.csswg.org/css-conditional-3/#at-media
+            // its rules stay in the sheet, marked with the media they need
+            "media" if block => {
+                let mut media = media.to_vec();
+                media.push(Self::serialize(Self::trim(&prelude)));
+                self.parse_rules(stylesheet, &media);
+                return;
+            }
+            "media" => self.error_at(ErrorCode::InvalidAtRule, start),
+            // https://drafts.csswg.org/css-cascade-4/#at-import
+            // ignored once any other rule has been seen
+            "import" => match Self::parse_import(&prelude) {
+                Some(import) if !block && media.is_empty() && stylesheet.rules.is_empty() => {
+                    stylesheet.imports.push(import)
+                }
+                _ => self.error_at(ErrorCode::InvalidAtRule, start),
+            },
+            "charset" => {}
+            _ => self.error_at(ErrorCode::UnsupportedAtRule(name), start),
+        }
+        if block {
+            self.consume_block(Token::OpenCurly);
+        }
+    }
 
-            if selector != Selector::default() {
-                selectors.push(selector);
+    // @import [ <string> | <url> ] <media-query-list>?
+    fn parse_import(prelude: &[ComponentValue]) -> Option<Import> {
+        let mut values = prelude.iter().skip_while(|v| v.is_whitespace());
+        let url = match values.next()? {
+            ComponentValue::Token(Token::String(url)) | ComponentValue::Token(Token::Url(url)) => {
+                url.clone()
             }
-            self.consume_while(char::is_whitespace);
-            if self.chars.peek().map_or(false, |c| *c == ',') {
-                self.chars.next();
+            ComponentValue::Function(name, arguments) if name.eq_ignore_ascii_case("url") => {
+                match Self::trim(arguments) {
+                    [ComponentValue::Token(Token::String(url))] => url.clone(),
+                    _ => return None,
+                }
             }
+            _ => return None,
+        };
+        let media: Vec<_> = values.cloned().collect();
+        Some(Import {
+            url,
+            media: Self::serialize(Self::trim(&media)),
+        })
+    }
+
+    // https://drafts.csswg.org/css-syntax-3/#consume-qualified-rule
+    // None for a rule whose selectors are invalid, which is dropped as a whole
+    fn parse_rule(&mut self) -> Option<Rule> {
+        let start = self.position();
+        let prelude_start = self.index;
+        self.consume_until(|t| *t == Token::OpenCurly);
+        let prelude_end = self.index;
+        if self.next().is_none() {
+            self.error(ErrorCode::EofInSelector);
+            return None;
         }
+        let declarations = self.parse_declarations();
+        let span = Span::new(start, self.previous_end());
 
-        self.chars.next();
-        selectors
+        let prelude = &self.tokens[prelude_start..prelude_end];
+        let end = self.tokens[prelude_end].1.start;
+        match SelectorParser::new(prelude, end).parse_list() {
+            Ok(selectors) => {
+                let mut rule = Rule::new(selectors, declarations);
+                rule.span = span;
+                Some(rule)
+            }
+            Err(error) => {
+                self.errors.push(error);
+                None
+            }
+        }
     }
-    fn parse_selector(&mut self) -> Selector {
-        let mut simple_selector = SimpleSelector::default();
-        let mut selector = Selector::default();
 
-        self.consume_while(char::is_whitespace);
-
-        simple_selector.tag_name = match self.chars.peek() {
-            Some(&c) if Self::is_valid_start_ident(c) => Some(self.parse_identifier()),
-            _ => None,
-        };
+    // https://drafts.csswg.org/css-syntax-3/#consume-list-of-declarations
+    // the `{` has been consumed; this goes up to and including the `}`
+    fn parse_declarations(&mut self) -> Vec<Declaration> {
+        let mut declarations = Vec::<Declaration>::new();
 
-        let mut multiple_ids = false;
-        while self
-            .chars
-            .peek()
-            .map_or(false, |c| *c != ',' && *c != '{' && !(*c).is_whitespace())
-        {
-            match self.chars.peek() {
-                Some(&c) if c == '#' => {
-                    self.chars.next();
-                    if simple_selector.id.is_some() || multiple_ids {
-                        simple_selector.id = None;
-                        multiple_ids = true;
-                        self.parse_id();
-                    } else {
-                        simple_selector.id = self.parse_id();
-                    }
+        loop {
+            match self.peek() {
+                None => {
+                    self.error(ErrorCode::EofInBlock);
+                    break;
                 }
-                Some(&c) if c == '.' => {
-                    self.chars.next();
-                    let class_name = self.parse_identifier();
-
-                    if class_name != *"" {
-                        simple_selector.classes.push(class_name);
+                Some(Token::CloseCurly) => {
+                    self.next();
+                    break;
+                }
+                Some(Token::Whitespace) | Some(Token::Semicolon) => {
+                    self.next();
+                }
+                // nested at-rules mean nothing inside a style rule yet
+                Some(Token::AtKeyword(name)) => {
+                    let name = name.to_ascii_lowercase();
+                    self.error(ErrorCode::UnsupportedAtRule(name));
+                    self.next();
+                    self.consume_until(|t| matches!(t, Token::Semicolon | Token::OpenCurly));
+                    if self.peek() == Some(&Token::OpenCurly) {
+                        self.next();
+                        self.consume_block(Token::OpenCurly);
                     }
                 }
-                _ => {
-                    self.consume_while(|c| c != ',' && c != '{');
+                Some(Token::Ident(_)) => declarations.extend(self.parse_declaration()),
+                Some(token) => {
+                    let code = match token {
+                        Token::Colon => ErrorCode::EmptyPropertyName,
+                        _ => ErrorCode::InvalidDeclaration,
+                    };
+                    self.error(code);
+                    self.consume_until(|t| matches!(t, Token::Semicolon | Token::CloseCurly));
                 }
             }
         }
+        declarations
+    }
 
-        if simple_selector != SimpleSelector::default() {
-            selector.simple.push(simple_selector);
+    // https://drafts.csswg.org/css-syntax-3/#consume-declaration
+    fn parse_declaration(&mut self) -> Option<Declaration> {
+        let start = self.position();
+        let property = match self.next() {
+            Some(Token::Ident(name)) => name.to_ascii_lowercase(),
+            _ => return None,
+        };
+        self.skip_whitespace();
+        if self.peek() != Some(&Token::Colon) {
+            self.error_at(ErrorCode::MissingColon, start);
+            self.consume_until(|t| matches!(t, Token::Semicolon | Token::CloseCurly));
+            return None;
         }
-        selector
+        self.next();
+
+        let value_start = self.position();
+        let mut value = self.consume_until(|t| matches!(t, Token::Semicolon | Token::CloseCurly));
+        let mut end = self.previous_end_skipping_whitespace();
+        if self.peek() == Some(&Token::Semicolon) {
+            self.next();
+            end = self.previous_end();
+        }
+        Self::to_ascii_lowercase(&mut value);
+        let important = Self::strip_important(&mut value);
+
+        let value_enum = self.parse_value(&property, Self::trim(&value), value_start)?;
+        let mut declaration = Declaration::new(property, value_enum);
+        declaration.important = important;
+        declaration.span = Span::new(start, end);
+        Some(declaration)
     }
 
-    fn parse_identifier(&mut self) -> String {
-        let mut ident = String::new();
+    // a trailing `!important`, which is taken off `value`
+    fn strip_important(value: &mut Vec<ComponentValue>) -> bool {
+        let mut rest = value.clone();
+        while rest.last().is_some_and(ComponentValue::is_whitespace) {
+            rest.pop();
+        }
+        if rest.pop() != Some(ComponentValue::Token(Token::Ident("important".to_owned()))) {
+            return false;
+        }
+        while rest.last().is_some_and(ComponentValue::is_whitespace) {
+            rest.pop();
+        }
+        if rest.pop() != Some(ComponentValue::Token(Token::Delim('!'))) {
+            return false;
+        }
+        *value = rest;
+        true
+    }
 
-        if let Some(&c) = self.chars.peek() {
-            if Self::is_valid_start_ident(c) {
-                ident.push_str(&self.consume_while(Self::is_valid_ident))
+    // None for a value the property does not accept, which drops the declaration
+    // and leaves any earlier one for the property in place
+    // https://drafts.csswg.org/css-syntax-3/#consume-declaration
+    fn parse_value(
+        &mut self,
+        property: &str,
+        value: &[ComponentValue],
+        start: SourcePosition,
+    ) -> Option<Value> {
+        let parsed = match property {
+            "background-color" | "border-color" | "color" => {
+                Self::translate_color(value).map(Value::Color)
             }
+            "margin-right"
+            | "margin-bottom"
+            | "margin-left"
+            | "margin-top"
+            | "padding-right"
+            | "padding-bottom"
+            | "padding-left"
+            | "padding-top"
+            | "border-right-width"
+            | "border-bottom-width"
+            | "border-left-width"
+            | "border-top-width"
+            | "height"
+            | "width" => match Self::translate_length(value) {
+                Some(length) => Some(length),
+                None if Self::is_keyword(value) => Some(Value::Length(0.0, Unit::Px)),
+                None => None,
+            },
+            _ => Some(Value::Other(Self::serialize(value))),
+        };
+        if parsed.is_none() {
+            self.error_at(ErrorCode::InvalidValue(Self::serialize(value)), start);
         }
-        ident.to_lowercase()
+        parsed
     }
 
-    fn parse_id(&mut self) -> Option<String> {
-        match &self.parse_identifier()[..] {
-            "" => None,
-            s => Some(s.to_string()), // wild card + options that are not an empty string
-        }
+    // https://drafts.csswg.org/css-syntax-3/#consume-component-value
+    fn consume_component_value(&mut self) -> Option<ComponentValue> {
+        let value = match self.next()? {
+            open @ (Token::OpenCurly | Token::OpenSquare | Token::OpenParen) => {
+                let values = self.consume_block(open.clone());
+                ComponentValue::Block(open, values)
+            }
+            Token::Function(name) => {
+                let arguments = self.consume_block(Token::OpenParen);
+                ComponentValue::Function(name, arguments)
+            }
+            token => ComponentValue::Token(token),
+        };
+        Some(value)
     }
 
-    fn parse_declarations(&mut self) -> Vec<Declaration> {
-        let mut declarations = Vec::<Declaration>::new();
+    // https://drafts.csswg.org/css-syntax-3/#consume-simple-block
+    // the opening token has been consumed; this goes up to and including the closing one
+    fn consume_block(&mut self, open: Token) -> Vec<ComponentValue> {
+        let close = match open {
+            Token::OpenCurly => Token::CloseCurly,
+            Token::OpenSquare => Token::CloseSquare,
+            _ => Token::CloseParen,
+        };
+        let mut values = Vec::new();
+        loop {
+            match self.peek() {
+                None => {
+                    self.error(ErrorCode::EofInBlock);
+                    return values;
+                }
+                Some(token) if *token == close => {
+                    self.next();
+                    return values;
+                }
+                Some(_) => values.extend(self.consume_component_value()),
+            }
+        }
+    }
 
-        while self.chars.peek().map_or(false, |c| *c != '}') {
-            self.consume_while(char::is_whitespace);
-            let property = self.consume_while(|x| x != ':').to_lowercase();
+    // component values up to a top-level token that `stop` accepts, which is left
+    // for the caller, or up to the end of the input
+    fn consume_until<F>(&mut self, stop: F) -> Vec<ComponentValue>
+    where
+        F: Fn(&Token) -> bool,
+    {
+        let mut values = Vec::new();
+        while self.peek().is_some_and(|t| !stop(t)) {
+            values.extend(self.consume_component_value());
+        }
+        values
+    }
 
-            self.chars.next();
-            self.consume_while(char::is_whitespace);
+    fn skip_whitespace(&mut self) {
+        while self.peek() == Some(&Token::Whitespace) {
+            self.next();
+        }
+    }
 
-            let value = self
-                .consume_while(|x| x != ';' && x != '\n' && x != '}')
-                .to_lowercase();
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.index).map(|(token, _)| token)
+    }
 
-            let value_enum = match property.as_ref() {
-                "background-color" | "border-color" | "color" => {
-                    Value::Color(Self::translate_color(&value))
-                }
-                "margin-right"
-                | "margin-bottom"
-                | "margin-left"
-                | "margin-top"
-                | "padding-right"
-                | "padding-bottom"
-                | "padding-left"
-                | "padding-top"
-                | "border-right-width"
-                | "border-bottom-width"
-                | "border-left-width"
-                | "border-top-width"
-                | "height"
-                | "width" => Self::translate_length(&value),
-                _ => Value::Other(value),
-            };
+    fn next(&mut self) -> Option<Token> {
+        let (token, _) = self.tokens.get(self.index)?;
+        self.index += 1;
+        Some(token.clone())
+    }
 
-            let declaration = Declaration::new(property, value_enum);
+    // where the next token starts
+    fn position(&self) -> SourcePosition {
+        self.tokens
+            .get(self.index)
+            .map_or(self.end, |(_, span)| span.start)
+    }
 
-            if self.chars.peek().map_or(false, |c| *c == ';') {
-                declarations.push(declaration);
-                self.chars.next();
-            } else {
-                self.consume_while(char::is_whitespace);
-                if self.chars.peek().map_or(false, |c| *c == '}') {
-                    declarations.push(declaration);
-                }
-            }
-            self.consume_while(char::is_whitespace);
+    // where the last consumed token ends
+    fn previous_end(&self) -> SourcePosition {
+        match self.index {
+            0 => self.position(),
+            i => self.tokens[i - 1].1.end,
         }
-        self.chars.next();
-        declarations
     }
 
-    fn consume_while<F>(&mut self, condition: F) -> String
-    where
-        F: Fn(char) -> bool,
-    {
-        let mut result = String::new();
-        while self.chars.peek().map_or(false, |c| condition(*c)) {
-            result.push(self.chars.next().unwrap());
+    // like `previous_end`, but before any whitespace that was consumed last
+    fn previous_end_skipping_whitespace(&self) -> SourcePosition {
+        let consumed = &self.tokens[..self.index];
+        match consumed
+            .iter()
+            .rposition(|(token, _)| *token != Token::Whitespace)
+        {
+            Some(i) => consumed[i].1.end,
+            None => self.previous_end(),
         }
+    }
 
-        result
+    fn error(&mut self, code: ErrorCode) {
+        self.error_at(code, self.position());
     }
 
-    fn translate_length(value: &str) -> Value {
-        let mut num_str = String::new();
-        let mut unit = String::new();
-        let mut parsing_num = true;
+    fn error_at(&mut self, code: ErrorCode, position: SourcePosition) {
+        self.errors.push(ParseError::new(code, position));
+    }
+
+    // the tokenizer's errors and the parser's in one list, in source order
+    fn take_errors(&mut self) -> Vec<ParseError<ErrorCode>> {
+        let mut errors = mem::take(&mut self.errors);
+        errors.sort_by_key(|e| e.position.offset);
+        errors
+    }
+
+    fn trim(values: &[ComponentValue]) -> &[ComponentValue] {
+        let start = values.iter().take_while(|v| v.is_whitespace()).count();
+        let trailing = values[start..]
+            .iter()
+            .rev()
+            .take_while(|v| v.is_whitespace())
+            .count();
+        &values[start..values.len() - trailing]
+    }
 
-        for c in value.chars() {
-            if c.is_numeric() && parsing_num {
-                num_str.push(c);
-            } else {
-                unit.push(c);
-                parsing_num = false;
+    // keywords, function names and units are ascii case-insensitive; strings and
+    // urls are left as they are
+    fn to_ascii_lowercase(values: &mut [ComponentValue]) {
+        for value in values {
+            match value {
+                ComponentValue::Token(Token::Ident(s))
+                | ComponentValue::Token(Token::Dimension(_, s)) => s.make_ascii_lowercase(),
+                ComponentValue::Token(Token::Hash { value, .. }) => value.make_ascii_lowercase(),
+                ComponentValue::Function(name, arguments) => {
+                    name.make_ascii_lowercase();
+                    Self::to_ascii_lowercase(arguments);
+                }
+                ComponentValue::Block(_, values) => Self::to_ascii_lowercase(values),
+                _ => {}
             }
         }
-        let number = num_str.parse().unwrap_or(0.0);
+    }
 
-        match unit.as_ref() {
+    fn serialize(values: &[ComponentValue]) -> String {
+        values.iter().map(ToString::to_string).collect()
+    }
+
+    fn translate_length(value: &[ComponentValue]) -> Option<Value> {
+        let (number, unit) = match value {
+            [ComponentValue::Token(Token::Dimension(n, unit))] => (n.value, &unit[..]),
+            [ComponentValue::Token(Token::Percentage(n))] => (n.value, "%"),
+            [ComponentValue::Token(Token::Number(n))] => (n.value, "px"),
+            _ => return None,
+        };
+
+        let value = match unit {
             "em" => Value::Length(number, Unit::Em),
             "ex" => Value::Length(number, Unit::Ex),
             "ch" => Value::Length(number, Unit::Ch),
@@ -201,7 +513,7 @@ impl<'a> CssParser<'a> {
             "vw" => Value::Length(number, Unit::Vw),
             "vmin" => Value::Length(number, Unit::Vmin),
             "vmax" => Value::Length(number, Unit::Vmax),
-            "px" | "" => Value::Length(number, Unit::Px),
+            "px" => Value::Length(number, Unit::Px),
             "mm" => Value::Length(number, Unit::Mm),
             "q" => Value::Length(number, Unit::Q),
             "cm" => Value::Length(number, Unit::Cm),
@@ -209,72 +521,657 @@ impl<'a> CssParser<'a> {
             "pt" => Value::Length(number, Unit::Pt),
             "pc" => Value::Length(number, Unit::Pc),
             "%" => Value::Length(number, Unit::Pct),
-            _ => Value::Length(number, Unit::Px),
+            _ => return None,
+        };
+        Some(value)
+    }
+
+    fn translate_color(value: &[ComponentValue]) -> Option<Color> {
+        match value {
+            [ComponentValue::Token(Token::Hash { value: hex, .. })] => Self::hex_color(hex),
+            [ComponentValue::Function(name, arguments)] => match &name[..] {
+                "rgb" | "rgba" => Self::rgb_color(arguments),
+                "hsl" | "hsla" => Self::hsl_color(arguments),
+                _ => None,
+            },
+            [ComponentValue::Token(Token::Ident(name))] => match &name[..] {
+                "black" => Some(Color::BLACK),
+                "white" => Some(Color::WHITE),
+                "blue" => Some(Color::BLUE),
+                "orange" => Some(Color::ORANGE),
+                "red" => Some(Color::RED),
+                "green" => Some(Color::GREEN),
+                "bronze" => Some(Color::BRONZE),
+                "orangered" => Some(Color::ORANGERED),
+                _ if Self::is_keyword(value) => Some(Color::default()),
+                _ => None,
+            },
+            _ => None,
         }
     }
 
-    fn translate_color(color: &str) -> Color {
-        if color.starts_with('#') {
-            if color.len() == 7 {
-                let r = match u8::from_str_radix(&color[1..3], 16) {
-                    Ok(n) => n as f32 / 255.,
-                    Err(_) => 0.0,
-                };
-                let g = match u8::from_str_radix(&color[3..5], 16) {
-                    Ok(n) => n as f32 / 255.,
-                    Err(_) => 0.0,
-                };
-                let b = match u8::from_str_radix(&color[5..7], 16) {
-                    Ok(n) => n as f32 / 255.,
-                    Err(_) => 0.0,
-                };
-                Color::new(r, g, b, 1.)
-            } else if color.len() == 4 {
-                let r = match u8::from_str_radix(&color[1..2], 16) {
-                    Ok(n) => n as f32 / 15.,
-                    Err(_) => 0.0,
-                };
-                let g = match u8::from_str_radix(&color[2..3], 16) {
-                    Ok(n) => n as f32 / 15.,
-                    Err(_) => 0.0,
-                };
-                let b = match u8::from_str_radix(&color[3..4], 16) {
-                    Ok(n) => n as f32 / 15.,
-                    Err(_) => 0.0,
+    // https://drafts.csswg.org/css-color-4/#hex-notation
+    fn hex_color(hex: &str) -> Option<Color> {
+        let digits = match hex.len() {
+            3 | 4 => 1,
+            6 | 8 => 2,
+            _ => return None,
+        };
+        let max = if digits == 1 { 15. } else { 255. };
+        let component = |i: usize| {
+            let digit = hex.get(i * digits..(i + 1) * digits)?;
+            Some(u8::from_str_radix(digit, 16).ok()? as f32 / max)
+        };
+        let alpha = match hex.len() {
+            4 | 8 => component(3)?,
+            _ => 1.,
+        };
+        Some(Color::new(
+            component(0)?,
+            component(1)?,
+            component(2)?,
+            alpha,
+        ))
+    }
+
+    // rgb(r, g, b[, a]) and rgb(r g b[ / a]), in numbers or percentages
+    // https://drafts.csswg.org/css-color-4/#rgb-functions
+    fn rgb_color(arguments: &[ComponentValue]) -> Option<Color> {
+        let values = Self::color_arguments(arguments)?;
+        let channel = |v: &Token| match v {
+            Token::Number(n) => Some((n.value / 255.).clamp(0., 1.)),
+            Token::Percentage(n) => Some((n.value / 100.).clamp(0., 1.)),
+            _ => None,
+        };
+        let alpha = match values.get(3) {
+            Some(a) => Self::alpha(a)?,
+            None => 1.,
+        };
+        Some(Color::new(
+            channel(values[0])?,
+            channel(values[1])?,
+            channel(values[2])?,
+            alpha,
+        ))
+    }
+
+    // https://drafts.csswg.org/css-color-4/#the-hsl-notation
+    fn hsl_color(arguments: &[ComponentValue]) -> Option<Color> {
+        let values = Self::color_arguments(arguments)?;
+        let hue = match values[0] {
+            Token::Number(n) => n.value,
+            Token::Dimension(n, unit) if unit == "deg" => n.value,
+            Token::Dimension(n, unit) if unit == "turn" => n.value * 360.,
+            _ => return None,
+        };
+        let percentage = |v: &Token| match v {
+            Token::Percentage(n) => Some((n.value / 100.).clamp(0., 1.)),
+            _ => None,
+        };
+        let saturation = percentage(values[1])?;
+        let lightness = percentage(values[2])?;
+        let alpha = match values.get(3) {
+            Some(a) => Self::alpha(a)?,
+            None => 1.,
+        };
+        // https://drafts.csswg.org/css-color-4/#hsl-to-rgb
+        let channel = |n: f32| {
+            let k = (n + hue.rem_euclid(360.) / 30.) % 12.;
+            let a = saturation * lightness.min(1. - lightness);
+            lightness - a * (k - 3.).min(9. - k).clamp(-1., 1.)
+        };
+        Some(Color::new(channel(0.), channel(8.), channel(4.), alpha))
+    }
+
+    // the three or four values of a color function, without the commas or the `/`
+    // between them
+    fn color_arguments(arguments: &[ComponentValue]) -> Option<Vec<&Token>> {
+        let mut values = Vec::new();
+        for argument in arguments {
+            match argument {
+                ComponentValue::Token(Token::Whitespace)
+                | ComponentValue::Token(Token::Comma)
+                | ComponentValue::Token(Token::Delim('/')) => {}
+                ComponentValue::Token(token) => values.push(token),
+                _ => return None,
+            }
+        }
+        match values.len() {
+            3 | 4 => Some(values),
+            _ => None,
+        }
+    }
+
+    fn alpha(value: &Token) -> Option<f32> {
+        match value {
+            Token::Number(n) => Some(n.value.clamp(0., 1.)),
+            Token::Percentage(n) => Some((n.value / 100.).clamp(0., 1.)),
+            _ => None,
+        }
+    }
+
+    /// Values that are valid for any property but that this parser has no representation for.
+    fn is_keyword(value: &[ComponentValue]) -> bool {
+        match value {
+            [ComponentValue::Token(Token::Ident(name))] => matches!(
+                &name[..],
+                "auto"
+                    | "inherit"
+                    | "initial"
+                    | "unset"
+                    | "revert"
+                    | "transparent"
+                    | "currentcolor"
+            ),
+            _ => false,
+        }
+    }
+}
+
+// https://drafts.csswg.org/css-syntax-3/#serialization
+impl fmt::Display for ComponentValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ComponentValue::Token(token) => write!(f, "{}", token),
+            ComponentValue::Function(name, arguments) => {
+                write!(f, "{}({})", name, CssParser::serialize(arguments))
+            }
+            ComponentValue::Block(open, values) => {
+                let close = match open {
+                    Token::OpenCurly => '}',
+                    Token::OpenSquare => ']',
+                    _ => ')',
                 };
-                Color::new(r, g, b, 1.)
-            } else {
-                Color::default()
+                write!(f, "{}{}{}", open, CssParser::serialize(values), close)
             }
-        } else if color.starts_with("rgb") || color.starts_with("hsl") {
-            Color::default()
-        } else {
-            match color {
-                "black" => Color::BLACK,
-                "white" => Color::WHITE,
-                "blue" => Color::BLUE,
-                "orange" => Color::ORANGE,
-                "red" => Color::RED,
-                "green" => Color::GREEN,
-                "bronze" => Color::BRONZE,
-                "orangered" => Color::ORANGERED,
-                _ => Color::default(),
+        }
+    }
+}
+
+// https://drafts.csswg.org/selectors-4/#parse-selector
+// works on the tokens of a rule's prelude, or of a whole `querySelector` argument
+struct SelectorParser<'t> {
+    tokens: &'t [(Token, Span)],
+    index: usize,
+    // where the tokens end
+    end: SourcePosition,
+}
+
+impl<'t> SelectorParser<'t> {
+    fn new(tokens: &'t [(Token, Span)], end: SourcePosition) -> Self {
+        Self {
+            tokens,
+            index: 0,
+            end,
+        }
+    }
+
+    fn parse_list(&mut self) -> Result<Vec<Selector>, ParseError<ErrorCode>> {
+        let selectors = self.parse_nested_list()?;
+        match self.peek() {
+            None => Ok(selectors),
+            Some(_) => Err(self.error()),
+        }
+    }
+
+    // a list that ends with the tokens or before the `)` of a functional pseudo-class
+    fn parse_nested_list(&mut self) -> Result<Vec<Selector>, ParseError<ErrorCode>> {
+        let mut selectors = Vec::new();
+        loop {
+            self.skip_whitespace();
+            selectors.push(self.parse_complex()?);
+            self.skip_whitespace();
+            match self.peek() {
+                Some(Token::Comma) => {
+                    self.next();
+                }
+                _ => return Ok(selectors),
             }
         }
     }
-    fn is_valid_ident(c: char) -> bool {
-        Self::is_valid_start_ident(c) || c.is_digit(10) || c == '-'
+
+    // https://drafts.csswg.org/selectors-4/#typedef-relative-selector-list
+    // each one is anchored with a `:scope` compound, see `PseudoClass::Has`
+    fn parse_relative_list(&mut self) -> Result<Vec<Selector>, ParseError<ErrorCode>> {
+        let mut selectors = Vec::new();
+        loop {
+            self.skip_whitespace();
+            let combinator = match self.peek() {
+                Some(&Token::Delim(c @ ('>' | '+' | '~'))) => {
+                    self.next();
+                    self.skip_whitespace();
+                    c
+                }
+                _ => ' ',
+            };
+            let mut selector = self.parse_complex()?;
+            let mut scope = SimpleSelector::default();
+            scope.pseudo_classes.push(PseudoClass::Scope);
+            selector.simple.insert(0, scope);
+            selector.combinations.insert(0, combinator);
+            selectors.push(selector);
+            self.skip_whitespace();
+            match self.peek() {
+                Some(Token::Comma) => {
+                    self.next();
+                }
+                _ => return Ok(selectors),
+            }
+        }
+    }
+
+    // https://drafts.csswg.org/selectors-4/#typedef-complex-selector
+    fn parse_complex(&mut self) -> Result<Selector, ParseError<ErrorCode>> {
+        let mut selector = Selector::new(vec![self.parse_compound()?], Vec::new());
+        loop {
+            let whitespace = self.skip_whitespace();
+            let combinator = match self.peek() {
+                Some(&Token::Delim(c @ ('>' | '+' | '~'))) => {
+                    self.next();
+                    self.skip_whitespace();
+                    c
+                }
+                None | Some(Token::Comma) | Some(Token::CloseParen) => return Ok(selector),
+                Some(_) if whitespace => ' ',
+                // left for the caller to reject
+                Some(_) => return Ok(selector),
+            };
+            selector.combinations.push(combinator);
+            selector.simple.push(self.parse_compound()?);
+        }
     }
 
-    fn is_valid_start_ident(c: char) -> bool {
-        Self::is_letter(c) || Self::is_non_ascii(c) || c == '_'
+    fn parse_compound(&mut self) -> Result<SimpleSelector, ParseError<ErrorCode>> {
+        let mut simple = SimpleSelector::default();
+        let mut empty = true;
+        match self.peek() {
+            // kept as written, as svg and mathml names are case-sensitive
+            Some(Token::Ident(name)) => {
+                simple.tag_name = Some(name.clone());
+                self.next();
+                empty = false;
+            }
+            Some(Token::Delim('*')) => {
+                self.next();
+                empty = false;
+            }
+            _ => {}
+        }
+        loop {
+            match self.peek() {
+                Some(Token::Hash { value, id: true }) => {
+                    // an element has one id, so two different ones can never match
+                    if simple.id.as_ref().is_some_and(|id| id != value) {
+                        return Err(self.error());
+                    }
+                    simple.id = Some(value.clone());
+                    self.next();
+                }
+                Some(Token::Delim('.')) => {
+                    self.next();
+                    match self.next() {
+                        Some(Token::Ident(class)) => simple.classes.push(class),
+                        _ => return Err(self.error_before()),
+                    }
+                }
+                Some(Token::OpenSquare) => {
+                    self.next();
+                    simple.attributes.push(self.parse_attribute()?);
+                }
+                Some(Token::Colon) => {
+                    self.next();
+                    simple.pseudo_classes.push(self.parse_pseudo_class()?);
+                }
+                _ => break,
+            }
+            empty = false;
+        }
+        if empty {
+            return Err(self.error());
+        }
+        Ok(simple)
+    }
+
+    // https://drafts.csswg.org/selectors-4/#typedef-attribute-selector
+    // the `[` has been consumed; this goes up to and including the `]`
+    fn parse_attribute(&mut self) -> Result<AttributeSelector, ParseError<ErrorCode>> {
+        self.skip_whitespace();
+        let name = match self.next() {
+            Some(Token::Ident(name)) => name,
+            _ => return Err(self.error_before()),
+        };
+        self.skip_whitespace();
+        let operator = match self.next() {
+            Some(Token::CloseSquare) => {
+                return Ok(AttributeSelector {
+                    name,
+                    operator: AttributeOperator::Exists,
+                    value: String::new(),
+                    case: CaseSensitivity::Default,
+                })
+            }
+            Some(Token::Delim('=')) => AttributeOperator::Equals,
+            Some(Token::Delim(c)) if self.peek() == Some(&Token::Delim('=')) => {
+                self.next();
+                match c {
+                    '~' => AttributeOperator::Includes,
+                    '|' => AttributeOperator::DashMatch,
+                    '^' => AttributeOperator::Prefix,
+                    '$' => AttributeOperator::Suffix,
+                    '*' => AttributeOperator::Substring,
+                    _ => return Err(self.error_before()),
+                }
+            }
+            _ => return Err(self.error_before()),
+        };
+        self.skip_whitespace();
+        let value = match self.next() {
+            Some(Token::Ident(value)) | Some(Token::String(value)) => value,
+            _ => return Err(self.error_before()),
+        };
+        self.skip_whitespace();
+        let case = match self.peek() {
+            Some(Token::Ident(flag)) if flag.eq_ignore_ascii_case("i") => {
+                CaseSensitivity::Insensitive
+            }
+            Some(Token::Ident(flag)) if flag.eq_ignore_ascii_case("s") => {
+                CaseSensitivity::Sensitive
+            }
+            _ => CaseSensitivity::Default,
+        };
+        if case != CaseSensitivity::Default {
+            self.next();
+            self.skip_whitespace();
+        }
+        match self.next() {
+            Some(Token::CloseSquare) => Ok(AttributeSelector {
+                name,
+                operator,
+                value,
+                case,
+            }),
+            _ => Err(self.error_before()),
+        }
+    }
+
+    // the `:` has been consumed; pseudo-elements and the pseudo-classes that are not
+    // known here are errors, which drops the rule as it could never match
+    fn parse_pseudo_class(&mut self) -> Result<PseudoClass, ParseError<ErrorCode>> {
+        let name = match self.next() {
+            Some(Token::Ident(name)) => name.to_ascii_lowercase(),
+            Some(Token::Function(name)) => return self.parse_functional_pseudo_class(&name),
+            _ => return Err(self.error_before()),
+        };
+        let pseudo_class = match &name[..] {
+            "root" => PseudoClass::Root,
+            "scope" => PseudoClass::Scope,
+            "empty" => PseudoClass::Empty,
+            "first-child" => PseudoClass::FirstChild,
+            "last-child" => PseudoClass::LastChild,
+            "only-child" => PseudoClass::OnlyChild,
+            "first-of-type" => PseudoClass::FirstOfType,
+            "last-of-type" => PseudoClass::LastOfType,
+            "only-of-type" => PseudoClass::OnlyOfType,
+            "hover" => PseudoClass::Hover,
+            "active" => PseudoClass::Active,
+            "focus" => PseudoClass::Focus,
+            "focus-within" => PseudoClass::FocusWithin,
+            "link" => PseudoClass::Link,
+            "visited" => PseudoClass::Visited,
+            "checked" => PseudoClass::Checked,
+            "disabled" => PseudoClass::Disabled,
+            _ => return Err(self.error_before()),
+        };
+        Ok(pseudo_class)
+    }
+
+    // the function token has been consumed; this goes up to and including the `)`
+    fn parse_functional_pseudo_class(
+        &mut self,
+        name: &str,
+    ) -> Result<PseudoClass, ParseError<ErrorCode>> {
+        let pseudo_class = match &name.to_ascii_lowercase()[..] {
+            "nth-child" => {
+                let nth = self.parse_nth()?;
+                PseudoClass::NthChild(nth, self.parse_of()?)
+            }
+            "nth-last-child" => {
+                let nth = self.parse_nth()?;
+                PseudoClass::NthLastChild(nth, self.parse_of()?)
+            }
+            "nth-of-type" => PseudoClass::NthOfType(self.parse_nth()?),
+            "nth-last-of-type" => PseudoClass::NthLastOfType(self.parse_nth()?),
+            "not" => PseudoClass::Not(self.parse_nested_list()?),
+            "is" => PseudoClass::Is(self.parse_nested_list()?),
+            "where" => PseudoClass::Where(self.parse_nested_list()?),
+            "has" => PseudoClass::Has(self.parse_relative_list()?),
+            _ => return Err(self.error_before()),
+        };
+        self.skip_whitespace();
+        match self.next() {
+            Some(Token::CloseParen) => Ok(pseudo_class),
+            _ => Err(self.error_before()),
+        }
+    }
+
+    // https://drafts.csswg.org/css-syntax-3/#anb-microsyntax
+    // the tokenizer splits `-n-3` and `2n-3` in several ways, so the `n` is looked
+    // for in idents and dimension units, and whatever follows it there is the start
+    // of `b`
+    fn parse_nth(&mut self) -> Result<Nth, ParseError<ErrorCode>> {
+        self.skip_whitespace();
+        let (a, rest) = match self.next() {
+            Some(Token::Ident(ident)) => {
+                let ident = ident.to_ascii_lowercase();
+                match &ident[..] {
+                    "odd" => return Ok(Nth::new(2, 1)),
+                    "even" => return Ok(Nth::new(2, 0)),
+                    _ => match ident.strip_prefix('-') {
+                        Some(ident) => (-1, Self::after_n(ident)),
+                        None => (1, Self::after_n(&ident)),
+                    },
+                }
+            }
+            // `+n`, which has to be written without whitespace after the `+`
+            Some(Token::Delim('+')) => match self.next() {
+                Some(Token::Ident(ident)) => (1, Self::after_n(&ident.to_ascii_lowercase())),
+                _ => return Err(self.error_before()),
+            },
+            Some(Token::Number(Numeric {
+                value,
+                integer: true,
+                ..
+            })) => return Ok(Nth::new(0, value as i32)),
+            Some(Token::Dimension(
+                Numeric {
+                    value,
+                    integer: true,
+                    ..
+                },
+                unit,
+            )) => (value as i32, Self::after_n(&unit.to_ascii_lowercase())),
+            _ => return Err(self.error_before()),
+        };
+        let rest = match rest {
+            Some(rest) => rest,
+            None => return Err(self.error_before()),
+        };
+        let b = match &rest[..] {
+            "" => {
+                self.skip_whitespace();
+                match self.peek() {
+                    Some(&Token::Delim(sign @ ('+' | '-'))) => {
+                        self.next();
+                        self.skip_whitespace();
+                        match self.next() {
+                            Some(Token::Number(Numeric {
+                                value,
+                                integer: true,
+                                signed: false,
+                            })) => match sign {
+                                '-' => -value as i32,
+                                _ => value as i32,
+                            },
+                            _ => return Err(self.error_before()),
+                        }
+                    }
+                    Some(&Token::Number(Numeric {
+                        value,
+                        integer: true,
+                        signed: true,
+                    })) => {
+                        self.next();
+                        value as i32
+                    }
+                    _ => 0,
+                }
+            }
+            "-" => {
+                self.skip_whitespace();
+                match self.next() {
+                    Some(Token::Number(Numeric {
+                        value,
+                        integer: true,
+                        signed: false,
+                    })) => -value as i32,
+                    _ => return Err(self.error_before()),
+                }
+            }
+            _ => match rest.strip_prefix('-') {
+                Some(digits) if digits.bytes().all(|b| b.is_ascii_digit()) => {
+                    -digits.parse::<i32>().map_err(|_| self.error_before())?
+                }
+                _ => return Err(self.error_before()),
+            },
+        };
+        Ok(Nth::new(a, b))
+    }
+
+    // what follows the `n` of `n`, `n-` or `n-3`
+    fn after_n(s: &str) -> Option<String> {
+        s.strip_prefix('n').map(str::to_owned)
+    }
+
+    // the optional `of S` of `:nth-child()`
+    fn parse_of(&mut self) -> Result<Vec<Selector>, ParseError<ErrorCode>> {
+        self.skip_whitespace();
+        match self.peek() {
+            Some(Token::Ident(of)) if of.eq_ignore_ascii_case("of") => {
+                self.next();
+                if !self.skip_whitespace() {
+                    return Err(self.error());
+                }
+                self.parse_nested_list()
+            }
+            _ => Ok(Vec::new()),
+        }
+    }
+
+    // whether there was any
+    fn skip_whitespace(&mut self) -> bool {
+        let start = self.index;
+        while self.peek() == Some(&Token::Whitespace) {
+            self.next();
+        }
+        self.index > start
+    }
+
+    fn peek(&self) -> Option<&'t Token> {
+        self.tokens.get(self.index).map(|(token, _)| token)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let (token, _) = self.tokens.get(self.index)?;
+        self.index += 1;
+        Some(token.clone())
+    }
+
+    // at the next token
+    fn error(&self) -> ParseError<ErrorCode> {
+        let position = self
+            .tokens
+            .get(self.index)
+            .map_or(self.end, |(_, span)| span.start);
+        ParseError::new(ErrorCode::InvalidSelector, position)
+    }
+
+    // at the token just consumed
+    fn error_before(&self) -> ParseError<ErrorCode> {
+        let position = match self.index {
+            0 => self.end,
+            i => self.tokens[i - 1].1.start,
+        };
+        ParseError::new(ErrorCode::InvalidSelector, position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn declarations_with_invalid_values_are_dropped() {
+        let css = "p { color: red; color: bogus; width: 10px; width: 5 apples; margin-top: auto }";
+        let (sheet, errors) = CssParser::new(css).parse_with_errors();
+        let declarations = &sheet.rules[0].declarations;
+        let properties: Vec<&str> = declarations.iter().map(|d| &d.property[..]).collect();
+        assert_eq!(properties, ["color", "width", "margin-top"]);
+        assert!(
+            matches!(declarations[0].value, Value::Color(c) if (c.r, c.g, c.b) == (1., 0., 0.))
+        );
+        assert!(matches!(declarations[1].value, Value::Length(n, Unit::Px) if n == 10.));
+        assert_eq!(
+            errors.into_iter().map(|e| e.code).collect::<Vec<_>>(),
+            [
+                ErrorCode::InvalidValue("bogus".to_owned()),
+                ErrorCode::InvalidValue("5 apples".to_owned()),
+            ]
+        );
     }
 
-    fn is_letter(c: char) -> bool {
-        ('A'..='Z').contains(&c) || ('a'..='z').contains(&c)
+    #[test]
+    fn media_rules_keep_their_place_and_media() {
+        let css = "a {} @media screen { b {} @media (min-width: 600px) { c {} } d {} } e {}";
+        let (sheet, errors) = CssParser::new(css).parse_with_errors();
+        let rules: Vec<_> = sheet
+            .rules
+            .iter()
+            .map(|r| (format!("{:?}", r.selectors[0]), r.media.join(" / ")))
+            .collect();
+        let expected = [
+            ("a", ""),
+            ("b", "screen"),
+            ("c", "screen / (min-width: 600px)"),
+            ("d", "screen"),
+            ("e", ""),
+        ];
+        let expected: Vec<_> = expected
+            .iter()
+            .map(|&(s, m)| (s.to_owned(), m.to_owned()))
+            .collect();
+        assert_eq!(rules, expected);
+        assert!(errors.is_empty());
     }
 
-    fn is_non_ascii(c: char) -> bool {
-        c >= '\u{0080}'
+    #[test]
+    fn skipped_at_rules_are_reported() {
+        let css = "@charset \"utf-8\"; @font-face { font-family: x } a { @page { } color: red }
+                   @media print; b {} @import 'late.css'; @media print { @import 'c.css'; }
+                   @media screen { d {}";
+        let (sheet, errors) = CssParser::new(css).parse_with_errors();
+        assert_eq!(sheet.rules.len(), 3);
+        assert_eq!(sheet.rules[0].declarations.len(), 1);
+        assert!(sheet.imports.is_empty());
+        assert_eq!(
+            errors.into_iter().map(|e| e.code).collect::<Vec<_>>(),
+            [
+                ErrorCode::UnsupportedAtRule("font-face".to_owned()),
+                ErrorCode::UnsupportedAtRule("page".to_owned()),
+                ErrorCode::InvalidAtRule,
+                ErrorCode::InvalidAtRule,
+                ErrorCode::InvalidAtRule,
+                ErrorCode::EofInBlock,
+            ]
+        );
     }
 }
@@ -0,0 +1,517 @@
+use crate::css_parser::ErrorCode;
+use crate::source::{ParseError, SourcePosition, Span};
+use std::fmt;
+use std::mem;
+
+// https://drafts.csswg.org/css-syntax-3/#tokenization
+#[derive(Debug, Clone, PartialEq)]
+pub enum Token {
+    Ident(String),
+    // the name of a function, which the `(` after it is part of
+    Function(String),
+    AtKeyword(String),
+    // `id` is set when the name would also start an identifier, as `#id` selectors need
+    Hash { value: String, id: bool },
+    String(String),
+    BadString,
+    Url(String),
+    BadUrl,
+    Delim(char),
+    Number(Numeric),
+    Percentage(Numeric),
+    Dimension(Numeric, String),
+    Whitespace,
+    Cdo,
+    Cdc,
+    Colon,
+    Semicolon,
+    Comma,
+    OpenSquare,
+    CloseSquare,
+    OpenParen,
+    CloseParen,
+    OpenCurly,
+    CloseCurly,
+}
+
+/// The value of a number, percentage or dimension token.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Numeric {
+    pub value: f32,
+    // written without a fraction or an exponent
+    pub integer: bool,
+    // written with a leading `+` or `-`
+    pub signed: bool,
+}
+
+/// Splits css into tokens. Comments are dropped, and each token comes with the
+/// span of source it was read from.
+pub struct Tokenizer {
+    input: Vec<char>,
+    pos: usize,
+    position: SourcePosition,
+    errors: Vec<ParseError<ErrorCode>>,
+}
+
+impl Tokenizer {
+    pub fn new(input: &str) -> Self {
+        Self::new_at(input, SourcePosition::default())
+    }
+
+    /// For css embedded in another document, so that spans and errors are relative
+    /// to that document.
+    pub fn new_at(input: &str, start: SourcePosition) -> Self {
+        Self {
+            input: input.chars().collect(),
+            pos: 0,
+            position: start,
+            errors: Vec::new(),
+        }
+    }
+
+    /// Where the next token starts, or the end of the input.
+    pub fn position(&self) -> SourcePosition {
+        self.position
+    }
+
+    /// The errors found so far, in source order.
+    pub fn take_errors(&mut self) -> Vec<ParseError<ErrorCode>> {
+        mem::take(&mut self.errors)
+    }
+
+    // https://drafts.csswg.org/css-syntax-3/#consume-token
+    fn consume_token(&mut self) -> Option<Token> {
+        let c = self.peek(0)?;
+        let token = match c {
+            c if is_whitespace(c) => {
+                while self.peek(0).is_some_and(is_whitespace) {
+                    self.next_char();
+                }
+                Token::Whitespace
+            }
+            '"' | '\'' => {
+                self.next_char();
+                self.consume_string(c)
+            }
+            '#' if self.peek(1).is_some_and(is_ident_char)
+                || valid_escape(self.peek(1), self.peek(2)) =>
+            {
+                self.next_char();
+                let id = would_start_ident(self.peek(0), self.peek(1), self.peek(2));
+                Token::Hash {
+                    value: self.consume_ident_sequence(),
+                    id,
+                }
+            }
+            '+' | '.' if starts_number(Some(c), self.peek(1), self.peek(2)) => {
+                self.consume_numeric()
+            }
+            '-' if starts_number(Some(c), self.peek(1), self.peek(2)) => self.consume_numeric(),
+            '-' if self.peek(1) == Some('-') && self.peek(2) == Some('>') => {
+                self.advance(3);
+                Token::Cdc
+            }
+            '-' if would_start_ident(Some(c), self.peek(1), self.peek(2)) => {
+                self.consume_ident_like()
+            }
+            '<' if self.peek(1) == Some('!')
+                && self.peek(2) == Some('-')
+                && self.peek(3) == Some('-') =>
+            {
+                self.advance(4);
+                Token::Cdo
+            }
+            '@' if would_start_ident(self.peek(1), self.peek(2), self.peek(3)) => {
+                self.next_char();
+                Token::AtKeyword(self.consume_ident_sequence())
+            }
+            '\\' if valid_escape(Some(c), self.peek(1)) => self.consume_ident_like(),
+            '\\' => {
+                self.error(ErrorCode::InvalidEscape);
+                self.next_char();
+                Token::Delim(c)
+            }
+            c if c.is_ascii_digit() => self.consume_numeric(),
+            c if is_ident_start(c) => self.consume_ident_like(),
+            _ => {
+                self.next_char();
+                match c {
+                    '(' => Token::OpenParen,
+                    ')' => Token::CloseParen,
+                    ',' => Token::Comma,
+                    ':' => Token::Colon,
+                    ';' => Token::Semicolon,
+                    '[' => Token::OpenSquare,
+                    ']' => Token::CloseSquare,
+                    '{' => Token::OpenCurly,
+                    '}' => Token::CloseCurly,
+                    _ => Token::Delim(c),
+                }
+            }
+        };
+        Some(token)
+    }
+
+    // https://drafts.csswg.org/css-syntax-3/#consume-comment
+    fn consume_comments(&mut self) {
+        while self.peek(0) == Some('/') && self.peek(1) == Some('*') {
+            self.advance(2);
+            loop {
+                match self.next_char() {
+                    Some('*') if self.peek(0) == Some('/') => {
+                        self.next_char();
+                        break;
+                    }
+                    Some(_) => {}
+                    None => {
+                        self.error(ErrorCode::EofInComment);
+                        return;
+                    }
+                }
+            }
+        }
+    }
+
+    // https://drafts.csswg.org/css-syntax-3/#consume-numeric-token
+    fn consume_numeric(&mut self) -> Token {
+        let number = self.consume_number();
+        if would_start_ident(self.peek(0), self.peek(1), self.peek(2)) {
+            Token::Dimension(number, self.consume_ident_sequence())
+        } else if self.peek(0) == Some('%') {
+            self.next_char();
+            Token::Percentage(number)
+        } else {
+            Token::Number(number)
+        }
+    }
+
+    // https://drafts.csswg.org/css-syntax-3/#consume-number
+    fn consume_number(&mut self) -> Numeric {
+        let mut repr = String::new();
+        let mut integer = true;
+        let signed = matches!(self.peek(0), Some('+') | Some('-'));
+        if signed {
+            repr.extend(self.next_char());
+        }
+        self.consume_digits(&mut repr);
+        if self.peek(0) == Some('.') && self.peek(1).is_some_and(|c| c.is_ascii_digit()) {
+            integer = false;
+            repr.extend(self.next_char());
+            self.consume_digits(&mut repr);
+        }
+        if matches!(self.peek(0), Some('e') | Some('E')) {
+            let exponent = match self.peek(1) {
+                Some('+') | Some('-') => self.peek(2).is_some_and(|c| c.is_ascii_digit()),
+                next => next.is_some_and(|c| c.is_ascii_digit()),
+            };
+            if exponent {
+                integer = false;
+                repr.push('e');
+                self.next_char();
+                if let Some(sign @ ('+' | '-')) = self.peek(0) {
+                    repr.push(sign);
+                    self.next_char();
+                }
+                self.consume_digits(&mut repr);
+            }
+        }
+        Numeric {
+            value: repr.parse::<f64>().unwrap_or_default() as f32,
+            integer,
+            signed,
+        }
+    }
+
+    fn consume_digits(&mut self, repr: &mut String) {
+        while self.peek(0).is_some_and(|c| c.is_ascii_digit()) {
+            repr.extend(self.next_char());
+        }
+    }
+
+    // https://drafts.csswg.org/css-syntax-3/#consume-ident-like-token
+    fn consume_ident_like(&mut self) -> Token {
+        let name = self.consume_ident_sequence();
+        if self.peek(0) != Some('(') {
+            return Token::Ident(name);
+        }
+        self.next_char();
+        if !name.eq_ignore_ascii_case("url") {
+            return Token::Function(name);
+        }
+        while self.peek(0).is_some_and(is_whitespace) && self.peek(1).is_some_and(is_whitespace) {
+            self.next_char();
+        }
+        let quoted = |c: Option<char>| c == Some('"') || c == Some('\'');
+        if quoted(self.peek(0)) || (self.peek(0).is_some_and(is_whitespace) && quoted(self.peek(1)))
+        {
+            // url("...") is a function taking a string, and not a url token
+            Token::Function(name)
+        } else {
+            self.consume_url()
+        }
+    }
+
+    // https://drafts.csswg.org/css-syntax-3/#consume-url-token
+    fn consume_url(&mut self) -> Token {
+        let mut url = String::new();
+        while self.peek(0).is_some_and(is_whitespace) {
+            self.next_char();
+        }
+        loop {
+            match self.next_char() {
+                Some(')') => return Token::Url(url),
+                None => {
+                    self.error(ErrorCode::EofInUrl);
+                    return Token::Url(url);
+                }
+                Some(c) if is_whitespace(c) => {
+                    while self.peek(0).is_some_and(is_whitespace) {
+                        self.next_char();
+                    }
+                    match self.peek(0) {
+                        Some(')') => {
+                            self.next_char();
+                            return Token::Url(url);
+                        }
+                        None => {
+                            self.error(ErrorCode::EofInUrl);
+                            return Token::Url(url);
+                        }
+                        Some(_) => return self.consume_bad_url(),
+                    }
+                }
+                Some('"') | Some('\'') | Some('(') => return self.consume_bad_url(),
+                Some(c) if is_non_printable(c) => return self.consume_bad_url(),
+                Some('\\') if valid_escape(Some('\\'), self.peek(0)) => {
+                    url.push(self.consume_escape());
+                }
+                Some('\\') => return self.consume_bad_url(),
+                Some(c) => url.push(c),
+            }
+        }
+    }
+
+    // https://drafts.csswg.org/css-syntax-3/#consume-remnants-of-bad-url
+    fn consume_bad_url(&mut self) -> Token {
+        self.error(ErrorCode::BadUrl);
+        loop {
+            match self.next_char() {
+                Some(')') | None => return Token::BadUrl,
+                Some('\\') if valid_escape(Some('\\'), self.peek(0)) => {
+                    self.consume_escape();
+                }
+                Some(_) => {}
+            }
+        }
+    }
+
+    // https://drafts.csswg.org/css-syntax-3/#consume-string-token
+    fn consume_string(&mut self, ending: char) -> Token {
+        let mut value = String::new();
+        loop {
+            match self.peek(0) {
+                Some(c) if c == ending => {
+                    self.next_char();
+                    return Token::String(value);
+                }
+                None => {
+                    self.error(ErrorCode::EofInString);
+                    return Token::String(value);
+                }
+                // left for the next token
+                Some(c) if is_newline(c) => {
+                    self.error(ErrorCode::NewlineInString);
+                    return Token::BadString;
+                }
+                Some('\\') => {
+                    self.next_char();
+                    match self.peek(0) {
+                        None => {}
+                        Some(c) if is_newline(c) => self.consume_whitespace_char(),
+                        Some(_) => value.push(self.consume_escape()),
+                    }
+                }
+                Some(c) => {
+                    self.next_char();
+                    value.push(c);
+                }
+            }
+        }
+    }
+
+    // https://drafts.csswg.org/css-syntax-3/#consume-name
+    fn consume_ident_sequence(&mut self) -> String {
+        let mut name = String::new();
+        loop {
+            match self.peek(0) {
+                Some(c) if is_ident_char(c) => {
+                    self.next_char();
+                    name.push(c);
+                }
+                Some('\\') if valid_escape(Some('\\'), self.peek(1)) => {
+                    self.next_char();
+                    name.push(self.consume_escape());
+                }
+                _ => return name,
+            }
+        }
+    }
+
+    // https://drafts.csswg.org/css-syntax-3/#consume-escaped-code-point
+    // the backslash has been consumed already
+    fn consume_escape(&mut self) -> char {
+        match self.next_char() {
+            Some(c) if c.is_ascii_hexdigit() => {
+                let mut hex = String::from(c);
+                while hex.len() < 6 && self.peek(0).is_some_and(|c| c.is_ascii_hexdigit()) {
+                    hex.extend(self.next_char());
+                }
+                if self.peek(0).is_some_and(is_whitespace) {
+                    self.consume_whitespace_char();
+                }
+                let code = u32::from_str_radix(&hex, 16).unwrap_or_default();
+                match char::from_u32(code) {
+                    Some(c) if code != 0 => c,
+                    _ => char::REPLACEMENT_CHARACTER,
+                }
+            }
+            Some(c) => c,
+            None => {
+                self.error(ErrorCode::InvalidEscape);
+                char::REPLACEMENT_CHARACTER
+            }
+        }
+    }
+
+    // one whitespace character, where CRLF counts as one
+    fn consume_whitespace_char(&mut self) {
+        if self.next_char() == Some('\r') && self.peek(0) == Some('\n') {
+            self.next_char();
+        }
+    }
+
+    fn peek(&self, n: usize) -> Option<char> {
+        self.input.get(self.pos + n).copied()
+    }
+
+    fn next_char(&mut self) -> Option<char> {
+        let c = self.peek(0)?;
+        self.pos += 1;
+        self.position.advance(c);
+        Some(c)
+    }
+
+    fn advance(&mut self, n: usize) {
+        for _ in 0..n {
+            self.next_char();
+        }
+    }
+
+    fn error(&mut self, code: ErrorCode) {
+        self.errors.push(ParseError::new(code, self.position));
+    }
+}
+
+impl Iterator for Tokenizer {
+    type Item = (Token, Span);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.consume_comments();
+        let start = self.position;
+        let token = self.consume_token()?;
+        Some((token, Span::new(start, self.position)))
+    }
+}
+
+// https://drafts.csswg.org/css-syntax-3/#serialization
+// enough to write a value back out; escapes are not put back
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Ident(name) => write!(f, "{}", name),
+            Token::Function(name) => write!(f, "{}(", name),
+            Token::AtKeyword(name) => write!(f, "@{}", name),
+            Token::Hash { value, .. } => write!(f, "#{}", value),
+            Token::String(value) => write!(
+                f,
+                "\"{}\"",
+                value.replace('\\', "\\\\").replace('"', "\\\"")
+            ),
+            Token::BadString => writeln!(f, "\""),
+            Token::Url(url) => write!(f, "url({})", url),
+            Token::BadUrl => write!(f, "url()"),
+            Token::Delim(c) => write!(f, "{}", c),
+            Token::Number(n) => write!(f, "{}", n),
+            Token::Percentage(n) => write!(f, "{}%", n),
+            Token::Dimension(n, unit) => write!(f, "{}{}", n, unit),
+            Token::Whitespace => write!(f, " "),
+            Token::Cdo => write!(f, "<!--"),
+            Token::Cdc => write!(f, "-->"),
+            Token::Colon => write!(f, ":"),
+            Token::Semicolon => write!(f, ";"),
+            Token::Comma => write!(f, ","),
+            Token::OpenSquare => write!(f, "["),
+            Token::CloseSquare => write!(f, "]"),
+            Token::OpenParen => write!(f, "("),
+            Token::CloseParen => write!(f, ")"),
+            Token::OpenCurly => write!(f, "{{"),
+            Token::CloseCurly => write!(f, "}}"),
+        }
+    }
+}
+
+impl fmt::Display for Numeric {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.signed && self.value >= 0. {
+            write!(f, "+")?;
+        }
+        write!(f, "{}", self.value)
+    }
+}
+
+fn is_newline(c: char) -> bool {
+    c == '\n' || c == '\r' || c == '\x0C'
+}
+
+fn is_whitespace(c: char) -> bool {
+    is_newline(c) || c == ' ' || c == '\t'
+}
+
+fn is_ident_start(c: char) -> bool {
+    c.is_ascii_alphabetic() || c == '_' || c >= '\u{0080}'
+}
+
+fn is_ident_char(c: char) -> bool {
+    is_ident_start(c) || c.is_ascii_digit() || c == '-'
+}
+
+fn is_non_printable(c: char) -> bool {
+    matches!(c, '\0'..='\x08' | '\x0B' | '\x0E'..='\x1F' | '\x7F')
+}
+
+// https://drafts.csswg.org/css-syntax-3/#starts-with-a-valid-escape
+fn valid_escape(first: Option<char>, second: Option<char>) -> bool {
+    first == Some('\\') && !second.is_some_and(is_newline)
+}
+
+// https://drafts.csswg.org/css-syntax-3/#would-start-an-identifier
+fn would_start_ident(first: Option<char>, second: Option<char>, third: Option<char>) -> bool {
+    match first {
+        Some('-') => {
+            second.is_some_and(|c| is_ident_start(c) || c == '-') || valid_escape(second, third)
+        }
+        Some('\\') => valid_escape(first, second),
+        Some(c) => is_ident_start(c),
+        None => false,
+    }
+}
+
+// https://drafts.csswg.org/css-syntax-3/#starts-with-a-number
+fn starts_number(first: Option<char>, second: Option<char>, third: Option<char>) -> bool {
+    let digit = |c: Option<char>| c.is_some_and(|c| c.is_ascii_digit());
+    match first {
+        Some('+') | Some('-') => digit(second) || (second == Some('.') && digit(third)),
+        Some('.') => digit(second),
+        c => digit(c),
+    }
+}
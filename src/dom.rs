@@ -1,115 +1,1492 @@
+use std::cmp::Ordering;
 use std::collections::{HashMap, HashSet};
 use std::fmt;
 use std::fmt::Formatter;
-use crate::{css_parser, html_parser};
-use crate::css::StyleSheet;
-use std::borrow::Borrow;
-use std::io::Read;
+use crate::{attributes, css_parser, html_parser};
+use crate::attributes::{AttrList, ClassList, Dir};
+use crate::mutation::{MutationObservers, MutationRecord, ObserverId, ObserverOptions};
+use crate::html_parser::QuirksMode;
+use crate::html_tokenizer::Doctype;
+use crate::css::Selector;
+use crate::html_serializer::HtmlSerializer;
+use crate::traversal::{
+    self, BreadthFirst, PostOrder, PreOrder, TreeBreadthFirst, TreePostOrder, TreePreOrder, Visit, Visitor,
+};
+use crate::source::{ParseError, Span};
+use crate::style::StyledNode;
+use url::Url;
+use encoding_rs::{Encoding, UTF_8};
 
 pub struct Node {
     pub children: Vec<Node>,
     pub node_type: NodeType,
+    // where the parser read this node from, if it was not implied
+    pub span: Option<Span>,
 }
 
+#[derive(Clone)]
 pub enum NodeType {
     Text(String),
     // Plain old text
     Element(ElementData),
     // <tag_name,attributes> TODO support for closed tags
     Comment(String), // <!--Comment-->
+    // the root of a Tree, holding the top level nodes
+    Document,
 }
 
+#[derive(Clone)]
 pub struct ElementData {
     pub(crate) tag_name: String,
-    attributes: AttrMap,
+    namespace: Namespace,
+    attributes: AttrList,
+}
+
+// https://infra.spec.whatwg.org/#namespaces
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Namespace {
+    Html,
+    MathMl,
+    Svg,
+    XLink,
+    Xml,
+    Xmlns,
+}
+
+impl Namespace {
+    pub fn uri(self) -> &'static str {
+        match self {
+            Namespace::Html => "http://www.w3.org/1999/xhtml",
+            Namespace::MathMl => "http://www.w3.org/1998/Math/MathML",
+            Namespace::Svg => "http://www.w3.org/2000/svg",
+            Namespace::XLink => "http://www.w3.org/1999/xlink",
+            Namespace::Xml => "http://www.w3.org/XML/1998/namespace",
+            Namespace::Xmlns => "http://www.w3.org/2000/xmlns/",
+        }
+    }
+
+    pub fn from_uri(uri: &str) -> Option<Self> {
+        [
+            Namespace::Html,
+            Namespace::MathMl,
+            Namespace::Svg,
+            Namespace::XLink,
+            Namespace::Xml,
+            Namespace::Xmlns,
+        ]
+        .into_iter()
+        .find(|n| n.uri() == uri)
+    }
+}
+
+// https://dom.spec.whatwg.org/#exceptionnames
+#[derive(Debug, Clone, PartialEq)]
+pub enum DomError {
+    // the node cannot go where it was asked to, such as inside a text node
+    HierarchyRequest,
+    // the child index is past the last child
+    NotFound,
+    // the attribute name is not one the html syntax could have produced
+    InvalidCharacter,
+    // the selector given to a query does not parse
+    Syntax(ParseError<css_parser::ErrorCode>),
+    // a SyntaxError too in the spec: a class list was given an empty token
+    EmptyToken,
+    // a TypeError rather than a DOMException in the spec: observe was given
+    // options that watch for nothing
+    Type,
 }
 
 impl ElementData {
-    pub fn new(tag_name: String, attributes: AttrMap) -> Self {
+    /// An element in the html namespace.
+    pub fn new(tag_name: String, attributes: AttrList) -> Self {
+        Self::new_ns(Namespace::Html, tag_name, attributes)
+    }
+
+    /// An element in `namespace`, such as the `svg` and `math` elements the
+    /// parser creates for foreign content.
+    pub fn new_ns(namespace: Namespace, tag_name: String, attributes: AttrList) -> Self {
         Self {
             tag_name,
+            namespace,
             attributes,
         }
     }
 
+    pub fn namespace(&self) -> Namespace {
+        self.namespace
+    }
+
+    pub fn is_html(&self) -> bool {
+        self.namespace == Namespace::Html
+    }
+
+    /// The attributes in source order.
+    pub fn attributes(&self) -> &AttrList {
+        &self.attributes
+    }
+
+    /// The namespace of the attribute with this qualified name, such as XLink
+    /// for `xlink:href` on an svg element.
+    pub fn attribute_namespace(&self, name: &str) -> Option<Namespace> {
+        let name = self.qualified_name(name);
+        self.attributes.iter().find(|a| a.name == name)?.namespace
+    }
+
     pub fn get_id(&self) -> Option<&String> {
         self.attributes.get("id")
     }
 
+    pub fn get_attribute(&self, name: &str) -> Option<&String> {
+        self.attributes.get(&self.qualified_name(name))
+    }
+
+    pub fn has_attribute(&self, name: &str) -> bool {
+        self.attributes.contains(&self.qualified_name(name))
+    }
+
+    pub fn get_attribute_ns(
+        &self,
+        namespace: Option<Namespace>,
+        local_name: &str,
+    ) -> Option<&String> {
+        self.attributes.get_ns(namespace, local_name)
+    }
+
+    /// Sets `name` to `value`. Names are lowercased on html elements as the parser
+    /// would have, and kept as they are on foreign ones.
+    pub fn set_attribute(&mut self, name: &str, value: &str) -> Result<(), DomError> {
+        if !is_valid_attribute_name(name) {
+            return Err(DomError::InvalidCharacter);
+        }
+        let name = self.qualified_name(name);
+        let namespace = self.attribute_namespace(&name);
+        self.attributes.set(&name, value, namespace);
+        Ok(())
+    }
+
+    /// Sets the attribute `qualified_name`, which carries its prefix if it has
+    /// one, in `namespace`.
+    pub fn set_attribute_ns(
+        &mut self,
+        namespace: Option<Namespace>,
+        qualified_name: &str,
+        value: &str,
+    ) -> Result<(), DomError> {
+        if !is_valid_attribute_name(qualified_name) {
+            return Err(DomError::InvalidCharacter);
+        }
+        self.attributes.set(qualified_name, value, namespace);
+        Ok(())
+    }
+
+    /// Removes `name` and returns the value it had.
+    pub fn remove_attribute(&mut self, name: &str) -> Option<String> {
+        let name = self.qualified_name(name);
+        self.attributes.remove(&name).map(|a| a.value)
+    }
+
+    // https://dom.spec.whatwg.org/#concept-element-attributes-get-by-name
+    pub(crate) fn qualified_name(&self, name: &str) -> String {
+        if self.is_html() {
+            name.to_ascii_lowercase()
+        } else {
+            name.to_owned()
+        }
+    }
+
+    // read from the attribute on every call, so it always reflects set_attribute
     pub fn get_classes(&self) -> HashSet<&str> {
         match self.attributes.get("class") {
             None => HashSet::new(),
-            Some(s) => s.split(' ').collect(),
+            Some(s) => s.split_ascii_whitespace().collect(),
+        }
+    }
+
+    /// The `class` attribute as a token list; see `set_class_list`.
+    pub fn class_list(&self) -> ClassList {
+        ClassList::parse(self.attributes.get("class").map_or("", |s| s.as_str()))
+    }
+
+    pub fn set_class_list(&mut self, classes: &ClassList) {
+        self.attributes.set("class", &classes.to_string(), None);
+    }
+
+    pub fn href(&self) -> Option<&str> {
+        self.get_attribute("href").map(String::as_str)
+    }
+
+    pub fn src(&self) -> Option<&str> {
+        self.get_attribute("src").map(String::as_str)
+    }
+
+    pub fn hidden(&self) -> bool {
+        self.has_attribute("hidden")
+    }
+
+    pub fn set_hidden(&mut self, hidden: bool) {
+        if hidden {
+            self.attributes.set("hidden", "", None);
+        } else {
+            self.attributes.remove("hidden");
         }
     }
+
+    /// The element's own `lang`, or `xml:lang` which wins over it; see
+    /// `Tree::language` for the inherited one.
+    pub fn lang(&self) -> Option<&str> {
+        self.attributes
+            .get_ns(Some(Namespace::Xml), "lang")
+            .or_else(|| self.attributes.get_ns(None, "lang"))
+            .map(String::as_str)
+    }
+
+    /// `dir` when it holds one of its keywords.
+    pub fn dir(&self) -> Option<Dir> {
+        self.get_attribute("dir").and_then(|d| Dir::parse(d))
+    }
+
+    pub fn set_dir(&mut self, dir: Dir) {
+        self.attributes.set("dir", dir.as_str(), None);
+    }
+
+    /// `tabindex` when it holds an integer.
+    pub fn tab_index(&self) -> Option<i32> {
+        self.get_attribute("tabindex").and_then(|t| attributes::parse_integer(t))
+    }
+
+    pub fn set_tab_index(&mut self, index: i32) {
+        self.attributes.set("tabindex", &index.to_string(), None);
+    }
+
+    /// The `data-*` attributes under their dataset names, `fooBar` for
+    /// `data-foo-bar`, in source order.
+    pub fn dataset(&self) -> Vec<(String, &str)> {
+        self.attributes
+            .iter()
+            .filter(|a| a.namespace.is_none())
+            .filter_map(|a| Some((attributes::dataset_name(&a.name)?, a.value.as_str())))
+            .collect()
+    }
+
+    /// The dataset entry `name`, so `get_data("fooBar")` reads `data-foo-bar`.
+    pub fn get_data(&self, name: &str) -> Option<&str> {
+        let attribute = attributes::dataset_attribute(name).ok()?;
+        self.attributes.get(&attribute).map(String::as_str)
+    }
+
+    pub fn set_data(&mut self, name: &str, value: &str) -> Result<(), DomError> {
+        let attribute = attributes::dataset_attribute(name)?;
+        self.set_attribute(&attribute, value)
+    }
+
+    pub fn remove_data(&mut self, name: &str) -> Option<String> {
+        let attribute = attributes::dataset_attribute(name).ok()?;
+        self.attributes.remove(&attribute).map(|a| a.value)
+    }
+
+    pub fn is_void(&self) -> bool {
+        self.is_html() && is_void_element(&self.tag_name)
+    }
+}
+
+fn is_valid_attribute_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.chars().any(|c| {
+            c.is_ascii_whitespace() || c.is_control() || matches!(c, '"' | '\'' | '>' | '/' | '=')
+        })
+}
+
+// https://html.spec.whatwg.org/multipage/syntax.html#void-elements
+// plus the legacy ones that the parser and serializer also treat as void
+const VOID_ELEMENTS: [&str; 18] = [
+    "area", "base", "basefont", "bgsound", "br", "col", "embed", "frame", "hr", "img", "input",
+    "keygen", "link", "meta", "param", "source", "track", "wbr",
+];
+
+fn parse_selectors(selectors: &str) -> Result<Vec<Selector>, DomError> {
+    css_parser::CssParser::new(selectors)
+        .parse_selector_list()
+        .map_err(DomError::Syntax)
 }
 
-pub(crate) type AttrMap = HashMap<String, String>;
+fn matches_any(node: NodeRef, selectors: &[Selector], scope: Option<NodeId>) -> bool {
+    selectors.iter().any(|s| StyledNode::scoped_selector_matches(node, s, scope))
+}
+
+/// Void elements have a start tag only and can never have children.
+pub fn is_void_element(tag_name: &str) -> bool {
+    VOID_ELEMENTS.contains(&tag_name)
+}
 
 impl Node {
     pub fn new(node_type: NodeType, children: Vec<Node>) -> Self {
         Self {
             node_type,
             children,
+            span: None,
         }
     }
-    pub fn get_stylesheet_from_file(&self, base_url: &str) -> Option<StyleSheet> {
-        let mut res: Option<StyleSheet> = None;
+
+    pub fn element(tag_name: &str) -> Self {
+        let element = ElementData::new(tag_name.to_ascii_lowercase(), AttrList::new());
+        Self::new(NodeType::Element(element), Vec::new())
+    }
+
+    pub fn text(data: &str) -> Self {
+        Self::new(NodeType::Text(data.to_owned()), Vec::new())
+    }
+
+    pub fn can_have_children(&self) -> bool {
         match self.node_type {
-            NodeType::Element(ref e) => {
-                if e.tag_name == "link" {
-                    match e.attributes.get("rel") {
-                        Some(ref s) => {
-                            if s == &"stylesheet" {
-                                let url = base_url.to_owned() + &*"\\" + e.attributes.get("href").unwrap();
-                                let css = std::fs::read_to_string(url).unwrap();
-                                return Some(css_parser::CssParser::new(&css).parse_stylesheet());
-                            }
-                        }
-                        _ => {}
-                    }
+            NodeType::Element(ref e) => !e.is_void(),
+            NodeType::Document => true,
+            _ => false,
+        }
+    }
+
+    pub fn as_element(&self) -> Option<&ElementData> {
+        match self.node_type {
+            NodeType::Element(ref e) => Some(e),
+            _ => None,
+        }
+    }
+
+    pub fn as_element_mut(&mut self) -> Option<&mut ElementData> {
+        match self.node_type {
+            NodeType::Element(ref mut e) => Some(e),
+            _ => None,
+        }
+    }
+
+    /// Adds `child` after the last child and returns it.
+    pub fn append_child(&mut self, child: Node) -> Result<&mut Node, DomError> {
+        let index = self.children.len();
+        self.insert_before(child, index)
+    }
+
+    /// Inserts `child` in front of the child at `index`, or last when `index` is the
+    /// number of children, and returns it.
+    pub fn insert_before(&mut self, child: Node, index: usize) -> Result<&mut Node, DomError> {
+        // https://dom.spec.whatwg.org/#concept-node-ensure-pre-insertion-validity
+        if !self.can_have_children() || matches!(child.node_type, NodeType::Document) {
+            return Err(DomError::HierarchyRequest);
+        }
+        if index > self.children.len() {
+            return Err(DomError::NotFound);
+        }
+        self.children.insert(index, child);
+        Ok(&mut self.children[index])
+    }
+
+    /// Takes out the child at `index`.
+    pub fn remove_child(&mut self, index: usize) -> Result<Node, DomError> {
+        if index >= self.children.len() {
+            return Err(DomError::NotFound);
+        }
+        Ok(self.children.remove(index))
+    }
+
+    /// Puts `child` where the child at `index` was and returns the one it replaced.
+    pub fn replace_child(&mut self, child: Node, index: usize) -> Result<Node, DomError> {
+        if matches!(child.node_type, NodeType::Document) {
+            return Err(DomError::HierarchyRequest);
+        }
+        match self.children.get_mut(index) {
+            Some(old) => Ok(std::mem::replace(old, child)),
+            None => Err(DomError::NotFound),
+        }
+    }
+
+    /// A copy of this node, with copies of all its descendants when `deep` is set.
+    pub fn clone_node(&self, deep: bool) -> Node {
+        let children = if deep {
+            self.children.iter().map(|c| c.clone_node(true)).collect()
+        } else {
+            Vec::new()
+        };
+        Self {
+            node_type: self.node_type.clone(),
+            children,
+            span: self.span,
+        }
+    }
+
+    /// The text of every text node in this subtree, in document order; for a
+    /// comment, its data. A document has no text content of its own.
+    pub fn text_content(&self) -> String {
+        match self.node_type {
+            NodeType::Text(ref t) | NodeType::Comment(ref t) => t.clone(),
+            NodeType::Document => String::new(),
+            NodeType::Element(_) => self
+                .pre_order()
+                .filter_map(|n| match n.node_type {
+                    NodeType::Text(ref t) => Some(&t[..]),
+                    _ => None,
+                })
+                .collect(),
+        }
+    }
+
+    /// Replaces the children of an element with a single text node, or with nothing
+    /// when `text` is empty; for a text node or comment, replaces its data. A
+    /// document is left as it is.
+    pub fn set_text_content(&mut self, text: &str) {
+        match self.node_type {
+            NodeType::Text(ref mut t) | NodeType::Comment(ref mut t) => *t = text.to_owned(),
+            NodeType::Document => {}
+            NodeType::Element(_) => {
+                self.children.clear();
+                if !text.is_empty() {
+                    self.children.push(Node::text(text));
                 }
             }
-            _ => {}
         }
+    }
 
-        for x in &self.children {
-            let s = x.get_stylesheet_from_file(base_url);
-            if let Some(_) = s {
-                res = s
+    /// The html of this node's children, see `HtmlSerializer::serialize_children`.
+    pub fn inner_html(&self) -> String {
+        HtmlSerializer::new().serialize_children(self)
+    }
+
+    /// The html of this node and its children.
+    pub fn outer_html(&self) -> String {
+        HtmlSerializer::new().serialize_node(self)
+    }
+
+    /// This node and everything below it, each node before its children.
+    pub fn pre_order(&self) -> PreOrder<'_> {
+        PreOrder::new(self)
+    }
+
+    /// This node and everything below it, each node after its children.
+    pub fn post_order(&self) -> PostOrder<'_> {
+        PostOrder::new(self)
+    }
+
+    /// This node and everything below it, level by level.
+    pub fn breadth_first(&self) -> BreadthFirst<'_> {
+        BreadthFirst::new(self)
+    }
+
+    /// Runs `visitor` over this node and everything below it.
+    pub fn accept<V: Visitor + ?Sized>(&self, visitor: &mut V) {
+        traversal::walk(self, visitor);
+    }
+}
+
+/// A handle to a node of a `Tree`. It stays valid for as long as the tree does,
+/// including after the node has been removed from its parent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct NodeId(usize);
+
+impl NodeId {
+    // the parser numbers its nodes the way the tree it builds does, see
+    // `HtmlParser::parse_tree`
+    pub(crate) fn from_index(index: usize) -> Self {
+        NodeId(index)
+    }
+}
+
+struct TreeNode {
+    node_type: NodeType,
+    span: Option<Span>,
+    parent: Option<NodeId>,
+    first_child: Option<NodeId>,
+    last_child: Option<NodeId>,
+    previous_sibling: Option<NodeId>,
+    next_sibling: Option<NodeId>,
+}
+
+/// A DOM whose nodes live in one arena and link to their parent, children and
+/// siblings by `NodeId`. Its root is a `NodeType::Document` node.
+pub struct Tree {
+    nodes: Vec<TreeNode>,
+}
+
+impl Tree {
+    const DOCUMENT: NodeId = NodeId(0);
+
+    pub fn new() -> Self {
+        let mut tree = Self { nodes: Vec::new() };
+        tree.create_node(NodeType::Document);
+        tree
+    }
+
+    /// Moves owned trees, such as `HtmlParser::parse_nodes` returns, under a new document.
+    pub fn from_nodes(nodes: Vec<Node>) -> Self {
+        let mut tree = Self::new();
+        for node in nodes {
+            let id = tree.import(node);
+            tree.append(Self::DOCUMENT, id);
+        }
+        tree
+    }
+
+    pub fn document(&self) -> NodeId {
+        Self::DOCUMENT
+    }
+
+    pub fn get(&self, id: NodeId) -> NodeRef<'_> {
+        NodeRef { tree: self, id }
+    }
+
+    pub fn node_type(&self, id: NodeId) -> &NodeType {
+        &self.nodes[id.0].node_type
+    }
+
+    pub fn node_type_mut(&mut self, id: NodeId) -> &mut NodeType {
+        &mut self.nodes[id.0].node_type
+    }
+
+    pub fn element(&self, id: NodeId) -> Option<&ElementData> {
+        match self.nodes[id.0].node_type {
+            NodeType::Element(ref e) => Some(e),
+            _ => None,
+        }
+    }
+
+    pub fn element_mut(&mut self, id: NodeId) -> Option<&mut ElementData> {
+        match self.nodes[id.0].node_type {
+            NodeType::Element(ref mut e) => Some(e),
+            _ => None,
+        }
+    }
+
+    pub fn span(&self, id: NodeId) -> Option<Span> {
+        self.nodes[id.0].span
+    }
+
+    pub(crate) fn set_span(&mut self, id: NodeId, span: Option<Span>) {
+        self.nodes[id.0].span = span;
+    }
+
+    pub fn parent(&self, id: NodeId) -> Option<NodeId> {
+        self.nodes[id.0].parent
+    }
+
+    pub fn first_child(&self, id: NodeId) -> Option<NodeId> {
+        self.nodes[id.0].first_child
+    }
+
+    pub fn last_child(&self, id: NodeId) -> Option<NodeId> {
+        self.nodes[id.0].last_child
+    }
+
+    pub fn previous_sibling(&self, id: NodeId) -> Option<NodeId> {
+        self.nodes[id.0].previous_sibling
+    }
+
+    pub fn next_sibling(&self, id: NodeId) -> Option<NodeId> {
+        self.nodes[id.0].next_sibling
+    }
+
+    pub fn children(&self, id: NodeId) -> Children<'_> {
+        Children {
+            tree: self,
+            next: self.first_child(id),
+        }
+    }
+
+    /// Whether `ancestor` is `id` or has it somewhere below.
+    pub fn is_inclusive_ancestor(&self, ancestor: NodeId, id: NodeId) -> bool {
+        let mut current = Some(id);
+        while let Some(node) = current {
+            if node == ancestor {
+                return true;
             }
+            current = self.parent(node);
         }
-        res
+        false
     }
-    pub fn get_stylesheet_from_url(&self, url: &str) -> Option<StyleSheet> {
-        let mut res: Option<StyleSheet> = None;
-        match self.node_type {
-            NodeType::Element(ref e) => {
-                if e.tag_name == "link" {
-                    match e.attributes.get("rel") {
-                        Some(ref s) => {
-                            if s == &"stylesheet" {
-                                let url = url.to_owned()+ e.attributes.get("href").unwrap();
-                                let mut res = reqwest::blocking::get(url).unwrap();
-                                let mut css = String::new();
-                                res.read_to_string(&mut css).unwrap();
-                                return Some(css_parser::CssParser::new(&css).parse_stylesheet());
-                            }
-                        }
-                        _ => {}
+
+    /// Where `a` comes relative to `b` in a depth first walk of their tree, in
+    /// which every node comes before its descendants. Nodes of different trees
+    /// are compared by their roots.
+    pub fn tree_order(&self, a: NodeId, b: NodeId) -> Ordering {
+        let path = |id| {
+            let mut path = vec![id];
+            let mut current = id;
+            while let Some(parent) = self.parent(current) {
+                path.push(parent);
+                current = parent;
+            }
+            path.reverse();
+            path
+        };
+        let (a_path, b_path) = (path(a), path(b));
+        match a_path.iter().zip(&b_path).position(|(a, b)| a != b) {
+            // one is an ancestor of the other, or they are the same node
+            None => a_path.len().cmp(&b_path.len()),
+            Some(0) => a_path[0].cmp(&b_path[0]),
+            Some(i) => {
+                let mut sibling = self.next_sibling(a_path[i]);
+                while let Some(next) = sibling {
+                    if next == b_path[i] {
+                        return Ordering::Less;
                     }
+                    sibling = self.next_sibling(next);
+                }
+                Ordering::Greater
+            }
+        }
+    }
+
+    /// The node at `id` and everything below it in tree order.
+    pub fn pre_order(&self, id: NodeId) -> TreePreOrder<'_> {
+        TreePreOrder::new(self, id)
+    }
+
+    /// The node at `id` and everything below it, each node after its children.
+    pub fn post_order(&self, id: NodeId) -> TreePostOrder<'_> {
+        TreePostOrder::new(self, id)
+    }
+
+    /// The node at `id` and everything below it, level by level.
+    pub fn breadth_first(&self, id: NodeId) -> TreeBreadthFirst<'_> {
+        TreeBreadthFirst::new(self, id)
+    }
+
+    /// The first element below `scope`, in tree order, that matches `selectors`.
+    pub fn query_selector(&self, scope: NodeId, selectors: &str) -> Result<Option<NodeId>, DomError> {
+        Ok(self.query_selector_all(scope, selectors)?.into_iter().next())
+    }
+
+    /// Every element below `scope` that matches `selectors`, in tree order.
+    /// Combinators see the whole tree, and `:scope` is the element at `scope`.
+    // https://dom.spec.whatwg.org/#scope-match-a-selectors-string
+    pub fn query_selector_all(&self, scope: NodeId, selectors: &str) -> Result<Vec<NodeId>, DomError> {
+        let selectors = parse_selectors(selectors)?;
+        // from a document, :scope is the root element
+        let scoping_root = self.element(scope).map(|_| scope);
+        let matches = self
+            .pre_order(scope)
+            .skip(1)
+            .filter(|&id| matches_any(self.get(id), &selectors, scoping_root))
+            .collect();
+        Ok(matches)
+    }
+
+    /// Adds a node that has no parent yet.
+    pub fn create_node(&mut self, node_type: NodeType) -> NodeId {
+        self.nodes.push(TreeNode {
+            node_type,
+            span: None,
+            parent: None,
+            first_child: None,
+            last_child: None,
+            previous_sibling: None,
+            next_sibling: None,
+        });
+        NodeId(self.nodes.len() - 1)
+    }
+
+    pub fn create_element(&mut self, tag_name: &str) -> NodeId {
+        let element = ElementData::new(tag_name.to_ascii_lowercase(), AttrList::new());
+        self.create_node(NodeType::Element(element))
+    }
+
+    pub fn create_text(&mut self, data: &str) -> NodeId {
+        self.create_node(NodeType::Text(data.to_owned()))
+    }
+
+    /// Moves an owned tree into the arena, without a parent.
+    pub fn import(&mut self, node: Node) -> NodeId {
+        let id = self.create_node(node.node_type);
+        self.set_span(id, node.span);
+        for child in node.children {
+            let child = self.import(child);
+            self.append(id, child);
+        }
+        id
+    }
+
+    /// An owned copy of the subtree at `id`, for the APIs that work on `Node`.
+    pub fn to_node(&self, id: NodeId) -> Node {
+        let children = self.children(id).map(|c| self.to_node(c)).collect();
+        let mut node = Node::new(self.node_type(id).clone(), children);
+        node.span = self.span(id);
+        node
+    }
+
+    /// Adds `child` after the last child of `parent`, first taking it from where it was.
+    pub fn append_child(&mut self, parent: NodeId, child: NodeId) -> Result<NodeId, DomError> {
+        self.insert_before(parent, child, None)
+    }
+
+    /// Inserts `child` in front of `reference`, which must be a child of `parent`, or
+    /// last when there is no reference. `child` is first taken from where it was.
+    pub fn insert_before(
+        &mut self,
+        parent: NodeId,
+        child: NodeId,
+        reference: Option<NodeId>,
+    ) -> Result<NodeId, DomError> {
+        self.check_insert(parent, child)?;
+        if let Some(reference) = reference {
+            if self.parent(reference) != Some(parent) {
+                return Err(DomError::NotFound);
+            }
+            if reference == child {
+                return Ok(child);
+            }
+        }
+        self.detach(child);
+        match reference {
+            Some(reference) => self.insert(parent, child, reference),
+            None => self.append(parent, child),
+        }
+        Ok(child)
+    }
+
+    /// Takes `child` out of `parent`. It keeps its own children and can be inserted again.
+    pub fn remove_child(&mut self, parent: NodeId, child: NodeId) -> Result<NodeId, DomError> {
+        if self.parent(child) != Some(parent) {
+            return Err(DomError::NotFound);
+        }
+        self.detach(child);
+        Ok(child)
+    }
+
+    /// Puts `new` where `old` was among the children of `parent` and returns `old`.
+    pub fn replace_child(
+        &mut self,
+        parent: NodeId,
+        new: NodeId,
+        old: NodeId,
+    ) -> Result<NodeId, DomError> {
+        if self.parent(old) != Some(parent) {
+            return Err(DomError::NotFound);
+        }
+        self.check_insert(parent, new)?;
+        if new != old {
+            let reference = match self.next_sibling(old) {
+                Some(next) if next == new => self.next_sibling(new),
+                next => next,
+            };
+            self.detach(old);
+            self.insert_before(parent, new, reference)?;
+        }
+        Ok(old)
+    }
+
+    /// A copy of the node at `id` without a parent, with copies of all its descendants
+    /// when `deep` is set.
+    pub fn clone_node(&mut self, id: NodeId, deep: bool) -> NodeId {
+        let copy = self.create_node(self.node_type(id).clone());
+        self.set_span(copy, self.span(id));
+        if deep {
+            let children: Vec<_> = self.children(id).collect();
+            for child in children {
+                let child = self.clone_node(child, true);
+                self.append(copy, child);
+            }
+        }
+        copy
+    }
+
+    /// As `Node::text_content`.
+    pub fn text_content(&self, id: NodeId) -> String {
+        match *self.node_type(id) {
+            NodeType::Text(ref t) | NodeType::Comment(ref t) => t.clone(),
+            NodeType::Document => String::new(),
+            NodeType::Element(_) => self
+                .pre_order(id)
+                .filter_map(|n| match *self.node_type(n) {
+                    NodeType::Text(ref t) => Some(&t[..]),
+                    _ => None,
+                })
+                .collect(),
+        }
+    }
+
+    /// The language of the node at `id`: the `lang` of the nearest element at or
+    /// above it that has one.
+    // https://html.spec.whatwg.org/multipage/dom.html#language
+    pub fn language(&self, id: NodeId) -> Option<&str> {
+        let mut current = Some(id);
+        while let Some(node) = current {
+            if let Some(lang) = self.element(node).and_then(ElementData::lang) {
+                return Some(lang);
+            }
+            current = self.parent(node);
+        }
+        None
+    }
+
+    /// As `Node::set_text_content`; the old children are detached.
+    pub fn set_text_content(&mut self, id: NodeId, text: &str) {
+        match self.nodes[id.0].node_type {
+            NodeType::Text(ref mut t) | NodeType::Comment(ref mut t) => *t = text.to_owned(),
+            NodeType::Document => {}
+            NodeType::Element(_) => {
+                while let Some(child) = self.first_child(id) {
+                    self.detach(child);
+                }
+                if !text.is_empty() {
+                    let child = self.create_text(text);
+                    self.append(id, child);
                 }
             }
+        }
+    }
+
+    // https://dom.spec.whatwg.org/#concept-node-ensure-pre-insertion-validity
+    fn check_insert(&self, parent: NodeId, child: NodeId) -> Result<(), DomError> {
+        let valid = self.get(parent).can_have_children()
+            && !matches!(self.node_type(child), NodeType::Document)
+            && !self.is_inclusive_ancestor(child, parent);
+        if valid {
+            Ok(())
+        } else {
+            Err(DomError::HierarchyRequest)
+        }
+    }
+
+    // links a parentless node in as the last child
+    fn append(&mut self, parent: NodeId, child: NodeId) {
+        let last = self.last_child(parent);
+        self.nodes[child.0].parent = Some(parent);
+        self.nodes[child.0].previous_sibling = last;
+        match last {
+            Some(last) => self.nodes[last.0].next_sibling = Some(child),
+            None => self.nodes[parent.0].first_child = Some(child),
+        }
+        self.nodes[parent.0].last_child = Some(child);
+    }
+
+    // links a parentless node in front of `reference`
+    fn insert(&mut self, parent: NodeId, child: NodeId, reference: NodeId) {
+        let previous = self.previous_sibling(reference);
+        self.nodes[child.0].parent = Some(parent);
+        self.nodes[child.0].previous_sibling = previous;
+        self.nodes[child.0].next_sibling = Some(reference);
+        self.nodes[reference.0].previous_sibling = Some(child);
+        match previous {
+            Some(previous) => self.nodes[previous.0].next_sibling = Some(child),
+            None => self.nodes[parent.0].first_child = Some(child),
+        }
+    }
+
+    fn detach(&mut self, id: NodeId) {
+        let parent = match self.parent(id) {
+            Some(parent) => parent,
+            None => return,
+        };
+        let previous = self.previous_sibling(id);
+        let next = self.next_sibling(id);
+        match previous {
+            Some(previous) => self.nodes[previous.0].next_sibling = next,
+            None => self.nodes[parent.0].first_child = next,
+        }
+        match next {
+            Some(next) => self.nodes[next.0].previous_sibling = previous,
+            None => self.nodes[parent.0].last_child = previous,
+        }
+        let node = &mut self.nodes[id.0];
+        node.parent = None;
+        node.previous_sibling = None;
+        node.next_sibling = None;
+    }
+}
+
+impl Default for Tree {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A parsed page: its tree together with what the parser learned about it, and
+/// indexes by id, class and tag name over the elements in the tree. The tree can
+/// only be changed through the document so that the indexes keep up.
+pub struct Document {
+    tree: Tree,
+    doctype: Option<Doctype>,
+    quirks_mode: QuirksMode,
+    url: Option<Url>,
+    encoding: &'static Encoding,
+    // every index holds the connected elements only, in no particular order
+    ids: HashMap<String, Vec<NodeId>>,
+    classes: HashMap<String, Vec<NodeId>>,
+    tags: HashMap<String, Vec<NodeId>>,
+    observers: MutationObservers,
+}
+
+impl Document {
+    pub fn new() -> Self {
+        Self::from_tree(Tree::new())
+    }
+
+    pub fn from_tree(tree: Tree) -> Self {
+        let mut document = Self {
+            tree,
+            doctype: None,
+            quirks_mode: QuirksMode::NoQuirks,
+            url: None,
+            encoding: UTF_8,
+            ids: HashMap::new(),
+            classes: HashMap::new(),
+            tags: HashMap::new(),
+            observers: MutationObservers::new(),
+        };
+        document.index(document.root());
+        document
+    }
+
+    pub fn tree(&self) -> &Tree {
+        &self.tree
+    }
+
+    /// The document node, which the top level nodes hang from.
+    pub fn root(&self) -> NodeId {
+        self.tree.document()
+    }
+
+    /// The `<html>` element.
+    pub fn document_element(&self) -> Option<NodeId> {
+        let root = self.root();
+        self.tree.children(root).find(|&c| self.tree.element(c).is_some())
+    }
+
+    pub fn doctype(&self) -> Option<&Doctype> {
+        self.doctype.as_ref()
+    }
+
+    pub fn set_doctype(&mut self, doctype: Option<Doctype>) {
+        self.doctype = doctype;
+    }
+
+    pub fn quirks_mode(&self) -> QuirksMode {
+        self.quirks_mode
+    }
+
+    pub fn set_quirks_mode(&mut self, quirks_mode: QuirksMode) {
+        self.quirks_mode = quirks_mode;
+    }
+
+    /// The address the document was loaded from.
+    pub fn url(&self) -> Option<&Url> {
+        self.url.as_ref()
+    }
+
+    pub fn set_url(&mut self, url: Option<Url>) {
+        self.url = url;
+    }
+
+    /// The encoding the document was decoded with, which is UTF-8 for a document
+    /// that was parsed from text. Its stylesheets fall back to it.
+    // https://dom.spec.whatwg.org/#concept-document-encoding
+    pub fn encoding(&self) -> &'static Encoding {
+        self.encoding
+    }
+
+    pub fn set_encoding(&mut self, encoding: &'static Encoding) {
+        self.encoding = encoding;
+    }
+
+    /// What relative urls in the document resolve against: the `href` of the first
+    /// `<base>` that has one, else the document's own url.
+    // https://html.spec.whatwg.org/multipage/urls-and-fetching.html#document-base-url
+    pub fn base_url(&self) -> Option<Url> {
+        let base = self
+            .get_elements_by_tag_name("base")
+            .into_iter()
+            .find_map(|id| self.tree.element(id)?.get_attribute("href"));
+        match (base, &self.url) {
+            (Some(href), Some(url)) => url.join(href).ok().or_else(|| Some(url.clone())),
+            (Some(href), None) => Url::parse(href).ok(),
+            (None, url) => url.clone(),
+        }
+    }
+
+    /// `url` (an `href` or `src`, say) resolved against the base url.
+    pub fn resolve_url(&self, url: &str) -> Option<Url> {
+        match self.base_url() {
+            Some(base) => base.join(url).ok(),
+            None => Url::parse(url).ok(),
+        }
+    }
+
+    /// The text of the first `<title>`, with its whitespace collapsed.
+    pub fn title(&self) -> String {
+        match self.get_elements_by_tag_name("title").first() {
+            Some(&title) => {
+                let text = self.tree.text_content(title);
+                text.split_ascii_whitespace().collect::<Vec<_>>().join(" ")
+            }
+            None => String::new(),
+        }
+    }
+
+    /// The first element in tree order whose id is `id`.
+    pub fn get_element_by_id(&self, id: &str) -> Option<NodeId> {
+        let candidates = self.ids.get(id)?;
+        candidates
+            .iter()
+            .copied()
+            .min_by(|&a, &b| self.tree.tree_order(a, b))
+    }
+
+    /// The elements, in tree order, that have every class in the space separated `names`.
+    pub fn get_elements_by_class_name(&self, names: &str) -> Vec<NodeId> {
+        let names: Vec<_> = names.split_ascii_whitespace().collect();
+        let candidates = match names.first().and_then(|n| self.classes.get(*n)) {
+            Some(candidates) => candidates,
+            None => return Vec::new(),
+        };
+        let matching = candidates.iter().copied().filter(|&id| {
+            self.tree.element(id).is_some_and(|e| {
+                let classes = e.get_classes();
+                names.iter().all(|n| classes.contains(n))
+            })
+        });
+        self.in_tree_order(matching.collect())
+    }
+
+    /// The elements named `name` in tree order, or every element for `*`.
+    // https://dom.spec.whatwg.org/#concept-getelementsbyqualifiedname
+    pub fn get_elements_by_tag_name(&self, name: &str) -> Vec<NodeId> {
+        if name == "*" {
+            return self.in_tree_order(self.tags.values().flatten().copied().collect());
+        }
+        // html elements match case-insensitively, foreign ones such as foreignObject exactly
+        let lowercase = name.to_ascii_lowercase();
+        let html = self.tags.get(&lowercase).into_iter().flatten().filter(|&&id| {
+            self.tree.element(id).is_some_and(ElementData::is_html)
+        });
+        let foreign = self.tags.get(name).into_iter().flatten().filter(|&&id| {
+            self.tree.element(id).is_some_and(|e| !e.is_html())
+        });
+        let matching = html.chain(foreign).copied().collect();
+        self.in_tree_order(matching)
+    }
+
+    /// The first element in the document that matches `selectors`.
+    pub fn query_selector(&self, selectors: &str) -> Result<Option<NodeId>, DomError> {
+        self.tree.query_selector(self.root(), selectors)
+    }
+
+    /// Every element in the document that matches `selectors`, in tree order.
+    pub fn query_selector_all(&self, selectors: &str) -> Result<Vec<NodeId>, DomError> {
+        self.tree.query_selector_all(self.root(), selectors)
+    }
+
+    fn in_tree_order(&self, mut nodes: Vec<NodeId>) -> Vec<NodeId> {
+        nodes.sort_by(|&a, &b| self.tree.tree_order(a, b));
+        nodes
+    }
+
+    pub fn create_element(&mut self, tag_name: &str) -> NodeId {
+        self.tree.create_element(tag_name)
+    }
+
+    pub fn create_text(&mut self, data: &str) -> NodeId {
+        self.tree.create_text(data)
+    }
+
+    /// As `Tree::clone_node`; the copy is not connected until it is inserted.
+    pub fn clone_node(&mut self, id: NodeId, deep: bool) -> NodeId {
+        self.tree.clone_node(id, deep)
+    }
+
+    pub fn append_child(&mut self, parent: NodeId, child: NodeId) -> Result<NodeId, DomError> {
+        self.insert_before(parent, child, None)
+    }
+
+    pub fn insert_before(
+        &mut self,
+        parent: NodeId,
+        child: NodeId,
+        reference: Option<NodeId>,
+    ) -> Result<NodeId, DomError> {
+        let removal = self.removal_record(child);
+        self.moving(child, |tree| tree.insert_before(parent, child, reference))?;
+        if reference != Some(child) {
+            self.queue_removal(removal);
+            self.queue_insertion(parent, child, Vec::new());
+        }
+        Ok(child)
+    }
+
+    pub fn remove_child(&mut self, parent: NodeId, child: NodeId) -> Result<NodeId, DomError> {
+        let removal = self.removal_record(child);
+        self.moving(child, |tree| tree.remove_child(parent, child))?;
+        self.queue_removal(removal);
+        Ok(child)
+    }
+
+    pub fn replace_child(
+        &mut self,
+        parent: NodeId,
+        new: NodeId,
+        old: NodeId,
+    ) -> Result<NodeId, DomError> {
+        if new == old {
+            return self.moving(old, |tree| tree.replace_child(parent, new, old));
+        }
+        let removal = match self.tree.parent(new) {
+            // a node moving within `parent` is only reported once, as added
+            Some(p) if p != parent => self.removal_record(new),
+            _ => None,
+        };
+        if self.is_connected(new) {
+            self.unindex(new);
+        }
+        let result = self.moving(old, |tree| tree.replace_child(parent, new, old));
+        if self.is_connected(new) {
+            self.index(new);
+        }
+        if result.is_ok() {
+            self.queue_removal(removal);
+            self.queue_insertion(parent, new, vec![old]);
+            self.observers.removed(&self.tree, parent, old);
+        }
+        result
+    }
+
+    pub fn set_attribute(&mut self, id: NodeId, name: &str, value: &str) -> Result<(), DomError> {
+        let old_value = self.tree.element(id).and_then(|e| e.get_attribute(name).cloned());
+        let connected = self.is_connected(id);
+        if connected {
+            self.unindex_element(id);
+        }
+        let result = match self.tree.element_mut(id) {
+            Some(e) => e.set_attribute(name, value),
+            None => Err(DomError::HierarchyRequest),
+        };
+        if connected {
+            self.index_element(id);
+        }
+        if result.is_ok() {
+            self.queue_attribute(id, name, old_value);
+        }
+        result
+    }
+
+    /// Writes `classes` back to the `class` attribute, see `ElementData::class_list`.
+    pub fn set_class_list(&mut self, id: NodeId, classes: &ClassList) -> Result<(), DomError> {
+        self.set_attribute(id, "class", &classes.to_string())
+    }
+
+    pub fn remove_attribute(&mut self, id: NodeId, name: &str) -> Option<String> {
+        let connected = self.is_connected(id);
+        if connected {
+            self.unindex_element(id);
+        }
+        // the record needs the namespace of the attribute while it is still there
+        let record = self.attribute_record(id, name, None);
+        let removed = self.tree.element_mut(id).and_then(|e| e.remove_attribute(name));
+        if connected {
+            self.index_element(id);
+        }
+        if let (Some(mut record), Some(old_value)) = (record, removed.as_ref()) {
+            record.old_value = Some(old_value.clone());
+            self.observers.queue(&self.tree, record);
+        }
+        removed
+    }
+
+    pub fn set_text_content(&mut self, id: NodeId, text: &str) {
+        let children: Vec<_> = self.tree.children(id).collect();
+        if self.is_connected(id) {
+            for &child in &children {
+                self.unindex(child);
+            }
+        }
+        let old_data = match *self.tree.node_type(id) {
+            NodeType::Text(ref t) | NodeType::Comment(ref t) => Some(t.clone()),
+            _ => None,
+        };
+        self.tree.set_text_content(id, text);
+        if !self.observers.is_observing() {
+            return;
+        }
+        match (old_data, self.tree.node_type(id)) {
+            (Some(old_data), _) => {
+                let record = MutationRecord::character_data(id, old_data);
+                self.observers.queue(&self.tree, record);
+            }
+            (None, NodeType::Element(_)) => {
+                let added: Vec<_> = self.tree.children(id).collect();
+                if added.is_empty() && children.is_empty() {
+                    return;
+                }
+                for &child in &children {
+                    self.observers.removed(&self.tree, id, child);
+                }
+                let record = MutationRecord::child_list(id, added, children, None, None);
+                self.observers.queue(&self.tree, record);
+            }
             _ => {}
         }
+    }
+
+    /// A new mutation observer, which sees nothing until it is given targets with `observe`.
+    pub fn create_observer(&mut self) -> ObserverId {
+        self.observers.create()
+    }
+
+    /// Has `observer` record the changes `options` asks for at `target`, and below it
+    /// with `subtree`. Observing a target again replaces the options it had.
+    pub fn observe(
+        &mut self,
+        observer: ObserverId,
+        target: NodeId,
+        options: ObserverOptions,
+    ) -> Result<(), DomError> {
+        self.observers.observe(observer, target, options)
+    }
+
+    /// The changes `observer` has seen since its records were last taken, oldest first.
+    pub fn take_records(&mut self, observer: ObserverId) -> Vec<MutationRecord> {
+        self.observers.take_records(observer)
+    }
+
+    pub fn disconnect(&mut self, observer: ObserverId) {
+        self.observers.disconnect(observer);
+    }
+
+    // the record for taking `node` from where it is now, made before it moves
+    fn removal_record(&self, node: NodeId) -> Option<(NodeId, MutationRecord)> {
+        if !self.observers.is_observing() {
+            return None;
+        }
+        let parent = self.tree.parent(node)?;
+        let record = MutationRecord::child_list(
+            parent,
+            Vec::new(),
+            vec![node],
+            self.tree.previous_sibling(node),
+            self.tree.next_sibling(node),
+        );
+        Some((node, record))
+    }
+
+    fn queue_removal(&mut self, removal: Option<(NodeId, MutationRecord)>) {
+        if let Some((node, record)) = removal {
+            self.observers.removed(&self.tree, record.target, node);
+            self.observers.queue(&self.tree, record);
+        }
+    }
+
+    fn queue_insertion(&mut self, parent: NodeId, node: NodeId, removed: Vec<NodeId>) {
+        if !self.observers.is_observing() {
+            return;
+        }
+        let record = MutationRecord::child_list(
+            parent,
+            vec![node],
+            removed,
+            self.tree.previous_sibling(node),
+            self.tree.next_sibling(node),
+        );
+        self.observers.queue(&self.tree, record);
+    }
+
+    fn queue_attribute(&mut self, id: NodeId, name: &str, old_value: Option<String>) {
+        if let Some(record) = self.attribute_record(id, name, old_value) {
+            self.observers.queue(&self.tree, record);
+        }
+    }
+
+    // made while the attribute is there, for its namespace
+    fn attribute_record(
+        &self,
+        id: NodeId,
+        name: &str,
+        old_value: Option<String>,
+    ) -> Option<MutationRecord> {
+        if !self.observers.is_observing() {
+            return None;
+        }
+        let e = self.tree.element(id)?;
+        let namespace = e.attribute_namespace(name);
+        let qualified_name = e.qualified_name(name);
+        // records name attributes by local name, `href` for `xlink:href`
+        let local_name = match (namespace, qualified_name.split_once(':')) {
+            (Some(_), Some((_, local))) => local,
+            _ => &qualified_name[..],
+        };
+        Some(MutationRecord::attributes(id, local_name, namespace, old_value))
+    }
+
+    /// Whether the node hangs from this document's root, as opposed to being
+    /// created or removed and not inserted (again).
+    pub fn is_connected(&self, id: NodeId) -> bool {
+        self.tree.is_inclusive_ancestor(self.root(), id)
+    }
+
+    // runs a tree change that may connect or disconnect the subtree at `node`
+    fn moving<F>(&mut self, node: NodeId, change: F) -> Result<NodeId, DomError>
+    where
+        F: FnOnce(&mut Tree) -> Result<NodeId, DomError>,
+    {
+        if self.is_connected(node) {
+            self.unindex(node);
+        }
+        let result = change(&mut self.tree);
+        if self.is_connected(node) {
+            self.index(node);
+        }
+        result
+    }
+
+    fn index(&mut self, id: NodeId) {
+        let nodes: Vec<_> = self.tree.pre_order(id).collect();
+        for node in nodes {
+            self.index_element(node);
+        }
+    }
+
+    fn unindex(&mut self, id: NodeId) {
+        let nodes: Vec<_> = self.tree.pre_order(id).collect();
+        for node in nodes {
+            self.unindex_element(node);
+        }
+    }
 
-        for x in &self.children {
-            let s = x.get_stylesheet_from_url(url);
-            if let Some(_) = s {
-                res = s
+    fn index_element(&mut self, id: NodeId) {
+        let e = match self.tree.element(id) {
+            Some(e) => e,
+            None => return,
+        };
+        self.tags.entry(e.tag_name.clone()).or_default().push(id);
+        if let Some(element_id) = e.get_id() {
+            self.ids.entry(element_id.clone()).or_default().push(id);
+        }
+        for class in e.get_classes() {
+            self.classes.entry(class.to_owned()).or_default().push(id);
+        }
+    }
+
+    fn unindex_element(&mut self, id: NodeId) {
+        let e = match self.tree.element(id) {
+            Some(e) => e,
+            None => return,
+        };
+        let tag_name = e.tag_name.clone();
+        let element_id = e.get_id().cloned();
+        let classes: Vec<_> = e.get_classes().into_iter().map(str::to_owned).collect();
+        Self::remove_from(&mut self.tags, &tag_name, id);
+        if let Some(element_id) = element_id {
+            Self::remove_from(&mut self.ids, &element_id, id);
+        }
+        for class in classes {
+            Self::remove_from(&mut self.classes, &class, id);
+        }
+    }
+
+    fn remove_from(index: &mut HashMap<String, Vec<NodeId>>, key: &str, id: NodeId) {
+        if let Some(nodes) = index.get_mut(key) {
+            nodes.retain(|&n| n != id);
+            if nodes.is_empty() {
+                index.remove(key);
             }
         }
-        res
+    }
+}
+
+impl Default for Document {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The children of a `Tree` node, first to last.
+pub struct Children<'a> {
+    tree: &'a Tree,
+    next: Option<NodeId>,
+}
+
+impl<'a> Iterator for Children<'a> {
+    type Item = NodeId;
+
+    fn next(&mut self) -> Option<NodeId> {
+        let current = self.next?;
+        self.next = self.tree.next_sibling(current);
+        Some(current)
+    }
+}
+
+/// A node of a `Tree` together with the tree, so it can be walked in every direction
+/// the way a `Node` is walked down through its children.
+#[derive(Clone, Copy)]
+pub struct NodeRef<'a> {
+    tree: &'a Tree,
+    id: NodeId,
+}
+
+impl<'a> NodeRef<'a> {
+    pub fn id(&self) -> NodeId {
+        self.id
+    }
+
+    pub fn tree(&self) -> &'a Tree {
+        self.tree
+    }
+
+    pub fn node_type(&self) -> &'a NodeType {
+        &self.tree.nodes[self.id.0].node_type
+    }
+
+    pub fn span(&self) -> Option<Span> {
+        self.tree.span(self.id)
+    }
+
+    pub fn as_element(&self) -> Option<&'a ElementData> {
+        match *self.node_type() {
+            NodeType::Element(ref e) => Some(e),
+            _ => None,
+        }
+    }
+
+    pub fn can_have_children(&self) -> bool {
+        match *self.node_type() {
+            NodeType::Element(ref e) => !e.is_void(),
+            NodeType::Document => true,
+            _ => false,
+        }
+    }
+
+    pub fn parent(&self) -> Option<NodeRef<'a>> {
+        self.related(self.tree.parent(self.id))
+    }
+
+    pub fn first_child(&self) -> Option<NodeRef<'a>> {
+        self.related(self.tree.first_child(self.id))
+    }
+
+    pub fn last_child(&self) -> Option<NodeRef<'a>> {
+        self.related(self.tree.last_child(self.id))
+    }
+
+    pub fn previous_sibling(&self) -> Option<NodeRef<'a>> {
+        self.related(self.tree.previous_sibling(self.id))
+    }
+
+    pub fn next_sibling(&self) -> Option<NodeRef<'a>> {
+        self.related(self.tree.next_sibling(self.id))
+    }
+
+    pub fn children(&self) -> impl Iterator<Item = NodeRef<'a>> {
+        let tree = self.tree;
+        tree.children(self.id).map(move |id| tree.get(id))
+    }
+
+    fn related(&self, id: Option<NodeId>) -> Option<NodeRef<'a>> {
+        id.map(|id| self.tree.get(id))
+    }
+}
+
+impl<'a> PartialEq for NodeRef<'a> {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self.tree, other.tree) && self.id == other.id
+    }
+}
+
+impl<'a> fmt::Debug for NodeRef<'a> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self.node_type())
     }
 }
 
@@ -125,6 +1502,7 @@ impl fmt::Debug for NodeType {
             NodeType::Text(t) => write!(f, "{}", t),
             NodeType::Comment(c) => write!(f, "{}", c),
             NodeType::Element(e) => write!(f, "{:?}", e),
+            NodeType::Document => write!(f, "#document"),
         }
     }
 }
@@ -133,26 +1511,347 @@ impl fmt::Debug for ElementData {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         let mut attribute_string = String::new();
 
-        for (attr, value) in self.attributes.iter() {
-            attribute_string.push_str(&format!(" {}=\"{}\"", attr, value));
+        for attribute in self.attributes.iter() {
+            attribute_string.push_str(&format!(" {}=\"{}\"", attribute.name, attribute.value));
         }
         write!(f, "<{},{}>", self.tag_name, attribute_string)
     }
 }
 
 pub fn pretty_print(n: &Node, indent_size: usize) {
-    let indent = (0..indent_size).map(|_| " ").collect::<String>();
+    n.accept(&mut PrettyPrinter { indent_size });
+}
+
+struct PrettyPrinter {
+    indent_size: usize,
+}
+
+impl Visitor for PrettyPrinter {
+    fn enter(&mut self, n: &Node) -> Visit {
+        let indent = (0..self.indent_size).map(|_| " ").collect::<String>();
+
+        let location = match n.span {
+            Some(span) => format!("  @{}", span),
+            None => String::new(),
+        };
+        match n.node_type {
+            NodeType::Text(ref t) => println!("{}{}{}", indent, t, location),
+            NodeType::Element(ref e) => println!("{}{:?}{}", indent, e, location),
+            NodeType::Comment(ref c) => println!("{}<!--{}-->{}", indent, c, location),
+            NodeType::Document => println!("{}#document", indent),
+        }
+        self.indent_size += 2;
+        Visit::Continue
+    }
+
+    fn leave(&mut self, n: &Node) {
+        self.indent_size -= 2;
+        let indent = (0..self.indent_size).map(|_| " ").collect::<String>();
+        if let NodeType::Element(ref e) = n.node_type {
+            if !e.is_void() {
+                println!("{}</{}>", indent, e.tag_name)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(node: &Node) -> Vec<String> {
+        node.children
+            .iter()
+            .map(|c| match c.node_type {
+                NodeType::Element(ref e) => e.tag_name.clone(),
+                NodeType::Text(ref t) => format!("{:?}", t),
+                _ => String::new(),
+            })
+            .collect()
+    }
 
-    match n.node_type {
-        NodeType::Text(ref t) => println!("{}{}", indent, t),
-        NodeType::Element(ref e) => println!("{}{:?}", indent, e),
-        NodeType::Comment(ref c) => println!("{}<!--{}-->", indent, c),
+    #[test]
+    fn node_children_are_inserted_removed_and_replaced_in_place() {
+        let mut div = Node::element("div");
+        div.append_child(Node::element("b")).unwrap();
+        div.append_child(Node::text("t")).unwrap();
+        div.insert_before(Node::element("a"), 0).unwrap();
+        div.insert_before(Node::element("i"), 2).unwrap();
+        assert_eq!(names(&div), ["a", "b", "i", "\"t\""]);
+        let removed = div.remove_child(1).unwrap();
+        assert_eq!(removed.as_element().unwrap().tag_name, "b");
+        let replaced = div.replace_child(Node::element("em"), 1).unwrap();
+        assert_eq!(replaced.as_element().unwrap().tag_name, "i");
+        assert_eq!(names(&div), ["a", "em", "\"t\""]);
     }
 
-    for child in n.children.iter() {
-        pretty_print(child, indent_size + 2);
+    #[test]
+    fn node_insertion_checks_the_hierarchy_and_indices() {
+        let mut div = Node::element("div");
+        assert_eq!(
+            div.insert_before(Node::element("p"), 1).err(),
+            Some(DomError::NotFound)
+        );
+        assert_eq!(div.remove_child(0).err(), Some(DomError::NotFound));
+        assert_eq!(
+            div.replace_child(Node::element("p"), 0).err(),
+            Some(DomError::NotFound)
+        );
+        let document = Node::new(NodeType::Document, Vec::new());
+        assert_eq!(
+            div.append_child(document.clone_node(false)).err(),
+            Some(DomError::HierarchyRequest)
+        );
+        div.append_child(Node::element("p")).unwrap();
+        assert_eq!(div.replace_child(document, 0).err(), Some(DomError::HierarchyRequest));
+        let mut text = Node::text("t");
+        assert_eq!(
+            text.append_child(Node::element("p")).err(),
+            Some(DomError::HierarchyRequest)
+        );
+        let mut br = Node::element("br");
+        assert_eq!(
+            br.append_child(Node::text("t")).err(),
+            Some(DomError::HierarchyRequest)
+        );
+        assert_eq!(names(&div), ["p"]);
     }
-    if let NodeType::Element(ref e) = n.node_type {
-        println!("{}</{}>", indent, e.tag_name)
+
+    // checks that the links of `parent`'s children agree with each other both ways
+    // and returns the children
+    fn linked_children(tree: &Tree, parent: NodeId) -> Vec<NodeId> {
+        let forward: Vec<NodeId> =
+            std::iter::successors(tree.first_child(parent), |&c| tree.next_sibling(c)).collect();
+        let mut backward: Vec<NodeId> =
+            std::iter::successors(tree.last_child(parent), |&c| tree.previous_sibling(c))
+                .collect();
+        backward.reverse();
+        assert_eq!(forward, backward);
+        for &child in &forward {
+            assert_eq!(tree.parent(child), Some(parent));
+        }
+        assert_eq!(tree.children(parent).collect::<Vec<_>>(), forward);
+        forward
+    }
+
+    fn detached(tree: &Tree, id: NodeId) -> bool {
+        tree.parent(id).is_none()
+            && tree.previous_sibling(id).is_none()
+            && tree.next_sibling(id).is_none()
+    }
+
+    #[test]
+    fn tree_links_stay_consistent_through_each_operation() {
+        let mut tree = Tree::new();
+        let root = tree.create_element("div");
+        tree.append_child(tree.document(), root).unwrap();
+        let [a, b, c, d] = ["a", "b", "c", "d"].map(|n| tree.create_element(n));
+        tree.append_child(root, a).unwrap();
+        tree.append_child(root, c).unwrap();
+        assert_eq!(linked_children(&tree, root), [a, c]);
+        tree.insert_before(root, b, Some(c)).unwrap();
+        assert_eq!(linked_children(&tree, root), [a, b, c]);
+        tree.insert_before(root, d, Some(a)).unwrap();
+        assert_eq!(linked_children(&tree, root), [d, a, b, c]);
+        // moving a child within its parent takes it out first
+        tree.append_child(root, d).unwrap();
+        assert_eq!(linked_children(&tree, root), [a, b, c, d]);
+        tree.insert_before(root, c, Some(a)).unwrap();
+        assert_eq!(linked_children(&tree, root), [c, a, b, d]);
+        tree.remove_child(root, a).unwrap();
+        assert!(detached(&tree, a));
+        assert_eq!(linked_children(&tree, root), [c, b, d]);
+        tree.replace_child(root, a, b).unwrap();
+        assert!(detached(&tree, b));
+        assert_eq!(linked_children(&tree, root), [c, a, d]);
+        // replacing with the next sibling of the old child
+        tree.replace_child(root, d, a).unwrap();
+        assert_eq!(linked_children(&tree, root), [c, d]);
+        // moving into another parent unlinks from the old one
+        tree.append_child(b, c).unwrap();
+        assert_eq!(linked_children(&tree, root), [d]);
+        assert_eq!(linked_children(&tree, b), [c]);
+        tree.remove_child(root, d).unwrap();
+        assert!(linked_children(&tree, root).is_empty());
+    }
+
+    #[test]
+    fn tree_insertion_checks_the_hierarchy() {
+        let mut tree = Tree::new();
+        let outer = tree.create_element("div");
+        let inner = tree.create_element("p");
+        let other = tree.create_element("span");
+        tree.append_child(outer, inner).unwrap();
+        assert_eq!(tree.append_child(inner, outer), Err(DomError::HierarchyRequest));
+        assert_eq!(tree.append_child(inner, inner), Err(DomError::HierarchyRequest));
+        let document = tree.document();
+        assert_eq!(tree.append_child(outer, document), Err(DomError::HierarchyRequest));
+        let text = tree.create_text("t");
+        assert_eq!(tree.append_child(text, other), Err(DomError::HierarchyRequest));
+        let br = tree.create_element("br");
+        assert_eq!(tree.append_child(br, other), Err(DomError::HierarchyRequest));
+        // the reference and the replaced node have to be children of the parent
+        assert_eq!(tree.insert_before(outer, other, Some(text)), Err(DomError::NotFound));
+        assert_eq!(tree.remove_child(outer, other), Err(DomError::NotFound));
+        assert_eq!(tree.replace_child(outer, other, text), Err(DomError::NotFound));
+        assert_eq!(tree.replace_child(inner, other, inner), Err(DomError::NotFound));
+        assert_eq!(linked_children(&tree, outer), [inner]);
+        assert!(detached(&tree, other));
+    }
+
+    fn indexed_document() -> Document {
+        crate::html_parser::HtmlParser::new(
+            "<div id=a class='x y'><p id=b class=x><span id=c class=y></span></p></div>\
+             <section id=d class=x><p id=e></p></section>",
+        )
+        .parse_document()
+    }
+
+    // the ids of what the indexes find, each checked against a walk of the document
+    fn by_id(document: &Document, id: &str) -> Option<String> {
+        let walked = document.tree().pre_order(document.root()).find(|&n| {
+            document.tree().element(n).and_then(ElementData::get_id).map(String::as_str)
+                == Some(id)
+        });
+        assert_eq!(document.get_element_by_id(id), walked);
+        walked.map(|n| id_of(document, n))
+    }
+
+    fn by_class(document: &Document, class: &str) -> Vec<String> {
+        let walked: Vec<_> = document
+            .tree()
+            .pre_order(document.root())
+            .filter(|&n| {
+                let element = document.tree().element(n);
+                element.is_some_and(|e| e.get_classes().contains(&class))
+            })
+            .collect();
+        assert_eq!(document.get_elements_by_class_name(class), walked);
+        walked.into_iter().map(|n| id_of(document, n)).collect()
+    }
+
+    fn by_tag(document: &Document, tag_name: &str) -> Vec<String> {
+        let walked: Vec<_> = document
+            .tree()
+            .pre_order(document.root())
+            .filter(|&n| document.tree().element(n).is_some_and(|e| e.tag_name == tag_name))
+            .collect();
+        assert_eq!(document.get_elements_by_tag_name(tag_name), walked);
+        walked.into_iter().map(|n| id_of(document, n)).collect()
+    }
+
+    fn id_of(document: &Document, id: NodeId) -> String {
+        let element = document.tree().element(id).unwrap();
+        element.get_id().cloned().unwrap_or_default()
+    }
+
+    #[test]
+    fn indexes_follow_attribute_changes() {
+        let mut document = indexed_document();
+        let b = document.get_element_by_id("b").unwrap();
+        assert_eq!(by_class(&document, "x"), ["a", "b", "d"]);
+        document.set_attribute(b, "id", "z").unwrap();
+        assert_eq!(by_id(&document, "b"), None);
+        assert_eq!(by_id(&document, "z").as_deref(), Some("z"));
+        document.set_attribute(b, "class", "y").unwrap();
+        assert_eq!(by_class(&document, "x"), ["a", "d"]);
+        assert_eq!(by_class(&document, "y"), ["a", "z", "c"]);
+        // a second element with the same id is found only after the first
+        let e = document.get_element_by_id("e").unwrap();
+        document.set_attribute(e, "id", "a").unwrap();
+        let a = document.get_element_by_id("a").unwrap();
+        assert_eq!(by_id(&document, "a").as_deref(), Some("a"));
+        document.remove_attribute(a, "id");
+        assert_eq!(document.get_element_by_id("a"), Some(e));
+        assert_eq!(by_id(&document, "a").as_deref(), Some("a"));
+        document.remove_attribute(b, "class");
+        assert_eq!(by_class(&document, "y"), ["", "c"]);
+        assert_eq!(by_tag(&document, "p"), ["z", "a"]);
+    }
+
+    #[test]
+    fn indexes_follow_moved_and_removed_subtrees() {
+        let mut document = indexed_document();
+        let a = document.get_element_by_id("a").unwrap();
+        let d = document.get_element_by_id("d").unwrap();
+        let body = document.tree().parent(a).unwrap();
+        document.remove_child(body, a).unwrap();
+        assert_eq!(by_id(&document, "c"), None);
+        assert_eq!(by_class(&document, "x"), ["d"]);
+        assert_eq!(by_tag(&document, "span"), Vec::<String>::new());
+        // changes to a disconnected subtree are picked up when it comes back
+        let c = document.tree().pre_order(a).last().unwrap();
+        document.set_attribute(c, "class", "x").unwrap();
+        assert_eq!(by_class(&document, "x"), ["d"]);
+        document.insert_before(body, a, Some(d)).unwrap();
+        assert_eq!(by_class(&document, "x"), ["a", "b", "c", "d"]);
+        assert_eq!(by_tag(&document, "p"), ["b", "e"]);
+        // moving within the document keeps a single entry for each element
+        document.append_child(d, a).unwrap();
+        assert_eq!(by_class(&document, "x"), ["d", "a", "b", "c"]);
+        assert_eq!(by_tag(&document, "span"), ["c"]);
+        let span = document.create_element("span");
+        document.set_attribute(span, "id", "f").unwrap();
+        assert_eq!(by_id(&document, "f"), None);
+        document.replace_child(d, span, a).unwrap();
+        assert_eq!(by_tag(&document, "span"), ["f"]);
+        assert_eq!(by_class(&document, "x"), ["d"]);
+        assert_eq!(by_id(&document, "b"), None);
+    }
+
+    #[test]
+    fn indexes_forget_children_replaced_by_text() {
+        let mut document = indexed_document();
+        let a = document.get_element_by_id("a").unwrap();
+        document.set_text_content(a, "text");
+        assert_eq!(by_id(&document, "b"), None);
+        assert_eq!(by_id(&document, "a").as_deref(), Some("a"));
+        assert_eq!(by_class(&document, "y"), ["a"]);
+        assert_eq!(by_tag(&document, "p"), ["e"]);
+        let body = document.tree().parent(a).unwrap();
+        document.set_text_content(body, "");
+        assert_eq!(by_tag(&document, "div"), Vec::<String>::new());
+        assert_eq!(by_class(&document, "x"), Vec::<String>::new());
+        assert_eq!(by_tag(&document, "body").len(), 1);
+    }
+
+    fn scoped(selectors: &str) -> Vec<String> {
+        let document = crate::html_parser::HtmlParser::new(
+            "<div id=outer><p id=p1></p>\
+             <section id=scope><p id=p2><span id=s1></span></p><span id=s2></span>\
+             <div id=inner><p id=p3></p></div></section><p id=p4></p></div>",
+        )
+        .parse_document();
+        let scope = document.get_element_by_id("scope").unwrap();
+        let found = document.tree().query_selector_all(scope, selectors).unwrap();
+        found.into_iter().map(|n| id_of(&document, n)).collect()
+    }
+
+    #[test]
+    fn scoped_queries_match_against_the_whole_tree() {
+        // only descendants of the scope are found, but their ancestors and
+        // siblings outside it still count for combinators
+        assert_eq!(scoped("p"), ["p2", "p3"]);
+        assert_eq!(scoped("#outer p"), ["p2", "p3"]);
+        assert_eq!(scoped("div p"), ["p2", "p3"]);
+        assert_eq!(scoped("div > p"), ["p3"]);
+        assert_eq!(scoped("section > *"), ["p2", "s2", "inner"]);
+        assert_eq!(scoped("p span"), ["s1"]);
+        assert_eq!(scoped("p + span"), ["s2"]);
+        assert_eq!(scoped("p ~ *"), ["s2", "inner"]);
+        assert_eq!(scoped("#p1 ~ * p"), ["p2", "p3"]);
+        assert_eq!(scoped("#p4"), Vec::<String>::new());
+        assert_eq!(scoped("#scope"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn scope_is_the_element_queried_from() {
+        assert_eq!(scoped(":scope > p"), ["p2"]);
+        assert_eq!(scoped(":scope > * > p"), ["p3"]);
+        assert_eq!(scoped(":scope p span"), ["s1"]);
+        assert_eq!(scoped(":scope + p"), Vec::<String>::new());
+        let document = crate::html_parser::HtmlParser::new("<p id=a>").parse_document();
+        let found = document.query_selector_all(":scope > body > p").unwrap();
+        assert_eq!(found, document.get_element_by_id("a").into_iter().collect::<Vec<_>>());
     }
 }
@@ -0,0 +1,385 @@
+use crate::css::{PseudoClass, Selector, StyleSheet};
+use crate::dom::{ElementData, NodeId, NodeRef, Tree};
+use crate::{mutation, stylesheets};
+use std::collections::{HashMap, HashSet};
+
+/// What the user has done to the elements of a document, which the dynamic
+/// pseudo-classes such as `:hover` and `:checked` match against. The renderer
+/// keeps it up to date; every setter returns the elements whose state changed,
+/// for `restyle_roots`.
+// https://html.spec.whatwg.org/multipage/semantics-other.html#pseudo-classes
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ElementStates {
+    // the element under the pointer
+    hovered: Option<NodeId>,
+    // the element the pointer was pressed on, until it is released
+    active: Option<NodeId>,
+    focused: Option<NodeId>,
+    // the links whose target is in the history
+    visited: HashSet<NodeId>,
+    // checkedness the user changed, over the `checked` attribute
+    checked: HashMap<NodeId, bool>,
+}
+
+impl ElementStates {
+    pub fn new() -> Self {
+        Self {
+            hovered: None,
+            active: None,
+            focused: None,
+            visited: HashSet::new(),
+            checked: HashMap::new(),
+        }
+    }
+
+    pub fn hovered(&self) -> Option<NodeId> {
+        self.hovered
+    }
+
+    pub fn active(&self) -> Option<NodeId> {
+        self.active
+    }
+
+    pub fn focused(&self) -> Option<NodeId> {
+        self.focused
+    }
+
+    pub fn set_hovered(&mut self, tree: &Tree, id: Option<NodeId>) -> Vec<NodeId> {
+        let changed = Self::changed_chain(tree, self.hovered, id);
+        self.hovered = id;
+        changed
+    }
+
+    pub fn set_active(&mut self, tree: &Tree, id: Option<NodeId>) -> Vec<NodeId> {
+        let changed = Self::changed_chain(tree, self.active, id);
+        self.active = id;
+        changed
+    }
+
+    pub fn set_focused(&mut self, tree: &Tree, id: Option<NodeId>) -> Vec<NodeId> {
+        let changed = Self::changed_chain(tree, self.focused, id);
+        self.focused = id;
+        changed
+    }
+
+    pub fn set_visited(&mut self, id: NodeId, visited: bool) -> Vec<NodeId> {
+        let changed = if visited {
+            self.visited.insert(id)
+        } else {
+            self.visited.remove(&id)
+        };
+        if changed {
+            vec![id]
+        } else {
+            Vec::new()
+        }
+    }
+
+    pub fn set_checked(&mut self, id: NodeId, checked: bool) -> Vec<NodeId> {
+        match self.checked.insert(id, checked) {
+            Some(old) if old == checked => Vec::new(),
+            _ => vec![id],
+        }
+    }
+
+    // :hover and :active hold for the ancestors of the element as well
+    // https://html.spec.whatwg.org/multipage/semantics-other.html#selector-hover
+    pub fn is_hovered(&self, node: NodeRef) -> bool {
+        self.hovered
+            .is_some_and(|h| node.tree().is_inclusive_ancestor(node.id(), h))
+    }
+
+    pub fn is_active(&self, node: NodeRef) -> bool {
+        self.active
+            .is_some_and(|a| node.tree().is_inclusive_ancestor(node.id(), a))
+    }
+
+    pub fn is_focused(&self, node: NodeRef) -> bool {
+        self.focused == Some(node.id())
+    }
+
+    pub fn is_focus_within(&self, node: NodeRef) -> bool {
+        self.focused
+            .is_some_and(|f| node.tree().is_inclusive_ancestor(node.id(), f))
+    }
+
+    pub fn is_visited(&self, node: NodeRef) -> bool {
+        node.as_element().is_some_and(is_link) && self.visited.contains(&node.id())
+    }
+
+    // https://html.spec.whatwg.org/multipage/semantics-other.html#selector-checked
+    pub fn is_checked(&self, node: NodeRef) -> bool {
+        let element = match node.as_element() {
+            Some(e) if e.is_html() => e,
+            _ => return false,
+        };
+        match &element.tag_name[..] {
+            "input" if is_checkable(element) => match self.checked.get(&node.id()) {
+                Some(&checked) => checked,
+                None => element.has_attribute("checked"),
+            },
+            "option" => element.has_attribute("selected"),
+            _ => false,
+        }
+    }
+
+    /// What clicking `id` does to checkboxes and radio buttons: a checkbox flips,
+    /// and a radio button is checked while the others of its group are not. A
+    /// group is every radio button in the tree with the same name.
+    // https://html.spec.whatwg.org/multipage/input.html#checkbox-state-(type=checkbox)
+    pub fn toggle_checked(&mut self, tree: &Tree, id: NodeId) -> Vec<NodeId> {
+        let node = tree.get(id);
+        let element = match node.as_element() {
+            Some(e) if e.is_html() && e.tag_name == "input" && !is_disabled(node) => e,
+            _ => return Vec::new(),
+        };
+        let input_type = element
+            .get_attribute("type")
+            .map(|t| t.to_ascii_lowercase());
+        match input_type.as_deref() {
+            Some("checkbox") => {
+                let checked = self.is_checked(node);
+                self.set_checked(id, !checked)
+            }
+            Some("radio") => {
+                let mut changed = self.set_checked(id, true);
+                let name = match element.get_attribute("name") {
+                    Some(name) if !name.is_empty() => name,
+                    _ => return changed,
+                };
+                let root = Self::inclusive_ancestors(tree, id).last().copied();
+                let others: Vec<NodeId> = root
+                    .into_iter()
+                    .flat_map(|r| tree.pre_order(r))
+                    .filter(|&other| other != id)
+                    .filter(|&other| {
+                        tree.element(other).is_some_and(|e| {
+                            e.is_html()
+                                && e.tag_name == "input"
+                                && e.get_attribute("type")
+                                    .is_some_and(|t| t.eq_ignore_ascii_case("radio"))
+                                && e.get_attribute("name") == Some(name)
+                        })
+                    })
+                    .collect();
+                for other in others {
+                    changed.extend(self.set_checked(other, false));
+                }
+                changed
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    // the elements whose state changes when it moves from `old` to `new`: those
+    // that are an inclusive ancestor of one but not of the other
+    fn changed_chain(tree: &Tree, old: Option<NodeId>, new: Option<NodeId>) -> Vec<NodeId> {
+        if old == new {
+            return Vec::new();
+        }
+        let old = old.map_or(Vec::new(), |id| Self::inclusive_ancestors(tree, id));
+        let new = new.map_or(Vec::new(), |id| Self::inclusive_ancestors(tree, id));
+        let mut changed: Vec<NodeId> = old.iter().filter(|id| !new.contains(id)).copied().collect();
+        changed.extend(new.iter().filter(|id| !old.contains(id)));
+        changed
+    }
+
+    fn inclusive_ancestors(tree: &Tree, id: NodeId) -> Vec<NodeId> {
+        std::iter::successors(Some(id), |&n| tree.parent(n)).collect()
+    }
+}
+
+/// The subtrees to style again after the state of `changed` changed, for a page
+/// styled with `style_sheets`. A selector can look at the state of an element
+/// from its descendants and, through `+` and `~`, from its following siblings, so
+/// each element stands for its parent's subtree. A `:has()` with a dynamic
+/// pseudo-class inside lets any ancestor, and whatever follows from it, look at
+/// the state too, so with such a rule the whole tree is styled again.
+pub fn restyle_roots(tree: &Tree, changed: &[NodeId], style_sheets: &[StyleSheet]) -> Vec<NodeId> {
+    let sheets = std::iter::once(stylesheets::user_agent()).chain(style_sheets);
+    let has_states = sheets
+        .flat_map(|sheet| &sheet.rules)
+        .flat_map(|rule| &rule.selectors)
+        .any(|selector| uses_states(selector, false));
+    let parents: Vec<NodeId> = changed
+        .iter()
+        .map(|&id| {
+            if has_states {
+                // the top of the tree
+                std::iter::successors(Some(id), |&n| tree.parent(n))
+                    .last()
+                    .unwrap_or(id)
+            } else {
+                tree.parent(id).unwrap_or(id)
+            }
+        })
+        .collect();
+    mutation::outermost(tree, &parents)
+}
+
+// whether `selector` has a dynamic pseudo-class that is inside a `:has()`, or
+// anywhere when it is itself inside one
+fn uses_states(selector: &Selector, in_has: bool) -> bool {
+    let pseudo_classes = selector.simple.iter().flat_map(|s| &s.pseudo_classes);
+    pseudo_classes
+        .into_iter()
+        .any(|pseudo_class| match pseudo_class {
+            PseudoClass::Has(selectors) => selectors.iter().any(|s| uses_states(s, true)),
+            PseudoClass::Not(selectors)
+            | PseudoClass::Is(selectors)
+            | PseudoClass::Where(selectors)
+            | PseudoClass::NthChild(_, selectors)
+            | PseudoClass::NthLastChild(_, selectors) => {
+                selectors.iter().any(|s| uses_states(s, in_has))
+            }
+            PseudoClass::Hover
+            | PseudoClass::Active
+            | PseudoClass::Focus
+            | PseudoClass::FocusWithin
+            | PseudoClass::Link
+            | PseudoClass::Visited
+            | PseudoClass::Checked => in_has,
+            _ => false,
+        })
+}
+
+// https://html.spec.whatwg.org/multipage/semantics-other.html#selector-link
+pub fn is_link(element: &ElementData) -> bool {
+    element.is_html()
+        && matches!(&element.tag_name[..], "a" | "area")
+        && element.has_attribute("href")
+}
+
+fn is_checkable(element: &ElementData) -> bool {
+    element
+        .get_attribute("type")
+        .is_some_and(|t| t.eq_ignore_ascii_case("checkbox") || t.eq_ignore_ascii_case("radio"))
+}
+
+// https://html.spec.whatwg.org/multipage/semantics-other.html#concept-element-disabled
+pub fn is_disabled(node: NodeRef) -> bool {
+    let element = match node.as_element() {
+        Some(e) if e.is_html() => e,
+        _ => return false,
+    };
+    match &element.tag_name[..] {
+        "button" | "input" | "select" | "textarea" | "fieldset" => {
+            element.has_attribute("disabled") || in_disabled_fieldset(node)
+        }
+        "optgroup" => element.has_attribute("disabled"),
+        "option" => {
+            element.has_attribute("disabled")
+                || node.parent().is_some_and(|p| {
+                    p.as_element()
+                        .is_some_and(|e| e.is_html() && e.tag_name == "optgroup")
+                        && is_disabled(p)
+                })
+        }
+        _ => false,
+    }
+}
+
+// a disabled fieldset disables what it holds, apart from its first legend
+// https://html.spec.whatwg.org/multipage/form-elements.html#concept-fieldset-disabled
+fn in_disabled_fieldset(node: NodeRef) -> bool {
+    let mut child = node;
+    while let Some(parent) = child.parent() {
+        let is_fieldset = parent.as_element().is_some_and(|e| {
+            e.is_html() && e.tag_name == "fieldset" && e.has_attribute("disabled")
+        });
+        if is_fieldset {
+            let first_legend = parent.children().find(|c| {
+                c.as_element()
+                    .is_some_and(|e| e.is_html() && e.tag_name == "legend")
+            });
+            if first_legend.is_none_or(|l| l.id() != child.id()) {
+                return true;
+            }
+        }
+        child = parent;
+    }
+    false
+}
+
+/// Whether clicking `node` focuses it.
+// https://html.spec.whatwg.org/multipage/interaction.html#focusable-area
+pub fn is_focusable(node: NodeRef) -> bool {
+    let element = match node.as_element() {
+        Some(e) if e.is_html() => e,
+        _ => return false,
+    };
+    if is_disabled(node) {
+        return false;
+    }
+    match &element.tag_name[..] {
+        "a" | "area" => element.has_attribute("href"),
+        "input" => element
+            .get_attribute("type")
+            .is_none_or(|t| !t.eq_ignore_ascii_case("hidden")),
+        "button" | "select" | "textarea" => true,
+        _ => element.has_attribute("tabindex"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::css_parser::CssParser;
+    use crate::dom::Document;
+    use crate::html_parser::HtmlParser;
+    use crate::style::{StyleCache, StyledNode};
+
+    fn document() -> Document {
+        HtmlParser::new("<div id=a><p id=b><span id=c></span></p></div><div id=d></div>")
+            .parse_document()
+    }
+
+    fn id(document: &Document, id: &str) -> NodeId {
+        document.get_element_by_id(id).unwrap()
+    }
+
+    fn roots(document: &Document, changed: &str, css: &str) -> Vec<NodeId> {
+        let sheets = [CssParser::new(css).parse_stylesheet()];
+        let changed = [id(document, changed)];
+        restyle_roots(document.tree(), &changed, &sheets)
+    }
+
+    #[test]
+    fn states_inside_has_restyle_the_whole_tree() {
+        let document = document();
+        let b = id(&document, "b");
+        assert_eq!(roots(&document, "c", "span:hover + p {}"), [b]);
+        assert_eq!(roots(&document, "c", "div:has(.x) {}"), [b]);
+        assert_eq!(roots(&document, "c", ":hover:has(p) {}"), [b]);
+        for css in [
+            "div:has(:hover) {}",
+            "div:has(> p span:is(:focus, .x)) {}",
+            ":is(.x, :not(:has(:checked))) p {}",
+        ] {
+            assert_eq!(roots(&document, "c", css), [document.root()], "{}", css);
+        }
+    }
+
+    #[test]
+    fn ancestors_match_has_again_after_a_state_changes() {
+        let document = document();
+        let css = "div:has(span:hover) { margin-top: 5px }";
+        let sheets = [CssParser::new(css).parse_stylesheet()];
+        let mut states = ElementStates::new();
+        let mut cache = StyleCache::new();
+        let margin = |document: &Document, states: &ElementStates, cache: &mut StyleCache| {
+            let a = document.tree().get(id(document, "a"));
+            StyledNode::with_states(a, &sheets, states, cache).num_or("margin-top", 0.)
+        };
+        assert_eq!(margin(&document, &states, &mut cache), 0.);
+        let c = id(&document, "c");
+        let changed = states.set_hovered(document.tree(), Some(c));
+        let roots = restyle_roots(document.tree(), &changed, &sheets);
+        cache.invalidate(document.tree(), &roots);
+        assert_eq!(margin(&document, &states, &mut cache), 5.);
+        let changed = states.set_hovered(document.tree(), None);
+        let roots = restyle_roots(document.tree(), &changed, &sheets);
+        cache.invalidate(document.tree(), &roots);
+        assert_eq!(margin(&document, &states, &mut cache), 0.);
+    }
+}
@@ -0,0 +1,429 @@
+use encoding_rs::{
+    CoderResult, Decoder, Encoding, UTF_16BE, UTF_16LE, UTF_8, WINDOWS_1252, X_USER_DEFINED,
+};
+
+// https://html.spec.whatwg.org/multipage/parsing.html#prescan-a-byte-stream-to-determine-its-encoding
+pub const PRESCAN_LENGTH: usize = 1024;
+
+/// Picks the encoding of an html document from its first bytes (at least
+/// `PRESCAN_LENGTH` of them unless the document is shorter) and the
+/// `Content-Type` it was served with.
+// https://html.spec.whatwg.org/multipage/parsing.html#encoding-sniffing-algorithm
+pub fn sniff_html(bytes: &[u8], content_type: Option<&str>) -> &'static Encoding {
+    if let Some((encoding, _)) = Encoding::for_bom(bytes) {
+        return encoding;
+    }
+    if let Some(encoding) = content_type.and_then(charset_from_content_type) {
+        return encoding;
+    }
+    let head = &bytes[..bytes.len().min(PRESCAN_LENGTH)];
+    if let Some(encoding) = prescan(head) {
+        return encoding;
+    }
+    // the autodetection step: a head that is valid utf-8 is taken to be utf-8,
+    // anything else gets the fallback the spec suggests for most locales
+    if looks_like_utf8(head, bytes.len() >= PRESCAN_LENGTH) {
+        UTF_8
+    } else {
+        WINDOWS_1252
+    }
+}
+
+/// Decodes a whole html document, see `sniff_html`.
+pub fn decode_html(bytes: &[u8], content_type: Option<&str>) -> String {
+    let encoding = sniff_html(bytes, content_type);
+    let (text, _) = encoding.decode_with_bom_removal(bytes);
+    text.into_owned()
+}
+
+/// The encoding a stylesheet is decoded with when it has no byte order mark.
+/// `environment` is the encoding of the document that linked it, or of the
+/// stylesheet that imported it.
+// https://www.w3.org/TR/css-syntax-3/#determine-the-fallback-encoding
+pub fn css_fallback_encoding(
+    bytes: &[u8],
+    content_type: Option<&str>,
+    environment: Option<&'static Encoding>,
+) -> &'static Encoding {
+    if let Some(encoding) = content_type.and_then(charset_from_content_type) {
+        return encoding;
+    }
+    if let Some(encoding) = charset_rule(bytes) {
+        return encoding;
+    }
+    environment.unwrap_or(UTF_8)
+}
+
+/// Decodes a whole stylesheet, see `css_fallback_encoding`, and returns it with
+/// the encoding it was decoded with, which is the environment of its `@import`s.
+pub fn decode_css(
+    bytes: &[u8],
+    content_type: Option<&str>,
+    environment: Option<&'static Encoding>,
+) -> (String, &'static Encoding) {
+    let fallback = css_fallback_encoding(bytes, content_type, environment);
+    // a byte order mark wins over everything else
+    let (text, encoding, _) = fallback.decode(bytes);
+    (text.into_owned(), encoding)
+}
+
+/// The encoding named by the `charset` parameter of a `Content-Type` value.
+pub fn charset_from_content_type(content_type: &str) -> Option<&'static Encoding> {
+    content_type.split(';').skip(1).find_map(|parameter| {
+        let (name, value) = parameter.split_once('=')?;
+        if !name.trim().eq_ignore_ascii_case("charset") {
+            return None;
+        }
+        let value = value.trim().trim_matches('"');
+        Encoding::for_label(value.as_bytes())
+    })
+}
+
+// the `@charset "...";` rule, which only counts written exactly like that at
+// the very start of the stylesheet
+fn charset_rule(bytes: &[u8]) -> Option<&'static Encoding> {
+    let rest = bytes.strip_prefix(b"@charset \"")?;
+    let rest = &rest[..rest.len().min(PRESCAN_LENGTH)];
+    let end = rest.iter().position(|&b| b == b'"')?;
+    if rest.get(end + 1) != Some(&b';') {
+        return None;
+    }
+    Encoding::for_label(&rest[..end]).map(utf16_as_utf8)
+}
+
+// utf-16 labels in ascii-compatible bytes can only be mistakes
+fn utf16_as_utf8(encoding: &'static Encoding) -> &'static Encoding {
+    if encoding == UTF_16BE || encoding == UTF_16LE {
+        UTF_8
+    } else {
+        encoding
+    }
+}
+
+// a truncated head may end in the middle of a character
+fn looks_like_utf8(bytes: &[u8], truncated: bool) -> bool {
+    match std::str::from_utf8(bytes) {
+        Ok(_) => true,
+        Err(e) => truncated && e.error_len().is_none(),
+    }
+}
+
+fn is_space(b: u8) -> bool {
+    matches!(b, b'\t' | b'\n' | 0x0C | b'\r' | b' ')
+}
+
+/// Looks for a `<meta charset>` or `<meta http-equiv=content-type>` in `bytes`
+/// the way a browser does before it starts parsing.
+pub fn prescan(bytes: &[u8]) -> Option<&'static Encoding> {
+    let mut position = 0;
+    while position < bytes.len() {
+        let rest = &bytes[position..];
+        if rest.starts_with(b"<!--") {
+            // the dashes of the opening may also end the comment, as in <!-->
+            position += 2 + find(&rest[2..], b"-->")? + 3;
+        } else if starts_with_ignore_case(rest, b"<meta")
+            && rest.get(5).is_some_and(|&b| is_space(b) || b == b'/')
+        {
+            position += 5;
+            if let Some(encoding) = meta_charset(bytes, &mut position) {
+                return Some(encoding);
+            }
+        } else if rest.starts_with(b"<")
+            && (rest.get(1).is_some_and(u8::is_ascii_alphabetic)
+                || (rest.get(1) == Some(&b'/') && rest.get(2).is_some_and(u8::is_ascii_alphabetic)))
+        {
+            position += 1;
+            while position < bytes.len() && !is_space(bytes[position]) && bytes[position] != b'>' {
+                position += 1;
+            }
+            while get_attribute(bytes, &mut position).is_some() {}
+        } else if rest.starts_with(b"<!") || rest.starts_with(b"</") || rest.starts_with(b"<?") {
+            position += find(rest, b">")? + 1;
+        } else {
+            position += 1;
+        }
+    }
+    None
+}
+
+// the attributes of a meta element, from just after its name
+fn meta_charset(bytes: &[u8], position: &mut usize) -> Option<&'static Encoding> {
+    let mut seen = Vec::new();
+    let mut got_pragma = false;
+    let mut need_pragma = None;
+    let mut charset = None;
+    while let Some((name, value)) = get_attribute(bytes, position) {
+        if seen.contains(&name) {
+            continue;
+        }
+        match name.as_slice() {
+            b"http-equiv" => got_pragma |= value == b"content-type",
+            b"content" if charset.is_none() => {
+                if let Some(encoding) = charset_from_meta_content(&value) {
+                    charset = Some(encoding);
+                    need_pragma = Some(true);
+                }
+            }
+            b"charset" => {
+                charset = Encoding::for_label(&value);
+                need_pragma = Some(false);
+            }
+            _ => {}
+        }
+        seen.push(name);
+    }
+    match need_pragma {
+        None => None,
+        Some(true) if !got_pragma => None,
+        _ => charset.map(|encoding| {
+            if encoding == X_USER_DEFINED {
+                WINDOWS_1252
+            } else {
+                utf16_as_utf8(encoding)
+            }
+        }),
+    }
+}
+
+// https://html.spec.whatwg.org/multipage/urls-and-fetching.html#algorithm-for-extracting-a-character-encoding-from-a-meta-element
+fn charset_from_meta_content(content: &[u8]) -> Option<&'static Encoding> {
+    let mut position = 0;
+    loop {
+        position += find_ignore_case(&content[position..], b"charset")? + 7;
+        while content.get(position).is_some_and(|&b| is_space(b)) {
+            position += 1;
+        }
+        if content.get(position) == Some(&b'=') {
+            position += 1;
+            break;
+        }
+    }
+    while content.get(position).is_some_and(|&b| is_space(b)) {
+        position += 1;
+    }
+    let rest = &content[position..];
+    match rest.first() {
+        Some(&quote) if quote == b'"' || quote == b'\'' => {
+            let end = rest[1..].iter().position(|&b| b == quote)?;
+            Encoding::for_label(&rest[1..end + 1])
+        }
+        Some(_) => {
+            let end = rest
+                .iter()
+                .position(|&b| is_space(b) || b == b';')
+                .unwrap_or(rest.len());
+            Encoding::for_label(&rest[..end])
+        }
+        None => None,
+    }
+}
+
+// https://html.spec.whatwg.org/multipage/parsing.html#concept-get-attributes-when-sniffing
+// names and values come back lowercased; None once the tag ends or the input does
+fn get_attribute(bytes: &[u8], position: &mut usize) -> Option<(Vec<u8>, Vec<u8>)> {
+    let at = |p: usize| bytes.get(p).copied();
+    while at(*position).is_some_and(|b| is_space(b) || b == b'/') {
+        *position += 1;
+    }
+    if at(*position)? == b'>' {
+        return None;
+    }
+
+    let mut name = Vec::new();
+    loop {
+        match at(*position)? {
+            b'=' if !name.is_empty() => {
+                *position += 1;
+                break;
+            }
+            b if is_space(b) => {
+                while at(*position).is_some_and(is_space) {
+                    *position += 1;
+                }
+                if at(*position)? != b'=' {
+                    return Some((name, Vec::new()));
+                }
+                *position += 1;
+                break;
+            }
+            b'/' | b'>' => return Some((name, Vec::new())),
+            b => {
+                name.push(b.to_ascii_lowercase());
+                *position += 1;
+            }
+        }
+    }
+
+    while at(*position).is_some_and(is_space) {
+        *position += 1;
+    }
+    let mut value = Vec::new();
+    match at(*position)? {
+        quote @ (b'"' | b'\'') => {
+            *position += 1;
+            loop {
+                let b = at(*position)?;
+                *position += 1;
+                if b == quote {
+                    return Some((name, value));
+                }
+                value.push(b.to_ascii_lowercase());
+            }
+        }
+        b'>' => Some((name, value)),
+        _ => {
+            while let Some(b) = at(*position) {
+                if is_space(b) || b == b'>' {
+                    break;
+                }
+                value.push(b.to_ascii_lowercase());
+                *position += 1;
+            }
+            at(*position)?;
+            Some((name, value))
+        }
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+fn find_ignore_case(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|w| w.eq_ignore_ascii_case(needle))
+}
+
+fn starts_with_ignore_case(bytes: &[u8], prefix: &[u8]) -> bool {
+    bytes.len() >= prefix.len() && bytes[..prefix.len()].eq_ignore_ascii_case(prefix)
+}
+
+/// Decodes a stream chunk by chunk once its encoding is known, keeping
+/// characters that straddle two chunks whole.
+pub struct StreamDecoder {
+    decoder: Decoder,
+}
+
+impl StreamDecoder {
+    pub fn new(encoding: &'static Encoding) -> Self {
+        Self {
+            decoder: encoding.new_decoder_with_bom_removal(),
+        }
+    }
+
+    pub fn encoding(&self) -> &'static Encoding {
+        self.decoder.encoding()
+    }
+
+    /// Decodes `bytes`; malformed sequences become U+FFFD.
+    pub fn decode(&mut self, bytes: &[u8], last: bool) -> String {
+        let mut text = String::new();
+        let mut bytes = bytes;
+        loop {
+            let needed = self
+                .decoder
+                .max_utf8_buffer_length(bytes.len())
+                .unwrap_or(bytes.len() * 3 + 16);
+            text.reserve(needed);
+            let (result, read, _) = self.decoder.decode_to_string(bytes, &mut text, last);
+            bytes = &bytes[read..];
+            if let CoderResult::InputEmpty = result {
+                return text;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::html_stream;
+    use encoding_rs::{SHIFT_JIS, WINDOWS_1251};
+
+    fn sniff(bytes: &[u8], content_type: Option<&str>) -> &'static str {
+        sniff_html(bytes, content_type).name()
+    }
+
+    #[test]
+    fn byte_order_mark_then_content_type_then_meta() {
+        let meta = b"<meta charset=windows-1251>";
+        assert_eq!(sniff(meta, None), "windows-1251");
+        let content_type = Some("text/html; charset=Shift_JIS");
+        assert_eq!(sniff(meta, content_type), "Shift_JIS");
+        let bom = [&b"\xef\xbb\xbf"[..], meta].concat();
+        assert_eq!(sniff(&bom, content_type), "UTF-8");
+        assert_eq!(sniff(b"\xff\xfe<\0p\0>\0", content_type), "UTF-16LE");
+        assert_eq!(sniff(b"\xfe\xff\0<\0p\0>", None), "UTF-16BE");
+        // a content type without a charset, or with one nobody knows, says nothing
+        assert_eq!(sniff(meta, Some("text/html")), "windows-1251");
+        assert_eq!(
+            sniff(meta, Some("text/html; charset=bogus")),
+            "windows-1251"
+        );
+    }
+
+    #[test]
+    fn prescan_understands_both_kinds_of_meta() {
+        let encoding = |html: &[u8]| prescan(html).map(Encoding::name);
+        assert_eq!(encoding(b"<META CHARSET='Latin1'>"), Some("windows-1252"));
+        let pragma = b"<meta http-equiv=Content-Type content='text/html; charset=shift_jis'>";
+        assert_eq!(encoding(pragma), Some("Shift_JIS"));
+        // content only counts together with the http-equiv
+        assert_eq!(
+            encoding(b"<meta content='text/html; charset=shift_jis'>"),
+            None
+        );
+        assert_eq!(
+            encoding(b"<!-- <meta charset=koi8-r> --><meta charset=utf-8>"),
+            Some("UTF-8")
+        );
+        assert_eq!(encoding(b"<p title='<meta charset=koi8-r>'>"), None);
+        // utf-16 in a document the prescan could read is a mistake for utf-8
+        assert_eq!(encoding(b"<meta charset=utf-16le>"), Some("UTF-8"));
+        assert_eq!(
+            encoding(b"<meta charset=x-user-defined>"),
+            Some("windows-1252")
+        );
+    }
+
+    #[test]
+    fn prescan_stops_after_its_limit() {
+        let padding = " ".repeat(PRESCAN_LENGTH);
+        let late = format!("<p>{}<meta charset=windows-1251>", padding);
+        assert_eq!(sniff(late.as_bytes(), None), "UTF-8");
+        let early = format!("<meta charset=windows-1251><p>{}", padding);
+        assert_eq!(sniff(early.as_bytes(), None), "windows-1251");
+        // without a meta, bytes that are not utf-8 are taken as windows-1252
+        assert_eq!(sniff(b"<p>caf\xe9", None), "windows-1252");
+        // and a head cut in the middle of a character is still utf-8
+        let cut = [" ".repeat(PRESCAN_LENGTH - 1).as_bytes(), "é".as_bytes()].concat();
+        assert_eq!(sniff(&cut, None), "UTF-8");
+    }
+
+    #[test]
+    fn documents_are_decoded_with_what_was_sniffed() {
+        assert_eq!(
+            decode_html(b"<meta charset=latin1><p>caf\xe9", None),
+            "<meta charset=latin1><p>café"
+        );
+        let (text, _, _) = SHIFT_JIS.encode("<p>日本語");
+        let content_type = Some("text/html; charset=shift_jis");
+        assert_eq!(decode_html(&text, content_type), "<p>日本語");
+        let utf16: Vec<u8> = "\u{feff}<p>й"
+            .encode_utf16()
+            .flat_map(u16::to_le_bytes)
+            .collect();
+        assert_eq!(decode_html(&utf16, None), "<p>й");
+    }
+
+    #[test]
+    fn a_meta_split_across_chunks_is_still_found() {
+        let html = b"<p><meta charset=windows-1251><p>\xe9";
+        for split in 1..html.len() {
+            let (first, second) = html.split_at(split);
+            let document = html_stream::parse_chunks([first, second], None, |_| {});
+            assert_eq!(document.encoding(), WINDOWS_1251);
+        }
+    }
+}
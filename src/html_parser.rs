@@ -1,256 +1,2967 @@
-use crate::dom::{AttrMap, ElementData, Node, NodeType};
-use std::iter::Peekable;
-use std::str::Chars;
+use crate::attributes::AttrList;
+use crate::dom::{self, Document, ElementData, Namespace, Node, NodeId, NodeType, Tree};
+use crate::html_tokenizer::{Doctype, State, Tag, Token, Tokenizer};
+use crate::source::{ParseError, SourcePosition, Span};
+use std::mem;
 
-pub struct HtmlParser<'a> {
-    chars: Peekable<Chars<'a>>,
-    node_q: Vec<String>,
+// https://html.spec.whatwg.org/multipage/parsing.html#parse-errors
+// the tokenizer reports the codes named in the spec; tree construction errors
+// are not named there, so those carry the offending tag name where there is one
+#[derive(Debug, Clone, PartialEq)]
+pub enum ErrorCode {
+    AbruptClosingOfEmptyComment,
+    AbruptDoctypePublicIdentifier,
+    AbruptDoctypeSystemIdentifier,
+    AbsenceOfDigitsInNumericCharacterReference,
+    CdataInHtmlContent,
+    CharacterReferenceOutsideUnicodeRange,
+    ControlCharacterReference,
+    DuplicateAttribute,
+    EndTagWithAttributes,
+    EndTagWithTrailingSolidus,
+    EofBeforeTagName,
+    EofInCdata,
+    EofInComment,
+    EofInDoctype,
+    EofInScriptHtmlCommentLikeText,
+    EofInTag,
+    IncorrectlyClosedComment,
+    IncorrectlyOpenedComment,
+    InvalidCharacterSequenceAfterDoctypeName,
+    InvalidFirstCharacterOfTagName,
+    MissingAttributeValue,
+    MissingDoctypeName,
+    MissingDoctypePublicIdentifier,
+    MissingDoctypeSystemIdentifier,
+    MissingEndTagName,
+    MissingQuoteBeforeDoctypePublicIdentifier,
+    MissingQuoteBeforeDoctypeSystemIdentifier,
+    MissingSemicolonAfterCharacterReference,
+    MissingWhitespaceAfterDoctypePublicKeyword,
+    MissingWhitespaceAfterDoctypeSystemKeyword,
+    MissingWhitespaceBeforeDoctypeName,
+    MissingWhitespaceBetweenAttributes,
+    MissingWhitespaceBetweenDoctypePublicAndSystemIdentifiers,
+    NestedComment,
+    NoncharacterCharacterReference,
+    NonVoidHtmlElementStartTagWithTrailingSolidus,
+    NullCharacterReference,
+    SurrogateCharacterReference,
+    UnexpectedCharacterAfterDoctypeSystemIdentifier,
+    UnexpectedCharacterInAttributeName,
+    UnexpectedCharacterInUnquotedAttributeValue,
+    UnexpectedEqualsSignBeforeAttributeName,
+    UnexpectedNullCharacter,
+    UnexpectedQuestionMarkInsteadOfTagName,
+    UnexpectedSolidusInTag,
+    UnknownNamedCharacterReference,
+    MissingDoctype,
+    NonConformingDoctype,
+    UnexpectedDoctype,
+    UnexpectedStartTag(String),
+    UnexpectedEndTag(String),
+    UnexpectedCharacter,
+    UnexpectedEof,
 }
 
-impl<'a> HtmlParser<'a> {
+// https://html.spec.whatwg.org/multipage/parsing.html#the-insertion-mode
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum InsertionMode {
+    Initial,
+    BeforeHtml,
+    BeforeHead,
+    InHead,
+    InHeadNoscript,
+    AfterHead,
+    InBody,
+    Text,
+    InTable,
+    InTableText,
+    InCaption,
+    InColumnGroup,
+    InTableBody,
+    InRow,
+    InCell,
+    InSelect,
+    InSelectInTable,
+    InTemplate,
+    AfterBody,
+    InFrameset,
+    AfterFrameset,
+    AfterAfterBody,
+    AfterAfterFrameset,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum QuirksMode {
+    NoQuirks,
+    LimitedQuirks,
+    Quirks,
+}
+
+#[derive(Clone, Copy)]
+enum Scope {
+    Default,
+    ListItem,
+    Button,
+    Table,
+    Select,
+}
+
+enum FormattingEntry {
+    Marker,
+    Element(usize, Tag),
+}
+
+enum ParseNodeData {
+    Document,
+    Element(Tag, Namespace),
+    Text(String),
+    Comment(String),
+}
+
+struct ParseNode {
+    data: ParseNodeData,
+    parent: Option<usize>,
+    children: Vec<usize>,
+    // None for elements the parser implied or recreated rather than read
+    span: Option<Span>,
+}
+
+// https://html.spec.whatwg.org/multipage/parsing.html#stack-of-open-elements
+// Everything that takes an element off the stack goes through here, so an element
+// is known to be complete the moment it is popped; `take_closed` hands them over.
+#[derive(Default)]
+struct OpenElements {
+    stack: Vec<usize>,
+    closed: Vec<usize>,
+}
+
+impl OpenElements {
+    fn push(&mut self, element: usize) {
+        self.stack.push(element);
+    }
+
+    fn insert(&mut self, index: usize, element: usize) {
+        self.stack.insert(index, element);
+    }
+
+    fn pop(&mut self) -> Option<usize> {
+        let element = self.stack.pop()?;
+        self.closed.push(element);
+        Some(element)
+    }
+
+    fn remove(&mut self, index: usize) -> usize {
+        let element = self.stack.remove(index);
+        self.closed.push(element);
+        element
+    }
+
+    fn replace(&mut self, index: usize, element: usize) {
+        let old = mem::replace(&mut self.stack[index], element);
+        self.closed.push(old);
+    }
+
+    fn truncate(&mut self, len: usize) {
+        while self.stack.len() > len {
+            self.pop();
+        }
+    }
+
+    fn retain(&mut self, mut keep: impl FnMut(usize) -> bool) {
+        for index in (0..self.stack.len()).rev() {
+            if !keep(self.stack[index]) {
+                self.remove(index);
+            }
+        }
+    }
+
+    fn clear(&mut self) {
+        self.truncate(0);
+    }
+
+    // void and self-closing elements never go on the stack, they are complete
+    // as soon as they are inserted
+    fn close(&mut self, element: usize) {
+        self.closed.push(element);
+    }
+
+    fn take_closed(&mut self) -> Vec<usize> {
+        mem::take(&mut self.closed)
+    }
+}
+
+impl std::ops::Deref for OpenElements {
+    type Target = [usize];
+
+    fn deref(&self) -> &[usize] {
+        &self.stack
+    }
+}
+
+const DOCUMENT: usize = 0;
+
+const SPECIAL_ELEMENTS: [&str; 83] = [
+    "address",
+    "applet",
+    "area",
+    "article",
+    "aside",
+    "base",
+    "basefont",
+    "bgsound",
+    "blockquote",
+    "body",
+    "br",
+    "button",
+    "caption",
+    "center",
+    "col",
+    "colgroup",
+    "dd",
+    "details",
+    "dir",
+    "div",
+    "dl",
+    "dt",
+    "embed",
+    "fieldset",
+    "figcaption",
+    "figure",
+    "footer",
+    "form",
+    "frame",
+    "frameset",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "head",
+    "header",
+    "hgroup",
+    "hr",
+    "html",
+    "iframe",
+    "img",
+    "input",
+    "keygen",
+    "li",
+    "link",
+    "listing",
+    "main",
+    "marquee",
+    "menu",
+    "meta",
+    "nav",
+    "noembed",
+    "noframes",
+    "noscript",
+    "object",
+    "ol",
+    "p",
+    "param",
+    "plaintext",
+    "pre",
+    "script",
+    "search",
+    "section",
+    "select",
+    "source",
+    "style",
+    "summary",
+    "table",
+    "tbody",
+    "td",
+    "template",
+    "textarea",
+    "tfoot",
+    "th",
+    "thead",
+    "title",
+    "tr",
+    "track",
+    "ul",
+    "wbr",
+    "xmp",
+];
+
+const FORMATTING_ELEMENTS: [&str; 14] = [
+    "a", "b", "big", "code", "em", "font", "i", "nobr", "s", "small", "strike", "strong", "tt", "u",
+];
+
+const HEADINGS: [&str; 6] = ["h1", "h2", "h3", "h4", "h5", "h6"];
+
+const IMPLIED_END_TAGS: [&str; 10] = [
+    "dd", "dt", "li", "optgroup", "option", "p", "rb", "rp", "rt", "rtc",
+];
+
+// https://html.spec.whatwg.org/multipage/parsing.html#parsing-main-inforeign
+// start tags that end foreign content, plus font when it has a color, face or size
+const FOREIGN_BREAKOUT_ELEMENTS: [&str; 44] = [
+    "b",
+    "big",
+    "blockquote",
+    "body",
+    "br",
+    "center",
+    "code",
+    "dd",
+    "div",
+    "dl",
+    "dt",
+    "em",
+    "embed",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "head",
+    "hr",
+    "i",
+    "img",
+    "li",
+    "listing",
+    "menu",
+    "meta",
+    "nobr",
+    "ol",
+    "p",
+    "pre",
+    "ruby",
+    "s",
+    "small",
+    "span",
+    "strong",
+    "strike",
+    "sub",
+    "sup",
+    "table",
+    "tt",
+    "u",
+    "ul",
+    "var",
+];
+
+// the tokenizer lowercases every tag name, these svg ones get their case back
+// https://html.spec.whatwg.org/multipage/parsing.html#parsing-main-inforeign
+const SVG_TAG_NAMES: [(&str, &str); 37] = [
+    ("altglyph", "altGlyph"),
+    ("altglyphdef", "altGlyphDef"),
+    ("altglyphitem", "altGlyphItem"),
+    ("animatecolor", "animateColor"),
+    ("animatemotion", "animateMotion"),
+    ("animatetransform", "animateTransform"),
+    ("clippath", "clipPath"),
+    ("feblend", "feBlend"),
+    ("fecolormatrix", "feColorMatrix"),
+    ("fecomponenttransfer", "feComponentTransfer"),
+    ("fecomposite", "feComposite"),
+    ("feconvolvematrix", "feConvolveMatrix"),
+    ("fediffuselighting", "feDiffuseLighting"),
+    ("fedisplacementmap", "feDisplacementMap"),
+    ("fedistantlight", "feDistantLight"),
+    ("fedropshadow", "feDropShadow"),
+    ("feflood", "feFlood"),
+    ("fefunca", "feFuncA"),
+    ("fefuncb", "feFuncB"),
+    ("fefuncg", "feFuncG"),
+    ("fefuncr", "feFuncR"),
+    ("fegaussianblur", "feGaussianBlur"),
+    ("feimage", "feImage"),
+    ("femerge", "feMerge"),
+    ("femergenode", "feMergeNode"),
+    ("femorphology", "feMorphology"),
+    ("feoffset", "feOffset"),
+    ("fepointlight", "fePointLight"),
+    ("fespecularlighting", "feSpecularLighting"),
+    ("fespotlight", "feSpotLight"),
+    ("fetile", "feTile"),
+    ("feturbulence", "feTurbulence"),
+    ("foreignobject", "foreignObject"),
+    ("glyphref", "glyphRef"),
+    ("lineargradient", "linearGradient"),
+    ("radialgradient", "radialGradient"),
+    ("textpath", "textPath"),
+];
+
+// https://html.spec.whatwg.org/multipage/parsing.html#adjust-svg-attributes
+const SVG_ATTRIBUTE_NAMES: [(&str, &str); 58] = [
+    ("attributename", "attributeName"),
+    ("attributetype", "attributeType"),
+    ("basefrequency", "baseFrequency"),
+    ("baseprofile", "baseProfile"),
+    ("calcmode", "calcMode"),
+    ("clippathunits", "clipPathUnits"),
+    ("diffuseconstant", "diffuseConstant"),
+    ("edgemode", "edgeMode"),
+    ("filterunits", "filterUnits"),
+    ("glyphref", "glyphRef"),
+    ("gradienttransform", "gradientTransform"),
+    ("gradientunits", "gradientUnits"),
+    ("kernelmatrix", "kernelMatrix"),
+    ("kernelunitlength", "kernelUnitLength"),
+    ("keypoints", "keyPoints"),
+    ("keysplines", "keySplines"),
+    ("keytimes", "keyTimes"),
+    ("lengthadjust", "lengthAdjust"),
+    ("limitingconeangle", "limitingConeAngle"),
+    ("markerheight", "markerHeight"),
+    ("markerunits", "markerUnits"),
+    ("markerwidth", "markerWidth"),
+    ("maskcontentunits", "maskContentUnits"),
+    ("maskunits", "maskUnits"),
+    ("numoctaves", "numOctaves"),
+    ("pathlength", "pathLength"),
+    ("patterncontentunits", "patternContentUnits"),
+    ("patterntransform", "patternTransform"),
+    ("patternunits", "patternUnits"),
+    ("pointsatx", "pointsAtX"),
+    ("pointsaty", "pointsAtY"),
+    ("pointsatz", "pointsAtZ"),
+    ("preservealpha", "preserveAlpha"),
+    ("preserveaspectratio", "preserveAspectRatio"),
+    ("primitiveunits", "primitiveUnits"),
+    ("refx", "refX"),
+    ("refy", "refY"),
+    ("repeatcount", "repeatCount"),
+    ("repeatdur", "repeatDur"),
+    ("requiredextensions", "requiredExtensions"),
+    ("requiredfeatures", "requiredFeatures"),
+    ("specularconstant", "specularConstant"),
+    ("specularexponent", "specularExponent"),
+    ("spreadmethod", "spreadMethod"),
+    ("startoffset", "startOffset"),
+    ("stddeviation", "stdDeviation"),
+    ("stitchtiles", "stitchTiles"),
+    ("surfacescale", "surfaceScale"),
+    ("systemlanguage", "systemLanguage"),
+    ("tablevalues", "tableValues"),
+    ("targetx", "targetX"),
+    ("targety", "targetY"),
+    ("textlength", "textLength"),
+    ("viewbox", "viewBox"),
+    ("viewtarget", "viewTarget"),
+    ("xchannelselector", "xChannelSelector"),
+    ("ychannelselector", "yChannelSelector"),
+    ("zoomandpan", "zoomAndPan"),
+];
+
+// https://html.spec.whatwg.org/multipage/parsing.html#adjust-foreign-attributes
+const FOREIGN_ATTRIBUTES: [(&str, Namespace); 11] = [
+    ("xlink:actuate", Namespace::XLink),
+    ("xlink:arcrole", Namespace::XLink),
+    ("xlink:href", Namespace::XLink),
+    ("xlink:role", Namespace::XLink),
+    ("xlink:show", Namespace::XLink),
+    ("xlink:title", Namespace::XLink),
+    ("xlink:type", Namespace::XLink),
+    ("xml:lang", Namespace::Xml),
+    ("xml:space", Namespace::Xml),
+    ("xmlns", Namespace::Xmlns),
+    ("xmlns:xlink", Namespace::Xmlns),
+];
+
+pub struct HtmlParser {
+    tokenizer: Tokenizer,
+    mode: InsertionMode,
+    original_mode: InsertionMode,
+    // https://html.spec.whatwg.org/multipage/parsing.html#stack-of-template-insertion-modes
+    template_modes: Vec<InsertionMode>,
+    nodes: Vec<ParseNode>,
+    open_elements: OpenElements,
+    formatting: Vec<FormattingEntry>,
+    head: Option<usize>,
+    form: Option<usize>,
+    frameset_ok: bool,
+    foster_parenting: bool,
+    pending_table_text: Vec<(char, Span)>,
+    doctype: Option<Doctype>,
+    quirks_mode: QuirksMode,
+    ignore_next_line_feed: bool,
+    done: bool,
+    errors: Vec<ParseError<ErrorCode>>,
+    current_span: Span,
+    last_token_end: SourcePosition,
+    completed: Vec<usize>,
+}
+
+impl HtmlParser {
     pub fn new(full_html: &str) -> HtmlParser {
+        Self::with_tokenizer(Tokenizer::new(full_html))
+    }
+
+    /// A parser that is given its input a chunk at a time through `feed`; see
+    /// `html_stream::HtmlStreamParser` for feeding it bytes.
+    pub fn streaming() -> HtmlParser {
+        Self::with_tokenizer(Tokenizer::streaming())
+    }
+
+    fn with_tokenizer(tokenizer: Tokenizer) -> HtmlParser {
         HtmlParser {
-            chars: full_html[15..].chars().peekable(),
-            node_q: Vec::new(),
+            tokenizer,
+            mode: InsertionMode::Initial,
+            original_mode: InsertionMode::Initial,
+            template_modes: Vec::new(),
+            nodes: vec![ParseNode {
+                data: ParseNodeData::Document,
+                parent: None,
+                children: Vec::new(),
+                span: None,
+            }],
+            open_elements: OpenElements::default(),
+            formatting: Vec::new(),
+            head: None,
+            form: None,
+            frameset_ok: true,
+            foster_parenting: false,
+            pending_table_text: Vec::new(),
+            doctype: None,
+            quirks_mode: QuirksMode::NoQuirks,
+            ignore_next_line_feed: false,
+            done: false,
+            errors: Vec::new(),
+            current_span: Span::default(),
+            last_token_end: SourcePosition::default(),
+            completed: Vec::new(),
+        }
+    }
+
+    /// Parses the whole input and returns the children of the document,
+    /// which is the `<html>` element plus any comments around it.
+    pub fn parse_nodes(&mut self) -> Vec<Node> {
+        self.tokenizer.finish();
+        self.run();
+        self.completed.clear();
+        let children = &self.nodes[DOCUMENT].children;
+        children.iter().map(|&id| self.build_node(id)).collect()
+    }
+
+    /// Parses the whole input into a `Document`, with the doctype and quirks mode
+    /// the parser found.
+    pub fn parse_document(&mut self) -> Document {
+        let mut document = Document::from_tree(self.parse_tree());
+        document.set_doctype(self.doctype.clone());
+        document.set_quirks_mode(self.quirks_mode);
+        document
+    }
+
+    /// Parses the whole input into an arena `Tree`, whose document node stands
+    /// for the document the parser built.
+    pub fn parse_tree(&mut self) -> Tree {
+        self.tokenizer.finish();
+        self.run();
+        self.completed.clear();
+        // the nodes are created in the parser's own order, so every node gets the
+        // id that `feed` and `finish` reported it under
+        let mut tree = Tree::new();
+        for id in 1..self.nodes.len() {
+            let node = tree.create_node(self.node_type(id));
+            tree.set_span(node, self.nodes[id].span);
+        }
+        for (id, node) in self.nodes.iter().enumerate() {
+            for &child in &node.children {
+                // the parser's own tree is well formed, so appending cannot fail
+                let _ = tree.append_child(NodeId::from_index(id), NodeId::from_index(child));
+            }
+        }
+        tree
+    }
+
+    /// Parses as much of `chunk` as possible and returns every element that was
+    /// closed on the way, in the order they closed. Each is given by the id it has
+    /// in the tree `parse_tree` and `parse_document` build once the input is over.
+    /// An element the parser goes back into, like `<head>` for a late `<meta>`,
+    /// is returned again when it closes again.
+    pub fn feed(&mut self, chunk: &str) -> Vec<NodeId> {
+        self.tokenizer.feed(chunk);
+        self.run();
+        self.take_completed()
+    }
+
+    /// Marks the end of the input and returns the elements that closed because of it.
+    pub fn finish(&mut self) -> Vec<NodeId> {
+        self.tokenizer.finish();
+        self.run();
+        self.take_completed()
+    }
+
+    fn take_completed(&mut self) -> Vec<NodeId> {
+        mem::take(&mut self.completed)
+            .into_iter()
+            .map(NodeId::from_index)
+            .collect()
+    }
+
+    fn run(&mut self) {
+        while !self.done {
+            let foreign = self
+                .namespace(self.current_node())
+                .is_some_and(|n| n != Namespace::Html);
+            self.tokenizer.set_allow_cdata(foreign);
+            let token = match self.tokenizer.next_token() {
+                Some(token) => token,
+                None => return,
+            };
+            self.current_span = self.tokenizer.token_span();
+            let end_tag = match token {
+                Token::EndTag(ref tag) => Some(tag.name.clone()),
+                _ => None,
+            };
+            self.process_token(token);
+            let closed = self.open_elements.take_closed();
+            self.close_elements(closed, end_tag.as_deref());
+            self.last_token_end = self.current_span.end;
+        }
+    }
+
+    /// Parses like `parse_nodes` and also returns every parse error in source order.
+    pub fn parse_with_errors(&mut self) -> (Vec<Node>, Vec<ParseError<ErrorCode>>) {
+        let nodes = self.parse_nodes();
+        let mut errors = self.tokenizer.take_errors();
+        errors.append(&mut self.errors);
+        errors.sort_by_key(|e| e.position);
+        (nodes, errors)
+    }
+
+    pub fn quirks_mode(&self) -> QuirksMode {
+        self.quirks_mode
+    }
+
+    pub fn doctype(&self) -> Option<&Doctype> {
+        self.doctype.as_ref()
+    }
+
+    /// Handles every element the last token took off the stack. Its span ends at
+    /// the end of its end tag, or where the content before an implied close ended.
+    fn close_elements(&mut self, closed: Vec<usize>, end_tag: Option<&str>) {
+        for node in closed {
+            let closed_by_tag =
+                end_tag.is_some_and(|t| t.eq_ignore_ascii_case(self.local_name(node)));
+            let end = if closed_by_tag {
+                self.current_span.end
+            } else {
+                self.last_token_end
+            };
+            if let Some(ref mut span) = self.nodes[node].span {
+                span.end = end.max(span.end);
+            }
+            self.completed.push(node);
+        }
+    }
+
+    fn build_node(&self, id: usize) -> Node {
+        let children = self.nodes[id]
+            .children
+            .iter()
+            .map(|&c| self.build_node(c))
+            .collect();
+        let mut node = Node::new(self.node_type(id), children);
+        node.span = self.nodes[id].span;
+        node
+    }
+
+    fn node_type(&self, id: usize) -> NodeType {
+        match self.nodes[id].data {
+            ParseNodeData::Element(ref tag, namespace) => {
+                let attributes: AttrList = tag.attributes.iter().cloned().collect();
+                let mut element = ElementData::new_ns(namespace, tag.name.clone(), attributes);
+                if namespace != Namespace::Html {
+                    for (name, value) in &tag.attributes {
+                        if let Some(&(_, ns)) = FOREIGN_ATTRIBUTES.iter().find(|(n, _)| n == name) {
+                            // the names in the table are all valid
+                            let _ = element.set_attribute_ns(Some(ns), name, value);
+                        }
+                    }
+                }
+                NodeType::Element(element)
+            }
+            ParseNodeData::Text(ref t) => NodeType::Text(t.clone()),
+            ParseNodeData::Comment(ref c) => NodeType::Comment(c.clone()),
+            ParseNodeData::Document => NodeType::Document,
+        }
+    }
+
+    // https://html.spec.whatwg.org/multipage/parsing.html#tree-construction-dispatcher
+    fn process_token(&mut self, token: Token) {
+        // a newline right after <pre>, <listing> or <textarea> is not part of the content
+        if mem::take(&mut self.ignore_next_line_feed) && token == Token::Character('\n') {
+            return;
+        }
+        if self.is_foreign_token(&token) {
+            self.in_foreign_content(token);
+        } else {
+            self.process_in_mode(token);
+        }
+    }
+
+    fn is_foreign_token(&self, token: &Token) -> bool {
+        let node = self.current_node();
+        match self.namespace(node) {
+            None | Some(Namespace::Html) => return false,
+            Some(_) => {}
+        }
+        match token {
+            Token::StartTag(tag) if self.is_mathml_text_integration_point(node) => {
+                matches!(&tag.name[..], "mglyph" | "malignmark")
+            }
+            Token::StartTag(tag)
+                if tag.name == "svg"
+                    && self.namespace(node) == Some(Namespace::MathMl)
+                    && self.local_name(node) == "annotation-xml" =>
+            {
+                false
+            }
+            Token::StartTag(_) | Token::Character(_) => {
+                !self.is_mathml_text_integration_point(node)
+                    && !self.is_html_integration_point(node)
+            }
+            Token::Eof => false,
+            _ => true,
+        }
+    }
+
+    fn process_in_mode(&mut self, token: Token) {
+        match self.mode {
+            InsertionMode::Initial => self.initial(token),
+            InsertionMode::BeforeHtml => self.before_html(token),
+            InsertionMode::BeforeHead => self.before_head(token),
+            InsertionMode::InHead => self.in_head(token),
+            InsertionMode::InHeadNoscript => self.in_head_noscript(token),
+            InsertionMode::AfterHead => self.after_head(token),
+            InsertionMode::InBody => self.in_body(token),
+            InsertionMode::Text => self.text(token),
+            InsertionMode::InTable => self.in_table(token),
+            InsertionMode::InTableText => self.in_table_text(token),
+            InsertionMode::InCaption => self.in_caption(token),
+            InsertionMode::InColumnGroup => self.in_column_group(token),
+            InsertionMode::InTableBody => self.in_table_body(token),
+            InsertionMode::InRow => self.in_row(token),
+            InsertionMode::InCell => self.in_cell(token),
+            InsertionMode::InSelect => self.in_select(token),
+            InsertionMode::InSelectInTable => self.in_select_in_table(token),
+            InsertionMode::InTemplate => self.in_template(token),
+            InsertionMode::AfterBody => self.after_body(token),
+            InsertionMode::InFrameset => self.in_frameset(token),
+            InsertionMode::AfterFrameset => self.after_frameset(token),
+            InsertionMode::AfterAfterBody => self.after_after_body(token),
+            InsertionMode::AfterAfterFrameset => self.after_after_frameset(token),
+        }
+    }
+
+    fn error(&mut self, code: ErrorCode) {
+        self.error_at(code, self.current_span.start);
+    }
+
+    fn error_at(&mut self, code: ErrorCode, position: SourcePosition) {
+        self.errors.push(ParseError::new(code, position));
+    }
+
+    fn unexpected_start_tag(&mut self, tag: &Tag) {
+        self.error(ErrorCode::UnexpectedStartTag(tag.name.clone()));
+    }
+
+    fn unexpected_end_tag(&mut self, tag: &Tag) {
+        self.error(ErrorCode::UnexpectedEndTag(tag.name.clone()));
+    }
+
+    fn unexpected_token(&mut self, token: &Token) {
+        match token {
+            Token::StartTag(tag) => self.unexpected_start_tag(tag),
+            Token::EndTag(tag) => self.unexpected_end_tag(tag),
+            Token::Doctype(_) => self.error(ErrorCode::UnexpectedDoctype),
+            Token::Character(_) => self.error(ErrorCode::UnexpectedCharacter),
+            Token::Comment(_) | Token::Eof => {}
+        }
+    }
+
+    fn reprocess_in(&mut self, mode: InsertionMode, token: Token) {
+        self.mode = mode;
+        self.process_in_mode(token);
+    }
+
+    fn is_whitespace(c: char) -> bool {
+        matches!(c, '\t' | '\n' | '\u{000C}' | '\r' | ' ')
+    }
+
+    fn initial(&mut self, token: Token) {
+        match token {
+            Token::Character(c) if Self::is_whitespace(c) => {}
+            Token::Comment(c) => self.append_comment_to(DOCUMENT, c),
+            Token::Doctype(doctype) => {
+                if !Self::is_conforming_doctype(&doctype) {
+                    self.error(ErrorCode::NonConformingDoctype);
+                }
+                self.quirks_mode = Self::quirks_mode_for(&doctype);
+                self.doctype = Some(doctype);
+                self.mode = InsertionMode::BeforeHtml;
+            }
+            token => {
+                self.error(ErrorCode::MissingDoctype);
+                self.quirks_mode = QuirksMode::Quirks;
+                self.reprocess_in(InsertionMode::BeforeHtml, token);
+            }
+        }
+    }
+
+    fn before_html(&mut self, token: Token) {
+        match token {
+            Token::Doctype(_) => self.error(ErrorCode::UnexpectedDoctype),
+            Token::Comment(c) => self.append_comment_to(DOCUMENT, c),
+            Token::Character(c) if Self::is_whitespace(c) => {}
+            Token::StartTag(tag) if tag.name == "html" => {
+                let html = self.create_node(ParseNodeData::Element(tag, Namespace::Html));
+                self.nodes[html].span = Some(self.current_span);
+                self.append_child(DOCUMENT, html);
+                self.open_elements.push(html);
+                self.mode = InsertionMode::BeforeHead;
+            }
+            Token::EndTag(ref tag) if !matches!(&tag.name[..], "head" | "body" | "html" | "br") => {
+                self.unexpected_end_tag(tag)
+            }
+            token => {
+                let html = self.create_node(ParseNodeData::Element(
+                    Self::empty_tag("html"),
+                    Namespace::Html,
+                ));
+                self.append_child(DOCUMENT, html);
+                self.open_elements.push(html);
+                self.reprocess_in(InsertionMode::BeforeHead, token);
+            }
+        }
+    }
+
+    fn before_head(&mut self, token: Token) {
+        match token {
+            Token::Character(c) if Self::is_whitespace(c) => {}
+            Token::Comment(c) => self.insert_comment(c),
+            Token::Doctype(_) => self.error(ErrorCode::UnexpectedDoctype),
+            Token::StartTag(ref tag) if tag.name == "html" => self.in_body(token),
+            Token::StartTag(tag) if tag.name == "head" => {
+                self.head = Some(self.insert_element(tag));
+                self.mode = InsertionMode::InHead;
+            }
+            Token::EndTag(ref tag) if !matches!(&tag.name[..], "head" | "body" | "html" | "br") => {
+                self.unexpected_end_tag(tag)
+            }
+            token => {
+                self.head = Some(self.insert_implied_element("head"));
+                self.reprocess_in(InsertionMode::InHead, token);
+            }
+        }
+    }
+
+    fn in_head(&mut self, token: Token) {
+        match token {
+            Token::Character(c) if Self::is_whitespace(c) => self.insert_character(c),
+            Token::Comment(c) => self.insert_comment(c),
+            Token::Doctype(_) => self.error(ErrorCode::UnexpectedDoctype),
+            Token::StartTag(ref tag) if tag.name == "html" => self.in_body(token),
+            Token::StartTag(tag)
+                if matches!(
+                    &tag.name[..],
+                    "base" | "basefont" | "bgsound" | "link" | "meta"
+                ) =>
+            {
+                self.insert_element(tag);
+            }
+            Token::StartTag(tag) if tag.name == "title" => {
+                self.parse_generic_text_element(tag, State::RcData)
+            }
+            Token::StartTag(tag) if matches!(&tag.name[..], "noframes" | "style") => {
+                self.parse_generic_text_element(tag, State::RawText)
+            }
+            Token::StartTag(tag) if tag.name == "noscript" => {
+                // scripting is disabled, so the contents are parsed as markup
+                self.insert_element(tag);
+                self.mode = InsertionMode::InHeadNoscript;
+            }
+            Token::StartTag(tag) if tag.name == "script" => {
+                self.parse_generic_text_element(tag, State::ScriptData)
+            }
+            // the dom has no document fragments, so the template contents are
+            // the children of the template element
+            Token::StartTag(tag) if tag.name == "template" => {
+                self.insert_element(tag);
+                self.formatting.push(FormattingEntry::Marker);
+                self.frameset_ok = false;
+                self.mode = InsertionMode::InTemplate;
+                self.template_modes.push(InsertionMode::InTemplate);
+            }
+            Token::EndTag(ref tag) if tag.name == "template" => {
+                if !self.is_open("template") {
+                    self.unexpected_end_tag(tag);
+                    return;
+                }
+                self.generate_all_implied_end_tags();
+                if self.tag_name(self.current_node()) != "template" {
+                    self.unexpected_end_tag(tag);
+                }
+                self.close_template();
+            }
+            Token::EndTag(ref tag) if tag.name == "head" => {
+                self.open_elements.pop();
+                self.mode = InsertionMode::AfterHead;
+            }
+            Token::StartTag(ref tag) if tag.name == "head" => self.unexpected_start_tag(tag),
+            Token::EndTag(ref tag) if !matches!(&tag.name[..], "body" | "html" | "br") => {
+                self.unexpected_end_tag(tag)
+            }
+            token => {
+                self.open_elements.pop();
+                self.reprocess_in(InsertionMode::AfterHead, token);
+            }
+        }
+    }
+
+    fn in_head_noscript(&mut self, token: Token) {
+        match token {
+            Token::Doctype(_) => self.error(ErrorCode::UnexpectedDoctype),
+            Token::StartTag(ref tag) if tag.name == "html" => self.in_body(token),
+            Token::EndTag(ref tag) if tag.name == "noscript" => {
+                self.open_elements.pop();
+                self.mode = InsertionMode::InHead;
+            }
+            Token::Character(c) if Self::is_whitespace(c) => self.in_head(token),
+            Token::Comment(_) => self.in_head(token),
+            Token::StartTag(ref tag)
+                if matches!(
+                    &tag.name[..],
+                    "basefont" | "bgsound" | "link" | "meta" | "noframes" | "style"
+                ) =>
+            {
+                self.in_head(token)
+            }
+            Token::StartTag(ref tag) if matches!(&tag.name[..], "head" | "noscript") => {
+                self.unexpected_start_tag(tag)
+            }
+            Token::EndTag(ref tag) if tag.name != "br" => self.unexpected_end_tag(tag),
+            token => {
+                self.unexpected_token(&token);
+                self.open_elements.pop();
+                self.reprocess_in(InsertionMode::InHead, token);
+            }
+        }
+    }
+
+    fn after_head(&mut self, token: Token) {
+        match token {
+            Token::Character(c) if Self::is_whitespace(c) => self.insert_character(c),
+            Token::Comment(c) => self.insert_comment(c),
+            Token::Doctype(_) => self.error(ErrorCode::UnexpectedDoctype),
+            Token::StartTag(ref tag) if tag.name == "html" => self.in_body(token),
+            Token::StartTag(tag) if tag.name == "body" => {
+                self.insert_element(tag);
+                self.frameset_ok = false;
+                self.mode = InsertionMode::InBody;
+            }
+            Token::StartTag(tag) if tag.name == "frameset" => {
+                self.insert_element(tag);
+                self.mode = InsertionMode::InFrameset;
+            }
+            Token::StartTag(ref tag)
+                if matches!(
+                    &tag.name[..],
+                    "base"
+                        | "basefont"
+                        | "bgsound"
+                        | "link"
+                        | "meta"
+                        | "noframes"
+                        | "script"
+                        | "style"
+                        | "template"
+                        | "title"
+                ) =>
+            {
+                // these still belong to the head, even after it was closed
+                self.unexpected_start_tag(tag);
+                if let Some(head) = self.head {
+                    self.open_elements.push(head);
+                    self.in_head(token);
+                    self.open_elements.retain(|n| n != head);
+                }
+            }
+            Token::EndTag(ref tag) if tag.name == "template" => self.in_head(token),
+            Token::StartTag(ref tag) if tag.name == "head" => self.unexpected_start_tag(tag),
+            Token::EndTag(ref tag) if !matches!(&tag.name[..], "body" | "html" | "br") => {
+                self.unexpected_end_tag(tag)
+            }
+            token => {
+                self.insert_implied_element("body");
+                self.reprocess_in(InsertionMode::InBody, token);
+            }
+        }
+    }
+
+    fn in_body(&mut self, token: Token) {
+        match token {
+            Token::Character('\0') => self.error(ErrorCode::UnexpectedNullCharacter),
+            Token::Character(c) => {
+                self.reconstruct_formatting();
+                self.insert_character(c);
+                if !Self::is_whitespace(c) {
+                    self.frameset_ok = false;
+                }
+            }
+            Token::Comment(c) => self.insert_comment(c),
+            Token::Doctype(_) => self.error(ErrorCode::UnexpectedDoctype),
+            Token::StartTag(tag) => self.in_body_start_tag(tag),
+            Token::EndTag(tag) => self.in_body_end_tag(tag),
+            Token::Eof if !self.template_modes.is_empty() => self.in_template(Token::Eof),
+            Token::Eof => {
+                if self.has_unclosed_elements() {
+                    self.error(ErrorCode::UnexpectedEof);
+                }
+                self.stop_parsing();
+            }
+        }
+    }
+
+    fn has_unclosed_elements(&self) -> bool {
+        self.open_elements.iter().any(|&n| {
+            !matches!(
+                self.tag_name(n),
+                "dd" | "dt"
+                    | "li"
+                    | "optgroup"
+                    | "option"
+                    | "p"
+                    | "rb"
+                    | "rp"
+                    | "rt"
+                    | "rtc"
+                    | "tbody"
+                    | "td"
+                    | "tfoot"
+                    | "th"
+                    | "thead"
+                    | "tr"
+                    | "body"
+                    | "html"
+            )
+        })
+    }
+
+    fn in_body_start_tag(&mut self, mut tag: Tag) {
+        match &tag.name[..] {
+            "html" => {
+                self.unexpected_start_tag(&tag);
+                if self.is_open("template") {
+                    return;
+                }
+                let html = self.open_elements[0];
+                self.merge_attributes(html, tag);
+            }
+            "base" | "basefont" | "bgsound" | "link" | "meta" | "noframes" | "script" | "style"
+            | "template" | "title" => self.in_head(Token::StartTag(tag)),
+            "body" => {
+                self.unexpected_start_tag(&tag);
+                if self.open_elements.len() < 2
+                    || self.tag_name(self.open_elements[1]) != "body"
+                    || self.is_open("template")
+                {
+                    return;
+                }
+                self.frameset_ok = false;
+                let body = self.open_elements[1];
+                self.merge_attributes(body, tag);
+            }
+            "frameset" => {
+                self.unexpected_start_tag(&tag);
+                if self.open_elements.len() < 2
+                    || self.tag_name(self.open_elements[1]) != "body"
+                    || !self.frameset_ok
+                {
+                    return;
+                }
+                let body = self.open_elements[1];
+                self.detach(body);
+                self.open_elements.truncate(1);
+                self.insert_element(tag);
+                self.mode = InsertionMode::InFrameset;
+            }
+            "address" | "article" | "aside" | "blockquote" | "center" | "details" | "dialog"
+            | "dir" | "div" | "dl" | "fieldset" | "figcaption" | "figure" | "footer" | "header"
+            | "hgroup" | "main" | "menu" | "nav" | "ol" | "p" | "search" | "section"
+            | "summary" | "ul" => {
+                self.close_p_in_button_scope();
+                self.insert_element(tag);
+            }
+            name if HEADINGS.contains(&name) => {
+                self.close_p_in_button_scope();
+                if HEADINGS.contains(&self.tag_name(self.current_node())) {
+                    self.unexpected_start_tag(&tag);
+                    self.open_elements.pop();
+                }
+                self.insert_element(tag);
+            }
+            "pre" | "listing" => {
+                self.close_p_in_button_scope();
+                self.insert_element(tag);
+                self.ignore_next_line_feed = true;
+                self.frameset_ok = false;
+            }
+            "form" => {
+                if self.form.is_some() && !self.is_open("template") {
+                    self.unexpected_start_tag(&tag);
+                    return;
+                }
+                self.close_p_in_button_scope();
+                let form = self.insert_element(tag);
+                if !self.is_open("template") {
+                    self.form = Some(form);
+                }
+            }
+            "li" | "dd" | "dt" => {
+                self.frameset_ok = false;
+                let closes: &[&str] = if tag.name == "li" {
+                    &["li"]
+                } else {
+                    &["dd", "dt"]
+                };
+                for &node in self.open_elements.to_vec().iter().rev() {
+                    let name = self.tag_name(node).to_string();
+                    if closes.contains(&&name[..]) {
+                        self.generate_implied_end_tags(Some(&name));
+                        self.pop_until(|n| n == name);
+                        break;
+                    }
+                    if self.is_special(node) && !matches!(&name[..], "address" | "div" | "p") {
+                        break;
+                    }
+                }
+                self.close_p_in_button_scope();
+                self.insert_element(tag);
+            }
+            "plaintext" => {
+                self.close_p_in_button_scope();
+                self.insert_element(tag);
+                self.tokenizer.switch_to(State::PlainText);
+            }
+            "button" => {
+                if self.has_in_scope("button", Scope::Default) {
+                    self.unexpected_start_tag(&tag);
+                    self.generate_all_implied_end_tags();
+                    self.pop_until(|n| n == "button");
+                }
+                self.reconstruct_formatting();
+                self.insert_element(tag);
+                self.frameset_ok = false;
+            }
+            "a" => {
+                if let Some(a) = self.formatting_element_after_marker("a") {
+                    self.unexpected_start_tag(&tag);
+                    self.adoption_agency("a");
+                    self.remove_from_formatting(a);
+                    self.open_elements.retain(|n| n != a);
+                }
+                self.reconstruct_formatting();
+                self.insert_formatting_element(tag);
+            }
+            "nobr" => {
+                self.reconstruct_formatting();
+                if self.has_in_scope("nobr", Scope::Default) {
+                    self.unexpected_start_tag(&tag);
+                    self.adoption_agency("nobr");
+                    self.reconstruct_formatting();
+                }
+                self.insert_formatting_element(tag);
+            }
+            name if FORMATTING_ELEMENTS.contains(&name) => {
+                self.reconstruct_formatting();
+                self.insert_formatting_element(tag);
+            }
+            "applet" | "marquee" | "object" => {
+                self.reconstruct_formatting();
+                self.insert_element(tag);
+                self.formatting.push(FormattingEntry::Marker);
+                self.frameset_ok = false;
+            }
+            "table" => {
+                if self.quirks_mode != QuirksMode::Quirks {
+                    self.close_p_in_button_scope();
+                }
+                self.insert_element(tag);
+                self.frameset_ok = false;
+                self.mode = InsertionMode::InTable;
+            }
+            "area" | "br" | "embed" | "img" | "keygen" | "wbr" => {
+                self.reconstruct_formatting();
+                self.insert_element(tag);
+                self.frameset_ok = false;
+            }
+            "input" => {
+                self.reconstruct_formatting();
+                let hidden = tag
+                    .get_attribute("type")
+                    .is_some_and(|t| t.eq_ignore_ascii_case("hidden"));
+                self.insert_element(tag);
+                if !hidden {
+                    self.frameset_ok = false;
+                }
+            }
+            "textarea" => {
+                self.parse_generic_text_element(tag, State::RcData);
+                self.ignore_next_line_feed = true;
+                self.frameset_ok = false;
+            }
+            "xmp" => {
+                self.close_p_in_button_scope();
+                self.reconstruct_formatting();
+                self.frameset_ok = false;
+                self.parse_generic_text_element(tag, State::RawText);
+            }
+            "iframe" => {
+                self.frameset_ok = false;
+                self.parse_generic_text_element(tag, State::RawText);
+            }
+            "noembed" => self.parse_generic_text_element(tag, State::RawText),
+            "param" | "source" | "track" => {
+                self.insert_element(tag);
+            }
+            "hr" => {
+                self.close_p_in_button_scope();
+                self.insert_element(tag);
+                self.frameset_ok = false;
+            }
+            "image" => {
+                self.unexpected_start_tag(&tag);
+                tag.name = "img".to_string();
+                self.in_body_start_tag(tag);
+            }
+            "select" => {
+                self.reconstruct_formatting();
+                self.insert_element(tag);
+                self.frameset_ok = false;
+                self.mode = match self.mode {
+                    InsertionMode::InTable
+                    | InsertionMode::InCaption
+                    | InsertionMode::InTableBody
+                    | InsertionMode::InRow
+                    | InsertionMode::InCell => InsertionMode::InSelectInTable,
+                    _ => InsertionMode::InSelect,
+                };
+            }
+            "optgroup" | "option" => {
+                if self.tag_name(self.current_node()) == "option" {
+                    self.open_elements.pop();
+                }
+                self.reconstruct_formatting();
+                self.insert_element(tag);
+            }
+            "rb" | "rtc" => {
+                if self.has_in_scope("ruby", Scope::Default) {
+                    self.generate_all_implied_end_tags();
+                }
+                self.insert_element(tag);
+            }
+            "rp" | "rt" => {
+                if self.has_in_scope("ruby", Scope::Default) {
+                    self.generate_implied_end_tags(Some("rtc"));
+                }
+                self.insert_element(tag);
+            }
+            "math" => {
+                self.reconstruct_formatting();
+                self.insert_foreign_element(tag, Namespace::MathMl);
+            }
+            "svg" => {
+                self.reconstruct_formatting();
+                self.insert_foreign_element(tag, Namespace::Svg);
+            }
+            "caption" | "col" | "colgroup" | "frame" | "head" | "tbody" | "td" | "tfoot" | "th"
+            | "thead" | "tr" => self.unexpected_start_tag(&tag),
+            _ => {
+                self.reconstruct_formatting();
+                self.insert_element(tag);
+            }
+        }
+    }
+
+    fn in_body_end_tag(&mut self, tag: Tag) {
+        match &tag.name[..] {
+            "template" => self.in_head(Token::EndTag(tag)),
+            "body" | "html" if !self.has_in_scope("body", Scope::Default) => {
+                self.unexpected_end_tag(&tag)
+            }
+            "body" => {
+                if self.has_unclosed_elements() {
+                    self.unexpected_end_tag(&tag);
+                }
+                self.mode = InsertionMode::AfterBody;
+            }
+            "html" => {
+                if self.has_unclosed_elements() {
+                    self.unexpected_end_tag(&tag);
+                }
+                self.reprocess_in(InsertionMode::AfterBody, Token::EndTag(tag));
+            }
+            "address" | "article" | "aside" | "blockquote" | "button" | "center" | "details"
+            | "dialog" | "dir" | "div" | "dl" | "fieldset" | "figcaption" | "figure" | "footer"
+            | "header" | "hgroup" | "listing" | "main" | "menu" | "nav" | "ol" | "pre"
+            | "search" | "section" | "summary" | "ul" => {
+                if !self.has_in_scope(&tag.name, Scope::Default) {
+                    self.unexpected_end_tag(&tag);
+                    return;
+                }
+                self.generate_all_implied_end_tags();
+                self.close_element(&tag);
+            }
+            "form" => {
+                if self.is_open("template") {
+                    if !self.has_in_scope("form", Scope::Default) {
+                        self.unexpected_end_tag(&tag);
+                        return;
+                    }
+                    self.generate_all_implied_end_tags();
+                    self.close_element(&tag);
+                    return;
+                }
+                let form = match self.form.take() {
+                    Some(form) if self.has_element_in_scope(form, Scope::Default) => form,
+                    _ => {
+                        self.unexpected_end_tag(&tag);
+                        return;
+                    }
+                };
+                self.generate_all_implied_end_tags();
+                if self.current_node() != form {
+                    self.unexpected_end_tag(&tag);
+                }
+                self.open_elements.retain(|n| n != form);
+            }
+            "p" => {
+                if !self.has_in_scope("p", Scope::Button) {
+                    self.unexpected_end_tag(&tag);
+                    self.insert_implied_element("p");
+                }
+                self.generate_implied_end_tags(Some("p"));
+                self.close_element(&tag);
+            }
+            "li" => {
+                if !self.has_in_scope("li", Scope::ListItem) {
+                    self.unexpected_end_tag(&tag);
+                    return;
+                }
+                self.generate_implied_end_tags(Some("li"));
+                self.close_element(&tag);
+            }
+            "dd" | "dt" => {
+                if !self.has_in_scope(&tag.name, Scope::Default) {
+                    self.unexpected_end_tag(&tag);
+                    return;
+                }
+                self.generate_implied_end_tags(Some(&tag.name));
+                self.close_element(&tag);
+            }
+            name if HEADINGS.contains(&name) => {
+                if !HEADINGS
+                    .iter()
+                    .any(|h| self.has_in_scope(h, Scope::Default))
+                {
+                    self.unexpected_end_tag(&tag);
+                    return;
+                }
+                self.generate_all_implied_end_tags();
+                if self.tag_name(self.current_node()) != name {
+                    self.unexpected_end_tag(&tag);
+                }
+                self.pop_until(|n| HEADINGS.contains(&n));
+            }
+            name if FORMATTING_ELEMENTS.contains(&name) => {
+                if !self.adoption_agency(name) {
+                    self.any_other_end_tag(name);
+                }
+            }
+            "applet" | "marquee" | "object" => {
+                if !self.has_in_scope(&tag.name, Scope::Default) {
+                    self.unexpected_end_tag(&tag);
+                    return;
+                }
+                self.generate_all_implied_end_tags();
+                self.close_element(&tag);
+                self.clear_formatting_to_marker();
+            }
+            "br" => {
+                self.unexpected_end_tag(&tag);
+                self.in_body_start_tag(Self::empty_tag("br"));
+            }
+            name => self.any_other_end_tag(name),
+        }
+    }
+
+    /// Pops elements up to and including the one `tag` closes, which is an
+    /// error when that element is not the current node.
+    fn close_element(&mut self, tag: &Tag) {
+        if self.tag_name(self.current_node()) != tag.name {
+            self.unexpected_end_tag(tag);
+        }
+        self.pop_until(|n| n == tag.name);
+    }
+
+    fn any_other_end_tag(&mut self, name: &str) {
+        for i in (0..self.open_elements.len()).rev() {
+            let node = self.open_elements[i];
+            if self.tag_name(node) == name {
+                self.generate_implied_end_tags(Some(name));
+                if i != self.open_elements.len() - 1 {
+                    self.error(ErrorCode::UnexpectedEndTag(name.to_string()));
+                }
+                self.open_elements.truncate(i);
+                return;
+            }
+            if self.is_special(node) {
+                self.error(ErrorCode::UnexpectedEndTag(name.to_string()));
+                return;
+            }
+        }
+    }
+
+    fn parse_generic_text_element(&mut self, tag: Tag, state: State) {
+        self.insert_element(tag);
+        self.tokenizer.switch_to(state);
+        self.original_mode = self.mode;
+        self.mode = InsertionMode::Text;
+    }
+
+    fn text(&mut self, token: Token) {
+        match token {
+            Token::Character(c) => self.insert_character(c),
+            Token::Eof => {
+                self.error(ErrorCode::UnexpectedEof);
+                self.open_elements.pop();
+                self.reprocess_in(self.original_mode, Token::Eof);
+            }
+            _ => {
+                self.open_elements.pop();
+                self.mode = self.original_mode;
+            }
+        }
+    }
+
+    fn in_table(&mut self, token: Token) {
+        match token {
+            Token::Character(_)
+                if matches!(
+                    self.tag_name(self.current_node()),
+                    "table" | "tbody" | "template" | "tfoot" | "thead" | "tr"
+                ) =>
+            {
+                self.pending_table_text.clear();
+                self.original_mode = self.mode;
+                self.reprocess_in(InsertionMode::InTableText, token);
+            }
+            Token::Comment(c) => self.insert_comment(c),
+            Token::Doctype(_) => self.error(ErrorCode::UnexpectedDoctype),
+            Token::StartTag(tag) => match &tag.name[..] {
+                "caption" => {
+                    self.clear_stack_to_context(&["table", "template", "html"]);
+                    self.formatting.push(FormattingEntry::Marker);
+                    self.insert_element(tag);
+                    self.mode = InsertionMode::InCaption;
+                }
+                "colgroup" => {
+                    self.clear_stack_to_context(&["table", "template", "html"]);
+                    self.insert_element(tag);
+                    self.mode = InsertionMode::InColumnGroup;
+                }
+                "col" => {
+                    self.clear_stack_to_context(&["table", "template", "html"]);
+                    self.insert_implied_element("colgroup");
+                    self.reprocess_in(InsertionMode::InColumnGroup, Token::StartTag(tag));
+                }
+                "tbody" | "tfoot" | "thead" => {
+                    self.clear_stack_to_context(&["table", "template", "html"]);
+                    self.insert_element(tag);
+                    self.mode = InsertionMode::InTableBody;
+                }
+                "td" | "th" | "tr" => {
+                    self.clear_stack_to_context(&["table", "template", "html"]);
+                    self.insert_implied_element("tbody");
+                    self.reprocess_in(InsertionMode::InTableBody, Token::StartTag(tag));
+                }
+                "table" => {
+                    self.unexpected_start_tag(&tag);
+                    if self.has_in_scope("table", Scope::Table) {
+                        self.pop_until(|n| n == "table");
+                        self.reset_insertion_mode();
+                        self.process_token(Token::StartTag(tag));
+                    }
+                }
+                "style" | "script" | "template" => self.in_head(Token::StartTag(tag)),
+                "input"
+                    if tag
+                        .get_attribute("type")
+                        .is_some_and(|t| t.eq_ignore_ascii_case("hidden")) =>
+                {
+                    self.unexpected_start_tag(&tag);
+                    self.insert_element(tag);
+                }
+                "form" => {
+                    self.unexpected_start_tag(&tag);
+                    if self.is_open("template") || self.form.is_some() {
+                        return;
+                    }
+                    let form = self.insert_element(tag);
+                    self.form = Some(form);
+                    self.open_elements.pop();
+                }
+                _ => self.in_table_anything_else(Token::StartTag(tag)),
+            },
+            Token::EndTag(tag) => match &tag.name[..] {
+                "table" => {
+                    if !self.has_in_scope("table", Scope::Table) {
+                        self.unexpected_end_tag(&tag);
+                        return;
+                    }
+                    self.pop_until(|n| n == "table");
+                    self.reset_insertion_mode();
+                }
+                "body" | "caption" | "col" | "colgroup" | "html" | "tbody" | "td" | "tfoot"
+                | "th" | "thead" | "tr" => self.unexpected_end_tag(&tag),
+                "template" => self.in_head(Token::EndTag(tag)),
+                _ => self.in_table_anything_else(Token::EndTag(tag)),
+            },
+            Token::Eof => self.in_body(Token::Eof),
+            token => self.in_table_anything_else(token),
+        }
+    }
+
+    fn in_table_anything_else(&mut self, token: Token) {
+        self.unexpected_token(&token);
+        self.foster_parent(token);
+    }
+
+    fn foster_parent(&mut self, token: Token) {
+        self.foster_parenting = true;
+        self.in_body(token);
+        self.foster_parenting = false;
+    }
+
+    fn in_table_text(&mut self, token: Token) {
+        match token {
+            Token::Character('\0') => self.error(ErrorCode::UnexpectedNullCharacter),
+            Token::Character(c) => self.pending_table_text.push((c, self.current_span)),
+            token => {
+                let pending = mem::take(&mut self.pending_table_text);
+                let span = self.current_span;
+                if pending.iter().any(|&(c, _)| !Self::is_whitespace(c)) {
+                    // reported once for the whole run of text rather than per character
+                    self.error_at(ErrorCode::UnexpectedCharacter, pending[0].1.start);
+                    for (c, span) in pending {
+                        self.current_span = span;
+                        self.foster_parent(Token::Character(c));
+                    }
+                } else {
+                    for (c, span) in pending {
+                        self.current_span = span;
+                        self.insert_character(c);
+                    }
+                }
+                self.current_span = span;
+                self.reprocess_in(self.original_mode, token);
+            }
+        }
+    }
+
+    fn in_caption(&mut self, token: Token) {
+        match token {
+            Token::EndTag(ref tag) if tag.name == "caption" => {
+                if !self.close_caption() {
+                    self.unexpected_end_tag(tag);
+                }
+            }
+            Token::StartTag(ref tag)
+                if matches!(
+                    &tag.name[..],
+                    "caption"
+                        | "col"
+                        | "colgroup"
+                        | "tbody"
+                        | "td"
+                        | "tfoot"
+                        | "th"
+                        | "thead"
+                        | "tr"
+                ) =>
+            {
+                self.unexpected_token(&token);
+                if self.close_caption() {
+                    self.process_token(token);
+                }
+            }
+            Token::EndTag(ref tag) if tag.name == "table" => {
+                self.unexpected_token(&token);
+                if self.close_caption() {
+                    self.process_token(token);
+                }
+            }
+            Token::EndTag(ref tag)
+                if matches!(
+                    &tag.name[..],
+                    "body"
+                        | "col"
+                        | "colgroup"
+                        | "html"
+                        | "tbody"
+                        | "td"
+                        | "tfoot"
+                        | "th"
+                        | "thead"
+                        | "tr"
+                ) =>
+            {
+                self.unexpected_end_tag(tag)
+            }
+            token => self.in_body(token),
+        }
+    }
+
+    fn close_caption(&mut self) -> bool {
+        if !self.has_in_scope("caption", Scope::Table) {
+            return false;
+        }
+        self.generate_all_implied_end_tags();
+        self.pop_until(|n| n == "caption");
+        self.clear_formatting_to_marker();
+        self.mode = InsertionMode::InTable;
+        true
+    }
+
+    fn in_column_group(&mut self, token: Token) {
+        match token {
+            Token::Character(c) if Self::is_whitespace(c) => self.insert_character(c),
+            Token::Comment(c) => self.insert_comment(c),
+            Token::Doctype(_) => self.error(ErrorCode::UnexpectedDoctype),
+            Token::StartTag(ref tag) if tag.name == "html" => self.in_body(token),
+            Token::StartTag(tag) if tag.name == "col" => {
+                self.insert_element(tag);
+            }
+            Token::EndTag(ref tag) if tag.name == "colgroup" => {
+                if self.tag_name(self.current_node()) != "colgroup" {
+                    self.unexpected_end_tag(tag);
+                    return;
+                }
+                self.open_elements.pop();
+                self.mode = InsertionMode::InTable;
+            }
+            Token::EndTag(ref tag) if tag.name == "col" => self.unexpected_end_tag(tag),
+            Token::StartTag(ref tag) if tag.name == "template" => self.in_head(token),
+            Token::EndTag(ref tag) if tag.name == "template" => self.in_head(token),
+            Token::Eof => self.in_body(token),
+            token => {
+                if self.tag_name(self.current_node()) != "colgroup" {
+                    self.unexpected_token(&token);
+                    return;
+                }
+                self.open_elements.pop();
+                self.reprocess_in(InsertionMode::InTable, token);
+            }
+        }
+    }
+
+    fn in_table_body(&mut self, token: Token) {
+        match token {
+            Token::StartTag(tag) if tag.name == "tr" => {
+                self.clear_stack_to_context(&["tbody", "tfoot", "thead", "template", "html"]);
+                self.insert_element(tag);
+                self.mode = InsertionMode::InRow;
+            }
+            Token::StartTag(tag) if matches!(&tag.name[..], "th" | "td") => {
+                self.clear_stack_to_context(&["tbody", "tfoot", "thead", "template", "html"]);
+                self.insert_implied_element("tr");
+                self.reprocess_in(InsertionMode::InRow, Token::StartTag(tag));
+            }
+            Token::EndTag(ref tag) if matches!(&tag.name[..], "tbody" | "tfoot" | "thead") => {
+                if !self.has_in_scope(&tag.name, Scope::Table) {
+                    self.unexpected_end_tag(tag);
+                    return;
+                }
+                self.clear_stack_to_context(&["tbody", "tfoot", "thead", "template", "html"]);
+                self.open_elements.pop();
+                self.mode = InsertionMode::InTable;
+            }
+            Token::StartTag(ref tag)
+                if matches!(
+                    &tag.name[..],
+                    "caption" | "col" | "colgroup" | "tbody" | "tfoot" | "thead"
+                ) =>
+            {
+                self.close_table_body(token)
+            }
+            Token::EndTag(ref tag) if tag.name == "table" => self.close_table_body(token),
+            Token::EndTag(ref tag)
+                if matches!(
+                    &tag.name[..],
+                    "body" | "caption" | "col" | "colgroup" | "html" | "td" | "th" | "tr"
+                ) =>
+            {
+                self.unexpected_end_tag(tag)
+            }
+            token => self.in_table(token),
+        }
+    }
+
+    fn close_table_body(&mut self, token: Token) {
+        if !["tbody", "thead", "tfoot"]
+            .iter()
+            .any(|n| self.has_in_scope(n, Scope::Table))
+        {
+            self.unexpected_token(&token);
+            return;
+        }
+        self.clear_stack_to_context(&["tbody", "tfoot", "thead", "template", "html"]);
+        self.open_elements.pop();
+        self.reprocess_in(InsertionMode::InTable, token);
+    }
+
+    fn in_row(&mut self, token: Token) {
+        match token {
+            Token::StartTag(tag) if matches!(&tag.name[..], "th" | "td") => {
+                self.clear_stack_to_context(&["tr", "template", "html"]);
+                self.insert_element(tag);
+                self.mode = InsertionMode::InCell;
+                self.formatting.push(FormattingEntry::Marker);
+            }
+            Token::EndTag(ref tag) if tag.name == "tr" => {
+                if !self.close_row() {
+                    self.unexpected_end_tag(tag);
+                }
+            }
+            Token::StartTag(ref tag)
+                if matches!(
+                    &tag.name[..],
+                    "caption" | "col" | "colgroup" | "tbody" | "tfoot" | "thead" | "tr"
+                ) =>
+            {
+                if self.close_row() {
+                    self.process_token(token);
+                } else {
+                    self.unexpected_token(&token);
+                }
+            }
+            Token::EndTag(ref tag) if tag.name == "table" => {
+                if self.close_row() {
+                    self.process_token(token);
+                } else {
+                    self.unexpected_end_tag(tag);
+                }
+            }
+            Token::EndTag(ref tag) if matches!(&tag.name[..], "tbody" | "tfoot" | "thead") => {
+                if self.has_in_scope(&tag.name, Scope::Table) && self.close_row() {
+                    self.process_token(token);
+                } else {
+                    self.unexpected_end_tag(tag);
+                }
+            }
+            Token::EndTag(ref tag)
+                if matches!(
+                    &tag.name[..],
+                    "body" | "caption" | "col" | "colgroup" | "html" | "td" | "th"
+                ) =>
+            {
+                self.unexpected_end_tag(tag)
+            }
+            token => self.in_table(token),
+        }
+    }
+
+    fn close_row(&mut self) -> bool {
+        if !self.has_in_scope("tr", Scope::Table) {
+            return false;
+        }
+        self.clear_stack_to_context(&["tr", "template", "html"]);
+        self.open_elements.pop();
+        self.mode = InsertionMode::InTableBody;
+        true
+    }
+
+    fn in_cell(&mut self, token: Token) {
+        match token {
+            Token::EndTag(ref tag) if matches!(&tag.name[..], "td" | "th") => {
+                if !self.has_in_scope(&tag.name, Scope::Table) {
+                    self.unexpected_end_tag(tag);
+                    return;
+                }
+                self.generate_all_implied_end_tags();
+                self.close_element(tag);
+                self.clear_formatting_to_marker();
+                self.mode = InsertionMode::InRow;
+            }
+            Token::StartTag(ref tag)
+                if matches!(
+                    &tag.name[..],
+                    "caption"
+                        | "col"
+                        | "colgroup"
+                        | "tbody"
+                        | "td"
+                        | "tfoot"
+                        | "th"
+                        | "thead"
+                        | "tr"
+                ) =>
+            {
+                if self.has_in_scope("td", Scope::Table) || self.has_in_scope("th", Scope::Table) {
+                    self.close_cell();
+                    self.process_token(token);
+                } else {
+                    self.unexpected_start_tag(tag);
+                }
+            }
+            Token::EndTag(ref tag)
+                if matches!(
+                    &tag.name[..],
+                    "body" | "caption" | "col" | "colgroup" | "html"
+                ) =>
+            {
+                self.unexpected_end_tag(tag)
+            }
+            Token::EndTag(ref tag)
+                if matches!(&tag.name[..], "table" | "tbody" | "tfoot" | "thead" | "tr") =>
+            {
+                if self.has_in_scope(&tag.name, Scope::Table) {
+                    self.close_cell();
+                    self.process_token(token);
+                } else {
+                    self.unexpected_end_tag(tag);
+                }
+            }
+            token => self.in_body(token),
         }
     }
 
-    pub fn parse_nodes(&mut self) -> Vec<Node> {
-        let mut nodes = Vec::new();
-
-        while self.chars.peek().is_some() {
-            self.consume_while(char::is_whitespace);
-            if self.chars.peek().map_or(false, |c| *c == '<') {
-                self.chars.next();
-                if self.chars.peek().map_or(false, |c| *c == '/') {
-                    self.chars.next();
-                    self.consume_while(char::is_whitespace);
-
-                    let close_tag_name = self.consume_while(Self::is_valid_tag_name);
-
-                    self.consume_while(|x| x != '>');
-                    self.chars.next();
+    fn close_cell(&mut self) {
+        self.generate_all_implied_end_tags();
+        self.pop_until(|n| n == "td" || n == "th");
+        self.clear_formatting_to_marker();
+        self.mode = InsertionMode::InRow;
+    }
 
-                    self.node_q.push(close_tag_name);
-                    break;
-                } else if self.chars.peek().map_or(false, |c| *c == '!') {
-                    self.chars.next();
-                    nodes.push(self.parse_comment_node());
+    fn in_select(&mut self, token: Token) {
+        match token {
+            Token::Character('\0') => self.error(ErrorCode::UnexpectedNullCharacter),
+            Token::Character(c) => self.insert_character(c),
+            Token::Comment(c) => self.insert_comment(c),
+            Token::Doctype(_) => self.error(ErrorCode::UnexpectedDoctype),
+            Token::StartTag(ref tag) if tag.name == "html" => self.in_body(token),
+            Token::StartTag(tag) if tag.name == "option" => {
+                if self.tag_name(self.current_node()) == "option" {
+                    self.open_elements.pop();
+                }
+                self.insert_element(tag);
+            }
+            Token::StartTag(tag) if tag.name == "optgroup" || tag.name == "hr" => {
+                if self.tag_name(self.current_node()) == "option" {
+                    self.open_elements.pop();
+                }
+                if self.tag_name(self.current_node()) == "optgroup" {
+                    self.open_elements.pop();
+                }
+                self.insert_element(tag);
+            }
+            Token::EndTag(ref tag) if tag.name == "optgroup" => {
+                let len = self.open_elements.len();
+                if self.tag_name(self.current_node()) == "option"
+                    && len > 1
+                    && self.tag_name(self.open_elements[len - 2]) == "optgroup"
+                {
+                    self.open_elements.pop();
+                }
+                if self.tag_name(self.current_node()) == "optgroup" {
+                    self.open_elements.pop();
                 } else {
-                    let mut node = self.parse_node();
-                    let insert_index = nodes.len();
-                    if let NodeType::Element(ref e) = node.node_type {
-                        if self.node_q.is_empty() {
-                            let assumed_tag = self.node_q.remove(0);
-
-                            if e.tag_name != assumed_tag {
-                                nodes.append(&mut node.children);
-                                self.node_q.insert(0, assumed_tag);
-                            }
-                        }
-                    }
+                    self.unexpected_end_tag(tag);
+                }
+            }
+            Token::EndTag(ref tag) if tag.name == "option" => {
+                if self.tag_name(self.current_node()) == "option" {
+                    self.open_elements.pop();
+                } else {
+                    self.unexpected_end_tag(tag);
+                }
+            }
+            Token::StartTag(ref tag) if tag.name == "select" => {
+                self.unexpected_start_tag(tag);
+                self.close_select();
+            }
+            Token::EndTag(ref tag) if tag.name == "select" => {
+                if !self.close_select() {
+                    self.unexpected_end_tag(tag);
+                }
+            }
+            Token::StartTag(ref tag)
+                if matches!(&tag.name[..], "input" | "keygen" | "textarea") =>
+            {
+                self.unexpected_start_tag(tag);
+                if self.close_select() {
+                    self.process_token(token);
+                }
+            }
+            Token::StartTag(ref tag) if matches!(&tag.name[..], "script" | "template") => {
+                self.in_head(token)
+            }
+            Token::EndTag(ref tag) if tag.name == "template" => self.in_head(token),
+            Token::Eof => self.in_body(token),
+            token => self.unexpected_token(&token),
+        }
+    }
+
+    fn close_select(&mut self) -> bool {
+        if !self.has_in_scope("select", Scope::Select) {
+            return false;
+        }
+        self.pop_until(|n| n == "select");
+        self.reset_insertion_mode();
+        true
+    }
 
-                    nodes.insert(insert_index, node);
+    fn in_select_in_table(&mut self, token: Token) {
+        const TABLE_TAGS: [&str; 8] = [
+            "caption", "table", "tbody", "tfoot", "thead", "tr", "td", "th",
+        ];
+        match token {
+            Token::StartTag(ref tag) if TABLE_TAGS.contains(&&tag.name[..]) => {
+                self.unexpected_start_tag(tag);
+                self.pop_until(|n| n == "select");
+                self.reset_insertion_mode();
+                self.process_token(token);
+            }
+            Token::EndTag(ref tag) if TABLE_TAGS.contains(&&tag.name[..]) => {
+                self.unexpected_end_tag(tag);
+                if self.has_in_scope(&tag.name, Scope::Table) {
+                    self.pop_until(|n| n == "select");
+                    self.reset_insertion_mode();
+                    self.process_token(token);
                 }
-            } else {
-                nodes.push(self.parse_text_node());
             }
+            token => self.in_select(token),
         }
-        nodes
     }
 
-    fn parse_node(&mut self) -> Node {
-        let tagname = self.consume_while(Self::is_valid_tag_name);
-        let attributes = self.parse_attributes();
+    // https://html.spec.whatwg.org/multipage/parsing.html#parsing-main-intemplate
+    fn in_template(&mut self, token: Token) {
+        match token {
+            Token::Character(_) | Token::Comment(_) | Token::Doctype(_) => self.in_body(token),
+            Token::StartTag(ref tag)
+                if matches!(
+                    &tag.name[..],
+                    "base"
+                        | "basefont"
+                        | "bgsound"
+                        | "link"
+                        | "meta"
+                        | "noframes"
+                        | "script"
+                        | "style"
+                        | "template"
+                        | "title"
+                ) =>
+            {
+                self.in_head(token)
+            }
+            Token::EndTag(ref tag) if tag.name == "template" => self.in_head(token),
+            Token::StartTag(ref tag) => {
+                let mode = match &tag.name[..] {
+                    "caption" | "colgroup" | "tbody" | "tfoot" | "thead" => InsertionMode::InTable,
+                    "col" => InsertionMode::InColumnGroup,
+                    "tr" => InsertionMode::InTableBody,
+                    "td" | "th" => InsertionMode::InRow,
+                    _ => InsertionMode::InBody,
+                };
+                self.template_modes.pop();
+                self.template_modes.push(mode);
+                self.reprocess_in(mode, token);
+            }
+            Token::EndTag(ref tag) => self.unexpected_end_tag(tag),
+            Token::Eof => {
+                if !self.is_open("template") {
+                    self.stop_parsing();
+                    return;
+                }
+                self.error(ErrorCode::UnexpectedEof);
+                self.close_template();
+                self.process_in_mode(Token::Eof);
+            }
+        }
+    }
 
-        let elem = ElementData::new(tagname, attributes);
-        let children = self.parse_nodes();
-        Node::new(NodeType::Element(elem), children)
+    // pops the template element and what is open inside it, after its end tag or
+    // the end of the input
+    fn close_template(&mut self) {
+        self.pop_until(|n| n == "template");
+        self.clear_formatting_to_marker();
+        self.template_modes.pop();
+        self.reset_insertion_mode();
     }
 
-    fn parse_text_node(&mut self) -> Node {
-        let mut text_content = String::new();
+    fn after_body(&mut self, token: Token) {
+        match token {
+            Token::Character(c) if Self::is_whitespace(c) => self.in_body(token),
+            Token::Comment(c) => {
+                let html = self.open_elements[0];
+                self.append_comment_to(html, c);
+            }
+            Token::Doctype(_) => self.error(ErrorCode::UnexpectedDoctype),
+            Token::StartTag(ref tag) if tag.name == "html" => self.in_body(token),
+            Token::EndTag(ref tag) if tag.name == "html" => {
+                self.mode = InsertionMode::AfterAfterBody
+            }
+            Token::Eof => self.stop_parsing(),
+            token => {
+                self.unexpected_token(&token);
+                self.reprocess_in(InsertionMode::InBody, token);
+            }
+        }
+    }
 
-        while self.chars.peek().map_or(false, |c| *c != '<') {
-            let whitespace = self.consume_while(char::is_whitespace);
-            if !whitespace.is_empty() {
-                text_content.push(' ');
+    fn in_frameset(&mut self, token: Token) {
+        match token {
+            Token::Character(c) if Self::is_whitespace(c) => self.insert_character(c),
+            Token::Comment(c) => self.insert_comment(c),
+            Token::Doctype(_) => self.error(ErrorCode::UnexpectedDoctype),
+            Token::StartTag(ref tag) if tag.name == "html" => self.in_body(token),
+            Token::StartTag(tag) if tag.name == "frameset" => {
+                self.insert_element(tag);
+            }
+            Token::EndTag(ref tag) if tag.name == "frameset" => {
+                if self.open_elements.len() < 2 {
+                    self.unexpected_end_tag(tag);
+                    return;
+                }
+                self.open_elements.pop();
+                if self.tag_name(self.current_node()) != "frameset" {
+                    self.mode = InsertionMode::AfterFrameset;
+                }
             }
-            let text_part = self.consume_while(|x| !x.is_whitespace() && x != '<');
-            text_content.push_str(&text_part);
+            Token::StartTag(tag) if tag.name == "frame" => {
+                self.insert_element(tag);
+            }
+            Token::StartTag(ref tag) if tag.name == "noframes" => self.in_head(token),
+            Token::Eof => self.stop_parsing(),
+            token => self.unexpected_token(&token),
         }
-        Node::new(NodeType::Text(text_content), Vec::new())
     }
 
-    fn parse_comment_node(&mut self) -> Node {
-        let mut comment_content = String::new();
+    fn after_frameset(&mut self, token: Token) {
+        match token {
+            Token::Character(c) if Self::is_whitespace(c) => self.insert_character(c),
+            Token::Comment(c) => self.insert_comment(c),
+            Token::Doctype(_) => self.error(ErrorCode::UnexpectedDoctype),
+            Token::StartTag(ref tag) if tag.name == "html" => self.in_body(token),
+            Token::EndTag(ref tag) if tag.name == "html" => {
+                self.mode = InsertionMode::AfterAfterFrameset
+            }
+            Token::StartTag(ref tag) if tag.name == "noframes" => self.in_head(token),
+            Token::Eof => self.stop_parsing(),
+            token => self.unexpected_token(&token),
+        }
+    }
 
-        if self.chars.peek().map_or(false, |c| *c == '-') {
-            self.chars.next();
-            if self.chars.peek().map_or(false, |c| *c == '-') {
-                self.chars.next();
-            } else {
-                self.consume_while(|c| c != '>');
-                return Node::new(NodeType::Comment(comment_content), Vec::new());
+    fn after_after_body(&mut self, token: Token) {
+        match token {
+            Token::Comment(c) => self.append_comment_to(DOCUMENT, c),
+            Token::Doctype(_) => self.in_body(token),
+            Token::Character(c) if Self::is_whitespace(c) => self.in_body(token),
+            Token::StartTag(ref tag) if tag.name == "html" => self.in_body(token),
+            Token::Eof => self.stop_parsing(),
+            token => {
+                self.unexpected_token(&token);
+                self.reprocess_in(InsertionMode::InBody, token);
             }
-        } else {
-            self.consume_while(|c| c != '>');
-            return Node::new(NodeType::Comment(comment_content), Vec::new());
         }
+    }
 
-        if self.chars.peek().map_or(false, |c| *c == '>') {
-            self.chars.next();
-            return Node::new(NodeType::Comment(comment_content), Vec::new());
+    fn after_after_frameset(&mut self, token: Token) {
+        match token {
+            Token::Comment(c) => self.append_comment_to(DOCUMENT, c),
+            Token::Doctype(_) => self.in_body(token),
+            Token::Character(c) if Self::is_whitespace(c) => self.in_body(token),
+            Token::StartTag(ref tag) if tag.name == "html" => self.in_body(token),
+            Token::StartTag(ref tag) if tag.name == "noframes" => self.in_head(token),
+            Token::Eof => self.stop_parsing(),
+            token => self.unexpected_token(&token),
         }
+    }
 
-        if self.chars.peek().map_or(false, |c| *c == '-') {
-            self.chars.next();
-            if self.chars.peek().map_or(false, |c| *c == '>') {
-                self.chars.next();
-                return Node::new(NodeType::Comment(comment_content), Vec::new());
-            } else {
-                comment_content.push('-');
+    // https://html.spec.whatwg.org/multipage/parsing.html#parsing-main-inforeign
+    fn in_foreign_content(&mut self, token: Token) {
+        match token {
+            Token::Character('\0') => {
+                self.error(ErrorCode::UnexpectedNullCharacter);
+                self.insert_character('\u{FFFD}');
+            }
+            Token::Character(c) => {
+                self.insert_character(c);
+                if !Self::is_whitespace(c) {
+                    self.frameset_ok = false;
+                }
+            }
+            Token::Comment(c) => self.insert_comment(c),
+            Token::Doctype(_) => self.error(ErrorCode::UnexpectedDoctype),
+            Token::StartTag(tag)
+                if FOREIGN_BREAKOUT_ELEMENTS.contains(&&tag.name[..])
+                    || (tag.name == "font"
+                        && ["color", "face", "size"]
+                            .iter()
+                            .any(|a| tag.get_attribute(a).is_some())) =>
+            {
+                self.unexpected_start_tag(&tag);
+                while !(self
+                    .namespace(self.current_node())
+                    .is_none_or(|n| n == Namespace::Html)
+                    || self.is_mathml_text_integration_point(self.current_node())
+                    || self.is_html_integration_point(self.current_node()))
+                {
+                    self.open_elements.pop();
+                }
+                self.process_token(Token::StartTag(tag));
+            }
+            Token::StartTag(tag) => {
+                let namespace = self
+                    .namespace(self.current_node())
+                    .unwrap_or(Namespace::Html);
+                self.insert_foreign_element(tag, namespace);
+            }
+            Token::EndTag(tag) => {
+                // script elements are never run, so an svg </script> is like any other
+                let mut index = self.open_elements.len() - 1;
+                if !self
+                    .local_name(self.open_elements[index])
+                    .eq_ignore_ascii_case(&tag.name)
+                {
+                    self.unexpected_end_tag(&tag);
+                }
+                while index > 0 {
+                    let node = self.open_elements[index];
+                    if self.local_name(node).eq_ignore_ascii_case(&tag.name) {
+                        self.open_elements.truncate(index);
+                        return;
+                    }
+                    index -= 1;
+                    if self.namespace(self.open_elements[index]) == Some(Namespace::Html) {
+                        self.process_in_mode(Token::EndTag(tag));
+                        return;
+                    }
+                }
             }
+            Token::Eof => self.process_in_mode(Token::Eof),
         }
+    }
 
-        while self.chars.peek().is_some() {
-            comment_content.push_str(&self.consume_while(|c| c != '<' && c != '-'));
-            if self.chars.peek().map_or(false, |c| *c == '<') {
-                self.chars.next();
-                if self.chars.peek().map_or(false, |c| *c == '!') {
-                    self.chars.next();
-                    if self.chars.peek().map_or(false, |c| *c == '-') {
-                        self.chars.next();
-                        if self.chars.peek().map_or(false, |c| *c == '-') {
-                            self.consume_while(|c| c != '>');
+    fn stop_parsing(&mut self) {
+        self.open_elements.clear();
+        self.done = true;
+    }
 
-                            return Node::new(NodeType::Comment(String::from("")), Vec::new());
-                        } else {
-                            comment_content.push_str("<!-");
-                        }
-                    } else if self.chars.peek().map_or(false, |c| *c == ' ') {
-                        self.chars.next();
-                        if self.chars.peek().map_or(false, |c| *c == '-') {
-                            self.chars.next();
-                            if self.chars.peek().map_or(false, |c| *c == '-') {
-                                self.chars.next();
-                                if self.chars.peek().map_or(false, |c| *c == '-') {
-                                    self.chars.next();
-                                    if self.chars.peek().map_or(false, |c| *c == '>') {
-                                        self.chars.next();
-                                        return Node::new(
-                                            NodeType::Comment(String::from("")),
-                                            Vec::new(),
-                                        );
-                                    } else {
-                                        comment_content.push_str("<! --");
-                                    }
-                                } else {
-                                    comment_content.push_str("<! -");
-                                }
-                            } else {
-                                comment_content.push_str("<! ");
-                            }
-                        }
+    // https://html.spec.whatwg.org/multipage/parsing.html#reset-the-insertion-mode-appropriately
+    fn reset_insertion_mode(&mut self) {
+        for i in (0..self.open_elements.len()).rev() {
+            let last = i == 0;
+            let node = self.open_elements[i];
+            self.mode = match self.tag_name(node) {
+                "select" => {
+                    let in_table = self.open_elements[..i]
+                        .iter()
+                        .rev()
+                        .take_while(|&&n| self.tag_name(n) != "template")
+                        .any(|&n| self.tag_name(n) == "table");
+                    if in_table {
+                        InsertionMode::InSelectInTable
                     } else {
-                        comment_content.push_str("<!");
+                        InsertionMode::InSelect
                     }
-                } else {
-                    comment_content.push('<');
-                }
-            } else if self.chars.peek().map_or(false, |c| *c == '-') {
-                self.chars.next();
-                if self.chars.peek().map_or(false, |c| *c == '-') {
-                    self.chars.next();
-                    if self.chars.peek().map_or(false, |c| *c == '>') {
-                        self.chars.next();
-                        break;
-                    } else {
-                        comment_content.push_str("--");
+                }
+                "template" => match self.template_modes.last() {
+                    Some(&mode) => mode,
+                    None => continue,
+                },
+                "td" | "th" if !last => InsertionMode::InCell,
+                "tr" => InsertionMode::InRow,
+                "tbody" | "thead" | "tfoot" => InsertionMode::InTableBody,
+                "caption" => InsertionMode::InCaption,
+                "colgroup" => InsertionMode::InColumnGroup,
+                "table" => InsertionMode::InTable,
+                "head" if !last => InsertionMode::InHead,
+                "body" => InsertionMode::InBody,
+                "frameset" => InsertionMode::InFrameset,
+                "html" if self.head.is_none() => InsertionMode::BeforeHead,
+                "html" => InsertionMode::AfterHead,
+                _ if last => InsertionMode::InBody,
+                _ => continue,
+            };
+            return;
+        }
+        self.mode = InsertionMode::InBody;
+    }
+
+    // https://html.spec.whatwg.org/multipage/parsing.html#adoption-agency-algorithm
+    // returns false when the end tag should be handled like any other end tag
+    fn adoption_agency(&mut self, subject: &str) -> bool {
+        let current = self.current_node();
+        if self.tag_name(current) == subject && !self.is_in_formatting(current) {
+            self.open_elements.pop();
+            return true;
+        }
+
+        for _ in 0..8 {
+            let formatting_element = match self.formatting_element_after_marker(subject) {
+                Some(e) => e,
+                None => return false,
+            };
+            let fe_stack_index = match self
+                .open_elements
+                .iter()
+                .position(|&n| n == formatting_element)
+            {
+                Some(i) => i,
+                None => {
+                    self.error(ErrorCode::UnexpectedEndTag(subject.to_string()));
+                    self.remove_from_formatting(formatting_element);
+                    return true;
+                }
+            };
+            if !self.has_element_in_scope(formatting_element, Scope::Default) {
+                self.error(ErrorCode::UnexpectedEndTag(subject.to_string()));
+                return true;
+            }
+            if formatting_element != self.current_node() {
+                self.error(ErrorCode::UnexpectedEndTag(subject.to_string()));
+            }
+
+            let furthest_block = self.open_elements[fe_stack_index + 1..]
+                .iter()
+                .copied()
+                .find(|&n| self.is_special(n));
+            let furthest_block = match furthest_block {
+                Some(b) => b,
+                None => {
+                    self.open_elements.truncate(fe_stack_index);
+                    self.remove_from_formatting(formatting_element);
+                    return true;
+                }
+            };
+
+            let common_ancestor = self.open_elements[fe_stack_index - 1];
+            let mut bookmark = self.formatting_index(formatting_element).unwrap();
+            let mut node_index = self
+                .open_elements
+                .iter()
+                .position(|&n| n == furthest_block)
+                .unwrap();
+            let mut last_node = furthest_block;
+            let mut inner = 0;
+
+            loop {
+                inner += 1;
+                node_index -= 1;
+                let node = self.open_elements[node_index];
+                if node == formatting_element {
+                    break;
+                }
+                if inner > 3 && self.is_in_formatting(node) {
+                    self.remove_from_formatting(node);
+                }
+                let list_index = match self.formatting_index(node) {
+                    Some(i) => i,
+                    None => {
+                        self.open_elements.remove(node_index);
+                        continue;
                     }
-                } else {
-                    comment_content.push('-');
+                };
+
+                let tag = match self.formatting[list_index] {
+                    FormattingEntry::Element(_, ref tag) => tag.clone(),
+                    FormattingEntry::Marker => unreachable!(),
+                };
+                let new_node =
+                    self.create_node(ParseNodeData::Element(tag.clone(), Namespace::Html));
+                self.formatting[list_index] = FormattingEntry::Element(new_node, tag);
+                self.open_elements.replace(node_index, new_node);
+
+                if last_node == furthest_block {
+                    bookmark = list_index + 1;
                 }
+                self.detach(last_node);
+                self.append_child(new_node, last_node);
+                last_node = new_node;
+            }
+
+            self.detach(last_node);
+            let (parent, before) = self.appropriate_insertion_place(Some(common_ancestor));
+            self.insert_child(parent, before, last_node);
+
+            let fe_index = self.formatting_index(formatting_element).unwrap();
+            let tag = match self.formatting.remove(fe_index) {
+                FormattingEntry::Element(_, tag) => tag,
+                FormattingEntry::Marker => unreachable!(),
+            };
+            if fe_index < bookmark {
+                bookmark -= 1;
+            }
+            let new_element =
+                self.create_node(ParseNodeData::Element(tag.clone(), Namespace::Html));
+            let children = mem::take(&mut self.nodes[furthest_block].children);
+            for child in children {
+                self.nodes[child].parent = None;
+                self.append_child(new_element, child);
             }
+            self.append_child(furthest_block, new_element);
+            let bookmark = bookmark.min(self.formatting.len());
+            self.formatting
+                .insert(bookmark, FormattingEntry::Element(new_element, tag));
+
+            self.open_elements.retain(|n| n != formatting_element);
+            let fb_index = self
+                .open_elements
+                .iter()
+                .position(|&n| n == furthest_block)
+                .unwrap();
+            self.open_elements.insert(fb_index + 1, new_element);
         }
+        true
+    }
 
-        Node::new(NodeType::Comment(comment_content), Vec::new())
+    // https://html.spec.whatwg.org/multipage/parsing.html#reconstruct-the-active-formatting-elements
+    fn reconstruct_formatting(&mut self) {
+        let needs_reconstruction = |entry: &FormattingEntry, open: &[usize]| match entry {
+            FormattingEntry::Marker => false,
+            FormattingEntry::Element(id, _) => !open.contains(id),
+        };
+        let mut i = match self.formatting.last() {
+            Some(entry) if needs_reconstruction(entry, &self.open_elements) => {
+                self.formatting.len() - 1
+            }
+            _ => return,
+        };
+        while i > 0 && needs_reconstruction(&self.formatting[i - 1], &self.open_elements) {
+            i -= 1;
+        }
+        for j in i..self.formatting.len() {
+            let tag = match self.formatting[j] {
+                FormattingEntry::Element(_, ref tag) => tag.clone(),
+                FormattingEntry::Marker => unreachable!(),
+            };
+            let element = self.insert_element(tag.clone());
+            self.nodes[element].span = None;
+            self.formatting[j] = FormattingEntry::Element(element, tag);
+        }
     }
 
-    fn parse_attributes(&mut self) -> AttrMap {
-        let mut attributes = AttrMap::new();
+    fn insert_formatting_element(&mut self, tag: Tag) {
+        // Noah's Ark clause: at most three identical entries after the last marker
+        let mut identical = Vec::new();
+        for (i, entry) in self.formatting.iter().enumerate().rev() {
+            match entry {
+                FormattingEntry::Marker => break,
+                FormattingEntry::Element(_, t)
+                    if t.name == tag.name && Self::same_attributes(t, &tag) =>
+                {
+                    identical.push(i)
+                }
+                _ => {}
+            }
+        }
+        if identical.len() >= 3 {
+            self.formatting.remove(*identical.last().unwrap());
+        }
+        let element = self.insert_element(tag.clone());
+        self.formatting.push(FormattingEntry::Element(element, tag));
+    }
 
-        while self.chars.peek().map_or(false, |c| *c != '>') {
-            self.consume_while(char::is_whitespace);
-            let name = self.consume_while(Self::is_valid_attr_name).to_lowercase();
-            self.consume_while(char::is_whitespace);
+    fn same_attributes(a: &Tag, b: &Tag) -> bool {
+        a.attributes.len() == b.attributes.len()
+            && a.attributes
+                .iter()
+                .all(|(n, v)| b.get_attribute(n) == Some(v.as_str()))
+    }
 
-            let value = if self.chars.peek().map_or(false, |c| *c == '=') {
-                self.chars.next();
-                self.consume_while(char::is_whitespace);
-                let s = self.parse_attr_value();
-                self.consume_while(|c| !c.is_whitespace() && c != '>');
-                self.consume_while(char::is_whitespace);
-                s
-            } else {
-                "".to_string()
-            };
-            attributes.insert(name, value);
+    fn formatting_element_after_marker(&self, name: &str) -> Option<usize> {
+        for entry in self.formatting.iter().rev() {
+            match entry {
+                FormattingEntry::Marker => return None,
+                FormattingEntry::Element(id, tag) if tag.name == name => return Some(*id),
+                _ => {}
+            }
         }
-        self.chars.next();
+        None
+    }
 
-        attributes
+    fn formatting_index(&self, node: usize) -> Option<usize> {
+        self.formatting
+            .iter()
+            .position(|e| matches!(e, FormattingEntry::Element(id, _) if *id == node))
     }
 
-    fn parse_attr_value(&mut self) -> String {
-        self.consume_while(char::is_whitespace);
+    fn is_in_formatting(&self, node: usize) -> bool {
+        self.formatting_index(node).is_some()
+    }
+
+    fn remove_from_formatting(&mut self, node: usize) {
+        if let Some(i) = self.formatting_index(node) {
+            self.formatting.remove(i);
+        }
+    }
 
-        let result = match self.chars.peek() {
-            Some(&c) if c == '"' || c == '\'' => {
-                self.chars.next();
-                let ret = self.consume_while(|x| x != c);
-                self.chars.next();
-                ret
+    fn clear_formatting_to_marker(&mut self) {
+        while let Some(entry) = self.formatting.pop() {
+            if let FormattingEntry::Marker = entry {
+                break;
             }
-            _ => self.consume_while(Self::is_valid_attr_value),
-        };
+        }
+    }
+
+    fn close_p_in_button_scope(&mut self) {
+        if self.has_in_scope("p", Scope::Button) {
+            self.close_p();
+        }
+    }
+
+    fn close_p(&mut self) {
+        self.generate_implied_end_tags(Some("p"));
+        self.pop_until(|n| n == "p");
+    }
+
+    fn generate_implied_end_tags(&mut self, except: Option<&str>) {
+        loop {
+            let name = self.tag_name(self.current_node());
+            if Some(name) == except || !IMPLIED_END_TAGS.contains(&name) {
+                return;
+            }
+            self.open_elements.pop();
+        }
+    }
 
-        result
+    fn generate_all_implied_end_tags(&mut self) {
+        self.generate_implied_end_tags(None);
     }
 
-    fn consume_while<F>(&mut self, condition: F) -> String
+    fn pop_until<F>(&mut self, condition: F)
     where
-        F: Fn(char) -> bool,
+        F: Fn(&str) -> bool,
     {
-        let mut result = String::new();
-        while self.chars.peek().map_or(false, |c| condition(*c)) {
-            result.push(self.chars.next().unwrap());
+        while let Some(node) = self.open_elements.pop() {
+            if condition(self.tag_name(node)) {
+                return;
+            }
+        }
+    }
+
+    fn clear_stack_to_context(&mut self, context: &[&str]) {
+        while !context.contains(&self.tag_name(self.current_node())) {
+            self.open_elements.pop();
+        }
+    }
+
+    fn is_scope_boundary(&self, node: usize, scope: Scope) -> bool {
+        const DEFAULT: [&str; 9] = [
+            "applet", "caption", "html", "table", "td", "th", "marquee", "object", "template",
+        ];
+        let name = self.tag_name(node);
+        let default = DEFAULT.contains(&name) || self.is_foreign_boundary(node);
+        match scope {
+            Scope::Default => default,
+            Scope::ListItem => default || name == "ol" || name == "ul",
+            Scope::Button => default || name == "button",
+            Scope::Table => matches!(name, "html" | "table" | "template"),
+            Scope::Select => !matches!(name, "optgroup" | "option"),
+        }
+    }
+
+    fn has_in_scope(&self, target: &str, scope: Scope) -> bool {
+        for &node in self.open_elements.iter().rev() {
+            if self.tag_name(node) == target {
+                return true;
+            }
+            if self.is_scope_boundary(node, scope) {
+                return false;
+            }
+        }
+        false
+    }
+
+    fn has_element_in_scope(&self, target: usize, scope: Scope) -> bool {
+        for &node in self.open_elements.iter().rev() {
+            if node == target {
+                return true;
+            }
+            if self.is_scope_boundary(node, scope) {
+                return false;
+            }
         }
+        false
+    }
+
+    fn is_open(&self, name: &str) -> bool {
+        self.open_elements.iter().any(|&n| self.tag_name(n) == name)
+    }
 
-        result
+    fn is_special(&self, node: usize) -> bool {
+        SPECIAL_ELEMENTS.contains(&self.tag_name(node)) || self.is_foreign_boundary(node)
     }
-    fn is_valid_tag_name(ch: char) -> bool {
-        ch.is_digit(36)
+
+    // the svg and mathml elements that are both special and scope boundaries
+    fn is_foreign_boundary(&self, node: usize) -> bool {
+        match self.namespace(node) {
+            Some(Namespace::MathMl) => {
+                self.is_mathml_text_integration_point(node)
+                    || self.local_name(node) == "annotation-xml"
+            }
+            Some(Namespace::Svg) => {
+                matches!(self.local_name(node), "foreignObject" | "desc" | "title")
+            }
+            _ => false,
+        }
     }
 
-    fn is_valid_attr_name(c: char) -> bool {
-        !Self::is_excluded_name(c) && !Self::is_control(c)
+    // https://html.spec.whatwg.org/multipage/parsing.html#mathml-text-integration-point
+    fn is_mathml_text_integration_point(&self, node: usize) -> bool {
+        self.namespace(node) == Some(Namespace::MathMl)
+            && matches!(self.local_name(node), "mi" | "mo" | "mn" | "ms" | "mtext")
     }
 
-    fn is_control(ch: char) -> bool {
-        match ch {
-            '\u{007F}' => true,
-            c if ('\u{0000}'..='\u{001F}').contains(&c) => true,
-            c if ('\u{0080}'..='\u{009F}').contains(&c) => true,
+    // https://html.spec.whatwg.org/multipage/parsing.html#html-integration-point
+    fn is_html_integration_point(&self, node: usize) -> bool {
+        match self.nodes[node].data {
+            ParseNodeData::Element(ref tag, Namespace::MathMl) => {
+                tag.name == "annotation-xml"
+                    && tag.get_attribute("encoding").is_some_and(|e| {
+                        e.eq_ignore_ascii_case("text/html")
+                            || e.eq_ignore_ascii_case("application/xhtml+xml")
+                    })
+            }
+            ParseNodeData::Element(ref tag, Namespace::Svg) => {
+                matches!(&tag.name[..], "foreignObject" | "desc" | "title")
+            }
             _ => false,
         }
     }
 
-    fn is_excluded_name(c: char) -> bool {
-        matches!(c, ' ' | '"' | '\'' | '>' | '/' | '=')
+    fn current_node(&self) -> usize {
+        *self.open_elements.last().unwrap_or(&DOCUMENT)
+    }
+
+    // the name of an html element; foreign elements never match html tag names
+    // so they get an empty one, like text and comments
+    fn tag_name(&self, node: usize) -> &str {
+        match self.nodes[node].data {
+            ParseNodeData::Element(ref tag, Namespace::Html) => &tag.name,
+            _ => "",
+        }
+    }
+
+    // the name of any element, whatever its namespace
+    fn local_name(&self, node: usize) -> &str {
+        match self.nodes[node].data {
+            ParseNodeData::Element(ref tag, _) => &tag.name,
+            _ => "",
+        }
+    }
+
+    fn namespace(&self, node: usize) -> Option<Namespace> {
+        match self.nodes[node].data {
+            ParseNodeData::Element(_, namespace) => Some(namespace),
+            _ => None,
+        }
+    }
+
+    fn empty_tag(name: &str) -> Tag {
+        Tag {
+            name: name.to_string(),
+            ..Tag::default()
+        }
+    }
+
+    fn merge_attributes(&mut self, node: usize, tag: Tag) {
+        if let ParseNodeData::Element(ref mut existing, _) = self.nodes[node].data {
+            for (name, value) in tag.attributes {
+                if existing.get_attribute(&name).is_none() {
+                    existing.attributes.push((name, value));
+                }
+            }
+        }
+    }
+
+    fn create_node(&mut self, data: ParseNodeData) -> usize {
+        self.nodes.push(ParseNode {
+            data,
+            parent: None,
+            children: Vec::new(),
+            span: None,
+        });
+        self.nodes.len() - 1
+    }
+
+    fn append_child(&mut self, parent: usize, child: usize) {
+        self.insert_child(parent, None, child);
+    }
+
+    fn insert_child(&mut self, parent: usize, before: Option<usize>, child: usize) {
+        let children = &mut self.nodes[parent].children;
+        let index = before
+            .and_then(|b| children.iter().position(|&c| c == b))
+            .unwrap_or(children.len());
+        children.insert(index, child);
+        self.nodes[child].parent = Some(parent);
+    }
+
+    fn detach(&mut self, node: usize) {
+        if let Some(parent) = self.nodes[node].parent.take() {
+            self.nodes[parent].children.retain(|&c| c != node);
+        }
+    }
+
+    // https://html.spec.whatwg.org/multipage/parsing.html#appropriate-place-for-inserting-a-node
+    fn appropriate_insertion_place(
+        &self,
+        override_target: Option<usize>,
+    ) -> (usize, Option<usize>) {
+        let target = override_target.unwrap_or_else(|| self.current_node());
+        if self.foster_parenting
+            && matches!(
+                self.tag_name(target),
+                "table" | "tbody" | "tfoot" | "thead" | "tr"
+            )
+        {
+            let table_index = self
+                .open_elements
+                .iter()
+                .rposition(|&n| self.tag_name(n) == "table");
+            return match table_index {
+                None => (self.open_elements[0], None),
+                Some(i) => {
+                    let table = self.open_elements[i];
+                    match self.nodes[table].parent {
+                        Some(parent) => (parent, Some(table)),
+                        None => (self.open_elements[i - 1], None),
+                    }
+                }
+            };
+        }
+        (target, None)
+    }
+
+    fn insert_element(&mut self, tag: Tag) -> usize {
+        let (parent, before) = self.appropriate_insertion_place(None);
+        // a trailing "/>" only closes void elements, on anything else it is ignored
+        let is_void = dom::is_void_element(&tag.name);
+        if tag.self_closing && !is_void {
+            self.error(ErrorCode::NonVoidHtmlElementStartTagWithTrailingSolidus);
+        }
+        let element = self.create_node(ParseNodeData::Element(tag, Namespace::Html));
+        self.nodes[element].span = Some(self.current_span);
+        self.insert_child(parent, before, element);
+        if is_void {
+            self.open_elements.close(element);
+        } else {
+            self.open_elements.push(element);
+        }
+        element
+    }
+
+    // https://html.spec.whatwg.org/multipage/parsing.html#insert-a-foreign-element
+    // with the attribute and svg tag name adjustments done first
+    fn insert_foreign_element(&mut self, mut tag: Tag, namespace: Namespace) -> usize {
+        match namespace {
+            Namespace::Svg => {
+                if let Some(&(_, name)) = SVG_TAG_NAMES.iter().find(|(n, _)| *n == tag.name) {
+                    tag.name = name.to_string();
+                }
+                for (name, _) in &mut tag.attributes {
+                    if let Some(&(_, adjusted)) =
+                        SVG_ATTRIBUTE_NAMES.iter().find(|(n, _)| n == name)
+                    {
+                        *name = adjusted.to_string();
+                    }
+                }
+            }
+            Namespace::MathMl => {
+                for (name, _) in &mut tag.attributes {
+                    if name == "definitionurl" {
+                        *name = "definitionURL".to_string();
+                    }
+                }
+            }
+            _ => {}
+        }
+        let (parent, before) = self.appropriate_insertion_place(None);
+        // a trailing "/>" closes any foreign element straight away
+        let self_closing = tag.self_closing;
+        let element = self.create_node(ParseNodeData::Element(tag, namespace));
+        self.nodes[element].span = Some(self.current_span);
+        self.insert_child(parent, before, element);
+        if self_closing {
+            self.open_elements.close(element);
+        } else {
+            self.open_elements.push(element);
+        }
+        element
+    }
+
+    fn insert_implied_element(&mut self, name: &str) -> usize {
+        let element = self.insert_element(Self::empty_tag(name));
+        self.nodes[element].span = None;
+        element
+    }
+
+    fn insert_character(&mut self, c: char) {
+        let (parent, before) = self.appropriate_insertion_place(None);
+        if parent == DOCUMENT {
+            return;
+        }
+        let children = &self.nodes[parent].children;
+        let previous = match before.and_then(|b| children.iter().position(|&n| n == b)) {
+            Some(0) => None,
+            Some(i) => Some(children[i - 1]),
+            None => children.last().copied(),
+        };
+        if let Some(previous) = previous {
+            let node = &mut self.nodes[previous];
+            if let ParseNodeData::Text(ref mut text) = node.data {
+                text.push(c);
+                if let Some(ref mut span) = node.span {
+                    span.end = self.current_span.end;
+                }
+                return;
+            }
+        }
+        let text = self.create_node(ParseNodeData::Text(c.to_string()));
+        self.nodes[text].span = Some(self.current_span);
+        self.insert_child(parent, before, text);
+    }
+
+    fn insert_comment(&mut self, comment: String) {
+        let (parent, before) = self.appropriate_insertion_place(None);
+        let node = self.create_node(ParseNodeData::Comment(comment));
+        self.nodes[node].span = Some(self.current_span);
+        self.insert_child(parent, before, node);
+    }
+
+    fn append_comment_to(&mut self, parent: usize, comment: String) {
+        let node = self.create_node(ParseNodeData::Comment(comment));
+        self.nodes[node].span = Some(self.current_span);
+        self.append_child(parent, node);
+    }
+
+    fn is_conforming_doctype(doctype: &Doctype) -> bool {
+        doctype.name.as_deref() == Some("html")
+            && doctype.public_id.is_none()
+            && doctype
+                .system_id
+                .as_deref()
+                .is_none_or(|id| id == "about:legacy-compat")
+    }
+
+    // https://html.spec.whatwg.org/multipage/parsing.html#the-initial-insertion-mode
+    fn quirks_mode_for(doctype: &Doctype) -> QuirksMode {
+        const QUIRKY_PUBLIC_PREFIXES: [&str; 55] = [
+            "+//silmaril//dtd html pro v0r11 19970101//",
+            "-//as//dtd html 3.0 aswedit + extensions//",
+            "-//advasoft ltd//dtd html 3.0 aswedit + extensions//",
+            "-//ietf//dtd html 2.0 level 1//",
+            "-//ietf//dtd html 2.0 level 2//",
+            "-//ietf//dtd html 2.0 strict level 1//",
+            "-//ietf//dtd html 2.0 strict level 2//",
+            "-//ietf//dtd html 2.0 strict//",
+            "-//ietf//dtd html 2.0//",
+            "-//ietf//dtd html 2.1e//",
+            "-//ietf//dtd html 3.0//",
+            "-//ietf//dtd html 3.2 final//",
+            "-//ietf//dtd html 3.2//",
+            "-//ietf//dtd html 3//",
+            "-//ietf//dtd html level 0//",
+            "-//ietf//dtd html level 1//",
+            "-//ietf//dtd html level 2//",
+            "-//ietf//dtd html level 3//",
+            "-//ietf//dtd html strict level 0//",
+            "-//ietf//dtd html strict level 1//",
+            "-//ietf//dtd html strict level 2//",
+            "-//ietf//dtd html strict level 3//",
+            "-//ietf//dtd html strict//",
+            "-//ietf//dtd html//",
+            "-//metrius//dtd metrius presentational//",
+            "-//microsoft//dtd internet explorer 2.0 html strict//",
+            "-//microsoft//dtd internet explorer 2.0 html//",
+            "-//microsoft//dtd internet explorer 2.0 tables//",
+            "-//microsoft//dtd internet explorer 3.0 html strict//",
+            "-//microsoft//dtd internet explorer 3.0 html//",
+            "-//microsoft//dtd internet explorer 3.0 tables//",
+            "-//netscape comm. corp.//dtd html//",
+            "-//netscape comm. corp.//dtd strict html//",
+            "-//o'reilly and associates//dtd html 2.0//",
+            "-//o'reilly and associates//dtd html extended 1.0//",
+            "-//o'reilly and associates//dtd html extended relaxed 1.0//",
+            "-//sq//dtd html 2.0 hotmetal + extensions//",
+            "-//softquad software//dtd hotmetal pro 6.0::19990601::extensions to html 4.0//",
+            "-//softquad//dtd hotmetal pro 4.0::19971010::extensions to html 4.0//",
+            "-//spyglass//dtd html 2.0 extended//",
+            "-//sun microsystems corp.//dtd hotjava html//",
+            "-//sun microsystems corp.//dtd hotjava strict html//",
+            "-//w3c//dtd html 3 1995-03-24//",
+            "-//w3c//dtd html 3.2 draft//",
+            "-//w3c//dtd html 3.2 final//",
+            "-//w3c//dtd html 3.2//",
+            "-//w3c//dtd html 3.2s draft//",
+            "-//w3c//dtd html 4.0 frameset//",
+            "-//w3c//dtd html 4.0 transitional//",
+            "-//w3c//dtd html experimental 19960712//",
+            "-//w3c//dtd html experimental 970421//",
+            "-//w3c//dtd w3 html//",
+            "-//w3o//dtd w3 html 3.0//",
+            "-//webtechs//dtd mozilla html 2.0//",
+            "-//webtechs//dtd mozilla html//",
+        ];
+        const QUIRKY_PUBLIC_IDS: [&str; 3] = [
+            "-//w3o//dtd w3 html strict 3.0//en//",
+            "-/w3c/dtd html 4.0 transitional/en",
+            "html",
+        ];
+
+        if doctype.force_quirks || doctype.name.as_deref() != Some("html") {
+            return QuirksMode::Quirks;
+        }
+        let public_id = doctype
+            .public_id
+            .as_deref()
+            .unwrap_or("")
+            .to_ascii_lowercase();
+        let system_id = doctype.system_id.as_deref().map(|s| s.to_ascii_lowercase());
+        let frameset_or_transitional = public_id.starts_with("-//w3c//dtd html 4.01 frameset//")
+            || public_id.starts_with("-//w3c//dtd html 4.01 transitional//");
+
+        if QUIRKY_PUBLIC_IDS.contains(&&public_id[..])
+            || system_id.as_deref()
+                == Some("http://www.ibm.com/data/dtd/v11/ibmxhtml1-transitional.dtd")
+            || QUIRKY_PUBLIC_PREFIXES
+                .iter()
+                .any(|p| public_id.starts_with(p))
+            || (system_id.is_none() && frameset_or_transitional)
+        {
+            QuirksMode::Quirks
+        } else if public_id.starts_with("-//w3c//dtd xhtml 1.0 frameset//")
+            || public_id.starts_with("-//w3c//dtd xhtml 1.0 transitional//")
+            || (system_id.is_some() && frameset_or_transitional)
+        {
+            QuirksMode::LimitedQuirks
+        } else {
+            QuirksMode::NoQuirks
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::html_serializer::HtmlSerializer;
+
+    fn parse(html: &str) -> String {
+        HtmlSerializer::new().serialize_document(&HtmlParser::new(html).parse_document())
+    }
+
+    #[test]
+    fn template_in_head_keeps_its_contents() {
+        assert_eq!(
+            parse("<template><p>x</p></template><p>y"),
+            "<html><head><template><p>x</p></template></head><body><p>y</p></body></html>"
+        );
+    }
+
+    #[test]
+    fn template_takes_table_rows_and_cells() {
+        assert_eq!(
+            parse("<body><template><tr><td>x</template>"),
+            "<html><head></head><body><template><tr><td>x</td></tr></template></body></html>"
+        );
+        assert_eq!(
+            parse("<template><col><td>y</td></template>"),
+            "<html><head><template><col></template></head><body></body></html>"
+        );
+    }
+
+    #[test]
+    fn template_resets_the_insertion_mode_when_it_closes() {
+        assert_eq!(
+            parse("<table><template><td>a</td></template><tr><td>b</table>"),
+            "<html><head></head><body><table><template><td>a</td></template>\
+             <tbody><tr><td>b</td></tr></tbody></table></body></html>"
+        );
+    }
+
+    #[test]
+    fn template_left_open_closes_at_the_end_of_input() {
+        let (nodes, errors) = HtmlParser::new("<body><template><div>x").parse_with_errors();
+        assert_eq!(
+            HtmlSerializer::new().serialize_document(&Document::from_tree(Tree::from_nodes(nodes))),
+            "<html><head></head><body><template><div>x</div></template></body></html>"
+        );
+        assert!(errors.iter().any(|e| e.code == ErrorCode::UnexpectedEof));
+    }
+
+    #[test]
+    fn implied_elements_are_inserted() {
+        assert_eq!(parse("x"), "<html><head></head><body>x</body></html>");
+        assert_eq!(
+            parse("<title>t</title><p>a<p>b<ul><li>1<li>2</ul>"),
+            "<html><head><title>t</title></head><body><p>a</p><p>b</p>\
+             <ul><li>1</li><li>2</li></ul></body></html>"
+        );
+    }
+
+    #[test]
+    fn misnested_formatting_elements_are_reopened() {
+        assert_eq!(
+            parse("<b>1<p>2</b>3</p>"),
+            "<html><head></head><body><b>1</b><p><b>2</b>3</p></body></html>"
+        );
+        assert_eq!(
+            parse("<p><i>a<b>b</i>c</b>d"),
+            "<html><head></head><body><p><i>a<b>b</b></i><b>c</b>d</p></body></html>"
+        );
+    }
+
+    #[test]
+    fn content_misplaced_in_tables_is_foster_parented() {
+        assert_eq!(
+            parse("<table>x<tr><td>y</table>"),
+            "<html><head></head><body>x<table><tbody><tr><td>y</td></tr></tbody></table>\
+             </body></html>"
+        );
+    }
+
+    #[test]
+    fn doctypes_set_the_quirks_mode() {
+        let mut parser = HtmlParser::new("<p>");
+        parser.parse_nodes();
+        assert_eq!(parser.quirks_mode(), QuirksMode::Quirks);
+        let mut parser = HtmlParser::new("<!DOCTYPE html><p>");
+        parser.parse_nodes();
+        assert_eq!(parser.quirks_mode(), QuirksMode::NoQuirks);
+    }
+
+    #[test]
+    fn tree_construction_errors_are_reported() {
+        let (_, errors) = HtmlParser::new("<p></div>").parse_with_errors();
+        let codes: Vec<ErrorCode> = errors.into_iter().map(|e| e.code).collect();
+        assert_eq!(
+            codes,
+            [
+                ErrorCode::MissingDoctype,
+                ErrorCode::UnexpectedEndTag("div".to_owned())
+            ]
+        );
     }
 
-    fn is_valid_attr_value(c: char) -> bool {
-        !matches!(c, ' ' | '"' | '\'' | '=' | '<' | '>' | '`')
+    #[test]
+    fn foreign_names_are_adjusted() {
+        assert_eq!(
+            parse("<svg viewbox='0 0 1 1'><foreignobject><p>x</foreignobject></svg>"),
+            "<html><head></head><body><svg viewBox=\"0 0 1 1\"><foreignObject><p>x</p>\
+             </foreignObject></svg></body></html>"
+        );
     }
 }
@@ -0,0 +1,291 @@
+use crate::dom::{Document, ElementData, Node, NodeType};
+
+// https://html.spec.whatwg.org/multipage/parsing.html#serialising-html-fragments
+// children of these are written as they are; noscript is not among them
+// because the parser runs with scripting disabled
+const RAW_TEXT_ELEMENTS: [&str; 7] = [
+    "iframe",
+    "noembed",
+    "noframes",
+    "plaintext",
+    "script",
+    "style",
+    "xmp",
+];
+
+// the parser drops a newline straight after these start tags, so one is
+// written back in front of content that starts with a newline of its own
+const LEADING_NEWLINE_ELEMENTS: [&str; 3] = ["listing", "pre", "textarea"];
+
+/// Turns `dom::Node` trees back into html.
+pub struct HtmlSerializer {
+    // None writes the tree exactly; Some(n) indents nested elements by n spaces
+    indent: Option<usize>,
+}
+
+impl HtmlSerializer {
+    pub fn new() -> Self {
+        Self { indent: None }
+    }
+
+    /// Puts every element that contains other elements on lines of its own, indented
+    /// by `indent` spaces per level. Whitespace between elements is replaced in the
+    /// process, so the output is for reading rather than for round trips; raw text,
+    /// `pre` and `textarea` contents are still written untouched.
+    pub fn pretty(indent: usize) -> Self {
+        Self {
+            indent: Some(indent),
+        }
+    }
+
+    /// The spec's fragment serialization: the children of `node`, as `innerHTML` reads.
+    pub fn serialize_children(&self, node: &Node) -> String {
+        let mut out = String::new();
+        let parent = match node.node_type {
+            NodeType::Element(ref e) => Some(e),
+            _ => None,
+        };
+        self.write_children(&node.children, parent, 0, self.indent.is_some(), &mut out);
+        Self::finish(out)
+    }
+
+    /// `node` itself followed by its children, as `outerHTML` reads.
+    pub fn serialize_node(&self, node: &Node) -> String {
+        let mut out = String::new();
+        self.write_node(node, None, 0, self.indent.is_some(), &mut out);
+        Self::finish(out)
+    }
+
+    /// A whole document, starting with its doctype so that parsing the result
+    /// again gives the same quirks mode.
+    // https://html.spec.whatwg.org/multipage/parsing.html#serialising-html-fragments
+    pub fn serialize_document(&self, document: &Document) -> String {
+        let mut out = String::new();
+        if let Some(doctype) = document.doctype() {
+            out.push_str("<!DOCTYPE ");
+            out.push_str(doctype.name.as_deref().unwrap_or_default());
+            out.push('>');
+        }
+        let root = document.tree().to_node(document.root());
+        self.write_children(&root.children, None, 0, self.indent.is_some(), &mut out);
+        Self::finish(out)
+    }
+
+    // pretty output starts every top level node on a new line, the first included
+    fn finish(out: String) -> String {
+        match out.strip_prefix('\n') {
+            Some(rest) => rest.to_owned(),
+            None => out,
+        }
+    }
+
+    fn write_node(
+        &self,
+        node: &Node,
+        parent: Option<&ElementData>,
+        depth: usize,
+        pretty: bool,
+        out: &mut String,
+    ) {
+        match node.node_type {
+            NodeType::Element(ref e) => {
+                out.push('<');
+                out.push_str(&e.tag_name);
+                for attribute in e.attributes() {
+                    out.push(' ');
+                    out.push_str(&attribute.name);
+                    out.push_str("=\"");
+                    escape(&attribute.value, true, out);
+                    out.push('"');
+                }
+                out.push('>');
+                if e.is_void() {
+                    return;
+                }
+                if e.is_html() && LEADING_NEWLINE_ELEMENTS.contains(&&e.tag_name[..]) {
+                    if let Some(NodeType::Text(ref t)) = node.children.first().map(|c| &c.node_type)
+                    {
+                        if t.starts_with('\n') {
+                            out.push('\n');
+                        }
+                    }
+                }
+                let pretty = pretty && !Self::preserves_whitespace(e);
+                if self.write_children(&node.children, Some(e), depth + 1, pretty, out) {
+                    self.new_line(depth, out);
+                }
+                out.push_str("</");
+                out.push_str(&e.tag_name);
+                out.push('>');
+            }
+            NodeType::Text(ref t) => {
+                let raw = parent
+                    .is_some_and(|p| p.is_html() && RAW_TEXT_ELEMENTS.contains(&&p.tag_name[..]));
+                let t = if pretty { t.trim() } else { &t[..] };
+                if raw {
+                    out.push_str(t);
+                } else {
+                    escape(t, false, out);
+                }
+            }
+            NodeType::Comment(ref c) => {
+                out.push_str("<!--");
+                out.push_str(c);
+                out.push_str("-->");
+            }
+            NodeType::Document => {
+                self.write_children(&node.children, None, depth, pretty, out);
+            }
+        }
+    }
+
+    // returns whether the children went on lines of their own
+    fn write_children(
+        &self,
+        children: &[Node],
+        parent: Option<&ElementData>,
+        depth: usize,
+        pretty: bool,
+        out: &mut String,
+    ) -> bool {
+        // text-only content stays on the line of its element
+        let block = pretty
+            && children
+                .iter()
+                .any(|c| !matches!(c.node_type, NodeType::Text(_)));
+        for child in children {
+            if block {
+                if let NodeType::Text(ref t) = child.node_type {
+                    if t.trim().is_empty() {
+                        continue;
+                    }
+                }
+                self.new_line(depth, out);
+            }
+            self.write_node(child, parent, depth, pretty, out);
+        }
+        block
+    }
+
+    fn new_line(&self, depth: usize, out: &mut String) {
+        out.push('\n');
+        let width = self.indent.unwrap_or(0) * depth;
+        out.push_str(&" ".repeat(width));
+    }
+
+    fn preserves_whitespace(element: &ElementData) -> bool {
+        element.is_html()
+            && (LEADING_NEWLINE_ELEMENTS.contains(&&element.tag_name[..])
+                || RAW_TEXT_ELEMENTS.contains(&&element.tag_name[..]))
+    }
+}
+
+impl Default for HtmlSerializer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// https://html.spec.whatwg.org/multipage/parsing.html#escapingString
+fn escape(s: &str, attribute_mode: bool, out: &mut String) {
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '\u{A0}' => out.push_str("&nbsp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' if attribute_mode => out.push_str("&quot;"),
+            c => out.push(c),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::html_parser::{HtmlParser, QuirksMode};
+
+    // serializes `html` after parsing it, and checks that parsing the result gives
+    // the same document again
+    fn round_trip(html: &str) -> String {
+        let serializer = HtmlSerializer::new();
+        let out = serializer.serialize_document(&HtmlParser::new(html).parse_document());
+        let again = serializer.serialize_document(&HtmlParser::new(&out).parse_document());
+        assert_eq!(again, out, "{:?} does not round trip", html);
+        out
+    }
+
+    fn body(html: &str) -> String {
+        let out = round_trip(html);
+        let start = out.find("<body>").unwrap() + "<body>".len();
+        out[start..out.len() - "</body></html>".len()].to_owned()
+    }
+
+    #[test]
+    fn doctype_is_written_first() {
+        let out = round_trip("<!DOCTYPE html><p>x");
+        assert_eq!(
+            out,
+            "<!DOCTYPE html><html><head></head><body><p>x</p></body></html>"
+        );
+        let mut parser = HtmlParser::new(&out);
+        parser.parse_document();
+        assert_eq!(parser.quirks_mode(), QuirksMode::NoQuirks);
+        assert!(!round_trip("<p>x").starts_with("<!DOCTYPE"));
+    }
+
+    #[test]
+    fn text_and_attribute_values_are_escaped() {
+        assert_eq!(body("a &amp; b &lt; c&nbsp;d"), "a &amp; b &lt; c&nbsp;d");
+        assert_eq!(
+            body("<p title='&quot;&amp;&lt;&nbsp;'>\"</p>"),
+            "<p title=\"&quot;&amp;&lt;&nbsp;\">\"</p>"
+        );
+    }
+
+    #[test]
+    fn void_elements_have_no_end_tag() {
+        assert_eq!(
+            body("<br><img src=x><input type=text>"),
+            "<br><img src=\"x\"><input type=\"text\">"
+        );
+    }
+
+    #[test]
+    fn raw_text_is_written_unescaped() {
+        let out = round_trip("<script>if (a < b && c) {}</script><style>a > b {}</style>");
+        assert!(out.contains("<script>if (a < b && c) {}</script>"));
+        assert!(out.contains("<style>a > b {}</style>"));
+        assert_eq!(
+            body("<textarea>\n\nx</textarea>"),
+            "<textarea>\n\nx</textarea>"
+        );
+    }
+
+    #[test]
+    fn template_contents_are_written() {
+        assert_eq!(
+            body("<body><template><tr><td>x &amp; y</td></tr></template>"),
+            "<template><tr><td>x &amp; y</td></tr></template>"
+        );
+    }
+
+    #[test]
+    fn foreign_elements_keep_their_names() {
+        assert_eq!(
+            body("<svg viewBox='0 0 1 1'><foreignObject><p>x</p></foreignObject></svg>"),
+            "<svg viewBox=\"0 0 1 1\"><foreignObject><p>x</p></foreignObject></svg>"
+        );
+        assert_eq!(body("<math><mi>x</mi></math>"), "<math><mi>x</mi></math>");
+    }
+
+    #[test]
+    fn pretty_output_indents_nested_elements() {
+        let document = HtmlParser::new("<!DOCTYPE html><ul><li>a</li></ul>").parse_document();
+        assert_eq!(
+            HtmlSerializer::pretty(2).serialize_document(&document),
+            "<!DOCTYPE html>\n<html>\n  <head></head>\n  <body>\n    <ul>\n      <li>a</li>\n    \
+             </ul>\n  </body>\n</html>"
+        );
+    }
+}
@@ -0,0 +1,267 @@
+use crate::dom::{Document, Node, NodeId, Tree};
+use crate::encoding::{self, StreamDecoder, PRESCAN_LENGTH};
+use crate::html_parser::HtmlParser;
+use encoding_rs::Encoding;
+use std::io::{self, Read};
+use std::mem;
+
+const READ_SIZE: usize = 8192;
+
+/// Feeds an `HtmlParser` bytes as they arrive. The first `PRESCAN_LENGTH` bytes
+/// are held back until the encoding has been sniffed from them; after that each
+/// chunk is decoded as it comes, keeping characters split across chunks whole.
+pub struct HtmlStreamParser {
+    parser: HtmlParser,
+    content_type: Option<String>,
+    pending: Vec<u8>,
+    decoder: Option<StreamDecoder>,
+    finished: bool,
+}
+
+impl HtmlStreamParser {
+    pub fn new() -> Self {
+        Self {
+            parser: HtmlParser::streaming(),
+            content_type: None,
+            pending: Vec::new(),
+            decoder: None,
+            finished: false,
+        }
+    }
+
+    /// For a document served with this `Content-Type`, whose charset then takes
+    /// precedence over any `<meta>` in the document.
+    pub fn with_content_type(content_type: &str) -> Self {
+        let mut parser = Self::new();
+        parser.content_type = Some(content_type.to_owned());
+        parser
+    }
+
+    /// The encoding the document is decoded with, once enough of it has arrived to tell.
+    pub fn encoding(&self) -> Option<&'static Encoding> {
+        self.decoder.as_ref().map(StreamDecoder::encoding)
+    }
+
+    /// Parses `chunk` and returns every element it completed, by the id it has in
+    /// the finished document; see `HtmlParser::feed`.
+    pub fn feed(&mut self, chunk: &[u8]) -> Vec<NodeId> {
+        let text = match self.decoder {
+            Some(ref mut decoder) => decoder.decode(chunk, false),
+            None => {
+                self.pending.extend_from_slice(chunk);
+                if self.pending.len() < PRESCAN_LENGTH {
+                    return Vec::new();
+                }
+                self.decode_pending(false)
+            }
+        };
+        self.parser.feed(&text)
+    }
+
+    /// Ends the input and returns the elements still open at that point.
+    pub fn finish(&mut self) -> Vec<NodeId> {
+        if self.finished {
+            return Vec::new();
+        }
+        self.finished = true;
+        let text = match self.decoder {
+            Some(ref mut decoder) => decoder.decode(&[], true),
+            None => self.decode_pending(true),
+        };
+        let mut nodes = self.parser.feed(&text);
+        nodes.extend(self.parser.finish());
+        nodes
+    }
+
+    /// The whole document, as `HtmlParser::parse_nodes` would have returned it.
+    pub fn into_nodes(mut self) -> Vec<Node> {
+        self.finish();
+        self.parser.parse_nodes()
+    }
+
+    /// The whole document, as `HtmlParser::parse_document` would have returned it,
+    /// with the encoding it was decoded with.
+    pub fn into_document(mut self) -> Document {
+        self.finish();
+        let mut document = self.parser.parse_document();
+        if let Some(encoding) = self.encoding() {
+            document.set_encoding(encoding);
+        }
+        document
+    }
+
+    /// The whole document, as `HtmlParser::parse_tree` would have returned it.
+    pub fn into_tree(mut self) -> Tree {
+        self.finish();
+        self.parser.parse_tree()
+    }
+
+    fn decode_pending(&mut self, last: bool) -> String {
+        let pending = mem::take(&mut self.pending);
+        let encoding = encoding::sniff_html(&pending, self.content_type.as_deref());
+        let decoder = self.decoder.insert(StreamDecoder::new(encoding));
+        decoder.decode(&pending, last)
+    }
+}
+
+impl Default for HtmlStreamParser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Parses everything `reader` produces, passing each element to `on_node` as
+/// soon as it is complete, and returns the finished document, in which the ids
+/// passed to `on_node` stand for those elements. `content_type` is the header
+/// the document was served with, if any.
+pub fn parse_reader<R, F>(
+    mut reader: R,
+    content_type: Option<&str>,
+    mut on_node: F,
+) -> io::Result<Document>
+where
+    R: Read,
+    F: FnMut(NodeId),
+{
+    let mut parser = match content_type {
+        Some(content_type) => HtmlStreamParser::with_content_type(content_type),
+        None => HtmlStreamParser::new(),
+    };
+    let mut buffer = [0; READ_SIZE];
+    loop {
+        let read = match reader.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        parser
+            .feed(&buffer[..read])
+            .into_iter()
+            .for_each(&mut on_node);
+    }
+    parser.finish().into_iter().for_each(&mut on_node);
+    Ok(parser.into_document())
+}
+
+/// Like `parse_reader`, for input that is already split into byte slices.
+pub fn parse_chunks<I, B, F>(chunks: I, content_type: Option<&str>, mut on_node: F) -> Document
+where
+    I: IntoIterator<Item = B>,
+    B: AsRef<[u8]>,
+    F: FnMut(NodeId),
+{
+    let mut parser = match content_type {
+        Some(content_type) => HtmlStreamParser::with_content_type(content_type),
+        None => HtmlStreamParser::new(),
+    };
+    for chunk in chunks {
+        parser
+            .feed(chunk.as_ref())
+            .into_iter()
+            .for_each(&mut on_node);
+    }
+    parser.finish().into_iter().for_each(&mut on_node);
+    parser.into_document()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dom::NodeType;
+    use crate::html_serializer::HtmlSerializer;
+
+    const DOCUMENTS: [&str; 5] = [
+        "<!DOCTYPE html><title>t</title><p>a<p>b<img src=x>c",
+        "<table><tr><td>1<td>2</table><b>x<i>y</b>z</i>",
+        "<ul><li>one<li>two</ul><svg><circle/></svg><!-- done -->",
+        "<template><tr><td>x</template><textarea>\n<a></textarea>",
+        "<head><meta charset=utf-8></head><p>x</p><meta name=late>",
+    ];
+
+    fn serialize(document: &Document) -> String {
+        HtmlSerializer::new().serialize_document(document)
+    }
+
+    fn names(tree: &Tree, ids: &[NodeId]) -> Vec<String> {
+        ids.iter()
+            .map(|&id| {
+                tree.element(id)
+                    .map_or(String::new(), |e| e.tag_name.clone())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn chunked_input_builds_the_same_document() {
+        for html in DOCUMENTS {
+            let whole = serialize(&HtmlParser::new(html).parse_document());
+            for size in 1..8 {
+                let mut parser = HtmlParser::streaming();
+                for chunk in html.as_bytes().chunks(size) {
+                    parser.feed(std::str::from_utf8(chunk).unwrap());
+                }
+                parser.finish();
+                assert_eq!(
+                    serialize(&parser.parse_document()),
+                    whole,
+                    "{:?} in {}s",
+                    html,
+                    size
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn every_element_is_reported_once_it_closes() {
+        for html in DOCUMENTS {
+            let mut parser = HtmlParser::streaming();
+            let mut reported: Vec<NodeId> = Vec::new();
+            for chunk in html.as_bytes().chunks(3) {
+                reported.extend(parser.feed(std::str::from_utf8(chunk).unwrap()));
+            }
+            reported.extend(parser.finish());
+            let tree = parser.parse_tree();
+            for id in tree.pre_order(tree.document()) {
+                if let NodeType::Element(_) = tree.node_type(id) {
+                    assert!(
+                        reported.contains(&id),
+                        "{:?} misses {:?}",
+                        html,
+                        names(&tree, &[id])
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn elements_are_reported_as_they_close() {
+        let mut parser = HtmlParser::streaming();
+        let mut reported = parser.feed("<title>t</title><p>a");
+        // the tokenizer holds back the last few characters for lookahead
+        reported.extend(parser.feed(&format!("<p>b{}", " ".repeat(100))));
+        let before_end = reported.len();
+        reported.extend(parser.finish());
+        let tree = parser.parse_tree();
+        // the implied <head> closes when the <p> implies a <body>
+        assert_eq!(
+            names(&tree, &reported[..before_end]),
+            ["title", "head", "p"]
+        );
+        assert_eq!(names(&tree, &reported[before_end..]), ["p", "body", "html"]);
+    }
+
+    #[test]
+    fn reported_ids_name_elements_of_the_document() {
+        let html = format!("<p>{}</p><div><b>x</b></div>", "a".repeat(PRESCAN_LENGTH));
+        let mut reported = Vec::new();
+        let chunks = html.as_bytes().chunks(100);
+        let document = parse_chunks(chunks, None, |id| reported.push(id));
+        assert_eq!(
+            names(document.tree(), &reported),
+            ["head", "p", "b", "div", "body", "html"]
+        );
+    }
+}
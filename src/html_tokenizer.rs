@@ -0,0 +1,1745 @@
+use crate::html_entities;
+use crate::html_parser::ErrorCode;
+use crate::source::{ParseError, SourcePosition, Span};
+use std::collections::VecDeque;
+use std::mem;
+
+// the furthest any state looks past the current character: a named character
+// reference, "DOCTYPE", or the LF of a CRLF pair
+const LOOKAHEAD: usize = html_entities::LONGEST_ENTITY;
+
+// https://html.spec.whatwg.org/multipage/parsing.html#tokenization
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum State {
+    Data,
+    RcData,
+    RawText,
+    ScriptData,
+    PlainText,
+    TagOpen,
+    EndTagOpen,
+    TagName,
+    RcDataLessThanSign,
+    RcDataEndTagOpen,
+    RcDataEndTagName,
+    RawTextLessThanSign,
+    RawTextEndTagOpen,
+    RawTextEndTagName,
+    ScriptDataLessThanSign,
+    ScriptDataEndTagOpen,
+    ScriptDataEndTagName,
+    ScriptDataEscapeStart,
+    ScriptDataEscapeStartDash,
+    ScriptDataEscaped,
+    ScriptDataEscapedDash,
+    ScriptDataEscapedDashDash,
+    ScriptDataEscapedLessThanSign,
+    ScriptDataEscapedEndTagOpen,
+    ScriptDataEscapedEndTagName,
+    ScriptDataDoubleEscapeStart,
+    ScriptDataDoubleEscaped,
+    ScriptDataDoubleEscapedDash,
+    ScriptDataDoubleEscapedDashDash,
+    ScriptDataDoubleEscapedLessThanSign,
+    ScriptDataDoubleEscapeEnd,
+    BeforeAttributeName,
+    AttributeName,
+    AfterAttributeName,
+    BeforeAttributeValue,
+    AttributeValueDoubleQuoted,
+    AttributeValueSingleQuoted,
+    AttributeValueUnquoted,
+    AfterAttributeValueQuoted,
+    SelfClosingStartTag,
+    BogusComment,
+    MarkupDeclarationOpen,
+    CommentStart,
+    CommentStartDash,
+    Comment,
+    CommentLessThanSign,
+    CommentLessThanSignBang,
+    CommentLessThanSignBangDash,
+    CommentLessThanSignBangDashDash,
+    CommentEndDash,
+    CommentEnd,
+    CommentEndBang,
+    Doctype,
+    BeforeDoctypeName,
+    DoctypeName,
+    AfterDoctypeName,
+    AfterDoctypePublicKeyword,
+    BeforeDoctypePublicIdentifier,
+    DoctypePublicIdentifierDoubleQuoted,
+    DoctypePublicIdentifierSingleQuoted,
+    AfterDoctypePublicIdentifier,
+    BetweenDoctypePublicAndSystemIdentifiers,
+    AfterDoctypeSystemKeyword,
+    BeforeDoctypeSystemIdentifier,
+    DoctypeSystemIdentifierDoubleQuoted,
+    DoctypeSystemIdentifierSingleQuoted,
+    AfterDoctypeSystemIdentifier,
+    BogusDoctype,
+    CdataSection,
+    CdataSectionBracket,
+    CdataSectionEnd,
+    CharacterReference,
+    NamedCharacterReference,
+    AmbiguousAmpersand,
+    NumericCharacterReference,
+    HexadecimalCharacterReferenceStart,
+    DecimalCharacterReferenceStart,
+    HexadecimalCharacterReference,
+    DecimalCharacterReference,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Token {
+    Doctype(Doctype),
+    StartTag(Tag),
+    EndTag(Tag),
+    Comment(String),
+    Character(char),
+    Eof,
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Tag {
+    pub name: String,
+    pub self_closing: bool,
+    pub attributes: Vec<(String, String)>,
+}
+
+impl Tag {
+    pub fn get_attribute(&self, name: &str) -> Option<&str> {
+        self.attributes
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Doctype {
+    pub name: Option<String>,
+    pub public_id: Option<String>,
+    pub system_id: Option<String>,
+    pub force_quirks: bool,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum TagKind {
+    Start,
+    End,
+}
+
+pub struct Tokenizer {
+    input: Vec<char>,
+    pos: usize,
+    current: Option<char>,
+    reconsume: bool,
+    state: State,
+    return_state: State,
+    tag_kind: TagKind,
+    tag: Tag,
+    attr_name: String,
+    attr_value: String,
+    comment: String,
+    doctype: Doctype,
+    temp_buffer: String,
+    last_start_tag: Option<String>,
+    allow_cdata: bool,
+    char_ref_code: u32,
+    tokens: VecDeque<(Token, Span)>,
+    input_complete: bool,
+    finished: bool,
+    position: SourcePosition,
+    next_position: SourcePosition,
+    markup_start: SourcePosition,
+    attr_start: SourcePosition,
+    token_span: Span,
+    errors: Vec<ParseError<ErrorCode>>,
+}
+
+impl Tokenizer {
+    pub fn new(input: &str) -> Self {
+        let mut tokenizer = Self::streaming();
+        tokenizer.feed(input);
+        tokenizer.finish();
+        tokenizer
+    }
+
+    /// A tokenizer that starts without input; it is given chunks through `feed`
+    /// and told that no more will follow with `finish`.
+    pub fn streaming() -> Self {
+        Self {
+            input: Vec::new(),
+            pos: 0,
+            current: None,
+            reconsume: false,
+            state: State::Data,
+            return_state: State::Data,
+            tag_kind: TagKind::Start,
+            tag: Tag::default(),
+            attr_name: String::new(),
+            attr_value: String::new(),
+            comment: String::new(),
+            doctype: Doctype::default(),
+            temp_buffer: String::new(),
+            last_start_tag: None,
+            allow_cdata: false,
+            char_ref_code: 0,
+            tokens: VecDeque::new(),
+            input_complete: false,
+            finished: false,
+            position: SourcePosition::default(),
+            next_position: SourcePosition::default(),
+            markup_start: SourcePosition::default(),
+            attr_start: SourcePosition::default(),
+            token_span: Span::default(),
+            errors: Vec::new(),
+        }
+    }
+
+    /// Used by the tree builder to switch into RCDATA, RAWTEXT, script data or PLAINTEXT.
+    pub fn switch_to(&mut self, state: State) {
+        self.state = state;
+    }
+
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    /// Set by the tree builder while the adjusted current node is an svg or
+    /// mathml element, the only place `<![CDATA[` opens a CDATA section.
+    pub fn set_allow_cdata(&mut self, allow: bool) {
+        self.allow_cdata = allow;
+    }
+
+    pub fn feed(&mut self, chunk: &str) {
+        // drop what has been consumed, keeping the current character for lookahead
+        if self.pos > 4096 {
+            self.input.drain(..self.pos - 1);
+            self.pos = 1;
+        }
+        self.input.extend(chunk.chars());
+    }
+
+    pub fn finish(&mut self) {
+        self.input_complete = true;
+    }
+
+    /// Returns `None` when the input fed so far runs out before another token is
+    /// complete. Once `finish` has been called it always returns a token.
+    pub fn next_token(&mut self) -> Option<Token> {
+        while self.tokens.is_empty() {
+            if !self.input_complete && self.input.len() - self.pos <= LOOKAHEAD {
+                return None;
+            }
+            self.step();
+        }
+        let (token, span) = self.tokens.pop_front().unwrap();
+        self.token_span = span;
+        Some(token)
+    }
+
+    /// Where the token last returned by `next_token` came from in the input.
+    pub fn token_span(&self) -> Span {
+        self.token_span
+    }
+
+    pub fn take_errors(&mut self) -> Vec<ParseError<ErrorCode>> {
+        mem::take(&mut self.errors)
+    }
+
+    fn error(&mut self, code: ErrorCode) {
+        self.errors.push(ParseError::new(code, self.position));
+    }
+
+    /// CR and CRLF are normalized to LF here rather than up front so that
+    /// positions keep referring to the original input.
+    fn consume(&mut self) -> Option<char> {
+        if self.reconsume {
+            self.reconsume = false;
+            return self.current;
+        }
+        self.position = self.next_position;
+        let mut c = self.input.get(self.pos).copied();
+        if let Some(ch) = c {
+            self.pos += 1;
+            self.next_position.advance(ch);
+            if ch == '\r' {
+                if self.input.get(self.pos) == Some(&'\n') {
+                    self.pos += 1;
+                    self.next_position.offset += 1;
+                }
+                c = Some('\n');
+            }
+        }
+        self.current = c;
+        c
+    }
+
+    fn reconsume_in(&mut self, state: State) {
+        self.reconsume = true;
+        self.state = state;
+    }
+
+    /// Looks ahead from the character that was just consumed.
+    fn next_chars_are(&self, s: &str, case_insensitive: bool) -> bool {
+        if self.current.is_none() {
+            return false;
+        }
+        let start = self.pos - 1;
+        s.chars().enumerate().all(|(i, expected)| {
+            self.input.get(start + i).is_some_and(|c| {
+                *c == expected || (case_insensitive && c.eq_ignore_ascii_case(&expected))
+            })
+        })
+    }
+
+    /// Skips `n` characters past the one that was just consumed.
+    fn skip(&mut self, n: usize) {
+        for &c in &self.input[self.pos..self.pos + n] {
+            self.next_position.advance(c);
+        }
+        self.pos += n;
+    }
+
+    fn emit(&mut self, token: Token) {
+        let span = Span::new(self.position, self.next_position);
+        self.tokens.push_back((token, span));
+    }
+
+    fn emit_markup(&mut self, token: Token) {
+        let span = Span::new(self.markup_start, self.next_position);
+        self.tokens.push_back((token, span));
+    }
+
+    fn emit_char(&mut self, c: char) {
+        self.emit(Token::Character(c));
+    }
+
+    fn emit_str(&mut self, s: &str) {
+        for c in s.chars() {
+            self.emit_char(c);
+        }
+    }
+
+    fn emit_eof(&mut self) {
+        self.finished = true;
+        self.emit(Token::Eof);
+    }
+
+    fn new_tag(&mut self, kind: TagKind) {
+        self.tag_kind = kind;
+        self.tag = Tag::default();
+    }
+
+    fn new_attribute(&mut self) {
+        self.finish_attribute();
+        self.attr_start = self.position;
+        self.attr_name.clear();
+        self.attr_value.clear();
+    }
+
+    fn finish_attribute(&mut self) {
+        if self.attr_name.is_empty() {
+            return;
+        }
+        let name = mem::take(&mut self.attr_name);
+        let value = mem::take(&mut self.attr_value);
+        // when an attribute name is repeated, the first occurrence wins
+        if self.tag.attributes.iter().all(|(n, _)| *n != name) {
+            self.tag.attributes.push((name, value));
+        } else {
+            self.errors.push(ParseError::new(
+                ErrorCode::DuplicateAttribute,
+                self.attr_start,
+            ));
+        }
+    }
+
+    fn emit_tag(&mut self) {
+        self.finish_attribute();
+        let tag = mem::take(&mut self.tag);
+        match self.tag_kind {
+            TagKind::Start => {
+                self.last_start_tag = Some(tag.name.clone());
+                self.emit_markup(Token::StartTag(tag));
+            }
+            TagKind::End => {
+                if !tag.attributes.is_empty() {
+                    self.error(ErrorCode::EndTagWithAttributes);
+                }
+                if tag.self_closing {
+                    self.error(ErrorCode::EndTagWithTrailingSolidus);
+                }
+                self.emit_markup(Token::EndTag(tag));
+            }
+        }
+    }
+
+    fn emit_comment(&mut self) {
+        let comment = mem::take(&mut self.comment);
+        self.emit_markup(Token::Comment(comment));
+    }
+
+    fn emit_doctype(&mut self) {
+        let doctype = mem::take(&mut self.doctype);
+        self.emit_markup(Token::Doctype(doctype));
+    }
+
+    fn is_appropriate_end_tag(&self) -> bool {
+        self.tag_kind == TagKind::End
+            && self.last_start_tag.as_deref() == Some(self.tag.name.as_str())
+    }
+
+    fn is_attribute_return_state(&self) -> bool {
+        matches!(
+            self.return_state,
+            State::AttributeValueDoubleQuoted
+                | State::AttributeValueSingleQuoted
+                | State::AttributeValueUnquoted
+        )
+    }
+
+    fn flush_code_points(&mut self) {
+        let buffer = mem::take(&mut self.temp_buffer);
+        if self.is_attribute_return_state() {
+            self.attr_value.push_str(&buffer);
+        } else {
+            self.emit_str(&buffer);
+        }
+    }
+
+    fn in_text_state(&self) -> bool {
+        matches!(
+            self.state,
+            State::Data
+                | State::RcData
+                | State::RawText
+                | State::ScriptData
+                | State::ScriptDataEscaped
+                | State::ScriptDataEscapedDash
+                | State::ScriptDataEscapedDashDash
+        )
+    }
+
+    fn is_whitespace(c: char) -> bool {
+        matches!(c, '\t' | '\n' | '\u{000C}' | ' ')
+    }
+
+    fn step(&mut self) {
+        if self.finished {
+            self.emit(Token::Eof);
+            return;
+        }
+        let c = self.consume();
+        if c == Some('<') && self.in_text_state() {
+            self.markup_start = self.position;
+        }
+        match self.state {
+            State::Data => match c {
+                Some('&') => {
+                    self.return_state = State::Data;
+                    self.state = State::CharacterReference;
+                }
+                Some('<') => self.state = State::TagOpen,
+                Some('\0') => {
+                    self.error(ErrorCode::UnexpectedNullCharacter);
+                    self.emit_char('\0');
+                }
+                Some(c) => self.emit_char(c),
+                None => self.emit_eof(),
+            },
+            State::RcData => match c {
+                Some('&') => {
+                    self.return_state = State::RcData;
+                    self.state = State::CharacterReference;
+                }
+                Some('<') => self.state = State::RcDataLessThanSign,
+                Some('\0') => {
+                    self.error(ErrorCode::UnexpectedNullCharacter);
+                    self.emit_char('\u{FFFD}');
+                }
+                Some(c) => self.emit_char(c),
+                None => self.emit_eof(),
+            },
+            State::RawText => match c {
+                Some('<') => self.state = State::RawTextLessThanSign,
+                Some('\0') => {
+                    self.error(ErrorCode::UnexpectedNullCharacter);
+                    self.emit_char('\u{FFFD}');
+                }
+                Some(c) => self.emit_char(c),
+                None => self.emit_eof(),
+            },
+            State::ScriptData => match c {
+                Some('<') => self.state = State::ScriptDataLessThanSign,
+                Some('\0') => {
+                    self.error(ErrorCode::UnexpectedNullCharacter);
+                    self.emit_char('\u{FFFD}');
+                }
+                Some(c) => self.emit_char(c),
+                None => self.emit_eof(),
+            },
+            State::PlainText => match c {
+                Some('\0') => {
+                    self.error(ErrorCode::UnexpectedNullCharacter);
+                    self.emit_char('\u{FFFD}');
+                }
+                Some(c) => self.emit_char(c),
+                None => self.emit_eof(),
+            },
+            State::TagOpen => match c {
+                Some('!') => self.state = State::MarkupDeclarationOpen,
+                Some('/') => self.state = State::EndTagOpen,
+                Some(c) if c.is_ascii_alphabetic() => {
+                    self.new_tag(TagKind::Start);
+                    self.reconsume_in(State::TagName);
+                }
+                Some('?') => {
+                    self.error(ErrorCode::UnexpectedQuestionMarkInsteadOfTagName);
+                    self.comment.clear();
+                    self.reconsume_in(State::BogusComment);
+                }
+                None => {
+                    self.error(ErrorCode::EofBeforeTagName);
+                    self.emit_char('<');
+                    self.emit_eof();
+                }
+                Some(_) => {
+                    self.error(ErrorCode::InvalidFirstCharacterOfTagName);
+                    self.emit_char('<');
+                    self.reconsume_in(State::Data);
+                }
+            },
+            State::EndTagOpen => match c {
+                Some(c) if c.is_ascii_alphabetic() => {
+                    self.new_tag(TagKind::End);
+                    self.reconsume_in(State::TagName);
+                }
+                Some('>') => {
+                    self.error(ErrorCode::MissingEndTagName);
+                    self.state = State::Data;
+                }
+                None => {
+                    self.error(ErrorCode::EofBeforeTagName);
+                    self.emit_str("</");
+                    self.emit_eof();
+                }
+                Some(_) => {
+                    self.error(ErrorCode::InvalidFirstCharacterOfTagName);
+                    self.comment.clear();
+                    self.reconsume_in(State::BogusComment);
+                }
+            },
+            State::TagName => match c {
+                Some(c) if Self::is_whitespace(c) => self.state = State::BeforeAttributeName,
+                Some('/') => self.state = State::SelfClosingStartTag,
+                Some('>') => {
+                    self.state = State::Data;
+                    self.emit_tag();
+                }
+                Some('\0') => {
+                    self.error(ErrorCode::UnexpectedNullCharacter);
+                    self.tag.name.push('\u{FFFD}');
+                }
+                Some(c) => self.tag.name.push(c.to_ascii_lowercase()),
+                None => {
+                    self.error(ErrorCode::EofInTag);
+                    self.emit_eof();
+                }
+            },
+            State::RcDataLessThanSign => {
+                self.less_than_sign(c, State::RcDataEndTagOpen, State::RcData)
+            }
+            State::RcDataEndTagOpen => self.end_tag_open(c, State::RcDataEndTagName, State::RcData),
+            State::RcDataEndTagName => self.end_tag_name(c, State::RcData),
+            State::RawTextLessThanSign => {
+                self.less_than_sign(c, State::RawTextEndTagOpen, State::RawText)
+            }
+            State::RawTextEndTagOpen => {
+                self.end_tag_open(c, State::RawTextEndTagName, State::RawText)
+            }
+            State::RawTextEndTagName => self.end_tag_name(c, State::RawText),
+            State::ScriptDataLessThanSign => match c {
+                Some('/') => {
+                    self.temp_buffer.clear();
+                    self.state = State::ScriptDataEndTagOpen;
+                }
+                Some('!') => {
+                    self.state = State::ScriptDataEscapeStart;
+                    self.emit_str("<!");
+                }
+                _ => {
+                    self.emit_char('<');
+                    self.reconsume_in(State::ScriptData);
+                }
+            },
+            State::ScriptDataEndTagOpen => {
+                self.end_tag_open(c, State::ScriptDataEndTagName, State::ScriptData)
+            }
+            State::ScriptDataEndTagName => self.end_tag_name(c, State::ScriptData),
+            State::ScriptDataEscapeStart => match c {
+                Some('-') => {
+                    self.state = State::ScriptDataEscapeStartDash;
+                    self.emit_char('-');
+                }
+                _ => self.reconsume_in(State::ScriptData),
+            },
+            State::ScriptDataEscapeStartDash => match c {
+                Some('-') => {
+                    self.state = State::ScriptDataEscapedDashDash;
+                    self.emit_char('-');
+                }
+                _ => self.reconsume_in(State::ScriptData),
+            },
+            State::ScriptDataEscaped => match c {
+                Some('-') => {
+                    self.state = State::ScriptDataEscapedDash;
+                    self.emit_char('-');
+                }
+                Some('<') => self.state = State::ScriptDataEscapedLessThanSign,
+                Some('\0') => {
+                    self.error(ErrorCode::UnexpectedNullCharacter);
+                    self.emit_char('\u{FFFD}');
+                }
+                Some(c) => self.emit_char(c),
+                None => {
+                    self.error(ErrorCode::EofInScriptHtmlCommentLikeText);
+                    self.emit_eof();
+                }
+            },
+            State::ScriptDataEscapedDash => match c {
+                Some('-') => {
+                    self.state = State::ScriptDataEscapedDashDash;
+                    self.emit_char('-');
+                }
+                Some('<') => self.state = State::ScriptDataEscapedLessThanSign,
+                Some('\0') => {
+                    self.error(ErrorCode::UnexpectedNullCharacter);
+                    self.state = State::ScriptDataEscaped;
+                    self.emit_char('\u{FFFD}');
+                }
+                Some(c) => {
+                    self.state = State::ScriptDataEscaped;
+                    self.emit_char(c);
+                }
+                None => {
+                    self.error(ErrorCode::EofInScriptHtmlCommentLikeText);
+                    self.emit_eof();
+                }
+            },
+            State::ScriptDataEscapedDashDash => match c {
+                Some('-') => self.emit_char('-'),
+                Some('<') => self.state = State::ScriptDataEscapedLessThanSign,
+                Some('>') => {
+                    self.state = State::ScriptData;
+                    self.emit_char('>');
+                }
+                Some('\0') => {
+                    self.error(ErrorCode::UnexpectedNullCharacter);
+                    self.state = State::ScriptDataEscaped;
+                    self.emit_char('\u{FFFD}');
+                }
+                Some(c) => {
+                    self.state = State::ScriptDataEscaped;
+                    self.emit_char(c);
+                }
+                None => {
+                    self.error(ErrorCode::EofInScriptHtmlCommentLikeText);
+                    self.emit_eof();
+                }
+            },
+            State::ScriptDataEscapedLessThanSign => match c {
+                Some('/') => {
+                    self.temp_buffer.clear();
+                    self.state = State::ScriptDataEscapedEndTagOpen;
+                }
+                Some(c) if c.is_ascii_alphabetic() => {
+                    self.temp_buffer.clear();
+                    self.emit_char('<');
+                    self.reconsume_in(State::ScriptDataDoubleEscapeStart);
+                }
+                _ => {
+                    self.emit_char('<');
+                    self.reconsume_in(State::ScriptDataEscaped);
+                }
+            },
+            State::ScriptDataEscapedEndTagOpen => self.end_tag_open(
+                c,
+                State::ScriptDataEscapedEndTagName,
+                State::ScriptDataEscaped,
+            ),
+            State::ScriptDataEscapedEndTagName => self.end_tag_name(c, State::ScriptDataEscaped),
+            State::ScriptDataDoubleEscapeStart => match c {
+                Some(c) if Self::is_whitespace(c) || c == '/' || c == '>' => {
+                    self.state = if self.temp_buffer == "script" {
+                        State::ScriptDataDoubleEscaped
+                    } else {
+                        State::ScriptDataEscaped
+                    };
+                    self.emit_char(c);
+                }
+                Some(c) if c.is_ascii_alphabetic() => {
+                    self.temp_buffer.push(c.to_ascii_lowercase());
+                    self.emit_char(c);
+                }
+                _ => self.reconsume_in(State::ScriptDataEscaped),
+            },
+            State::ScriptDataDoubleEscaped => match c {
+                Some('-') => {
+                    self.state = State::ScriptDataDoubleEscapedDash;
+                    self.emit_char('-');
+                }
+                Some('<') => {
+                    self.state = State::ScriptDataDoubleEscapedLessThanSign;
+                    self.emit_char('<');
+                }
+                Some('\0') => {
+                    self.error(ErrorCode::UnexpectedNullCharacter);
+                    self.emit_char('\u{FFFD}');
+                }
+                Some(c) => self.emit_char(c),
+                None => {
+                    self.error(ErrorCode::EofInScriptHtmlCommentLikeText);
+                    self.emit_eof();
+                }
+            },
+            State::ScriptDataDoubleEscapedDash => match c {
+                Some('-') => {
+                    self.state = State::ScriptDataDoubleEscapedDashDash;
+                    self.emit_char('-');
+                }
+                Some('<') => {
+                    self.state = State::ScriptDataDoubleEscapedLessThanSign;
+                    self.emit_char('<');
+                }
+                Some('\0') => {
+                    self.error(ErrorCode::UnexpectedNullCharacter);
+                    self.state = State::ScriptDataDoubleEscaped;
+                    self.emit_char('\u{FFFD}');
+                }
+                Some(c) => {
+                    self.state = State::ScriptDataDoubleEscaped;
+                    self.emit_char(c);
+                }
+                None => {
+                    self.error(ErrorCode::EofInScriptHtmlCommentLikeText);
+                    self.emit_eof();
+                }
+            },
+            State::ScriptDataDoubleEscapedDashDash => match c {
+                Some('-') => self.emit_char('-'),
+                Some('<') => {
+                    self.state = State::ScriptDataDoubleEscapedLessThanSign;
+                    self.emit_char('<');
+                }
+                Some('>') => {
+                    self.state = State::ScriptData;
+                    self.emit_char('>');
+                }
+                Some('\0') => {
+                    self.error(ErrorCode::UnexpectedNullCharacter);
+                    self.state = State::ScriptDataDoubleEscaped;
+                    self.emit_char('\u{FFFD}');
+                }
+                Some(c) => {
+                    self.state = State::ScriptDataDoubleEscaped;
+                    self.emit_char(c);
+                }
+                None => {
+                    self.error(ErrorCode::EofInScriptHtmlCommentLikeText);
+                    self.emit_eof();
+                }
+            },
+            State::ScriptDataDoubleEscapedLessThanSign => match c {
+                Some('/') => {
+                    self.temp_buffer.clear();
+                    self.state = State::ScriptDataDoubleEscapeEnd;
+                    self.emit_char('/');
+                }
+                _ => self.reconsume_in(State::ScriptDataDoubleEscaped),
+            },
+            State::ScriptDataDoubleEscapeEnd => match c {
+                Some(c) if Self::is_whitespace(c) || c == '/' || c == '>' => {
+                    self.state = if self.temp_buffer == "script" {
+                        State::ScriptDataEscaped
+                    } else {
+                        State::ScriptDataDoubleEscaped
+                    };
+                    self.emit_char(c);
+                }
+                Some(c) if c.is_ascii_alphabetic() => {
+                    self.temp_buffer.push(c.to_ascii_lowercase());
+                    self.emit_char(c);
+                }
+                _ => self.reconsume_in(State::ScriptDataDoubleEscaped),
+            },
+            State::BeforeAttributeName => match c {
+                Some(c) if Self::is_whitespace(c) => {}
+                Some('/') | Some('>') | None => self.reconsume_in(State::AfterAttributeName),
+                Some('=') => {
+                    self.error(ErrorCode::UnexpectedEqualsSignBeforeAttributeName);
+                    self.new_attribute();
+                    self.attr_name.push('=');
+                    self.state = State::AttributeName;
+                }
+                Some(_) => {
+                    self.new_attribute();
+                    self.reconsume_in(State::AttributeName);
+                }
+            },
+            State::AttributeName => match c {
+                Some(c) if Self::is_whitespace(c) || c == '/' || c == '>' => {
+                    self.reconsume_in(State::AfterAttributeName)
+                }
+                None => self.reconsume_in(State::AfterAttributeName),
+                Some('=') => self.state = State::BeforeAttributeValue,
+                Some('\0') => {
+                    self.error(ErrorCode::UnexpectedNullCharacter);
+                    self.attr_name.push('\u{FFFD}');
+                }
+                Some(c) => {
+                    if matches!(c, '"' | '\'' | '<') {
+                        self.error(ErrorCode::UnexpectedCharacterInAttributeName);
+                    }
+                    self.attr_name.push(c.to_ascii_lowercase());
+                }
+            },
+            State::AfterAttributeName => match c {
+                Some(c) if Self::is_whitespace(c) => {}
+                Some('/') => self.state = State::SelfClosingStartTag,
+                Some('=') => self.state = State::BeforeAttributeValue,
+                Some('>') => {
+                    self.state = State::Data;
+                    self.emit_tag();
+                }
+                None => {
+                    self.error(ErrorCode::EofInTag);
+                    self.emit_eof();
+                }
+                Some(_) => {
+                    self.new_attribute();
+                    self.reconsume_in(State::AttributeName);
+                }
+            },
+            State::BeforeAttributeValue => match c {
+                Some(c) if Self::is_whitespace(c) => {}
+                Some('"') => self.state = State::AttributeValueDoubleQuoted,
+                Some('\'') => self.state = State::AttributeValueSingleQuoted,
+                Some('>') => {
+                    self.error(ErrorCode::MissingAttributeValue);
+                    self.state = State::Data;
+                    self.emit_tag();
+                }
+                _ => self.reconsume_in(State::AttributeValueUnquoted),
+            },
+            State::AttributeValueDoubleQuoted => self.attribute_value_quoted(c, '"'),
+            State::AttributeValueSingleQuoted => self.attribute_value_quoted(c, '\''),
+            State::AttributeValueUnquoted => match c {
+                Some(c) if Self::is_whitespace(c) => self.state = State::BeforeAttributeName,
+                Some('&') => {
+                    self.return_state = State::AttributeValueUnquoted;
+                    self.state = State::CharacterReference;
+                }
+                Some('>') => {
+                    self.state = State::Data;
+                    self.emit_tag();
+                }
+                Some('\0') => {
+                    self.error(ErrorCode::UnexpectedNullCharacter);
+                    self.attr_value.push('\u{FFFD}');
+                }
+                Some(c) => {
+                    if matches!(c, '"' | '\'' | '<' | '=' | '`') {
+                        self.error(ErrorCode::UnexpectedCharacterInUnquotedAttributeValue);
+                    }
+                    self.attr_value.push(c);
+                }
+                None => {
+                    self.error(ErrorCode::EofInTag);
+                    self.emit_eof();
+                }
+            },
+            State::AfterAttributeValueQuoted => match c {
+                Some(c) if Self::is_whitespace(c) => self.state = State::BeforeAttributeName,
+                Some('/') => self.state = State::SelfClosingStartTag,
+                Some('>') => {
+                    self.state = State::Data;
+                    self.emit_tag();
+                }
+                None => {
+                    self.error(ErrorCode::EofInTag);
+                    self.emit_eof();
+                }
+                Some(_) => {
+                    self.error(ErrorCode::MissingWhitespaceBetweenAttributes);
+                    self.reconsume_in(State::BeforeAttributeName);
+                }
+            },
+            State::SelfClosingStartTag => match c {
+                Some('>') => {
+                    self.tag.self_closing = true;
+                    self.state = State::Data;
+                    self.emit_tag();
+                }
+                None => {
+                    self.error(ErrorCode::EofInTag);
+                    self.emit_eof();
+                }
+                Some(_) => {
+                    self.error(ErrorCode::UnexpectedSolidusInTag);
+                    self.reconsume_in(State::BeforeAttributeName);
+                }
+            },
+            State::BogusComment => match c {
+                Some('>') => {
+                    self.state = State::Data;
+                    self.emit_comment();
+                }
+                None => {
+                    self.emit_comment();
+                    self.emit_eof();
+                }
+                Some('\0') => {
+                    self.error(ErrorCode::UnexpectedNullCharacter);
+                    self.comment.push('\u{FFFD}');
+                }
+                Some(c) => self.comment.push(c),
+            },
+            State::MarkupDeclarationOpen => {
+                if self.next_chars_are("--", false) {
+                    self.skip(1);
+                    self.comment.clear();
+                    self.state = State::CommentStart;
+                } else if self.next_chars_are("DOCTYPE", true) {
+                    self.skip(6);
+                    self.state = State::Doctype;
+                } else if self.next_chars_are("[CDATA[", false) {
+                    self.skip(6);
+                    if self.allow_cdata {
+                        self.state = State::CdataSection;
+                    } else {
+                        self.error(ErrorCode::CdataInHtmlContent);
+                        self.comment = "[CDATA[".to_string();
+                        self.state = State::BogusComment;
+                    }
+                } else {
+                    self.error(ErrorCode::IncorrectlyOpenedComment);
+                    self.comment.clear();
+                    self.reconsume_in(State::BogusComment);
+                }
+            }
+            State::CommentStart => match c {
+                Some('-') => self.state = State::CommentStartDash,
+                Some('>') => {
+                    self.error(ErrorCode::AbruptClosingOfEmptyComment);
+                    self.state = State::Data;
+                    self.emit_comment();
+                }
+                _ => self.reconsume_in(State::Comment),
+            },
+            State::CommentStartDash => match c {
+                Some('-') => self.state = State::CommentEnd,
+                Some('>') => {
+                    self.error(ErrorCode::AbruptClosingOfEmptyComment);
+                    self.state = State::Data;
+                    self.emit_comment();
+                }
+                None => {
+                    self.error(ErrorCode::EofInComment);
+                    self.emit_comment();
+                    self.emit_eof();
+                }
+                Some(_) => {
+                    self.comment.push('-');
+                    self.reconsume_in(State::Comment);
+                }
+            },
+            State::Comment => match c {
+                Some('<') => {
+                    self.comment.push('<');
+                    self.state = State::CommentLessThanSign;
+                }
+                Some('-') => self.state = State::CommentEndDash,
+                Some('\0') => {
+                    self.error(ErrorCode::UnexpectedNullCharacter);
+                    self.comment.push('\u{FFFD}');
+                }
+                Some(c) => self.comment.push(c),
+                None => {
+                    self.error(ErrorCode::EofInComment);
+                    self.emit_comment();
+                    self.emit_eof();
+                }
+            },
+            State::CommentLessThanSign => match c {
+                Some('!') => {
+                    self.comment.push('!');
+                    self.state = State::CommentLessThanSignBang;
+                }
+                Some('<') => self.comment.push('<'),
+                _ => self.reconsume_in(State::Comment),
+            },
+            State::CommentLessThanSignBang => match c {
+                Some('-') => self.state = State::CommentLessThanSignBangDash,
+                _ => self.reconsume_in(State::Comment),
+            },
+            State::CommentLessThanSignBangDash => match c {
+                Some('-') => self.state = State::CommentLessThanSignBangDashDash,
+                _ => self.reconsume_in(State::CommentEndDash),
+            },
+            State::CommentLessThanSignBangDashDash => {
+                if !matches!(c, Some('>') | None) {
+                    self.error(ErrorCode::NestedComment);
+                }
+                self.reconsume_in(State::CommentEnd);
+            }
+            State::CommentEndDash => match c {
+                Some('-') => self.state = State::CommentEnd,
+                None => {
+                    self.error(ErrorCode::EofInComment);
+                    self.emit_comment();
+                    self.emit_eof();
+                }
+                Some(_) => {
+                    self.comment.push('-');
+                    self.reconsume_in(State::Comment);
+                }
+            },
+            State::CommentEnd => match c {
+                Some('>') => {
+                    self.state = State::Data;
+                    self.emit_comment();
+                }
+                Some('!') => self.state = State::CommentEndBang,
+                Some('-') => self.comment.push('-'),
+                None => {
+                    self.error(ErrorCode::EofInComment);
+                    self.emit_comment();
+                    self.emit_eof();
+                }
+                Some(_) => {
+                    self.comment.push_str("--");
+                    self.reconsume_in(State::Comment);
+                }
+            },
+            State::CommentEndBang => match c {
+                Some('-') => {
+                    self.comment.push_str("--!");
+                    self.state = State::CommentEndDash;
+                }
+                Some('>') => {
+                    self.error(ErrorCode::IncorrectlyClosedComment);
+                    self.state = State::Data;
+                    self.emit_comment();
+                }
+                None => {
+                    self.error(ErrorCode::EofInComment);
+                    self.emit_comment();
+                    self.emit_eof();
+                }
+                Some(_) => {
+                    self.comment.push_str("--!");
+                    self.reconsume_in(State::Comment);
+                }
+            },
+            State::Doctype => match c {
+                Some(c) if Self::is_whitespace(c) => self.state = State::BeforeDoctypeName,
+                Some('>') => self.reconsume_in(State::BeforeDoctypeName),
+                None => {
+                    self.error(ErrorCode::EofInDoctype);
+                    self.doctype = Doctype {
+                        force_quirks: true,
+                        ..Doctype::default()
+                    };
+                    self.emit_doctype();
+                    self.emit_eof();
+                }
+                Some(_) => {
+                    self.error(ErrorCode::MissingWhitespaceBeforeDoctypeName);
+                    self.reconsume_in(State::BeforeDoctypeName);
+                }
+            },
+            State::BeforeDoctypeName => match c {
+                Some(c) if Self::is_whitespace(c) => {}
+                Some('>') => {
+                    self.error(ErrorCode::MissingDoctypeName);
+                    self.doctype = Doctype {
+                        force_quirks: true,
+                        ..Doctype::default()
+                    };
+                    self.state = State::Data;
+                    self.emit_doctype();
+                }
+                None => {
+                    self.error(ErrorCode::EofInDoctype);
+                    self.doctype = Doctype {
+                        force_quirks: true,
+                        ..Doctype::default()
+                    };
+                    self.emit_doctype();
+                    self.emit_eof();
+                }
+                Some(c) => {
+                    let c = if c == '\0' {
+                        self.error(ErrorCode::UnexpectedNullCharacter);
+                        '\u{FFFD}'
+                    } else {
+                        c.to_ascii_lowercase()
+                    };
+                    self.doctype = Doctype {
+                        name: Some(c.to_string()),
+                        ..Doctype::default()
+                    };
+                    self.state = State::DoctypeName;
+                }
+            },
+            State::DoctypeName => match c {
+                Some(c) if Self::is_whitespace(c) => self.state = State::AfterDoctypeName,
+                Some('>') => {
+                    self.state = State::Data;
+                    self.emit_doctype();
+                }
+                None => self.eof_in_doctype(),
+                Some(c) => {
+                    let c = if c == '\0' {
+                        self.error(ErrorCode::UnexpectedNullCharacter);
+                        '\u{FFFD}'
+                    } else {
+                        c.to_ascii_lowercase()
+                    };
+                    self.doctype.name.get_or_insert_with(String::new).push(c);
+                }
+            },
+            State::AfterDoctypeName => match c {
+                Some(c) if Self::is_whitespace(c) => {}
+                Some('>') => {
+                    self.state = State::Data;
+                    self.emit_doctype();
+                }
+                None => self.eof_in_doctype(),
+                Some(_) => {
+                    if self.next_chars_are("PUBLIC", true) {
+                        self.skip(5);
+                        self.state = State::AfterDoctypePublicKeyword;
+                    } else if self.next_chars_are("SYSTEM", true) {
+                        self.skip(5);
+                        self.state = State::AfterDoctypeSystemKeyword;
+                    } else {
+                        self.error(ErrorCode::InvalidCharacterSequenceAfterDoctypeName);
+                        self.doctype.force_quirks = true;
+                        self.reconsume_in(State::BogusDoctype);
+                    }
+                }
+            },
+            State::AfterDoctypePublicKeyword | State::BeforeDoctypePublicIdentifier => match c {
+                Some(c) if Self::is_whitespace(c) => {
+                    self.state = State::BeforeDoctypePublicIdentifier
+                }
+                Some(c @ '"') | Some(c @ '\'') => {
+                    if self.state == State::AfterDoctypePublicKeyword {
+                        self.error(ErrorCode::MissingWhitespaceAfterDoctypePublicKeyword);
+                    }
+                    self.doctype.public_id = Some(String::new());
+                    self.state = if c == '"' {
+                        State::DoctypePublicIdentifierDoubleQuoted
+                    } else {
+                        State::DoctypePublicIdentifierSingleQuoted
+                    };
+                }
+                Some('>') => {
+                    self.error(ErrorCode::MissingDoctypePublicIdentifier);
+                    self.doctype.force_quirks = true;
+                    self.state = State::Data;
+                    self.emit_doctype();
+                }
+                None => self.eof_in_doctype(),
+                Some(_) => {
+                    self.error(ErrorCode::MissingQuoteBeforeDoctypePublicIdentifier);
+                    self.doctype.force_quirks = true;
+                    self.reconsume_in(State::BogusDoctype);
+                }
+            },
+            State::DoctypePublicIdentifierDoubleQuoted => self.doctype_identifier(c, '"', true),
+            State::DoctypePublicIdentifierSingleQuoted => self.doctype_identifier(c, '\'', true),
+            State::AfterDoctypePublicIdentifier
+            | State::BetweenDoctypePublicAndSystemIdentifiers => match c {
+                Some(c) if Self::is_whitespace(c) => {
+                    self.state = State::BetweenDoctypePublicAndSystemIdentifiers
+                }
+                Some('>') => {
+                    self.state = State::Data;
+                    self.emit_doctype();
+                }
+                Some(c @ '"') | Some(c @ '\'') => {
+                    if self.state == State::AfterDoctypePublicIdentifier {
+                        self.error(
+                            ErrorCode::MissingWhitespaceBetweenDoctypePublicAndSystemIdentifiers,
+                        );
+                    }
+                    self.start_system_identifier(c);
+                }
+                None => self.eof_in_doctype(),
+                Some(_) => {
+                    self.error(ErrorCode::MissingQuoteBeforeDoctypeSystemIdentifier);
+                    self.doctype.force_quirks = true;
+                    self.reconsume_in(State::BogusDoctype);
+                }
+            },
+            State::AfterDoctypeSystemKeyword | State::BeforeDoctypeSystemIdentifier => match c {
+                Some(c) if Self::is_whitespace(c) => {
+                    self.state = State::BeforeDoctypeSystemIdentifier
+                }
+                Some(c @ '"') | Some(c @ '\'') => {
+                    if self.state == State::AfterDoctypeSystemKeyword {
+                        self.error(ErrorCode::MissingWhitespaceAfterDoctypeSystemKeyword);
+                    }
+                    self.start_system_identifier(c);
+                }
+                Some('>') => {
+                    self.error(ErrorCode::MissingDoctypeSystemIdentifier);
+                    self.doctype.force_quirks = true;
+                    self.state = State::Data;
+                    self.emit_doctype();
+                }
+                None => self.eof_in_doctype(),
+                Some(_) => {
+                    self.error(ErrorCode::MissingQuoteBeforeDoctypeSystemIdentifier);
+                    self.doctype.force_quirks = true;
+                    self.reconsume_in(State::BogusDoctype);
+                }
+            },
+            State::DoctypeSystemIdentifierDoubleQuoted => self.doctype_identifier(c, '"', false),
+            State::DoctypeSystemIdentifierSingleQuoted => self.doctype_identifier(c, '\'', false),
+            State::AfterDoctypeSystemIdentifier => match c {
+                Some(c) if Self::is_whitespace(c) => {}
+                Some('>') => {
+                    self.state = State::Data;
+                    self.emit_doctype();
+                }
+                None => self.eof_in_doctype(),
+                Some(_) => {
+                    self.error(ErrorCode::UnexpectedCharacterAfterDoctypeSystemIdentifier);
+                    self.reconsume_in(State::BogusDoctype);
+                }
+            },
+            State::BogusDoctype => match c {
+                Some('>') => {
+                    self.state = State::Data;
+                    self.emit_doctype();
+                }
+                None => {
+                    self.emit_doctype();
+                    self.emit_eof();
+                }
+                Some(_) => {}
+            },
+            State::CdataSection => match c {
+                Some(']') => self.state = State::CdataSectionBracket,
+                Some(c) => self.emit_char(c),
+                None => {
+                    self.error(ErrorCode::EofInCdata);
+                    self.emit_eof();
+                }
+            },
+            State::CdataSectionBracket => match c {
+                Some(']') => self.state = State::CdataSectionEnd,
+                _ => {
+                    self.emit_char(']');
+                    self.reconsume_in(State::CdataSection);
+                }
+            },
+            State::CdataSectionEnd => match c {
+                Some(']') => self.emit_char(']'),
+                Some('>') => self.state = State::Data,
+                _ => {
+                    self.emit_str("]]");
+                    self.reconsume_in(State::CdataSection);
+                }
+            },
+            State::CharacterReference => {
+                self.temp_buffer.clear();
+                self.temp_buffer.push('&');
+                match c {
+                    Some(c) if c.is_ascii_alphanumeric() => {
+                        self.reconsume_in(State::NamedCharacterReference)
+                    }
+                    Some('#') => {
+                        self.temp_buffer.push('#');
+                        self.state = State::NumericCharacterReference;
+                    }
+                    _ => {
+                        self.flush_code_points();
+                        let return_state = self.return_state;
+                        self.reconsume_in(return_state);
+                    }
+                }
+            }
+            State::NamedCharacterReference => self.named_character_reference(),
+            State::AmbiguousAmpersand => match c {
+                Some(c) if c.is_ascii_alphanumeric() => {
+                    if self.is_attribute_return_state() {
+                        self.attr_value.push(c);
+                    } else {
+                        self.emit_char(c);
+                    }
+                }
+                _ => {
+                    if c == Some(';') {
+                        self.error(ErrorCode::UnknownNamedCharacterReference);
+                    }
+                    let return_state = self.return_state;
+                    self.reconsume_in(return_state);
+                }
+            },
+            State::NumericCharacterReference => {
+                self.char_ref_code = 0;
+                match c {
+                    Some(c @ 'x') | Some(c @ 'X') => {
+                        self.temp_buffer.push(c);
+                        self.state = State::HexadecimalCharacterReferenceStart;
+                    }
+                    _ => self.reconsume_in(State::DecimalCharacterReferenceStart),
+                }
+            }
+            State::HexadecimalCharacterReferenceStart => match c {
+                Some(c) if c.is_ascii_hexdigit() => {
+                    self.reconsume_in(State::HexadecimalCharacterReference)
+                }
+                _ => {
+                    self.error(ErrorCode::AbsenceOfDigitsInNumericCharacterReference);
+                    self.flush_code_points();
+                    let return_state = self.return_state;
+                    self.reconsume_in(return_state);
+                }
+            },
+            State::DecimalCharacterReferenceStart => match c {
+                Some(c) if c.is_ascii_digit() => {
+                    self.reconsume_in(State::DecimalCharacterReference)
+                }
+                _ => {
+                    self.error(ErrorCode::AbsenceOfDigitsInNumericCharacterReference);
+                    self.flush_code_points();
+                    let return_state = self.return_state;
+                    self.reconsume_in(return_state);
+                }
+            },
+            State::HexadecimalCharacterReference => self.numeric_character_reference(c, 16),
+            State::DecimalCharacterReference => self.numeric_character_reference(c, 10),
+        }
+    }
+
+    // https://html.spec.whatwg.org/multipage/parsing.html#named-character-reference-state
+    fn named_character_reference(&mut self) {
+        let start = self.pos - 1;
+        let mut candidate = String::new();
+        let mut matched = None;
+        for &c in self.input[start..]
+            .iter()
+            .take(html_entities::LONGEST_ENTITY)
+        {
+            if !c.is_ascii_alphanumeric() && c != ';' {
+                break;
+            }
+            candidate.push(c);
+            if let Some(value) = html_entities::lookup(&candidate) {
+                matched = Some((candidate.len(), value));
+            }
+            if c == ';' {
+                break;
+            }
+        }
+
+        let (len, value) = match matched {
+            Some(m) => m,
+            None => {
+                self.flush_code_points();
+                self.reconsume_in(State::AmbiguousAmpersand);
+                return;
+            }
+        };
+        let name: String = self.input[start..start + len].iter().collect();
+        self.temp_buffer.push_str(&name);
+        self.skip(len - 1);
+
+        let return_state = self.return_state;
+        let next = self.input.get(self.pos).copied();
+        // for historical reasons "&copy=" or "&notit" inside an attribute is left alone
+        if !name.ends_with(';')
+            && self.is_attribute_return_state()
+            && next.is_some_and(|c| c == '=' || c.is_ascii_alphanumeric())
+        {
+            self.flush_code_points();
+        } else {
+            if !name.ends_with(';') {
+                self.error(ErrorCode::MissingSemicolonAfterCharacterReference);
+            }
+            self.temp_buffer = value.to_string();
+            self.flush_code_points();
+        }
+        self.state = return_state;
+    }
+
+    fn numeric_character_reference(&mut self, c: Option<char>, radix: u32) {
+        let return_state = self.return_state;
+        match c {
+            Some(c) if c.is_digit(radix) => {
+                let digit = c.to_digit(radix).unwrap();
+                self.char_ref_code = self
+                    .char_ref_code
+                    .saturating_mul(radix)
+                    .saturating_add(digit)
+                    .min(0x11_0000);
+            }
+            Some(';') => {
+                self.numeric_character_reference_end();
+                self.state = return_state;
+            }
+            _ => {
+                self.error(ErrorCode::MissingSemicolonAfterCharacterReference);
+                self.numeric_character_reference_end();
+                self.reconsume_in(return_state);
+            }
+        }
+    }
+
+    // https://html.spec.whatwg.org/multipage/parsing.html#numeric-character-reference-end-state
+    fn numeric_character_reference_end(&mut self) {
+        match self.char_ref_code {
+            0 => self.error(ErrorCode::NullCharacterReference),
+            0x11_0000.. => self.error(ErrorCode::CharacterReferenceOutsideUnicodeRange),
+            0xD800..=0xDFFF => self.error(ErrorCode::SurrogateCharacterReference),
+            0xFDD0..=0xFDEF => self.error(ErrorCode::NoncharacterCharacterReference),
+            code if code & 0xFFFE == 0xFFFE => {
+                self.error(ErrorCode::NoncharacterCharacterReference)
+            }
+            0x0D | 0x80..=0x9F => self.error(ErrorCode::ControlCharacterReference),
+            code if code < 0x20 && !matches!(code, 0x09 | 0x0A | 0x0C) => {
+                self.error(ErrorCode::ControlCharacterReference)
+            }
+            0x7F => self.error(ErrorCode::ControlCharacterReference),
+            _ => {}
+        }
+        let code = match self.char_ref_code {
+            0 | 0xD800..=0xDFFF | 0x11_0000.. => 0xFFFD,
+            0x80..=0x9F => Self::windows_1252_replacement(self.char_ref_code),
+            code => code,
+        };
+        self.temp_buffer.clear();
+        self.temp_buffer
+            .push(char::from_u32(code).unwrap_or('\u{FFFD}'));
+        self.flush_code_points();
+    }
+
+    fn windows_1252_replacement(code: u32) -> u32 {
+        match code {
+            0x80 => 0x20AC,
+            0x82 => 0x201A,
+            0x83 => 0x0192,
+            0x84 => 0x201E,
+            0x85 => 0x2026,
+            0x86 => 0x2020,
+            0x87 => 0x2021,
+            0x88 => 0x02C6,
+            0x89 => 0x2030,
+            0x8A => 0x0160,
+            0x8B => 0x2039,
+            0x8C => 0x0152,
+            0x8E => 0x017D,
+            0x91 => 0x2018,
+            0x92 => 0x2019,
+            0x93 => 0x201C,
+            0x94 => 0x201D,
+            0x95 => 0x2022,
+            0x96 => 0x2013,
+            0x97 => 0x2014,
+            0x98 => 0x02DC,
+            0x99 => 0x2122,
+            0x9A => 0x0161,
+            0x9B => 0x203A,
+            0x9C => 0x0153,
+            0x9E => 0x017E,
+            0x9F => 0x0178,
+            code => code,
+        }
+    }
+
+    fn less_than_sign(&mut self, c: Option<char>, end_tag_open: State, text_state: State) {
+        match c {
+            Some('/') => {
+                self.temp_buffer.clear();
+                self.state = end_tag_open;
+            }
+            _ => {
+                self.emit_char('<');
+                self.reconsume_in(text_state);
+            }
+        }
+    }
+
+    fn end_tag_open(&mut self, c: Option<char>, end_tag_name: State, text_state: State) {
+        match c {
+            Some(c) if c.is_ascii_alphabetic() => {
+                self.new_tag(TagKind::End);
+                self.reconsume_in(end_tag_name);
+            }
+            _ => {
+                self.emit_str("</");
+                self.reconsume_in(text_state);
+            }
+        }
+    }
+
+    fn end_tag_name(&mut self, c: Option<char>, text_state: State) {
+        match c {
+            Some(c) if Self::is_whitespace(c) && self.is_appropriate_end_tag() => {
+                self.state = State::BeforeAttributeName
+            }
+            Some('/') if self.is_appropriate_end_tag() => self.state = State::SelfClosingStartTag,
+            Some('>') if self.is_appropriate_end_tag() => {
+                self.state = State::Data;
+                self.emit_tag();
+            }
+            Some(c) if c.is_ascii_alphabetic() => {
+                self.tag.name.push(c.to_ascii_lowercase());
+                self.temp_buffer.push(c);
+            }
+            _ => {
+                self.emit_str("</");
+                let buffer = mem::take(&mut self.temp_buffer);
+                self.emit_str(&buffer);
+                self.reconsume_in(text_state);
+            }
+        }
+    }
+
+    fn attribute_value_quoted(&mut self, c: Option<char>, quote: char) {
+        match c {
+            Some(c) if c == quote => self.state = State::AfterAttributeValueQuoted,
+            Some('&') => {
+                self.return_state = self.state;
+                self.state = State::CharacterReference;
+            }
+            Some('\0') => {
+                self.error(ErrorCode::UnexpectedNullCharacter);
+                self.attr_value.push('\u{FFFD}');
+            }
+            Some(c) => self.attr_value.push(c),
+            None => {
+                self.error(ErrorCode::EofInTag);
+                self.emit_eof();
+            }
+        }
+    }
+
+    fn doctype_identifier(&mut self, c: Option<char>, quote: char, public: bool) {
+        let after = if public {
+            State::AfterDoctypePublicIdentifier
+        } else {
+            State::AfterDoctypeSystemIdentifier
+        };
+        let c = match c {
+            Some(c) if c == quote => {
+                self.state = after;
+                return;
+            }
+            Some('>') => {
+                self.error(if public {
+                    ErrorCode::AbruptDoctypePublicIdentifier
+                } else {
+                    ErrorCode::AbruptDoctypeSystemIdentifier
+                });
+                self.doctype.force_quirks = true;
+                self.state = State::Data;
+                self.emit_doctype();
+                return;
+            }
+            Some('\0') => {
+                self.error(ErrorCode::UnexpectedNullCharacter);
+                '\u{FFFD}'
+            }
+            Some(c) => c,
+            None => return self.eof_in_doctype(),
+        };
+        let id = if public {
+            &mut self.doctype.public_id
+        } else {
+            &mut self.doctype.system_id
+        };
+        id.get_or_insert_with(String::new).push(c);
+    }
+
+    fn start_system_identifier(&mut self, quote: char) {
+        self.doctype.system_id = Some(String::new());
+        self.state = if quote == '"' {
+            State::DoctypeSystemIdentifierDoubleQuoted
+        } else {
+            State::DoctypeSystemIdentifierSingleQuoted
+        };
+    }
+
+    fn eof_in_doctype(&mut self) {
+        self.error(ErrorCode::EofInDoctype);
+        self.doctype.force_quirks = true;
+        self.emit_doctype();
+        self.emit_eof();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the tokens of `html`, with runs of characters written as one string
+    fn tokens(html: &str) -> Vec<String> {
+        let mut tokenizer = Tokenizer::new(html);
+        let mut tokens: Vec<String> = Vec::new();
+        let mut text = String::new();
+        loop {
+            let token = tokenizer.next_token().unwrap();
+            if let Token::Character(c) = token {
+                text.push(c);
+                continue;
+            }
+            if !text.is_empty() {
+                tokens.push(format!("{:?}", mem::take(&mut text)));
+            }
+            match token {
+                Token::Eof => return tokens,
+                Token::StartTag(tag) => tokens.push(format!(
+                    "<{}{}{}>",
+                    tag.name,
+                    tag.attributes
+                        .iter()
+                        .map(|(n, v)| format!(" {}={:?}", n, v))
+                        .collect::<String>(),
+                    if tag.self_closing { " /" } else { "" }
+                )),
+                Token::EndTag(tag) => tokens.push(format!("</{}>", tag.name)),
+                Token::Comment(comment) => tokens.push(format!("<!--{}-->", comment)),
+                token => tokens.push(format!("{:?}", token)),
+            }
+        }
+    }
+
+    fn errors(html: &str) -> Vec<ErrorCode> {
+        let mut tokenizer = Tokenizer::new(html);
+        while tokenizer.next_token() != Some(Token::Eof) {}
+        tokenizer
+            .take_errors()
+            .into_iter()
+            .map(|e| e.code)
+            .collect()
+    }
+
+    #[test]
+    fn tags_and_attributes() {
+        assert_eq!(
+            tokens("<A HREF='x' id=y checked>t</A><br/>"),
+            [
+                r#"<a href="x" id="y" checked="">"#,
+                r#""t""#,
+                "</a>",
+                "<br />"
+            ]
+        );
+        assert_eq!(errors("<p id=a id=b>"), [ErrorCode::DuplicateAttribute]);
+        assert_eq!(tokens("<p id=a id=b>"), [r#"<p id="a">"#]);
+        assert_eq!(errors("</p class=x>"), [ErrorCode::EndTagWithAttributes]);
+    }
+
+    #[test]
+    fn comments_and_doctypes() {
+        assert_eq!(
+            tokens("<!-- a -- b --><!--><?php x ?>"),
+            ["<!-- a -- b -->", "<!---->", "<!--?php x ?-->"]
+        );
+        assert_eq!(
+            errors("<!--><?x>"),
+            [
+                ErrorCode::AbruptClosingOfEmptyComment,
+                ErrorCode::UnexpectedQuestionMarkInsteadOfTagName,
+            ]
+        );
+        let doctype = Doctype {
+            name: Some("html".to_owned()),
+            public_id: Some("-//W3C//DTD HTML 4.01//EN".to_owned()),
+            system_id: None,
+            force_quirks: false,
+        };
+        assert_eq!(
+            tokens(r#"<!DOCTYPE HTML PUBLIC "-//W3C//DTD HTML 4.01//EN">"#),
+            [format!("{:?}", Token::Doctype(doctype))]
+        );
+        assert_eq!(errors("<!DOCTYPE>"), [ErrorCode::MissingDoctypeName]);
+    }
+
+    #[test]
+    fn character_references() {
+        assert_eq!(tokens("&amp;&lt;&#65;&#x42;&notin;"), [r#""&<AB∉""#]);
+        // without the semicolon only the legacy names count, and not in attributes
+        // when what follows could be part of the name
+        assert_eq!(tokens("&notit; &ampx"), [r#""¬it; &x""#]);
+        assert_eq!(
+            tokens("<a href='?a=1&ampx=2'>"),
+            [r#"<a href="?a=1&ampx=2">"#]
+        );
+        assert_eq!(tokens("&#0;&#x110000;"), ["\"\u{fffd}\u{fffd}\""]);
+        assert_eq!(
+            errors("&#0;&#x110000;&bogus;"),
+            [
+                ErrorCode::NullCharacterReference,
+                ErrorCode::CharacterReferenceOutsideUnicodeRange,
+                ErrorCode::UnknownNamedCharacterReference,
+            ]
+        );
+    }
+
+    #[test]
+    fn text_states_end_at_the_matching_end_tag_only() {
+        let mut tokenizer = Tokenizer::new("<title>a</b>&amp;</title>");
+        assert!(matches!(tokenizer.next_token(), Some(Token::StartTag(_))));
+        tokenizer.switch_to(State::RcData);
+        let mut text = String::new();
+        while let Some(Token::Character(c)) = tokenizer.next_token() {
+            text.push(c);
+        }
+        assert_eq!(text, "a</b>&");
+    }
+
+    #[test]
+    fn input_fed_in_pieces_gives_the_same_tokens() {
+        let html = "<!DOCTYPE html><p class='a b'>x &amp; y<!-- c --></p>&notin;";
+        for size in 1..6 {
+            let mut tokenizer = Tokenizer::streaming();
+            let mut streamed = Vec::new();
+            for chunk in html.as_bytes().chunks(size) {
+                tokenizer.feed(std::str::from_utf8(chunk).unwrap());
+                while let Some(token) = tokenizer.next_token() {
+                    streamed.push(token);
+                }
+            }
+            tokenizer.finish();
+            while let Some(token) = tokenizer.next_token() {
+                let eof = token == Token::Eof;
+                streamed.push(token);
+                if eof {
+                    break;
+                }
+            }
+            let mut whole = Vec::new();
+            let mut tokenizer = Tokenizer::new(html);
+            while let Some(token) = tokenizer.next_token() {
+                let eof = token == Token::Eof;
+                whole.push(token);
+                if eof {
+                    break;
+                }
+            }
+            assert_eq!(streamed, whole, "in pieces of {}", size);
+        }
+    }
+}
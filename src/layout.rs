@@ -45,7 +45,7 @@ impl<'a> LayoutBox<'a> {
         let margin_r = s.value("margin-right");
 
         let margin_l_num = if let Some(m) = margin_l {
-            if let Value::Other(ref s) = **m {
+            if let Value::Other(ref s) = *m {
                 s.parse().unwrap_or(0.)
             } else {
                 0.
@@ -55,7 +55,7 @@ impl<'a> LayoutBox<'a> {
         };
 
         let margin_r_num = if let Some(m) = margin_r {
-            if let Value::Other(ref s) = **m {
+            if let Value::Other(ref s) = *m {
                 s.parse().unwrap_or(0.)
             } else {
                 0.
@@ -131,7 +131,7 @@ impl<'a> LayoutBox<'a> {
 
     fn calculate_height(&mut self) {
         self.styled_node.value("height").map_or((), |h| {
-            if let Value::Length(n, _) = **h {
+            if let Value::Length(n, _) = *h {
                 self.dimensions.content.height = n;
             }
         })
@@ -216,7 +216,7 @@ impl<'a> LayoutBox<'a> {
 
     fn absolute_num(s_node: &StyledNode, b_box: Dimensions, prop: &str) -> Option<f32> {
         if let Some(ref v) = s_node.value(prop) {
-            if let Value::Length(l, ref u) = ***v {
+            if let Value::Length(l, ref u) = **v {
                 return match *u {
                     Unit::Px => Some(l),
                     Unit::Pct => Some(l * b_box.content.width / 100.),
@@ -1,8 +1,20 @@
+pub mod attributes;
 pub mod command;
 pub mod css;
 pub mod css_parser;
+pub mod css_tokenizer;
 pub mod dom;
+pub mod element_state;
+pub mod encoding;
+pub mod html_entities;
 pub mod html_parser;
+pub mod html_serializer;
+pub mod html_stream;
+pub mod html_tokenizer;
 pub mod layout;
+pub mod mutation;
 pub mod render;
+pub mod source;
 pub mod style;
+pub mod stylesheets;
+pub mod traversal;
@@ -0,0 +1,419 @@
+use crate::dom::{DomError, Namespace, NodeId, Tree};
+
+// https://dom.spec.whatwg.org/#mutationrecord
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MutationKind {
+    ChildList,
+    Attributes,
+    CharacterData,
+}
+
+/// One change to a `dom::Document`, as a `MutationObserver` would see it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MutationRecord {
+    pub kind: MutationKind,
+    // the node whose children, attribute or data changed
+    pub target: NodeId,
+    pub added_nodes: Vec<NodeId>,
+    pub removed_nodes: Vec<NodeId>,
+    // the siblings around the added or removed nodes
+    pub previous_sibling: Option<NodeId>,
+    pub next_sibling: Option<NodeId>,
+    pub attribute_name: Option<String>,
+    pub attribute_namespace: Option<Namespace>,
+    // the attribute value or text before the change, when the observer asked for it
+    pub old_value: Option<String>,
+}
+
+impl MutationRecord {
+    pub(crate) fn child_list(
+        target: NodeId,
+        added_nodes: Vec<NodeId>,
+        removed_nodes: Vec<NodeId>,
+        previous_sibling: Option<NodeId>,
+        next_sibling: Option<NodeId>,
+    ) -> Self {
+        Self {
+            kind: MutationKind::ChildList,
+            target,
+            added_nodes,
+            removed_nodes,
+            previous_sibling,
+            next_sibling,
+            attribute_name: None,
+            attribute_namespace: None,
+            old_value: None,
+        }
+    }
+
+    pub(crate) fn attributes(
+        target: NodeId,
+        name: &str,
+        namespace: Option<Namespace>,
+        old_value: Option<String>,
+    ) -> Self {
+        Self {
+            kind: MutationKind::Attributes,
+            attribute_name: Some(name.to_owned()),
+            attribute_namespace: namespace,
+            old_value,
+            ..Self::child_list(target, Vec::new(), Vec::new(), None, None)
+        }
+    }
+
+    pub(crate) fn character_data(target: NodeId, old_value: String) -> Self {
+        Self {
+            kind: MutationKind::CharacterData,
+            old_value: Some(old_value),
+            ..Self::child_list(target, Vec::new(), Vec::new(), None, None)
+        }
+    }
+}
+
+/// What an observer is told about; see `Document::observe`.
+// https://dom.spec.whatwg.org/#dictdef-mutationobserverinit
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ObserverOptions {
+    pub child_list: bool,
+    pub attributes: bool,
+    pub character_data: bool,
+    // the target's descendants as well as the target itself
+    pub subtree: bool,
+    pub attribute_old_value: bool,
+    pub character_data_old_value: bool,
+    // only these attributes, by local name
+    pub attribute_filter: Option<Vec<String>>,
+}
+
+/// Names an observer created with `Document::create_observer`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ObserverId(usize);
+
+struct Registration {
+    target: NodeId,
+    options: ObserverOptions,
+    // set on nodes taken out of an observed subtree, so that changes made to them
+    // before the records are taken are still seen; dropped with the records
+    transient: bool,
+}
+
+struct Observer {
+    registrations: Vec<Registration>,
+    records: Vec<MutationRecord>,
+}
+
+/// The observers of a document and the records queued for each of them.
+#[derive(Default)]
+pub struct MutationObservers {
+    // indexed by ObserverId
+    observers: Vec<Observer>,
+}
+
+impl MutationObservers {
+    pub fn new() -> Self {
+        Self {
+            observers: Vec::new(),
+        }
+    }
+
+    pub fn create(&mut self) -> ObserverId {
+        self.observers.push(Observer {
+            registrations: Vec::new(),
+            records: Vec::new(),
+        });
+        ObserverId(self.observers.len() - 1)
+    }
+
+    // https://dom.spec.whatwg.org/#dom-mutationobserver-observe
+    pub fn observe(
+        &mut self,
+        observer: ObserverId,
+        target: NodeId,
+        mut options: ObserverOptions,
+    ) -> Result<(), DomError> {
+        options.attributes |= options.attribute_old_value || options.attribute_filter.is_some();
+        options.character_data |= options.character_data_old_value;
+        if !options.child_list && !options.attributes && !options.character_data {
+            return Err(DomError::Type);
+        }
+        let observer = self.get_mut(observer).ok_or(DomError::NotFound)?;
+        observer
+            .registrations
+            .retain(|r| r.transient || r.target != target);
+        observer.registrations.push(Registration {
+            target,
+            options,
+            transient: false,
+        });
+        Ok(())
+    }
+
+    /// Stops `observer` from seeing anything more and drops its queued records.
+    /// It can be given targets again with `observe`.
+    pub fn disconnect(&mut self, observer: ObserverId) {
+        if let Some(observer) = self.get_mut(observer) {
+            observer.registrations.clear();
+            observer.records.clear();
+        }
+    }
+
+    /// The records queued for `observer` since the last call, oldest first.
+    pub fn take_records(&mut self, observer: ObserverId) -> Vec<MutationRecord> {
+        match self.get_mut(observer) {
+            Some(observer) => {
+                observer.registrations.retain(|r| !r.transient);
+                std::mem::take(&mut observer.records)
+            }
+            None => Vec::new(),
+        }
+    }
+
+    /// Whether any observer is watching anything, so that callers can skip
+    /// collecting old values when nobody would see them.
+    pub fn is_observing(&self) -> bool {
+        self.observers.iter().any(|o| !o.registrations.is_empty())
+    }
+
+    // https://dom.spec.whatwg.org/#queue-a-mutation-record
+    // `record.target` must still be in `tree` the way it was when the change was made
+    pub(crate) fn queue(&mut self, tree: &Tree, record: MutationRecord) {
+        let ancestors = Self::inclusive_ancestors(tree, record.target);
+        for observer in self.observers.iter_mut() {
+            let mut interested = false;
+            let mut with_old_value = false;
+            for registration in &observer.registrations {
+                if !ancestors.contains(&registration.target) {
+                    continue;
+                }
+                let options = &registration.options;
+                if registration.target != record.target && !options.subtree {
+                    continue;
+                }
+                let wanted = match record.kind {
+                    MutationKind::ChildList => options.child_list,
+                    MutationKind::Attributes => {
+                        options.attributes
+                            && options.attribute_filter.as_ref().is_none_or(|filter| {
+                                record.attribute_namespace.is_none()
+                                    && record
+                                        .attribute_name
+                                        .as_ref()
+                                        .is_some_and(|name| filter.contains(name))
+                            })
+                    }
+                    MutationKind::CharacterData => options.character_data,
+                };
+                if !wanted {
+                    continue;
+                }
+                interested = true;
+                with_old_value |= match record.kind {
+                    MutationKind::Attributes => options.attribute_old_value,
+                    MutationKind::CharacterData => options.character_data_old_value,
+                    MutationKind::ChildList => false,
+                };
+            }
+            if interested {
+                let mut record = record.clone();
+                if !with_old_value {
+                    record.old_value = None;
+                }
+                observer.records.push(record);
+            }
+        }
+    }
+
+    // https://dom.spec.whatwg.org/#concept-node-remove step 13
+    // `parent` is where `node` was taken from
+    pub(crate) fn removed(&mut self, tree: &Tree, parent: NodeId, node: NodeId) {
+        let ancestors = Self::inclusive_ancestors(tree, parent);
+        for observer in self.observers.iter_mut() {
+            let transient: Vec<_> = observer
+                .registrations
+                .iter()
+                .filter(|r| r.options.subtree && ancestors.contains(&r.target))
+                .map(|r| Registration {
+                    target: node,
+                    options: r.options.clone(),
+                    transient: true,
+                })
+                .collect();
+            observer.registrations.extend(transient);
+        }
+    }
+
+    fn inclusive_ancestors(tree: &Tree, id: NodeId) -> Vec<NodeId> {
+        let mut ancestors = vec![id];
+        let mut current = id;
+        while let Some(parent) = tree.parent(current) {
+            ancestors.push(parent);
+            current = parent;
+        }
+        ancestors
+    }
+
+    fn get_mut(&mut self, observer: ObserverId) -> Option<&mut Observer> {
+        self.observers.get_mut(observer.0)
+    }
+}
+
+/// The nodes of `nodes` that are not inside another one of them, once each and in
+/// tree order.
+pub fn outermost(tree: &Tree, nodes: &[NodeId]) -> Vec<NodeId> {
+    let mut targets: Vec<NodeId> = Vec::new();
+    for &node in nodes {
+        if !targets.contains(&node) {
+            targets.push(node);
+        }
+    }
+    let mut roots: Vec<NodeId> = targets
+        .iter()
+        .copied()
+        .filter(|&t| {
+            !targets
+                .iter()
+                .any(|&other| other != t && tree.is_inclusive_ancestor(other, t))
+        })
+        .collect();
+    roots.sort_by(|&a, &b| tree.tree_order(a, b));
+    roots
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dom::Document;
+    use crate::html_parser::HtmlParser;
+
+    fn document() -> Document {
+        HtmlParser::new("<div id=a><p id=b><span id=c></span></p></div>").parse_document()
+    }
+
+    fn id(document: &Document, id: &str) -> NodeId {
+        document.get_element_by_id(id).unwrap()
+    }
+
+    fn attributes(subtree: bool) -> ObserverOptions {
+        ObserverOptions {
+            attributes: true,
+            subtree,
+            ..ObserverOptions::default()
+        }
+    }
+
+    #[test]
+    fn descendants_are_only_seen_with_subtree() {
+        let mut document = document();
+        let (a, c) = (id(&document, "a"), id(&document, "c"));
+        let target_only = document.create_observer();
+        let subtree = document.create_observer();
+        document.observe(target_only, a, attributes(false)).unwrap();
+        document.observe(subtree, a, attributes(true)).unwrap();
+        document.set_attribute(c, "title", "x").unwrap();
+        assert!(document.take_records(target_only).is_empty());
+        let records = document.take_records(subtree);
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].target, c);
+        assert_eq!(records[0].attribute_name.as_deref(), Some("title"));
+    }
+
+    #[test]
+    fn removed_nodes_are_watched_until_the_records_are_taken() {
+        let mut document = document();
+        let (a, b, c) = (id(&document, "a"), id(&document, "b"), id(&document, "c"));
+        let observer = document.create_observer();
+        let options = ObserverOptions {
+            child_list: true,
+            ..attributes(true)
+        };
+        document.observe(observer, a, options).unwrap();
+        document.remove_child(a, b).unwrap();
+        document.set_attribute(c, "title", "x").unwrap();
+        let records = document.take_records(observer);
+        let kinds: Vec<MutationKind> = records.iter().map(|r| r.kind).collect();
+        assert_eq!(kinds, [MutationKind::ChildList, MutationKind::Attributes]);
+        assert_eq!(records[0].removed_nodes, [b]);
+        // taking the records drops the transient registration on the removed node
+        document.set_attribute(c, "title", "y").unwrap();
+        assert!(document.take_records(observer).is_empty());
+    }
+
+    #[test]
+    fn attribute_filter_matches_local_names_without_a_namespace() {
+        let mut document = document();
+        let (a, b) = (id(&document, "a"), id(&document, "b"));
+        let observer = document.create_observer();
+        let options = ObserverOptions {
+            attribute_filter: Some(vec!["class".to_owned()]),
+            subtree: true,
+            ..ObserverOptions::default()
+        };
+        document.observe(observer, a, options.clone()).unwrap();
+        document.set_attribute(b, "title", "x").unwrap();
+        document.set_attribute(b, "class", "x").unwrap();
+        let records = document.take_records(observer);
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].attribute_name.as_deref(), Some("class"));
+
+        let mut observers = MutationObservers::new();
+        let observer = observers.create();
+        observers.observe(observer, a, options).unwrap();
+        let namespaced = MutationRecord::attributes(b, "class", Some(Namespace::XLink), None);
+        observers.queue(document.tree(), namespaced);
+        assert!(observers.take_records(observer).is_empty());
+    }
+
+    #[test]
+    fn old_values_are_kept_when_any_matching_registration_asks() {
+        let mut document = document();
+        let (a, b, c) = (id(&document, "a"), id(&document, "b"), id(&document, "c"));
+        let observer = document.create_observer();
+        let other = document.create_observer();
+        document.observe(observer, a, attributes(true)).unwrap();
+        let old_values = ObserverOptions {
+            attribute_old_value: true,
+            ..ObserverOptions::default()
+        };
+        document.observe(observer, c, old_values).unwrap();
+        document.observe(other, a, attributes(true)).unwrap();
+        document.set_attribute(b, "title", "1").unwrap();
+        document.set_attribute(c, "title", "2").unwrap();
+        document.set_attribute(c, "title", "3").unwrap();
+        let old: Vec<Option<String>> = document
+            .take_records(observer)
+            .into_iter()
+            .map(|r| r.old_value)
+            .collect();
+        assert_eq!(old, [None, None, Some("2".to_owned())]);
+        let old: Vec<Option<String>> = document
+            .take_records(other)
+            .into_iter()
+            .map(|r| r.old_value)
+            .collect();
+        assert_eq!(old, [None, None, None]);
+    }
+
+    #[test]
+    fn observing_a_target_again_replaces_its_options() {
+        let mut document = document();
+        let a = id(&document, "a");
+        let observer = document.create_observer();
+        document.observe(observer, a, attributes(false)).unwrap();
+        let child_list = ObserverOptions {
+            child_list: true,
+            ..ObserverOptions::default()
+        };
+        document.observe(observer, a, child_list).unwrap();
+        document.set_attribute(a, "title", "x").unwrap();
+        let text = document.create_text("t");
+        document.append_child(a, text).unwrap();
+        let records = document.take_records(observer);
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].kind, MutationKind::ChildList);
+        assert_eq!(records[0].added_nodes, [text]);
+        assert_eq!(
+            document.observe(observer, a, ObserverOptions::default()),
+            Err(DomError::Type)
+        );
+    }
+}
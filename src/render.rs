@@ -1,13 +1,18 @@
 use crate::command::{DisplayCommand, DisplayCommandList, Console};
-use crate::{layout, style, render, command, dom, css, html_parser, css_parser};
+use crate::{layout, style, render, command, dom, css, html_stream, css_parser, stylesheets};
+use crate::element_state::{self, ElementStates};
 use iced::{Column, Container, Length, Rule, Radio, Text, Element, button, Sandbox, Settings, Align, Button, Color, Canvas, Point, Size, Scrollable, scrollable, TextInput, Row, Background};
 use std::fmt::Alignment;
 use crate::layout::Rectangle;
-use iced::canvas::{Program, Frame, Path, Stroke, Fill, FillRule, Geometry, Cache};
+use iced::canvas::{Program, Frame, Path, Stroke, Fill, FillRule, Geometry, Cache, Cursor, Event, event};
+use iced::mouse;
 use crate::command::DisplayCommand::SolidRectangle;
 use iced::scrollable::{Scrollbar, Scroller};
 use std::borrow::Borrow;
 use std::collections::HashSet;
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::fmt;
 use iced::container::Style;
 use std::iter::Peekable;
 use crate::css::StyleSheet;
@@ -54,7 +59,9 @@ struct Main {
     url_state: iced::text_input::State,
     send_state: iced::button::State,
     url: String,
-    console: Console,
+    page: Option<Rc<RefCell<Page>>>,
+    // every url loaded, for :visited
+    history: Vec<url::Url>,
 }
 
 #[derive(Debug, Clone)]
@@ -63,6 +70,10 @@ enum Message {
     PageRefreshed(DisplayCommandList),
     UrlChanged(String),
     SendClicked,
+    // where the pointer is over the canvas, if it is
+    CursorMoved(Option<Point>),
+    MousePressed(Point),
+    MouseReleased(Option<Point>),
 }
 
 impl Sandbox for Main {
@@ -71,7 +82,6 @@ impl Sandbox for Main {
     fn new() -> Self {
         Self {
             commands: Vec::new(),
-            console: Console::new(Vec::new()),
             ..Default::default()
         }
     }
@@ -90,6 +100,9 @@ impl Sandbox for Main {
                     self.commands = c;
                 }
             }
+            Message::CursorMoved(point) => self.interact(|page| page.hover(point)),
+            Message::MousePressed(point) => self.interact(|page| page.press(point)),
+            Message::MouseReleased(point) => self.interact(|page| page.release(point)),
             _ => {}
         }
     }
@@ -116,8 +129,7 @@ impl Sandbox for Main {
 impl Main {
     fn parse_url(&mut self) -> Option<DisplayCommandList> {
         let url = &self.url.to_lowercase();
-        let mut path = PathBuf::from(&self.url);
-        path.pop();
+        let media = stylesheets::Media::screen(render::SCREEN_WIDTH as f32, render::SCREEN_HEIGHT as f32);
 
         // let mut url  = &self.url.to_lowercase().chars().peekable();
         // while url.peek().is_some() {
@@ -127,54 +139,215 @@ impl Main {
         //     }
         // }
 
-        let first_char = url.chars().into_iter().next().unwrap();
-        let mut html = Vec::new();
-        if ('a'..'z').contains(&url.chars().nth(0).unwrap()) && &url[1..3] == ":\\" {
-            html = get_html_from_file(&*url);
-            let ref root_node = html[0];
-            dom::pretty_print(root_node, 0);
+        let first_char = url.chars().next()?;
+        if ('a'..'z').contains(&first_char) && &url[1..3] == ":\\" {
+            let document = match get_html_from_file(&*url) {
+                Ok(document) => document,
+                Err(e) => {
+                    eprintln!("could not open {}: {}", url, e);
+                    return None;
+                }
+            };
+            let root = document.document_element()?;
+            let root_node = document.tree().to_node(root);
+            dom::pretty_print(&root_node, 0);
 
-            let style_sheet = root_node.get_stylesheet_from_file(path.to_str().unwrap()).unwrap();
+            return Some(self.show(document, &media));
 
-            let style_tree_root = style::StyledNode::new(&root_node, &style_sheet);
-            style::StyledNode::pretty_print(&style_tree_root, 0);
+        } else if url.starts_with("https://") || url.starts_with("http://") || first_char.is_ascii() {
+            let document = match get_html_from_url(&*url) {
+                Ok(document) => document,
+                Err(e) => {
+                    eprintln!("could not load {}: {}", url, e);
+                    return None;
+                }
+            };
+            let root = document.document_element()?;
+            let root_node = document.tree().to_node(root);
+            dom::pretty_print(&root_node, 0);
 
-            let mut viewport = layout::Dimensions::default();
-            viewport.content.width = render::SCREEN_WIDTH as f32;
-            viewport.content.height = render::SCREEN_HEIGHT as f32;
+            return Some(self.show(document, &media));
 
-            let layout_tree = layout::LayoutBox::layout_tree(&style_tree_root, viewport);
-            layout::LayoutBox::pretty_print(&layout_tree, 0);
+        } else {
+            return None;
+        }
+       None
+    }
 
-            return Some(self.console.build_display_commands(&layout_tree));
+    // keeps `document` as the page shown, styled for `media`, and paints it
+    fn show(&mut self, document: dom::Document, media: &stylesheets::Media) -> DisplayCommandList {
+        if let Some(url) = document.url() {
+            if !self.history.contains(url) {
+                self.history.push(url.clone());
+            }
+        }
+        let mut page = Page::new(document, media, &self.history);
+        let commands = page.render();
+        self.page = Some(Rc::new(RefCell::new(page)));
+        commands
+    }
 
-        } else if url.starts_with("https://") || url.starts_with("http://") || first_char.is_ascii() {
-            html = get_html_from_url(&*url).unwrap();
-            let ref root_node = html[0];
-            dom::pretty_print(root_node, 0);
+    // paints the page again when `f` changed the state of any of its elements
+    fn interact(&mut self, f: impl FnOnce(&mut Page) -> bool) {
+        if let Some(page) = &self.page {
+            let mut page = page.borrow_mut();
+            if f(&mut *page) {
+                self.commands = page.render();
+            }
+        }
+    }
+}
+
+// a loaded document and what it takes to paint it again as the pointer moves
+// over it and clicks its elements
+struct Page {
+    document: dom::Document,
+    style_sheets: Vec<StyleSheet>,
+    states: ElementStates,
+    style_cache: style::StyleCache,
+    // the border box of every element laid out, in paint order, for hit testing
+    boxes: Vec<(Rectangle, dom::NodeId)>,
+}
+
+impl fmt::Debug for Page {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Page({:?})", self.document.url().map(url::Url::as_str))
+    }
+}
+
+impl Page {
+    // links to anything in `history` are visited
+    fn new(document: dom::Document, media: &stylesheets::Media, history: &[url::Url]) -> Self {
+        let style_sheets = stylesheets::collect(&document, media, stylesheets::fetch);
+        let mut states = ElementStates::new();
+        let tree = document.tree();
+        for id in tree.pre_order(document.root()) {
+            let visited = tree
+                .element(id)
+                .filter(|e| element_state::is_link(e))
+                .and_then(|e| e.href())
+                .and_then(|href| document.resolve_url(href))
+                .is_some_and(|url| history.contains(&url));
+            if visited {
+                states.set_visited(id, true);
+            }
+        }
+        Self {
+            document,
+            style_sheets,
+            states,
+            style_cache: style::StyleCache::new(),
+            boxes: Vec::new(),
+        }
+    }
 
-            let style_sheet = root_node.get_stylesheet_from_url(url).unwrap();
+    // styles what the cache lost since the last time, lays the page out and paints it
+    fn render(&mut self) -> DisplayCommandList {
+        let root = match self.document.document_element() {
+            Some(root) => root,
+            None => return Vec::new(),
+        };
+        let style_tree_root = style::StyledNode::with_states(
+            self.document.tree().get(root),
+            &self.style_sheets,
+            &self.states,
+            &mut self.style_cache,
+        );
+
+        let mut viewport = layout::Dimensions::default();
+        viewport.content.width = render::SCREEN_WIDTH as f32;
+        viewport.content.height = render::SCREEN_HEIGHT as f32;
+
+        let layout_tree = layout::LayoutBox::layout_tree(&style_tree_root, viewport);
+        self.boxes.clear();
+        Self::collect_boxes(&layout_tree, &mut self.boxes);
+
+        Console::new(Vec::new()).build_display_commands(&layout_tree)
+    }
 
-            let style_tree_root = style::StyledNode::new(&root_node, &style_sheet);
-            style::StyledNode::pretty_print(&style_tree_root, 0);
+    fn collect_boxes(layout_box: &layout::LayoutBox, boxes: &mut Vec<(Rectangle, dom::NodeId)>) {
+        let id = layout_box.styled_node.node().id();
+        boxes.push((layout_box.dimensions.border_box(), id));
+        for child in &layout_box.children {
+            Self::collect_boxes(child, boxes);
+        }
+    }
 
-            let mut viewport = layout::Dimensions::default();
-            viewport.content.width = render::SCREEN_WIDTH as f32;
-            viewport.content.height = render::SCREEN_HEIGHT as f32;
+    // the element painted on top at `point`
+    fn hit(&self, point: Point) -> Option<dom::NodeId> {
+        self.boxes
+            .iter()
+            .rev()
+            .find(|(r, _)| {
+                point.x >= r.x && point.x < r.x + r.width && point.y >= r.y && point.y < r.y + r.height
+            })
+            .map(|&(_, id)| id)
+    }
 
-            let layout_tree = layout::LayoutBox::layout_tree(&style_tree_root, viewport);
-            layout::LayoutBox::pretty_print(&layout_tree, 0);
+    // each of these returns whether the state of any element changed
 
-            return Some(self.console.build_display_commands(&layout_tree));
+    fn hover(&mut self, point: Option<Point>) -> bool {
+        let hit = point.and_then(|p| self.hit(p));
+        let changed = self.states.set_hovered(self.document.tree(), hit);
+        self.invalidate(&changed)
+    }
 
-        } else {
-            return None;
+    // the element pressed becomes active, and focus goes to the nearest element
+    // around it that can take it, or nowhere
+    fn press(&mut self, point: Point) -> bool {
+        let hit = self.hit(point);
+        let tree = self.document.tree();
+        let focus = hit.and_then(|id| {
+            std::iter::successors(Some(tree.get(id)), |n| n.parent())
+                .find(|&n| element_state::is_focusable(n))
+                .map(|n| n.id())
+        });
+        let mut changed = self.states.set_active(tree, hit);
+        changed.extend(self.states.set_focused(tree, focus));
+        self.invalidate(&changed)
+    }
+
+    // a press and a release on the same element is a click, which checks or
+    // unchecks it
+    fn release(&mut self, point: Option<Point>) -> bool {
+        let active = self.states.active();
+        let mut changed = self.states.set_active(self.document.tree(), None);
+        let released = point.and_then(|p| self.hit(p));
+        if let Some(id) = active.filter(|&a| released == Some(a)) {
+            changed.extend(self.states.toggle_checked(self.document.tree(), id));
         }
-       None
+        self.invalidate(&changed)
+    }
+
+    // drops the cached styles of what `changed` affects
+    fn invalidate(&mut self, changed: &[dom::NodeId]) -> bool {
+        if changed.is_empty() {
+            return false;
+        }
+        let tree = self.document.tree();
+        let roots = element_state::restyle_roots(tree, changed, &self.style_sheets);
+        self.style_cache.invalidate(tree, &roots);
+        true
     }
 }
 
 impl Program<Message> for Main {
+    fn update(&mut self, event: Event, bounds: iced::Rectangle, cursor: Cursor) -> (event::Status, Option<Message>) {
+        let position = cursor.position_in(&bounds);
+        let message = match event {
+            Event::Mouse(mouse::Event::CursorMoved { .. }) | Event::Mouse(mouse::Event::CursorLeft) => {
+                Message::CursorMoved(position)
+            }
+            Event::Mouse(mouse::Event::ButtonPressed(mouse::Button::Left)) => match position {
+                Some(p) => Message::MousePressed(p),
+                None => return (event::Status::Ignored, None),
+            },
+            Event::Mouse(mouse::Event::ButtonReleased(mouse::Button::Left)) => Message::MouseReleased(position),
+            _ => return (event::Status::Ignored, None),
+        };
+        (event::Status::Captured, Some(message))
+    }
+
     fn draw(&self, bounds: iced::Rectangle, _: iced::canvas::Cursor) -> Vec<iced::canvas::Geometry> {
         let mut list = Vec::new();
         for command in &self.commands {
@@ -208,19 +381,54 @@ impl Program<Message> for Main {
     }
 }
 
-fn get_html_from_file(p: &str) -> Vec<dom::Node> {
-    let html = std::fs::read_to_string(p).unwrap();
-    let nodes = html_parser::HtmlParser::new(&html).parse_nodes();
-    nodes
+// why a page could not be shown
+#[derive(Debug)]
+enum LoadError {
+    Io(std::io::Error),
+    Http(reqwest::Error),
 }
 
-fn get_html_from_url(url: &str) -> Result<Vec<dom::Node>, std::fmt::Error> {
-    let mut res = reqwest::blocking::get(url).unwrap();
-    let mut body = String::new();
-    res.read_to_string(&mut body).unwrap();
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::Io(e) => write!(f, "{}", e),
+            LoadError::Http(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<std::io::Error> for LoadError {
+    fn from(e: std::io::Error) -> Self {
+        LoadError::Io(e)
+    }
+}
+
+impl From<reqwest::Error> for LoadError {
+    fn from(e: reqwest::Error) -> Self {
+        LoadError::Http(e)
+    }
+}
+
+// the whole document is read before anything is shown; painting it as it
+// arrives needs the loading moved off the ui thread first
+fn get_html_from_file(p: &str) -> Result<dom::Document, LoadError> {
+    let file = std::fs::File::open(p)?;
+    let mut document = html_stream::parse_reader(file, None, |_| {})?;
+    document.set_url(url::Url::from_file_path(p).ok());
+    Ok(document)
+}
 
-    let nodes = html_parser::HtmlParser::new(&*body).parse_nodes();
-    Ok(nodes)
+fn get_html_from_url(url: &str) -> Result<dom::Document, LoadError> {
+    let res = reqwest::blocking::get(url)?.error_for_status()?;
+    let content_type = res
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_owned);
+
+    let mut document = html_stream::parse_reader(res, content_type.as_deref(), |_| {})?;
+    document.set_url(url::Url::parse(url).ok());
+    Ok(document)
 }
 
 mod styling {
@@ -0,0 +1,96 @@
+use std::fmt;
+use std::fmt::Formatter;
+
+/// A location in a source text: a byte offset plus a 1-based line and column.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct SourcePosition {
+    pub offset: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl SourcePosition {
+    pub fn new(offset: usize, line: usize, column: usize) -> Self {
+        Self {
+            offset,
+            line,
+            column,
+        }
+    }
+
+    /// Moves past `c`, where a carriage return counts as a line break on its own.
+    pub fn advance(&mut self, c: char) {
+        self.offset += c.len_utf8();
+        if c == '\n' || c == '\r' {
+            self.line += 1;
+            self.column = 1;
+        } else {
+            self.column += 1;
+        }
+    }
+}
+
+impl Default for SourcePosition {
+    fn default() -> Self {
+        Self::new(0, 1, 1)
+    }
+}
+
+impl fmt::Debug for SourcePosition {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{} (byte {})", self.line, self.column, self.offset)
+    }
+}
+
+impl fmt::Display for SourcePosition {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+#[derive(Clone, PartialEq)]
+pub struct ParseError<C> {
+    pub code: C,
+    pub position: SourcePosition,
+}
+
+impl<C> ParseError<C> {
+    pub fn new(code: C, position: SourcePosition) -> Self {
+        Self { code, position }
+    }
+}
+
+impl<C: fmt::Debug> fmt::Debug for ParseError<C> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {:?}", self.position, self.code)
+    }
+}
+
+/// The stretch of source text between two positions, end exclusive.
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+pub struct Span {
+    pub start: SourcePosition,
+    pub end: SourcePosition,
+}
+
+impl Span {
+    pub fn new(start: SourcePosition, end: SourcePosition) -> Self {
+        Self { start, end }
+    }
+}
+
+impl fmt::Debug for Span {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}-{} (bytes {}..{})",
+            self.start, self.end, self.start.offset, self.end.offset
+        )
+    }
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.start, self.end)
+    }
+}
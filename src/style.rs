@@ -1,17 +1,75 @@
-use crate::css::{Selector, StyleSheet, Value};
-use crate::dom::{ElementData, Node, NodeType};
+use crate::css::{
+    AttributeOperator, AttributeSelector, CaseSensitivity, Declaration, Nth, PseudoClass,
+    Selector, SimpleSelector, StyleSheet, Value,
+};
+use crate::dom::{ElementData, NodeId, NodeRef, NodeType, Tree};
+use crate::element_state::{self, ElementStates};
+use crate::stylesheets;
 use std::collections::HashMap;
 use std::fmt;
 use std::fmt::Formatter;
 
-type PropertyMap<'a> = HashMap<&'a str, &'a Value>;
+// html attributes whose values selectors match ignoring ascii case, unless
+// they have the `s` flag
+// https://html.spec.whatwg.org/multipage/semantics-other.html#case-sensitivity-of-selectors
+const CASE_INSENSITIVE_ATTRIBUTES: [&str; 46] = [
+    "accept", "accept-charset", "align", "alink", "axis", "bgcolor", "charset", "checked",
+    "clear", "codetype", "color", "compact", "declare", "defer", "dir", "direction", "disabled",
+    "enctype", "face", "frame", "hreflang", "http-equiv", "lang", "language", "link", "media",
+    "method", "multiple", "nohref", "noresize", "noshade", "nowrap", "readonly", "rel", "rev",
+    "rules", "scope", "scrolling", "selected", "shape", "target", "text", "type", "valign",
+    "valuetype", "vlink",
+];
+
+// the winning declaration per property, kept whole so its span stays available
+type PropertyMap<'a> = HashMap<&'a str, &'a Declaration>;
+
+// the rules an element matched, in cascade order, as (sheet, rule) indices where
+// sheet 0 is the user-agent sheet and the author sheets follow from 1; later
+// rules win
+type MatchedRules = Vec<(usize, usize)>;
 
 pub struct StyledNode<'a> {
-    node: &'a Node,
+    node: NodeRef<'a>,
     styles: PropertyMap<'a>,
     pub children: Vec<StyledNode<'a>>,
 }
 
+/// The rules each element matched when it was last styled, so that styling a
+/// tree again after `ElementStates` changed only matches selectors for the
+/// elements that were invalidated. It belongs to one document and one list of
+/// style sheets.
+#[derive(Debug, Clone, Default)]
+pub struct StyleCache {
+    matched: HashMap<NodeId, MatchedRules>,
+}
+
+impl StyleCache {
+    pub fn new() -> Self {
+        Self {
+            matched: HashMap::new(),
+        }
+    }
+
+    /// Forgets the elements in the subtrees at `roots`, such as those that
+    /// `element_state::restyle_roots` returns.
+    pub fn invalidate(&mut self, tree: &Tree, roots: &[NodeId]) {
+        for &root in roots {
+            for id in tree.pre_order(root) {
+                self.matched.remove(&id);
+            }
+        }
+    }
+}
+
+// what matching depends on besides the tree
+#[derive(Clone, Copy)]
+struct MatchContext<'s> {
+    states: &'s ElementStates,
+    // the element `:scope` stands for, the subject of `:has()`
+    scope: Option<NodeId>,
+}
+
 pub enum Display {
     Block,
     Inline,
@@ -20,15 +78,36 @@ pub enum Display {
 }
 
 impl<'a> StyledNode<'a> {
-    pub fn new(node: &'a Node,style_sheet: &'a StyleSheet) -> Self {
+    /// Styles `node` and its descendants with `style_sheets`, which are in cascade
+    /// order as `stylesheets::collect` returns them.
+    pub fn new(node: NodeRef<'a>, style_sheets: &'a [StyleSheet]) -> Self {
+        Self::with_states(node, style_sheets, &ElementStates::new(), &mut StyleCache::new())
+    }
+
+    /// Styles `node` and its descendants as `new` does, with the dynamic
+    /// pseudo-classes matched against `states`. Elements that `cache` holds are
+    /// styled with the rules they matched before; the others are matched and
+    /// added to it.
+    pub fn with_states(
+        node: NodeRef<'a>,
+        style_sheets: &'a [StyleSheet],
+        states: &ElementStates,
+        cache: &mut StyleCache,
+    ) -> Self {
         let mut style_children = Vec::new();
-        let styles = match node.node_type {
-            NodeType::Element(ref e) => Self::styles(e, style_sheet),
-            _ => PropertyMap::new(),
+        let styles = match node.as_element() {
+            Some(_) => {
+                let matched = cache
+                    .matched
+                    .entry(node.id())
+                    .or_insert_with(|| Self::matched_rules(node, style_sheets, states));
+                Self::cascade(matched, style_sheets)
+            }
+            None => PropertyMap::new(),
         };
-        for child in &node.children {
-            if let NodeType::Element(_) = child.node_type {
-                style_children.push(StyledNode::new(child,style_sheet));
+        for child in node.children() {
+            if child.as_element().is_some() {
+                style_children.push(StyledNode::with_states(child, style_sheets, states, cache));
             }
         }
 
@@ -38,24 +117,78 @@ impl<'a> StyledNode<'a> {
             children: style_children,
         }
     }
-    pub fn styles(element: &'a ElementData, stylesheet: &'a StyleSheet) -> PropertyMap<'a> {
-        let mut styles = PropertyMap::new();
+    pub fn styles(node: NodeRef<'a>, style_sheets: &'a [StyleSheet]) -> PropertyMap<'a> {
+        let matched = Self::matched_rules(node, style_sheets, &ElementStates::new());
+        Self::cascade(&matched, style_sheets)
+    }
+
+    pub fn node(&self) -> NodeRef<'a> {
+        self.node
+    }
 
-        for rule in &stylesheet.rules {
-            for selector in &rule.selectors {
-                if Self::selector_matches(element, &selector) {
-                    for declar in &rule.declarations {
-                        styles.insert(&declar.property, &declar.value);
-                    }
-                    break;
+    fn matched_rules(
+        node: NodeRef,
+        style_sheets: &[StyleSheet],
+        states: &ElementStates,
+    ) -> MatchedRules {
+        let cx = MatchContext {
+            states,
+            scope: None,
+        };
+        let sheets = std::iter::once(stylesheets::user_agent()).chain(style_sheets);
+        let mut matched = Vec::new();
+        for (s, sheet) in sheets.enumerate() {
+            for (r, rule) in sheet.rules.iter().enumerate() {
+                // a rule is as specific as the most specific of its selectors that match
+                let specificity = rule
+                    .selectors
+                    .iter()
+                    .filter(|selector| Self::matches_in(node, selector, cx))
+                    .map(Selector::specificity)
+                    .max();
+                if let Some(specificity) = specificity {
+                    matched.push((s, r, specificity));
                 }
             }
         }
+        // https://drafts.csswg.org/css-cascade-4/#cascade-sort
+        // any author rule wins over the user-agent sheet, then the more specific
+        // rule wins, then the later one; the sort is stable, so it keeps that order
+        matched.sort_by_key(|&(s, _, specificity)| (s > 0, specificity));
+        matched.into_iter().map(|(s, r, _)| (s, r)).collect()
+    }
+
+    // https://drafts.csswg.org/css-cascade-4/#importance
+    // the normal declarations go in the order the rules were matched, then the
+    // important ones, among which the user-agent sheet wins over the author's
+    fn cascade(matched: &[(usize, usize)], style_sheets: &'a [StyleSheet]) -> PropertyMap<'a> {
+        let declarations = |&(s, r): &(usize, usize)| {
+            let sheet = match s {
+                0 => stylesheets::user_agent(),
+                s => &style_sheets[s - 1],
+            };
+            sheet.rules[r].declarations.iter()
+        };
+        let normal = matched.iter().flat_map(declarations).filter(|d| !d.important);
+        let (user_agent, author): (Vec<_>, Vec<_>) = matched.iter().partition(|&&(s, _)| s == 0);
+        let important = author
+            .into_iter()
+            .chain(user_agent)
+            .flat_map(declarations)
+            .filter(|d| d.important);
+        let mut styles = PropertyMap::new();
+        for declar in normal.chain(important) {
+            styles.insert(&declar.property, declar);
+        }
         styles
     }
 
-    pub fn value(&self, name: &str) -> Option<&&Value> {
-        self.styles.get(name)
+    pub fn value(&self, name: &str) -> Option<&Value> {
+        self.styles.get(name).map(|d| &d.value)
+    }
+
+    pub fn declaration(&self, name: &str) -> Option<&Declaration> {
+        self.styles.get(name).copied()
     }
     pub fn get_display(&self) -> Display {
         match self.value("display") {
@@ -64,6 +197,11 @@ impl<'a> StyledNode<'a> {
                 "inline" => Display::Inline,
                 "none" => Display::None,
                 "inline-block" => Display::InlineBlock,
+                // laid out as plain blocks until there are list markers and table layout
+                "list-item" | "flow-root" | "table" | "table-caption" | "table-header-group"
+                | "table-row-group" | "table-footer-group" | "table-row" => Display::Block,
+                "table-cell" => Display::InlineBlock,
+                "table-column" | "table-column-group" => Display::None,
                 _ => Display::Inline,
             },
             _ => Display::Inline,
@@ -77,40 +215,268 @@ impl<'a> StyledNode<'a> {
             _ => default,
         }
     }
-    fn selector_matches(element: &ElementData, selector: &Selector) -> bool {
-        for simple in &selector.simple {
-            let mut selector_match = true;
-            if let Some(ref t) = &simple.tag_name {
-                if *t != element.tag_name {
-                    continue;
-                }
+    /// Whether the element at `node` matches `selector`, which is checked from its
+    /// last compound selector back to its first.
+    // https://drafts.csswg.org/selectors-4/#match-a-complex-selector-against-an-element
+    pub fn selector_matches(node: NodeRef, selector: &Selector) -> bool {
+        Self::scoped_selector_matches(node, selector, None)
+    }
+
+    /// As `selector_matches`, with `:scope` standing for the element at `scope`
+    /// rather than the root element.
+    pub fn scoped_selector_matches(
+        node: NodeRef,
+        selector: &Selector,
+        scope: Option<NodeId>,
+    ) -> bool {
+        let states = ElementStates::new();
+        let cx = MatchContext {
+            states: &states,
+            scope,
+        };
+        Self::matches_in(node, selector, cx)
+    }
+
+    fn matches_in(node: NodeRef, selector: &Selector, cx: MatchContext) -> bool {
+        match selector.simple.len() {
+            0 => false,
+            n => Self::matches_from(node, selector, n - 1, cx),
+        }
+    }
+
+    fn matches_any(node: NodeRef, selectors: &[Selector], cx: MatchContext) -> bool {
+        selectors.iter().any(|s| Self::matches_in(node, s, cx))
+    }
+
+    // whether `node` matches `selector.simple[i]` and the elements it is combined
+    // with match the compound selectors before it
+    fn matches_from(node: NodeRef, selector: &Selector, i: usize, cx: MatchContext) -> bool {
+        if !Self::compound_matches(node, &selector.simple[i], cx) {
+            return false;
+        }
+        if i == 0 {
+            return true;
+        }
+        let rest_matches = |n: NodeRef| Self::matches_from(n, selector, i - 1, cx);
+        match selector.combinations.get(i - 1) {
+            Some(' ') => Self::walk(node, NodeRef::parent).any(rest_matches),
+            Some('>') => node.parent().is_some_and(rest_matches),
+            Some('+') => Self::walk(node, NodeRef::previous_sibling)
+                .find(|n| n.as_element().is_some())
+                .is_some_and(rest_matches),
+            Some('~') => Self::walk(node, NodeRef::previous_sibling).any(rest_matches),
+            _ => false,
+        }
+    }
+
+    // the nodes reached by following `step` from `node`, not including it
+    fn walk<'t>(
+        node: NodeRef<'t>,
+        step: fn(&NodeRef<'t>) -> Option<NodeRef<'t>>,
+    ) -> impl Iterator<Item = NodeRef<'t>> {
+        std::iter::successors(step(&node), step)
+    }
+
+    fn compound_matches(node: NodeRef, simple: &SimpleSelector, cx: MatchContext) -> bool {
+        let element = match node.as_element() {
+            Some(e) => e,
+            None => return false,
+        };
+        if let Some(ref t) = simple.tag_name {
+            // the name is lowercased for html elements, as every document here is
+            // an html document, and compared as written for any other element
+            // https://drafts.csswg.org/selectors-4/#case-sensitive
+            let name_matches = if element.is_html() {
+                t.to_ascii_lowercase() == element.tag_name
+            } else {
+                *t == element.tag_name
+            };
+            if !name_matches {
+                return false;
             }
-            match element.get_id() {
-                Some(i) => {
-                    if let Some(ref id) = simple.id {
-                        if *id != *i {
-                            continue;
-                        }
-                    }
-                }
-                None => if let Some(_) = simple.id {
-                    continue;
-                },
+        }
+        if let Some(ref id) = simple.id {
+            if element.get_id() != Some(id) {
+                return false;
+            }
+        }
+        let element_classes = element.get_classes();
+        simple.classes.iter().all(|class| element_classes.contains::<str>(class))
+            && simple.attributes.iter().all(|a| Self::attribute_matches(element, a))
+            && simple.pseudo_classes.iter().all(|p| Self::pseudo_class_matches(node, p, cx))
+    }
+
+    // https://drafts.csswg.org/selectors-4/#structural-pseudos
+    fn pseudo_class_matches(
+        node: NodeRef,
+        pseudo_class: &PseudoClass,
+        cx: MatchContext,
+    ) -> bool {
+        let is_root = || {
+            node.parent()
+                .is_some_and(|p| matches!(p.node_type(), NodeType::Document))
+        };
+        let of_type = |n: &NodeRef| Self::same_type(node, *n);
+        let any = |n: &NodeRef| n.as_element().is_some();
+        match pseudo_class {
+            PseudoClass::Root => is_root(),
+            PseudoClass::Scope => cx.scope.map_or_else(is_root, |s| s == node.id()),
+            // comments and empty text do not count
+            PseudoClass::Empty => node.children().all(|c| match c.node_type() {
+                NodeType::Element(_) => false,
+                NodeType::Text(text) => text.is_empty(),
+                _ => true,
+            }),
+            PseudoClass::FirstChild => Self::count(node, NodeRef::previous_sibling, any) == 0,
+            PseudoClass::LastChild => Self::count(node, NodeRef::next_sibling, any) == 0,
+            PseudoClass::OnlyChild => {
+                Self::count(node, NodeRef::previous_sibling, any) == 0
+                    && Self::count(node, NodeRef::next_sibling, any) == 0
+            }
+            PseudoClass::FirstOfType => Self::count(node, NodeRef::previous_sibling, of_type) == 0,
+            PseudoClass::LastOfType => Self::count(node, NodeRef::next_sibling, of_type) == 0,
+            PseudoClass::OnlyOfType => {
+                Self::count(node, NodeRef::previous_sibling, of_type) == 0
+                    && Self::count(node, NodeRef::next_sibling, of_type) == 0
+            }
+            PseudoClass::NthChild(nth, of) => {
+                Self::nth_child_matches(node, nth, of, NodeRef::previous_sibling, cx)
             }
-            let element_classes = element.get_classes();
-            for class in &simple.classes {
-                // boolean selector match
-                selector_match &= element_classes.contains::<str>(class);
+            PseudoClass::NthLastChild(nth, of) => {
+                Self::nth_child_matches(node, nth, of, NodeRef::next_sibling, cx)
             }
-            if selector_match {
-                return true;
+            PseudoClass::NthOfType(nth) => {
+                nth.matches(Self::count(node, NodeRef::previous_sibling, of_type) + 1)
             }
+            PseudoClass::NthLastOfType(nth) => {
+                nth.matches(Self::count(node, NodeRef::next_sibling, of_type) + 1)
+            }
+            PseudoClass::Not(selectors) => !Self::matches_any(node, selectors, cx),
+            PseudoClass::Is(selectors) | PseudoClass::Where(selectors) => {
+                Self::matches_any(node, selectors, cx)
+            }
+            PseudoClass::Has(selectors) => Self::has_matches(node, selectors, cx),
+            PseudoClass::Hover => cx.states.is_hovered(node),
+            PseudoClass::Active => cx.states.is_active(node),
+            PseudoClass::Focus => cx.states.is_focused(node),
+            PseudoClass::FocusWithin => cx.states.is_focus_within(node),
+            PseudoClass::Link => {
+                node.as_element().is_some_and(element_state::is_link)
+                    && !cx.states.is_visited(node)
+            }
+            PseudoClass::Visited => cx.states.is_visited(node),
+            PseudoClass::Checked => cx.states.is_checked(node),
+            PseudoClass::Disabled => element_state::is_disabled(node),
+        }
+    }
+
+    // how many of the siblings reached by `step` from `node` pass `filter`
+    fn count<'t>(
+        node: NodeRef<'t>,
+        step: fn(&NodeRef<'t>) -> Option<NodeRef<'t>>,
+        filter: impl Fn(&NodeRef<'t>) -> bool,
+    ) -> i32 {
+        Self::walk(node, step).filter(filter).count() as i32
+    }
+
+    // `step` is the way siblings are counted from; with an `of S` list both the
+    // element and the siblings counted have to match it
+    fn nth_child_matches<'t>(
+        node: NodeRef<'t>,
+        nth: &Nth,
+        of: &[Selector],
+        step: fn(&NodeRef<'t>) -> Option<NodeRef<'t>>,
+        cx: MatchContext,
+    ) -> bool {
+        if of.is_empty() {
+            return nth.matches(Self::count(node, step, |n| n.as_element().is_some()) + 1);
+        }
+        Self::matches_any(node, of, cx)
+            && nth.matches(Self::count(node, step, |n| Self::matches_any(*n, of, cx)) + 1)
+    }
+
+    // elements of the same type have the same name and namespace
+    fn same_type(a: NodeRef, b: NodeRef) -> bool {
+        match (a.as_element(), b.as_element()) {
+            (Some(a), Some(b)) => a.tag_name == b.tag_name && a.namespace() == b.namespace(),
+            _ => false,
+        }
+    }
+
+    // https://drafts.csswg.org/selectors-4/#relational
+    // each selector starts with a `:scope` compound for `node`, so the elements to
+    // try are those a relative selector could end at: the descendants of `node`,
+    // or, after a sibling combinator, its following siblings and their descendants
+    fn has_matches(node: NodeRef, selectors: &[Selector], cx: MatchContext) -> bool {
+        let tree = node.tree();
+        let cx = MatchContext {
+            scope: Some(node.id()),
+            ..cx
+        };
+        selectors.iter().any(|selector| {
+            let candidates: Vec<_> = match selector.combinations.first() {
+                Some('+') | Some('~') => Self::walk(node, NodeRef::next_sibling)
+                    .flat_map(|sibling| tree.pre_order(sibling.id()))
+                    .collect(),
+                _ => tree.pre_order(node.id()).skip(1).collect(),
+            };
+            candidates
+                .into_iter()
+                .any(|id| Self::matches_in(tree.get(id), selector, cx))
+        })
+    }
+
+    // https://drafts.csswg.org/selectors-4/#attribute-representation
+    fn attribute_matches(element: &ElementData, selector: &AttributeSelector) -> bool {
+        // names without a namespace prefix only match attributes without a namespace
+        let value = if element.is_html() {
+            element.get_attribute_ns(None, &selector.name.to_ascii_lowercase())
+        } else {
+            element.get_attribute_ns(None, &selector.name)
+        };
+        let value = match value {
+            Some(v) => v,
+            None => return false,
+        };
+        let insensitive = match selector.case {
+            CaseSensitivity::Insensitive => true,
+            CaseSensitivity::Sensitive => false,
+            CaseSensitivity::Default => {
+                element.is_html()
+                    && CASE_INSENSITIVE_ATTRIBUTES.contains(&&selector.name.to_ascii_lowercase()[..])
+            }
+        };
+        let (value, wanted) = if insensitive {
+            (value.to_ascii_lowercase(), selector.value.to_ascii_lowercase())
+        } else {
+            (value.clone(), selector.value.clone())
+        };
+        // an empty value can never be included in, start, end or be part of another
+        match selector.operator {
+            AttributeOperator::Exists => true,
+            AttributeOperator::Equals => value == wanted,
+            AttributeOperator::Includes => {
+                !wanted.is_empty()
+                    && !wanted.contains(|c: char| c.is_ascii_whitespace())
+                    && value.split_ascii_whitespace().any(|v| v == wanted)
+            }
+            AttributeOperator::DashMatch => {
+                value == wanted || value.starts_with(&format!("{}-", wanted))
+            }
+            AttributeOperator::Prefix => !wanted.is_empty() && value.starts_with(&wanted),
+            AttributeOperator::Suffix => !wanted.is_empty() && value.ends_with(&wanted),
+            AttributeOperator::Substring => !wanted.is_empty() && value.contains(&wanted),
         }
-        false
     }
     pub fn pretty_print(node: &'a StyledNode, indent_size: usize) {
         let indent = (0..indent_size).map(|_| " ").collect::<String>();
-        println!("{}{:?}", indent, node);
+        match node.node.span() {
+            Some(span) => println!("{}{:?}  @{}", indent, node.node, span),
+            None => println!("{}{:?}", indent, node.node),
+        }
+        for declaration in node.styles.values() {
+            println!("{}  {:?}  @{}", indent, declaration, declaration.span);
+        }
         for child in node.children.iter() {
             Self::pretty_print(child, indent_size + 2);
         }
@@ -119,6 +485,212 @@ impl<'a> StyledNode<'a> {
 
 impl<'a> fmt::Debug for StyledNode<'a> {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        write!(f, "{:?}: {:?}", self.node, self.styles)
+        let values: HashMap<_, _> = self.styles.iter().map(|(k, d)| (k, &d.value)).collect();
+        write!(f, "{:?}: {:?}", self.node, values)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::css_parser::CssParser;
+    use crate::dom::Document;
+    use crate::html_parser::HtmlParser;
+
+    fn styled<'a>(document: &'a Document, id: &str, sheets: &'a [StyleSheet]) -> StyledNode<'a> {
+        let id = document.get_element_by_id(id).unwrap();
+        StyledNode::new(document.tree().get(id), sheets)
+    }
+
+    // the ids of the elements `selector` matches, in document order
+    fn select(document: &Document, selector: &str) -> Vec<String> {
+        let selectors = CssParser::new(selector).parse_selector_list().unwrap();
+        let tree = document.tree();
+        tree.pre_order(document.root())
+            .filter(|&id| {
+                let node = tree.get(id);
+                selectors.iter().any(|s| StyledNode::selector_matches(node, s))
+            })
+            .filter_map(|id| tree.element(id).and_then(|e| e.get_id().cloned()))
+            .collect()
+    }
+
+    #[test]
+    fn type_selectors_ignore_case_for_html_elements_only() {
+        let document = HtmlParser::new(
+            "<div id=d></div><svg id=s><foreignObject id=f></foreignObject></svg>",
+        )
+        .parse_document();
+        assert_eq!(select(&document, "DIV"), ["d"]);
+        assert_eq!(select(&document, "foreignObject"), ["f"]);
+        assert!(select(&document, "foreignobject").is_empty());
+        assert!(select(&document, "SVG").is_empty());
+    }
+
+    #[test]
+    fn closed_dialogs_are_not_displayed() {
+        let document =
+            HtmlParser::new("<dialog id=closed></dialog><dialog id=open open></dialog>")
+                .parse_document();
+        assert!(matches!(styled(&document, "closed", &[]).get_display(), Display::None));
+        assert!(matches!(styled(&document, "open", &[]).get_display(), Display::Block));
+    }
+
+    #[test]
+    fn author_rules_win_over_the_user_agent_sheet() {
+        let document = HtmlParser::new("<div id=h hidden></div>").parse_document();
+        let sheets = [CssParser::new("div { display: inline }").parse_stylesheet()];
+        assert!(matches!(styled(&document, "h", &sheets).get_display(), Display::Inline));
+    }
+
+    #[test]
+    fn hidden_elements_are_not_displayed() {
+        // [hidden] comes before the div rule in the user-agent sheet
+        let document = HtmlParser::new("<div id=h hidden></div>").parse_document();
+        assert!(matches!(styled(&document, "h", &[]).get_display(), Display::None));
+    }
+
+    #[test]
+    fn more_specific_rules_win_then_later_ones() {
+        let document = HtmlParser::new("<p id=a class=x></p><p id=b></p>").parse_document();
+        let css = "#a { margin-top: 1px } p.x { margin-top: 2px } p { margin-top: 3px }
+                   p { margin-top: 4px }";
+        let sheets = [CssParser::new(css).parse_stylesheet()];
+        assert_eq!(styled(&document, "a", &sheets).num_or("margin-top", 0.), 1.);
+        assert_eq!(styled(&document, "b", &sheets).num_or("margin-top", 0.), 4.);
+    }
+
+    #[test]
+    fn important_declarations_win_over_normal_ones() {
+        let document = HtmlParser::new("<p id=a class=x></p><p id=b></p>").parse_document();
+        let css = "#a { margin-top: 1px } p { margin-top: 2px !important }
+                   p.x { margin-top: 3px ! IMPORTANT; margin-bottom: 4px }
+                   #b { margin-top: 5px } p { margin-bottom: 6px !important }";
+        let sheets = [CssParser::new(css).parse_stylesheet()];
+        let a = styled(&document, "a", &sheets);
+        assert_eq!(a.num_or("margin-top", 0.), 3.);
+        assert_eq!(a.num_or("margin-bottom", 0.), 6.);
+        let b = styled(&document, "b", &sheets);
+        assert_eq!(b.num_or("margin-top", 0.), 2.);
+        // an important declaration beats the user-agent sheet's normal ones
+        let document = HtmlParser::new("<div id=d hidden></div>").parse_document();
+        let sheets = [CssParser::new("div { display: block !important }").parse_stylesheet()];
+        assert!(matches!(styled(&document, "d", &sheets).get_display(), Display::Block));
+    }
+
+    #[test]
+    fn where_adds_no_specificity() {
+        let document = HtmlParser::new("<p id=b></p>").parse_document();
+        let css = "p:is(#b) { margin-top: 1px } :where(#b) { margin-top: 2px }
+                   p { margin-top: 3px }";
+        let sheets = [CssParser::new(css).parse_stylesheet()];
+        assert_eq!(styled(&document, "b", &sheets).num_or("margin-top", 0.), 1.);
+        let css = ":where(#b) { margin-top: 2px } p { margin-top: 3px }";
+        let sheets = [CssParser::new(css).parse_stylesheet()];
+        assert_eq!(styled(&document, "b", &sheets).num_or("margin-top", 0.), 3.);
+    }
+
+    #[test]
+    fn combinators() {
+        let document = HtmlParser::new(
+            "<nav id=n><ul id=u><li id=l1><a id=a1></a></li><li id=l2></li><li id=l3></li></ul>\
+             </nav><a id=a2></a>",
+        )
+        .parse_document();
+        assert_eq!(select(&document, "nav a"), ["a1"]);
+        assert_eq!(select(&document, "ul > li"), ["l1", "l2", "l3"]);
+        assert!(select(&document, "nav > li").is_empty());
+        assert_eq!(select(&document, "#l1 + li"), ["l2"]);
+        assert_eq!(select(&document, "#l1 ~ li"), ["l2", "l3"]);
+        assert_eq!(select(&document, "nav ~ a, body > nav ul"), ["u", "a2"]);
+    }
+
+    #[test]
+    fn attribute_selectors() {
+        let document = HtmlParser::new(
+            "<a id=a href='https://x.org/a.PDF' lang=en-GB class='big red'></a>\
+             <input id=i type=Checkbox>",
+        )
+        .parse_document();
+        assert_eq!(select(&document, "[href]"), ["a"]);
+        assert_eq!(select(&document, "[href^='https:']"), ["a"]);
+        assert_eq!(select(&document, "[href$='.pdf']"), Vec::<String>::new());
+        assert_eq!(select(&document, "[href$='.pdf' i]"), ["a"]);
+        assert_eq!(select(&document, "[href*='x.org']"), ["a"]);
+        assert_eq!(select(&document, "[lang|=en]"), ["a"]);
+        assert_eq!(select(&document, "[class~=red]"), ["a"]);
+        assert!(select(&document, "[class~=re]").is_empty());
+        // the type attribute of input is matched case-insensitively unless asked not to
+        assert_eq!(select(&document, "input[type=checkbox]"), ["i"]);
+        assert!(select(&document, "input[type=checkbox s]").is_empty());
+    }
+
+    #[test]
+    fn structural_pseudo_classes() {
+        let document = HtmlParser::new(
+            "<ul id=u><li id=l1></li><li id=l2 class=x></li><li id=l3></li><li id=l4 class=x>\
+             </li></ul><p id=p1></p><div id=d></div><p id=p2>t</p>",
+        )
+        .parse_document();
+        assert_eq!(select(&document, "li:first-child, li:last-child"), ["l1", "l4"]);
+        assert_eq!(select(&document, "li:nth-child(2n+1)"), ["l1", "l3"]);
+        assert_eq!(select(&document, "li:nth-last-child(-n+2)"), ["l3", "l4"]);
+        assert_eq!(select(&document, ":nth-child(2 of .x)"), ["l4"]);
+        assert_eq!(select(&document, "p:first-of-type, p:last-of-type"), ["p1", "p2"]);
+        assert_eq!(select(&document, "div:only-of-type"), ["d"]);
+        assert_eq!(select(&document, "body > :empty"), ["p1", "d"]);
+        assert_eq!(select(&document, ":root > body > ul"), ["u"]);
+    }
+
+    #[test]
+    fn logical_pseudo_classes() {
+        let document = HtmlParser::new(
+            "<div id=d1><img></div><div id=d2><p><img></p></div><div id=d3></div><p id=p></p>",
+        )
+        .parse_document();
+        assert_eq!(select(&document, "div:has(> img)"), ["d1"]);
+        assert_eq!(select(&document, "div:has(img)"), ["d1", "d2"]);
+        assert_eq!(select(&document, "div:not(:has(img))"), ["d3"]);
+        assert_eq!(select(&document, ":is(#d1, #p)"), ["d1", "p"]);
+        assert_eq!(select(&document, "div:where(:empty)"), ["d3"]);
+        assert!(select(&document, "div:has(+ div)").contains(&"d1".to_owned()));
+    }
+
+    #[test]
+    fn dynamic_pseudo_classes_follow_the_element_states() {
+        let document = HtmlParser::new(
+            "<div id=d><a id=a href=x></a><input id=c type=checkbox checked><button id=b>\
+             </button></div><fieldset disabled><input id=f></fieldset>",
+        )
+        .parse_document();
+        let tree = document.tree();
+        let id = |id: &str| document.get_element_by_id(id).unwrap();
+        let select_in = |states: &ElementStates, selector: &str| -> Vec<String> {
+            let selectors = CssParser::new(selector).parse_selector_list().unwrap();
+            let cx = MatchContext {
+                states,
+                scope: None,
+            };
+            tree.pre_order(document.root())
+                .filter(|&n| StyledNode::matches_any(tree.get(n), &selectors, cx))
+                .filter_map(|n| tree.element(n).and_then(|e| e.get_id().cloned()))
+                .collect()
+        };
+        let mut states = ElementStates::new();
+        assert_eq!(select_in(&states, ":link"), ["a"]);
+        assert_eq!(select_in(&states, ":checked"), ["c"]);
+        assert_eq!(select_in(&states, ":disabled"), ["f"]);
+        assert!(select_in(&states, ":hover").is_empty());
+
+        states.set_hovered(tree, Some(id("a")));
+        states.set_focused(tree, Some(id("b")));
+        states.set_visited(id("a"), true);
+        states.toggle_checked(tree, id("c"));
+        assert_eq!(select_in(&states, "div:hover, a:hover"), ["d", "a"]);
+        assert_eq!(select_in(&states, ":focus"), ["b"]);
+        assert_eq!(select_in(&states, "div:focus-within"), ["d"]);
+        assert_eq!(select_in(&states, ":visited"), ["a"]);
+        assert!(select_in(&states, ":link").is_empty());
+        assert!(select_in(&states, ":checked").is_empty());
     }
 }
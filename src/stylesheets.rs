@@ -0,0 +1,451 @@
+use crate::css::StyleSheet;
+use crate::css_parser::CssParser;
+use crate::dom::{Document, ElementData, NodeId};
+use crate::encoding;
+use crate::source::SourcePosition;
+use encoding_rs::Encoding;
+use std::sync::OnceLock;
+use url::Url;
+
+/// A fetched stylesheet: its bytes and the `Content-Type` it was served with.
+pub struct Resource {
+    pub bytes: Vec<u8>,
+    pub content_type: Option<String>,
+}
+
+/// The screen a document is shown on, which `media` attributes and the media
+/// lists of `@import` rules are matched against. Lengths are in css pixels.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Media {
+    pub width: f32,
+    pub height: f32,
+}
+
+impl Media {
+    pub fn screen(width: f32, height: f32) -> Self {
+        Self { width, height }
+    }
+
+    /// Whether a media query list, such as `screen and (min-width: 600px), print`,
+    /// matches. An empty list matches everything; a query this does not understand
+    /// matches nothing.
+    // https://drafts.csswg.org/mediaqueries-4/#mq-list
+    pub fn matches(&self, list: &str) -> bool {
+        if list.trim().is_empty() {
+            return true;
+        }
+        list.split(',').any(|query| self.matches_query(query))
+    }
+
+    fn matches_query(&self, query: &str) -> bool {
+        let query = query.trim().to_ascii_lowercase();
+        let (negated, mut rest) = match query.split_once(|c: char| c.is_ascii_whitespace()) {
+            Some(("not", rest)) => (true, rest),
+            Some(("only", rest)) => (false, rest),
+            _ => (false, &query[..]),
+        };
+        let mut matches = true;
+        loop {
+            rest = rest.trim_start();
+            if rest.is_empty() {
+                break;
+            }
+            if let Some(feature) = rest.strip_prefix('(') {
+                let (feature, after) = match feature.split_once(')') {
+                    Some(split) => split,
+                    None => return false,
+                };
+                matches &= match self.matches_feature(feature) {
+                    Some(m) => m,
+                    None => return false,
+                };
+                rest = after;
+                continue;
+            }
+            let end = rest
+                .find(|c: char| c.is_ascii_whitespace() || c == '(')
+                .unwrap_or(rest.len());
+            match &rest[..end] {
+                "and" => {}
+                "all" | "screen" => {}
+                // print, speech and the media types deprecated in css 2
+                "print" | "speech" | "tty" | "tv" | "projection" | "handheld" | "braille"
+                | "embossed" | "aural" => matches = false,
+                _ => return false,
+            }
+            rest = &rest[end..];
+        }
+        matches != negated
+    }
+
+    // None for a feature or value it does not know
+    fn matches_feature(&self, feature: &str) -> Option<bool> {
+        let (name, value) = match feature.split_once(':') {
+            Some((name, value)) => (name.trim(), Some(value.trim())),
+            None => (feature.trim(), None),
+        };
+        let value = match value {
+            Some(value) => value,
+            // https://drafts.csswg.org/mediaqueries-4/#mq-boolean-context
+            None => return matches!(name, "width" | "height" | "color").then(|| true),
+        };
+        let matches = match name {
+            "width" => self.width == Self::length(value)?,
+            "min-width" => self.width >= Self::length(value)?,
+            "max-width" => self.width <= Self::length(value)?,
+            "height" => self.height == Self::length(value)?,
+            "min-height" => self.height >= Self::length(value)?,
+            "max-height" => self.height <= Self::length(value)?,
+            "orientation" => match value {
+                "portrait" => self.height >= self.width,
+                "landscape" => self.width > self.height,
+                _ => return None,
+            },
+            _ => return None,
+        };
+        Some(matches)
+    }
+
+    // relative units are against the initial font size, as media queries are
+    // https://drafts.csswg.org/mediaqueries-4/#units
+    fn length(value: &str) -> Option<f32> {
+        let end = value
+            .find(|c: char| !(c.is_ascii_digit() || c == '.' || c == '-' || c == '+'))
+            .unwrap_or(value.len());
+        let number: f32 = value[..end].parse().ok()?;
+        match &value[end..] {
+            "px" => Some(number),
+            "" if number == 0. => Some(0.),
+            "em" | "rem" => Some(number * 16.),
+            _ => None,
+        }
+    }
+}
+
+/// The built-in stylesheet that gives elements their default rendering, such as
+/// `display: block` for `div` and the margins of `p`. It is the lowest origin of
+/// the cascade, below every sheet that `collect` returns. Lengths the spec gives
+/// in `em` are written in pixels at the default 16px font size, as layout does not
+/// resolve font-relative units yet.
+// https://html.spec.whatwg.org/multipage/rendering.html
+pub fn user_agent() -> &'static StyleSheet {
+    static USER_AGENT: OnceLock<StyleSheet> = OnceLock::new();
+    USER_AGENT.get_or_init(|| CssParser::new(include_str!("user_agent.css")).parse_stylesheet())
+}
+
+/// Reads a stylesheet from a `file:` url, or downloads one over http. None for
+/// other schemes, for a file that cannot be read and for a response that is not
+/// a success.
+pub fn fetch(url: &Url) -> Option<Resource> {
+    match url.scheme() {
+        "file" => {
+            let bytes = std::fs::read(url.to_file_path().ok()?).ok()?;
+            Some(Resource {
+                bytes,
+                content_type: None,
+            })
+        }
+        "http" | "https" => {
+            let res = reqwest::blocking::get(url.as_str()).ok()?;
+            if !res.status().is_success() {
+                return None;
+            }
+            let content_type = res
+                .headers()
+                .get(reqwest::header::CONTENT_TYPE)
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_owned);
+            let bytes = res.bytes().ok()?.to_vec();
+            Some(Resource {
+                bytes,
+                content_type,
+            })
+        }
+        _ => None,
+    }
+}
+
+/// The stylesheets that apply to `document`, in the order the cascade takes them:
+/// every `<link rel=stylesheet>` and `<style>` in tree order, each preceded by the
+/// sheets it `@import`s. Sheets whose media list does not match `media`, disabled
+/// links and links that `fetch` cannot load are left out.
+// https://html.spec.whatwg.org/multipage/semantics.html#interactions-of-styling-and-scripting
+pub fn collect<F>(document: &Document, media: &Media, fetch: F) -> Vec<StyleSheet>
+where
+    F: FnMut(&Url) -> Option<Resource>,
+{
+    let mut collector = Collector {
+        media,
+        fetch,
+        loading: Vec::new(),
+        sheets: Vec::new(),
+    };
+    let tree = document.tree();
+    let base = document.base_url();
+    for id in tree.pre_order(document.root()) {
+        let element = match tree.element(id) {
+            Some(e) if e.is_html() => e,
+            _ => continue,
+        };
+        if !media.matches(element.get_attribute("media").map_or("", |m| &m[..])) {
+            continue;
+        }
+        match &element.tag_name[..] {
+            "style" if is_css(element) => {
+                // parsed where it stands so rule spans point into the html source
+                let start = tree
+                    .first_child(id)
+                    .and_then(|c| tree.span(c))
+                    .map(|s| s.start)
+                    .unwrap_or_default();
+                collector.add(
+                    &tree.text_content(id),
+                    start,
+                    base.as_ref(),
+                    document.encoding(),
+                );
+            }
+            "link" if is_stylesheet_link(element) && is_css(element) => {
+                collector.link(document, id);
+            }
+            _ => {}
+        }
+    }
+    collector.sheets
+}
+
+// https://html.spec.whatwg.org/multipage/links.html#link-type-stylesheet
+// alternative stylesheets are only used when the user picks one, which cannot
+// be done here
+fn is_stylesheet_link(element: &ElementData) -> bool {
+    let rel = match element.get_attribute("rel") {
+        Some(rel) => rel.to_ascii_lowercase(),
+        None => return false,
+    };
+    let mut tokens = rel.split_ascii_whitespace();
+    tokens.clone().any(|t| t == "stylesheet")
+        && !tokens.any(|t| t == "alternate")
+        && !element.has_attribute("disabled")
+}
+
+// a `type` that is given has to name css
+fn is_css(element: &ElementData) -> bool {
+    element.get_attribute("type").is_none_or(|t| {
+        let essence = t.split(';').next().unwrap_or_default().trim();
+        essence.is_empty() || essence.eq_ignore_ascii_case("text/css")
+    })
+}
+
+struct Collector<'m, F> {
+    media: &'m Media,
+    fetch: F,
+    // the urls of the sheets being imported, outermost first, so that an import
+    // cycle is cut where it closes
+    loading: Vec<Url>,
+    sheets: Vec<StyleSheet>,
+}
+
+impl<'m, F> Collector<'m, F>
+where
+    F: FnMut(&Url) -> Option<Resource>,
+{
+    fn link(&mut self, document: &Document, id: NodeId) {
+        let url = document
+            .tree()
+            .element(id)
+            .and_then(|e| e.href())
+            .and_then(|href| document.resolve_url(href));
+        if let Some(url) = url {
+            self.load(url, document.encoding());
+        }
+    }
+
+    // `environment` is the encoding of the document or sheet that refers to it
+    fn load(&mut self, url: Url, environment: &'static Encoding) {
+        if self.loading.contains(&url) {
+            return;
+        }
+        let resource = match (self.fetch)(&url) {
+            Some(resource) => resource,
+            None => return,
+        };
+        let content_type = resource.content_type.as_deref();
+        let (css, encoding) =
+            encoding::decode_css(&resource.bytes, content_type, Some(environment));
+        self.loading.push(url);
+        let base = self.loading.last().cloned();
+        self.add(&css, SourcePosition::default(), base.as_ref(), encoding);
+        self.loading.pop();
+    }
+
+    // `base` is what the urls of the sheet's @import rules are relative to, and
+    // `encoding` what the sheets they name fall back to
+    fn add(
+        &mut self,
+        css: &str,
+        start: SourcePosition,
+        base: Option<&Url>,
+        encoding: &'static Encoding,
+    ) {
+        let mut sheet = CssParser::new_at(css, start).parse_stylesheet();
+        let media = self.media;
+        sheet
+            .rules
+            .retain(|rule| rule.media.iter().all(|m| media.matches(m)));
+        for import in &sheet.imports {
+            if !self.media.matches(&import.media) {
+                continue;
+            }
+            let url = match base {
+                Some(base) => base.join(&import.url).ok(),
+                None => Url::parse(&import.url).ok(),
+            };
+            if let Some(url) = url {
+                self.load(url, encoding);
+            }
+        }
+        self.sheets.push(sheet);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::css::Value;
+    use crate::html_stream;
+
+    fn font_family(sheet: &StyleSheet) -> String {
+        match sheet.rules[0].declarations[0].value {
+            Value::Other(ref family) => family.clone(),
+            ref value => panic!("{:?}", value),
+        }
+    }
+
+    #[test]
+    fn sheets_fall_back_to_the_encoding_of_what_refers_to_them() {
+        let html = b"<meta charset=windows-1252><link rel=stylesheet href=a.css><p>";
+        let mut document = html_stream::parse_chunks([html], None, |_| {});
+        document.set_url(Url::parse("http://example.com/").ok());
+        let fetch = |url: &Url| {
+            let (css, content_type): (&[u8], _) = match url.path() {
+                "/a.css" => (b"p { font-family: \xe9 }", None),
+                "/b.css" => (
+                    b"@import 'c.css'; p { font-family: \xe9 }",
+                    Some("text/css; charset=iso-8859-5"),
+                ),
+                "/c.css" => (b"p { font-family: \xe9 }", None),
+                _ => return None,
+            };
+            Some(Resource {
+                bytes: css.to_vec(),
+                content_type: content_type.map(str::to_owned),
+            })
+        };
+        let sheets = collect(&document, &Media::screen(800., 600.), fetch);
+        assert_eq!(sheets.len(), 1);
+        assert_eq!(font_family(&sheets[0]), "é");
+
+        let html = b"<meta charset=windows-1252><link rel=stylesheet href=b.css><p>";
+        let mut document = html_stream::parse_chunks([html], None, |_| {});
+        document.set_url(Url::parse("http://example.com/").ok());
+        let sheets = collect(&document, &Media::screen(800., 600.), fetch);
+        // c.css is decoded like b.css, which imports it, and not like the document
+        let families: Vec<String> = sheets.iter().map(font_family).collect();
+        assert_eq!(families, ["щ", "щ"]);
+    }
+
+    #[test]
+    fn media_rules_apply_only_when_their_media_matches() {
+        let html = "<style>a { color: red } @media (max-width: 600px) { b { color: red }
+                    @media print { c { color: red } } } @media screen { d { color: red } }
+                    </style>";
+        let document = html_stream::parse_chunks([html.as_bytes()], None, |_| {});
+        let selectors = |media: Media| -> Vec<String> {
+            let sheets = collect(&document, &media, |_| None);
+            let rules = sheets.iter().flat_map(|s| &s.rules);
+            rules.map(|r| format!("{:?}", r.selectors[0])).collect()
+        };
+        assert_eq!(selectors(Media::screen(800., 600.)), ["a", "d"]);
+        assert_eq!(selectors(Media::screen(500., 600.)), ["a", "b", "d"]);
+    }
+
+    // the selectors of the rules of each sheet `collect` finds for `html`, which
+    // links to `files` by their paths on example.com
+    fn collected(html: &str, media: Media, files: &[(&str, &str)]) -> Vec<String> {
+        let mut document = html_stream::parse_chunks([html.as_bytes()], None, |_| {});
+        document.set_url(Url::parse("http://example.com/").ok());
+        let fetch = |url: &Url| {
+            let (_, css) = files.iter().find(|(path, _)| *path == url.path())?;
+            Some(Resource {
+                bytes: css.as_bytes().to_vec(),
+                content_type: None,
+            })
+        };
+        let sheets = collect(&document, &media, fetch);
+        let selectors = |sheet: &StyleSheet| {
+            let rules = sheet.rules.iter().map(|r| format!("{:?}", r.selectors[0]));
+            rules.collect::<Vec<_>>().join(" ")
+        };
+        sheets.iter().map(selectors).collect()
+    }
+
+    fn screen() -> Media {
+        Media::screen(800., 600.)
+    }
+
+    #[test]
+    fn sheets_are_in_document_order() {
+        let html = "<link rel=stylesheet href=/a.css><style>b {}</style>\
+                    <p><link rel='Preload StyleSheet' href=/c.css><style>d {} e {}</style>";
+        let files = [("/a.css", "a {}"), ("/c.css", "c {}")];
+        assert_eq!(collected(html, screen(), &files), ["a", "b", "c", "d e"]);
+    }
+
+    #[test]
+    fn sheets_that_do_not_apply_are_left_out() {
+        let html = "<style media=print>a {}</style><style media='screen and (min-width: 900px)'>\
+                    b {}</style><style media='(max-width: 900px)'>c {}</style>\
+                    <style type=text/less>d {}</style><style type='text/css; charset=utf-8'>\
+                    e {}</style><link rel=stylesheet href=/f.css disabled>\
+                    <link rel='alternate stylesheet' href=/g.css><link rel=icon href=/h.css>\
+                    <link rel=stylesheet href=/i.css type=text/plain>\
+                    <link rel=stylesheet href=/missing.css><link rel=stylesheet href=/j.css>";
+        let files = [
+            ("/f.css", "f {}"),
+            ("/g.css", "g {}"),
+            ("/h.css", "h {}"),
+            ("/i.css", "i {}"),
+            ("/j.css", "j {}"),
+        ];
+        assert_eq!(collected(html, screen(), &files), ["c", "e", "j"]);
+        let wide = Media::screen(1000., 600.);
+        assert_eq!(collected(html, wide, &files), ["b", "e", "j"]);
+    }
+
+    #[test]
+    fn imported_sheets_go_before_the_sheet_that_imports_them() {
+        let html = "<style>@import '/a.css'; @import url(/b.css) print; s {}</style>\
+                    <link rel=stylesheet href=/c.css>";
+        let files = [
+            ("/a.css", "@import 'd/e.css'; a {}"),
+            ("/b.css", "b {}"),
+            ("/c.css", "@import '/a.css'; c {}"),
+            // relative to the sheet that imports it
+            ("/d/e.css", "e {}"),
+        ];
+        assert_eq!(
+            collected(html, screen(), &files),
+            ["e", "a", "s", "e", "a", "c"]
+        );
+    }
+
+    #[test]
+    fn import_cycles_are_cut_where_they_close() {
+        let html = "<link rel=stylesheet href=/a.css>";
+        let files = [
+            ("/a.css", "@import 'b.css'; a {}"),
+            ("/b.css", "@import 'a.css'; @import 'b.css'; b {}"),
+        ];
+        assert_eq!(collected(html, screen(), &files), ["b", "a"]);
+    }
+}
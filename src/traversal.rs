@@ -0,0 +1,744 @@
+use crate::dom::{Node, NodeId, NodeRef, NodeType, Tree};
+use std::collections::VecDeque;
+use std::ops::BitOr;
+
+/// Walks an owned tree from `root` down, each node before its children.
+pub struct PreOrder<'a> {
+    stack: Vec<&'a Node>,
+}
+
+impl<'a> PreOrder<'a> {
+    pub fn new(root: &'a Node) -> Self {
+        Self { stack: vec![root] }
+    }
+}
+
+impl<'a> Iterator for PreOrder<'a> {
+    type Item = &'a Node;
+
+    fn next(&mut self) -> Option<&'a Node> {
+        let node = self.stack.pop()?;
+        self.stack.extend(node.children.iter().rev());
+        Some(node)
+    }
+}
+
+/// Walks an owned tree from `root` down, each node after its children.
+pub struct PostOrder<'a> {
+    // each node with the number of its children already walked
+    stack: Vec<(&'a Node, usize)>,
+}
+
+impl<'a> PostOrder<'a> {
+    pub fn new(root: &'a Node) -> Self {
+        Self {
+            stack: vec![(root, 0)],
+        }
+    }
+}
+
+impl<'a> Iterator for PostOrder<'a> {
+    type Item = &'a Node;
+
+    fn next(&mut self) -> Option<&'a Node> {
+        loop {
+            let (node, walked) = self.stack.last_mut()?;
+            let node = *node;
+            match node.children.get(*walked) {
+                Some(child) => {
+                    *walked += 1;
+                    self.stack.push((child, 0));
+                }
+                None => {
+                    self.stack.pop();
+                    return Some(node);
+                }
+            }
+        }
+    }
+}
+
+/// Walks an owned tree level by level from `root`.
+pub struct BreadthFirst<'a> {
+    queue: VecDeque<&'a Node>,
+}
+
+impl<'a> BreadthFirst<'a> {
+    pub fn new(root: &'a Node) -> Self {
+        Self {
+            queue: VecDeque::from(vec![root]),
+        }
+    }
+}
+
+impl<'a> Iterator for BreadthFirst<'a> {
+    type Item = &'a Node;
+
+    fn next(&mut self) -> Option<&'a Node> {
+        let node = self.queue.pop_front()?;
+        self.queue.extend(node.children.iter());
+        Some(node)
+    }
+}
+
+/// `PreOrder` for the subtree of a `Tree` at `root`, which is tree order.
+pub struct TreePreOrder<'a> {
+    tree: &'a Tree,
+    root: NodeId,
+    next: Option<NodeId>,
+}
+
+impl<'a> TreePreOrder<'a> {
+    pub fn new(tree: &'a Tree, root: NodeId) -> Self {
+        Self {
+            tree,
+            root,
+            next: Some(root),
+        }
+    }
+}
+
+impl<'a> Iterator for TreePreOrder<'a> {
+    type Item = NodeId;
+
+    fn next(&mut self) -> Option<NodeId> {
+        let current = self.next?;
+        self.next = following(self.tree, self.root, current);
+        Some(current)
+    }
+}
+
+/// `PostOrder` for the subtree of a `Tree` at `root`.
+pub struct TreePostOrder<'a> {
+    tree: &'a Tree,
+    root: NodeId,
+    next: Option<NodeId>,
+}
+
+impl<'a> TreePostOrder<'a> {
+    pub fn new(tree: &'a Tree, root: NodeId) -> Self {
+        Self {
+            tree,
+            root,
+            next: Some(first_leaf(tree, root)),
+        }
+    }
+}
+
+impl<'a> Iterator for TreePostOrder<'a> {
+    type Item = NodeId;
+
+    fn next(&mut self) -> Option<NodeId> {
+        let current = self.next?;
+        self.next = if current == self.root {
+            None
+        } else {
+            match self.tree.next_sibling(current) {
+                Some(sibling) => Some(first_leaf(self.tree, sibling)),
+                None => self.tree.parent(current),
+            }
+        };
+        Some(current)
+    }
+}
+
+/// `BreadthFirst` for the subtree of a `Tree` at `root`.
+pub struct TreeBreadthFirst<'a> {
+    tree: &'a Tree,
+    queue: VecDeque<NodeId>,
+}
+
+impl<'a> TreeBreadthFirst<'a> {
+    pub fn new(tree: &'a Tree, root: NodeId) -> Self {
+        Self {
+            tree,
+            queue: VecDeque::from(vec![root]),
+        }
+    }
+}
+
+impl<'a> Iterator for TreeBreadthFirst<'a> {
+    type Item = NodeId;
+
+    fn next(&mut self) -> Option<NodeId> {
+        let node = self.queue.pop_front()?;
+        self.queue.extend(self.tree.children(node));
+        Some(node)
+    }
+}
+
+// the node after `node` in tree order, without leaving the subtree at `root`
+fn following(tree: &Tree, root: NodeId, node: NodeId) -> Option<NodeId> {
+    if let Some(child) = tree.first_child(node) {
+        return Some(child);
+    }
+    let mut current = node;
+    while current != root {
+        if let Some(sibling) = tree.next_sibling(current) {
+            return Some(sibling);
+        }
+        current = tree.parent(current)?;
+    }
+    None
+}
+
+// the node before `node` in tree order, without leaving the subtree at `root`
+fn preceding(tree: &Tree, root: NodeId, node: NodeId) -> Option<NodeId> {
+    if node == root {
+        return None;
+    }
+    match tree.previous_sibling(node) {
+        Some(mut sibling) => {
+            while let Some(child) = tree.last_child(sibling) {
+                sibling = child;
+            }
+            Some(sibling)
+        }
+        None => tree.parent(node),
+    }
+}
+
+fn first_leaf(tree: &Tree, mut node: NodeId) -> NodeId {
+    while let Some(child) = tree.first_child(node) {
+        node = child;
+    }
+    node
+}
+
+/// Which kinds of node a `TreeWalker` or `NodeIterator` stops at; combine them with `|`.
+// https://dom.spec.whatwg.org/#interface-nodefilter
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WhatToShow(u32);
+
+impl WhatToShow {
+    pub const ALL: WhatToShow = WhatToShow(0xFFFF_FFFF);
+    pub const ELEMENT: WhatToShow = WhatToShow(0x1);
+    pub const TEXT: WhatToShow = WhatToShow(0x4);
+    pub const COMMENT: WhatToShow = WhatToShow(0x80);
+    pub const DOCUMENT: WhatToShow = WhatToShow(0x100);
+
+    pub fn shows(&self, node_type: &NodeType) -> bool {
+        let bit = match node_type {
+            NodeType::Element(_) => Self::ELEMENT,
+            NodeType::Text(_) => Self::TEXT,
+            NodeType::Comment(_) => Self::COMMENT,
+            NodeType::Document => Self::DOCUMENT,
+        };
+        self.0 & bit.0 != 0
+    }
+}
+
+impl BitOr for WhatToShow {
+    type Output = WhatToShow;
+
+    fn bitor(self, other: WhatToShow) -> WhatToShow {
+        WhatToShow(self.0 | other.0)
+    }
+}
+
+/// What a filter makes of a node. `Reject` also hides the node's descendants from
+/// a `TreeWalker`; to a `NodeIterator` it is the same as `Skip`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilterResult {
+    Accept,
+    Reject,
+    Skip,
+}
+
+type NodeFilter<'a> = Box<dyn Fn(NodeRef<'a>) -> FilterResult + 'a>;
+
+struct Filter<'a> {
+    what_to_show: WhatToShow,
+    filter: Option<NodeFilter<'a>>,
+}
+
+impl<'a> Filter<'a> {
+    fn apply(&self, tree: &'a Tree, id: NodeId) -> FilterResult {
+        let node = tree.get(id);
+        if !self.what_to_show.shows(node.node_type()) {
+            return FilterResult::Skip;
+        }
+        match self.filter {
+            Some(ref filter) => filter(node),
+            None => FilterResult::Accept,
+        }
+    }
+}
+
+/// Moves a current node around the subtree at `root`, seeing only the nodes the
+/// filter accepts as if the others were not there.
+// https://dom.spec.whatwg.org/#interface-treewalker
+pub struct TreeWalker<'a> {
+    tree: &'a Tree,
+    root: NodeId,
+    current: NodeId,
+    filter: Filter<'a>,
+}
+
+impl<'a> TreeWalker<'a> {
+    pub fn new(tree: &'a Tree, root: NodeId, what_to_show: WhatToShow) -> Self {
+        Self {
+            tree,
+            root,
+            current: root,
+            filter: Filter {
+                what_to_show,
+                filter: None,
+            },
+        }
+    }
+
+    /// Also asks `filter` about every node `what_to_show` lets through.
+    pub fn with_filter<F>(mut self, filter: F) -> Self
+    where
+        F: Fn(NodeRef<'a>) -> FilterResult + 'a,
+    {
+        self.filter.filter = Some(Box::new(filter));
+        self
+    }
+
+    pub fn root(&self) -> NodeId {
+        self.root
+    }
+
+    pub fn current_node(&self) -> NodeId {
+        self.current
+    }
+
+    pub fn set_current_node(&mut self, node: NodeId) {
+        self.current = node;
+    }
+
+    pub fn parent_node(&mut self) -> Option<NodeId> {
+        let mut node = self.current;
+        while node != self.root {
+            node = self.tree.parent(node)?;
+            if self.accepts(node) {
+                self.current = node;
+                return Some(node);
+            }
+        }
+        None
+    }
+
+    pub fn first_child(&mut self) -> Option<NodeId> {
+        self.traverse_children(true)
+    }
+
+    pub fn last_child(&mut self) -> Option<NodeId> {
+        self.traverse_children(false)
+    }
+
+    pub fn next_sibling(&mut self) -> Option<NodeId> {
+        self.traverse_siblings(true)
+    }
+
+    pub fn previous_sibling(&mut self) -> Option<NodeId> {
+        self.traverse_siblings(false)
+    }
+
+    pub fn previous_node(&mut self) -> Option<NodeId> {
+        let mut node = self.current;
+        while node != self.root {
+            while let Some(sibling) = self.tree.previous_sibling(node) {
+                node = sibling;
+                let mut result = self.filter.apply(self.tree, node);
+                while result != FilterResult::Reject {
+                    match self.tree.last_child(node) {
+                        Some(child) => {
+                            node = child;
+                            result = self.filter.apply(self.tree, node);
+                        }
+                        None => break,
+                    }
+                }
+                if result == FilterResult::Accept {
+                    self.current = node;
+                    return Some(node);
+                }
+            }
+            node = self.tree.parent(node)?;
+            if self.accepts(node) {
+                self.current = node;
+                return Some(node);
+            }
+        }
+        None
+    }
+
+    pub fn next_node(&mut self) -> Option<NodeId> {
+        let mut node = self.current;
+        let mut result = FilterResult::Accept;
+        loop {
+            while result != FilterResult::Reject {
+                match self.tree.first_child(node) {
+                    Some(child) => {
+                        node = child;
+                        result = self.filter.apply(self.tree, node);
+                        if result == FilterResult::Accept {
+                            self.current = node;
+                            return Some(node);
+                        }
+                    }
+                    None => break,
+                }
+            }
+            node = self.next_skipping_children(node)?;
+            result = self.filter.apply(self.tree, node);
+            if result == FilterResult::Accept {
+                self.current = node;
+                return Some(node);
+            }
+        }
+    }
+
+    fn accepts(&self, node: NodeId) -> bool {
+        self.filter.apply(self.tree, node) == FilterResult::Accept
+    }
+
+    // the next node in tree order that is not below `node`, within the root
+    fn next_skipping_children(&self, node: NodeId) -> Option<NodeId> {
+        let mut current = node;
+        while current != self.root {
+            if let Some(sibling) = self.tree.next_sibling(current) {
+                return Some(sibling);
+            }
+            current = self.tree.parent(current)?;
+        }
+        None
+    }
+
+    fn traverse_children(&mut self, first: bool) -> Option<NodeId> {
+        let tree = self.tree;
+        let child = |n| {
+            if first {
+                tree.first_child(n)
+            } else {
+                tree.last_child(n)
+            }
+        };
+        let sibling = |n| {
+            if first {
+                tree.next_sibling(n)
+            } else {
+                tree.previous_sibling(n)
+            }
+        };
+
+        let mut node = child(self.current)?;
+        loop {
+            match self.filter.apply(tree, node) {
+                FilterResult::Accept => {
+                    self.current = node;
+                    return Some(node);
+                }
+                FilterResult::Skip => {
+                    if let Some(c) = child(node) {
+                        node = c;
+                        continue;
+                    }
+                }
+                FilterResult::Reject => {}
+            }
+            loop {
+                if let Some(s) = sibling(node) {
+                    node = s;
+                    break;
+                }
+                let parent = tree.parent(node)?;
+                if parent == self.root || parent == self.current {
+                    return None;
+                }
+                node = parent;
+            }
+        }
+    }
+
+    fn traverse_siblings(&mut self, next: bool) -> Option<NodeId> {
+        let tree = self.tree;
+        let child = |n| {
+            if next {
+                tree.first_child(n)
+            } else {
+                tree.last_child(n)
+            }
+        };
+        let sibling = |n| {
+            if next {
+                tree.next_sibling(n)
+            } else {
+                tree.previous_sibling(n)
+            }
+        };
+
+        let mut node = self.current;
+        if node == self.root {
+            return None;
+        }
+        loop {
+            let mut candidate = sibling(node);
+            while let Some(c) = candidate {
+                node = c;
+                let result = self.filter.apply(tree, node);
+                if result == FilterResult::Accept {
+                    self.current = node;
+                    return Some(node);
+                }
+                candidate = child(node);
+                if result == FilterResult::Reject || candidate.is_none() {
+                    candidate = sibling(node);
+                }
+            }
+            node = tree.parent(node)?;
+            if node == self.root || self.accepts(node) {
+                return None;
+            }
+        }
+    }
+}
+
+/// Steps through the nodes of the subtree at `root` in tree order, stopping only at
+/// the ones the filter accepts. It is also an `Iterator` over them.
+// https://dom.spec.whatwg.org/#interface-nodeiterator
+pub struct NodeIterator<'a> {
+    tree: &'a Tree,
+    root: NodeId,
+    reference: NodeId,
+    pointer_before_reference: bool,
+    filter: Filter<'a>,
+}
+
+impl<'a> NodeIterator<'a> {
+    pub fn new(tree: &'a Tree, root: NodeId, what_to_show: WhatToShow) -> Self {
+        Self {
+            tree,
+            root,
+            reference: root,
+            pointer_before_reference: true,
+            filter: Filter {
+                what_to_show,
+                filter: None,
+            },
+        }
+    }
+
+    /// Also asks `filter` about every node `what_to_show` lets through.
+    pub fn with_filter<F>(mut self, filter: F) -> Self
+    where
+        F: Fn(NodeRef<'a>) -> FilterResult + 'a,
+    {
+        self.filter.filter = Some(Box::new(filter));
+        self
+    }
+
+    pub fn root(&self) -> NodeId {
+        self.root
+    }
+
+    pub fn reference_node(&self) -> NodeId {
+        self.reference
+    }
+
+    pub fn pointer_before_reference_node(&self) -> bool {
+        self.pointer_before_reference
+    }
+
+    pub fn next_node(&mut self) -> Option<NodeId> {
+        self.traverse(true)
+    }
+
+    pub fn previous_node(&mut self) -> Option<NodeId> {
+        self.traverse(false)
+    }
+
+    fn traverse(&mut self, next: bool) -> Option<NodeId> {
+        let mut node = self.reference;
+        let mut before = self.pointer_before_reference;
+        loop {
+            if next {
+                if before {
+                    before = false;
+                } else {
+                    node = following(self.tree, self.root, node)?;
+                }
+            } else if before {
+                node = preceding(self.tree, self.root, node)?;
+            } else {
+                before = true;
+            }
+            if self.filter.apply(self.tree, node) == FilterResult::Accept {
+                break;
+            }
+        }
+        self.reference = node;
+        self.pointer_before_reference = before;
+        Some(node)
+    }
+}
+
+impl<'a> Iterator for NodeIterator<'a> {
+    type Item = NodeId;
+
+    fn next(&mut self) -> Option<NodeId> {
+        self.next_node()
+    }
+}
+
+/// Whether a walk driven by a `Visitor` goes on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Visit {
+    Continue,
+    // leave out the children of the node just entered
+    SkipChildren,
+    Stop,
+}
+
+/// A pass over an owned tree: `enter` is called on the way down to each node and
+/// `leave` on the way back up, for every node whose children were not skipped.
+pub trait Visitor {
+    fn enter(&mut self, node: &Node) -> Visit;
+
+    fn leave(&mut self, _node: &Node) {}
+}
+
+/// Runs `visitor` over `node` and everything below it.
+pub fn walk<V: Visitor + ?Sized>(node: &Node, visitor: &mut V) -> Visit {
+    match visitor.enter(node) {
+        Visit::Stop => return Visit::Stop,
+        Visit::SkipChildren => return Visit::Continue,
+        Visit::Continue => {}
+    }
+    for child in &node.children {
+        if walk(child, visitor) == Visit::Stop {
+            return Visit::Stop;
+        }
+    }
+    visitor.leave(node);
+    Visit::Continue
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dom::Document;
+    use crate::html_parser::HtmlParser;
+
+    fn document() -> Document {
+        HtmlParser::new(
+            "<div id=r><p id=a><span id=a1></span>text</p>\
+             <section id=b><em id=b1></em><i id=b2></i></section><p id=c></p></div>",
+        )
+        .parse_document()
+    }
+
+    fn id(document: &Document, id: &str) -> NodeId {
+        document.get_element_by_id(id).unwrap()
+    }
+
+    fn ids(document: &Document, nodes: impl IntoIterator<Item = NodeId>) -> Vec<String> {
+        let tree = document.tree();
+        let id = |n| {
+            tree.element(n)
+                .and_then(|e| e.get_id())
+                .cloned()
+                .unwrap_or_default()
+        };
+        nodes.into_iter().map(id).collect()
+    }
+
+    // a filter that gives `result` for the element with the id `b`
+    fn on_b(result: FilterResult) -> impl Fn(NodeRef) -> FilterResult {
+        move |node| match node.as_element().and_then(|e| e.get_id()) {
+            Some(id) if id == "b" => result,
+            _ => FilterResult::Accept,
+        }
+    }
+
+    fn walker(document: &Document, result: FilterResult) -> TreeWalker<'_> {
+        let root = id(document, "r");
+        TreeWalker::new(document.tree(), root, WhatToShow::ELEMENT).with_filter(on_b(result))
+    }
+
+    #[test]
+    fn walker_skips_a_node_but_rejects_its_subtree() {
+        let document = document();
+        let mut skip = walker(&document, FilterResult::Skip);
+        let forward = std::iter::from_fn(|| skip.next_node()).collect::<Vec<_>>();
+        assert_eq!(ids(&document, forward), ["a", "a1", "b1", "b2", "c"]);
+        let backward = std::iter::from_fn(|| skip.previous_node()).collect::<Vec<_>>();
+        assert_eq!(ids(&document, backward), ["b2", "b1", "a1", "a", "r"]);
+        let mut reject = walker(&document, FilterResult::Reject);
+        let forward = std::iter::from_fn(|| reject.next_node()).collect::<Vec<_>>();
+        assert_eq!(ids(&document, forward), ["a", "a1", "c"]);
+        let backward = std::iter::from_fn(|| reject.previous_node()).collect::<Vec<_>>();
+        assert_eq!(ids(&document, backward), ["a1", "a", "r"]);
+    }
+
+    #[test]
+    fn walker_moves_between_relatives_around_a_skipped_node() {
+        let document = document();
+        let mut walker = walker(&document, FilterResult::Skip);
+        assert_eq!(walker.first_child(), Some(id(&document, "a")));
+        // the children of a skipped node stand in for it among its siblings
+        assert_eq!(walker.next_sibling(), Some(id(&document, "b1")));
+        assert_eq!(walker.next_sibling(), Some(id(&document, "b2")));
+        assert_eq!(walker.next_sibling(), Some(id(&document, "c")));
+        assert_eq!(walker.next_sibling(), None);
+        assert_eq!(walker.previous_sibling(), Some(id(&document, "b2")));
+        // and their parent is the one above the skipped node
+        assert_eq!(walker.parent_node(), Some(id(&document, "r")));
+        assert_eq!(walker.parent_node(), None);
+        assert_eq!(walker.current_node(), id(&document, "r"));
+        assert_eq!(walker.last_child(), Some(id(&document, "c")));
+        walker.set_current_node(id(&document, "b"));
+        assert_eq!(walker.first_child(), Some(id(&document, "b1")));
+        let mut reject = self::walker(&document, FilterResult::Reject);
+        reject.first_child();
+        assert_eq!(reject.next_sibling(), Some(id(&document, "c")));
+    }
+
+    #[test]
+    fn walker_stays_inside_its_root() {
+        let document = document();
+        let a = id(&document, "a");
+        let mut walker = TreeWalker::new(document.tree(), a, WhatToShow::ALL);
+        assert_eq!(walker.next_sibling(), None);
+        assert_eq!(walker.parent_node(), None);
+        let below: Vec<_> = std::iter::from_fn(|| walker.next_node()).collect();
+        assert_eq!(below.len(), 2);
+        assert!(document.tree().element(below[1]).is_none());
+        assert_eq!(walker.next_node(), None);
+        assert_eq!(walker.previous_node(), Some(id(&document, "a1")));
+        assert_eq!(walker.previous_node(), Some(a));
+        assert_eq!(walker.previous_node(), None);
+    }
+
+    #[test]
+    fn iterator_treats_reject_as_skip() {
+        let document = document();
+        let root = id(&document, "r");
+        for result in [FilterResult::Skip, FilterResult::Reject] {
+            let iterator = NodeIterator::new(document.tree(), root, WhatToShow::ELEMENT)
+                .with_filter(on_b(result));
+            assert_eq!(ids(&document, iterator), ["r", "a", "a1", "b1", "b2", "c"]);
+        }
+    }
+
+    #[test]
+    fn iterator_returns_the_reference_again_when_turning_around() {
+        let document = document();
+        let root = id(&document, "r");
+        let mut iterator = NodeIterator::new(document.tree(), root, WhatToShow::ELEMENT);
+        assert_eq!(iterator.previous_node(), None);
+        assert_eq!(iterator.next_node(), Some(root));
+        assert_eq!(iterator.next_node(), Some(id(&document, "a")));
+        assert!(!iterator.pointer_before_reference_node());
+        assert_eq!(iterator.previous_node(), Some(id(&document, "a")));
+        assert!(iterator.pointer_before_reference_node());
+        assert_eq!(iterator.previous_node(), Some(root));
+        assert_eq!(iterator.previous_node(), None);
+        let rest: Vec<_> = std::iter::from_fn(|| iterator.next_node()).collect();
+        assert_eq!(ids(&document, rest), ["r", "a", "a1", "b", "b1", "b2", "c"]);
+        assert_eq!(iterator.reference_node(), id(&document, "c"));
+    }
+}